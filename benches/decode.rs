@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use json_stream_compressor::encoded_data::EncodedData;
+use std::collections::HashMap;
+
+fn string_heavy_document() -> Vec<u8> {
+    let list = (0..1000)
+        .map(|i| {
+            let mut map = HashMap::new();
+            map.insert(
+                "name".to_string(),
+                EncodedData::String(format!("user number {}", i)),
+            );
+            map.insert(
+                "description".to_string(),
+                EncodedData::String("a fairly long description string ".repeat(4)),
+            );
+            EncodedData::Object(map)
+        })
+        .collect();
+    EncodedData::Array(list).encode()
+}
+
+// Many short strings, pure ASCII or not.
+fn short_strings_document(s: &str) -> Vec<u8> {
+    let list = (0..10_000)
+        .map(|i| EncodedData::String(format!("{}{:05}", s, i)))
+        .collect();
+    EncodedData::Array(list).encode()
+}
+
+fn decode(c: &mut Criterion) {
+    let data = string_heavy_document();
+    c.bench_function("decode owned", |b| {
+        b.iter(|| EncodedData::decode(&data).unwrap())
+    });
+    c.bench_function("decode borrowed", |b| {
+        b.iter(|| EncodedData::decode_borrowed(&data).unwrap())
+    });
+    let ascii = short_strings_document("status-");
+    c.bench_function("decode short ascii strings", |b| {
+        b.iter(|| EncodedData::decode(&ascii).unwrap())
+    });
+    let non_ascii = short_strings_document("état-");
+    c.bench_function("decode short non ascii strings", |b| {
+        b.iter(|| EncodedData::decode(&non_ascii).unwrap())
+    });
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use json_stream_compressor::encoded_data::EncodedData;
+
+// Small records, as a server would encode one per request.
+fn small_record() -> EncodedData {
+    EncodedData::from(serde_json::json!({
+        "id": 1234,
+        "status": "active",
+        "score": 0.75,
+        "tags": ["a", "b"],
+    }))
+}
+
+fn encode(c: &mut Criterion) {
+    let record = small_record();
+    c.bench_function("encode small record", |b| b.iter(|| record.encode()));
+    c.bench_function("encode_pooled small record", |b| {
+        b.iter(|| record.encode_pooled())
+    });
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+#![no_main]
+// Arbitrary bytes must be rejected with an error, never read out of bounds (caught by the
+// sanitizer) or panic.
+use json_stream_compressor::encoded_data::{DecodeOptions, EncodedData};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let decoded = EncodedData::decode(data);
+    assert_eq!(
+        EncodedData::validate(data).ok(),
+        decoded.as_ref().ok().map(|(_, size)| *size)
+    );
+    let _ = EncodedData::skip(data);
+    let _ = EncodedData::decode_resolved(data);
+    let _ = EncodedData::decode_with_options(
+        data,
+        &DecodeOptions {
+            lossy_utf8: true,
+            skip_unknown: true,
+            ordered_objects: true,
+            ..Default::default()
+        },
+    );
+});
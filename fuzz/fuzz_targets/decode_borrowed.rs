@@ -0,0 +1,13 @@
+#![no_main]
+// Borrowed decoding must reject arbitrary bytes with an error, never panic, and read as many bytes
+// as decode for what both accept.
+use json_stream_compressor::encoded_data::EncodedData;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, size)) = EncodedData::decode_borrowed(data) {
+        if let Ok((_, decoded_size)) = EncodedData::decode(data) {
+            assert_eq!(size, decoded_size);
+        }
+    }
+});
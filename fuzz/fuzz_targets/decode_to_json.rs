@@ -0,0 +1,10 @@
+#![no_main]
+// Arbitrary bytes must be rejected with an error, never panic, including the aliases and values
+// that JSON cannot represent.
+use json_stream_compressor::codec;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = codec::decode_to_json(data);
+    let _ = codec::from_bytes(data);
+});
@@ -0,0 +1,31 @@
+#![no_main]
+// Arbitrary bytes must be rejected with an error under any schema, never panic.
+use json_stream_compressor::encoded_data::EncodedData;
+use json_stream_compressor::schema::{Schema, SchemaOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let fields = [
+        ("id", Schema::Integer),
+        ("price", Schema::Fixed(2)),
+        ("kind", Schema::Enum(&["a", "b"])),
+        ("tags", Schema::Array(Box::new(Schema::String))),
+        ("any", Schema::Any),
+    ];
+    let schemas = [
+        Schema::Any,
+        Schema::Number,
+        Schema::Array(Box::new(Schema::Fixed(3))),
+        Schema::Object(&fields),
+    ];
+    for schema in schemas.iter() {
+        let _ = EncodedData::decode_with_schema(data, schema);
+        let _ = EncodedData::decode_with_schema_options(
+            data,
+            schema,
+            &SchemaOptions {
+                deny_unknown_fields: true,
+            },
+        );
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+// Arbitrary bytes must end the events with an error, never panic, and read the values decode
+// accepts to the end.
+use json_stream_compressor::encoded_data::EncodedData;
+use json_stream_compressor::event_reader::EventReader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = EventReader::new(data);
+    let failed = reader.by_ref().any(|event| event.is_err());
+    if EncodedData::validate(data) == Ok(data.len()) {
+        assert!(!failed);
+        assert_eq!(reader.position(), data.len());
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+// The first byte is the length of the pointer following it, the rest the value to patch. Patching
+// must fail with an error or give a value as valid as the original, never panic.
+use json_stream_compressor::encoded_data::{EncodedData, EncodedSpecial};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (length, data) = match data.split_first() {
+        Some((length, data)) => (*length as usize, data),
+        None => return,
+    };
+    let (pointer, data) = data.split_at(length.min(data.len()));
+    let pointer = String::from_utf8_lossy(pointer);
+    let new_value = EncodedData::Special(EncodedSpecial::Null);
+    if let Ok(patched) = EncodedData::patch_pointer(data, &pointer, &new_value) {
+        if EncodedData::validate(data).is_ok() {
+            EncodedData::validate(&patched).expect("patched value must validate");
+        }
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+// Whatever decodes must encode back to bytes decoding to the same value.
+use json_stream_compressor::encoded_data::EncodedData;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((o, _)) = EncodedData::decode(data) {
+        let data = o.encode();
+        let (decoded, size) = EncodedData::decode(&data).expect("re-encoded value must decode");
+        assert_eq!(size, data.len());
+        assert!(decoded.eq_logical(&o));
+        assert_eq!(decoded.encode().len(), data.len());
+    }
+});
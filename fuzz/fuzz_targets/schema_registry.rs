@@ -0,0 +1,12 @@
+#![no_main]
+// Arbitrary bytes, read as a registry header followed by a value, must be rejected with an error,
+// never panic.
+use json_stream_compressor::schema_registry::SchemaRegistry;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = SchemaRegistry::new().decode(data);
+    if let Ok((registry, size)) = SchemaRegistry::decode_header(data) {
+        let _ = registry.decode(&data[size..]);
+    }
+});
@@ -0,0 +1,33 @@
+#![no_main]
+// The first byte is the size of the chunks the rest is fed in. However it is split, the stream
+// must decode to the values it decodes to when fed at once, without panicking.
+use json_stream_compressor::stream_compressor::{NextValue, StreamDecoder};
+use libfuzzer_sys::fuzz_target;
+
+// The encodings of the values decoded until the first error, NaN floats not comparing equal.
+fn values(chunks: std::slice::Chunks<'_, u8>) -> Vec<Vec<u8>> {
+    let mut decoder = StreamDecoder::new();
+    let mut ret = vec![];
+    for chunk in chunks {
+        decoder.feed(chunk);
+        loop {
+            match decoder.next_value() {
+                Ok(NextValue::Value(o)) => ret.push(o.encode()),
+                Ok(_) => break,
+                Err(_) => return ret,
+            }
+        }
+    }
+    ret
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (chunk_size, data) = match data.split_first() {
+        Some((chunk_size, data)) => (*chunk_size as usize + 1, data),
+        None => return,
+    };
+    assert_eq!(
+        values(data.chunks(chunk_size)),
+        values(data.chunks(data.len().max(1)))
+    );
+});
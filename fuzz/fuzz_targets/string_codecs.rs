@@ -0,0 +1,15 @@
+#![no_main]
+// Arbitrary bytes must be rejected with an error by the default codecs, never panic, and whatever
+// decodes must encode back to the same value.
+use json_stream_compressor::string_codec::StringCodecs;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let codecs = StringCodecs::with_defaults();
+    if let Ok((o, _)) = codecs.decode(data) {
+        let data = codecs.encode(&o);
+        let (decoded, size) = codecs.decode(&data).expect("re-encoded value must decode");
+        assert_eq!(size, data.len());
+        assert!(decoded.eq_logical(&o));
+    }
+});
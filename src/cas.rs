@@ -0,0 +1,168 @@
+use crate::encoded_data::{DecodeError, EncodedData};
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+// Key of a value in a Cas.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Hash(pub u64);
+
+// Content addressable store: each value is stored once, as its canonical encoding (the one
+// EncodedData::decode_hashing hashes: default options, sorted keys, minimal integers), under the
+// 64 bits FNV-1a hash of that encoding. Logically equal values encoded differently share a single
+// entry, and a value gets the same key in every store, whatever the Rust release.
+#[derive(Debug, Default)]
+pub struct Cas {
+    entries: HashMap<Hash, Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CasError {
+    // Another value is stored under the hash of the one put.
+    Collision(Hash),
+    Decode(DecodeError),
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Collects what write_canonical feeds its hasher, i.e. the canonical encoding.
+struct Canonical(Vec<u8>);
+
+impl Hasher for Canonical {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        0
+    }
+}
+
+impl Cas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stores `value` unless an equal one already is, and returns its key. A value whose hash is
+    // the key of a different one is not stored.
+    pub fn put(&mut self, value: &EncodedData) -> Result<Hash, CasError> {
+        let mut canonical = Canonical(vec![]);
+        value.write_canonical(&mut canonical);
+        let bytes = canonical.0;
+        let hash = Hash(fnv1a(&bytes));
+        match self.entries.get(&hash) {
+            Some(stored) if *stored != bytes => return Err(CasError::Collision(hash)),
+            Some(_) => {}
+            None => {
+                self.entries.insert(hash, bytes);
+            }
+        }
+        Ok(hash)
+    }
+
+    pub fn get(&self, hash: Hash) -> Result<Option<EncodedData>, DecodeError> {
+        self.get_bytes(hash)
+            .map(|bytes| EncodedData::decode(bytes).map(|(o, _)| o))
+            .transpose()
+    }
+
+    // The canonical encoding stored under `hash`.
+    pub fn get_bytes(&self, hash: Hash) -> Option<&[u8]> {
+        self.entries.get(&hash).map(Vec::as_slice)
+    }
+
+    // Same as put, for an encoded value. Returns the key and the size of the value in `data`.
+    pub fn put_encoded(&mut self, data: &[u8]) -> Result<(Hash, usize), CasError> {
+        let (o, size) = EncodedData::decode(data).map_err(CasError::Decode)?;
+        Ok((self.put(&o)?, size))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::define::data_type;
+    use crate::encoded_data::{EncodeOptions, EncodedInteger};
+
+    #[test]
+    fn dedup() {
+        let value = EncodedData::from(serde_json::json!({
+            "bools": vec![true; 20],
+            "n": 5,
+            "nested": {"b": 1, "a": 2},
+        }));
+        let packed = value.encode_with_options(&EncodeOptions {
+            sized_containers: true,
+            packed_arrays: true,
+            ..Default::default()
+        });
+        assert_ne!(packed, value.encode());
+
+        let mut cas = Cas::new();
+        assert!(cas.is_empty());
+        let hash = cas.put(&value).unwrap();
+        assert_eq!(cas.put_encoded(&packed), Ok((hash, packed.len())));
+        assert_eq!(cas.len(), 1);
+        assert_eq!(cas.get(hash), Ok(Some(value.clone())));
+        assert_eq!(cas.get_bytes(hash), Some(&value.encode()[..]));
+
+        // Key order and integer widths do not matter either.
+        let ordered = EncodedData::OrderedObject(vec![
+            (
+                "b".to_string(),
+                EncodedData::Integer(EncodedInteger::Positive(1)),
+            ),
+            (
+                "a".to_string(),
+                EncodedData::Integer(EncodedInteger::Positive(2)),
+            ),
+        ]);
+        let nested = EncodedData::from(serde_json::json!({"a": 2, "b": 1}));
+        assert_eq!(cas.put(&ordered), cas.put(&nested));
+        let wide = [data_type::INTEGER << 5 | 4, 5, 0, 0, 0];
+        let n = EncodedData::Integer(EncodedInteger::Positive(5));
+        let hash = cas.put(&n).unwrap();
+        assert_eq!(cas.put_encoded(&wide), Ok((hash, wide.len())));
+        assert_eq!(cas.len(), 3);
+        // The key only depends on the value.
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash, Hash(fnv1a(&n.encode())));
+        assert_eq!(Cas::new().put(&n), Ok(hash));
+
+        let other = cas.put(&EncodedData::from(serde_json::json!(6))).unwrap();
+        assert_ne!(other, hash);
+        assert_eq!(cas.len(), 4);
+        assert_eq!(cas.get(Hash(other.0 ^ 1)), Ok(None));
+        assert!(matches!(
+            cas.put_encoded(&wide[..2]),
+            Err(CasError::Decode(DecodeError::MissingBytes(_)))
+        ));
+    }
+
+    #[test]
+    fn bad_entries() {
+        let mut cas = Cas::new();
+        let n = EncodedData::from(serde_json::json!(5));
+        let hash = cas.put(&n).unwrap();
+        // Standing for a colliding value, and for a corrupted entry.
+        let other = EncodedData::from(serde_json::json!(6));
+        cas.entries.insert(Hash(fnv1a(&other.encode())), vec![0xFF]);
+        assert_eq!(
+            cas.put(&other),
+            Err(CasError::Collision(Hash(fnv1a(&other.encode()))))
+        );
+        assert!(cas.get(Hash(fnv1a(&other.encode()))).is_err());
+        assert_eq!(cas.get(hash), Ok(Some(n)));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::encoded_data::EncodedData;
+use crate::stream_compressor::{Conf, StreamCompressor, StreamDecoder};
+use crate::varint;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+// Framed stream format: every JSON value of the input is compressed by a single StreamCompressor
+// and written as [varint length][compressed value].
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Reads JSON values (whitespace separated, e.g. NDJSON) from `r` until its end. Returns the number
+// of values written.
+pub fn compress_stream<R: Read, W: Write>(r: R, w: &mut W) -> io::Result<usize> {
+    let mut compressor = StreamCompressor::new(Conf::default());
+    let mut nb = 0;
+    for value in serde_json::Deserializer::from_reader(r).into_iter::<serde_json::Value>() {
+        let value = value.map_err(|e| {
+            if e.is_io() {
+                io::Error::other(e.to_string())
+            } else {
+                invalid_data(e)
+            }
+        })?;
+        let data = compressor.compress(&EncodedData::from(value));
+        w.write_all(&varint::encode(data.len() as u64))?;
+        w.write_all(&data)?;
+        nb += 1;
+    }
+    w.flush()?;
+    Ok(nb)
+}
+
+// Writes every decompressed value as one NDJSON line. Returns the number of values written.
+pub fn decompress_stream<R: Read, W: Write>(mut r: R, w: &mut W) -> io::Result<usize> {
+    let mut decoder = StreamDecoder::new();
+    let mut nb = 0;
+    loop {
+        let mut first = [0u8; 1];
+        if r.read(&mut first)? == 0 {
+            break;
+        }
+        let length = varint::read_from(&mut (&first[..]).chain(&mut r))?;
+        // Grown as bytes arrive rather than allocated from the untrusted length.
+        let mut data = vec![];
+        (&mut r).take(length).read_to_end(&mut data)?;
+        if (data.len() as u64) < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated record",
+            ));
+        }
+        let (o, size) = decoder
+            .decompress(&data)
+            .map_err(|e| invalid_data(format!("{:?}", e)))?;
+        if size != data.len() {
+            return Err(invalid_data("trailing bytes in record"));
+        }
+        let value: serde_json::Value =
+            o.try_into().map_err(|e| invalid_data(format!("{:?}", e)))?;
+        serde_json::to_writer(&mut *w, &value)?;
+        w.write_all(b"\n")?;
+        nb += 1;
+    }
+    w.flush()?;
+    Ok(nb)
+}
+
+// Entry point for a thin binary: `tool compress` or `tool decompress`, from stdin to stdout.
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    match std::env::args().nth(1).as_deref() {
+        Some("compress") => compress_stream(stdin.lock(), &mut out).map(|_| ()),
+        Some("decompress") => decompress_stream(stdin.lock(), &mut out).map(|_| ()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: <compress|decompress> < input > output",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let input = concat!(
+            "{\"name\": \"sensor\", \"value\": 1}\n",
+            "{\"name\": \"sensor\", \"value\": -2.5}\n",
+            "\n",
+            "[null, true, \"sensor\"]\n",
+        );
+        let mut compressed = vec![];
+        assert_eq!(
+            compress_stream(input.as_bytes(), &mut compressed).unwrap(),
+            3
+        );
+
+        let mut output = vec![];
+        assert_eq!(decompress_stream(&compressed[..], &mut output).unwrap(), 3);
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let expected: Vec<serde_json::Value> = input
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines, expected);
+
+        // The string repeated across records is aliased.
+        let mut separate = 0;
+        for line in input.lines().filter(|l| !l.is_empty()) {
+            let mut out = vec![];
+            compress_stream(line.as_bytes(), &mut out).unwrap();
+            separate += out.len();
+        }
+        assert!(compressed.len() < separate);
+
+        let mut output = vec![];
+        assert_eq!(
+            decompress_stream(&compressed[..compressed.len() - 1], &mut output)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        // A record length far beyond the input fails without allocating it.
+        let huge = [varint::encode(u64::MAX >> 1), vec![0x01; 4]].concat();
+        assert_eq!(
+            decompress_stream(&huge[..], &mut vec![])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            compress_stream("{\"a\": ".as_bytes(), &mut vec![])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,553 @@
+use crate::define::{data_type, special_type};
+use crate::encoded_data::{self, DecodeError, EncodedData, EncodedDataToJsonError};
+use serde::Deserialize;
+use std::convert::TryInto;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CodecError {
+    BadFormat(encoded_data::DecodeError),
+    TrailingBytes(usize),
+    Json(EncodedDataToJsonError),
+    BadJson(String),
+    // YAML, TOML, JSON5 or BSON that could not be parsed or rendered.
+    BadDocument(String),
+    BadGzip(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompressionStats {
+    pub json_len: usize,
+    pub compressed_len: usize,
+    // compressed_len / json_len
+    pub ratio: f64,
+}
+
+pub fn to_bytes(value: &serde_json::Value) -> Vec<u8> {
+    EncodedData::from(value.clone()).encode()
+}
+
+pub fn to_bytes_with_stats(value: &serde_json::Value) -> (Vec<u8>, CompressionStats) {
+    let bytes = to_bytes(value);
+    let json_len = serde_json::to_vec(value).unwrap().len();
+    let stats = CompressionStats {
+        json_len,
+        compressed_len: bytes.len(),
+        ratio: bytes.len() as f64 / json_len as f64,
+    };
+    (bytes, stats)
+}
+
+// Same output as to_bytes(&serde_json::from_str(json)?), without building the serde_json::Value.
+pub fn compress_str(json: &str) -> Result<Vec<u8>, CodecError> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let data = EncodedData::deserialize(&mut deserializer)
+        .map_err(|e| CodecError::BadJson(e.to_string()))?;
+    deserializer
+        .end()
+        .map_err(|e| CodecError::BadJson(e.to_string()))?;
+    Ok(data.encode())
+}
+
+pub fn from_bytes(data: &[u8]) -> Result<serde_json::Value, CodecError> {
+    let (decoded, size) = EncodedData::decode(data).map_err(CodecError::BadFormat)?;
+    if size != data.len() {
+        return Err(CodecError::TrailingBytes(data.len() - size));
+    }
+    decoded.try_into().map_err(CodecError::Json)
+}
+
+// YAML and TOML documents go through serde_json::Value, so they are limited to what JSON can
+// represent (e.g. YAML tags are dropped and TOML dates become strings).
+#[cfg(feature = "yaml")]
+pub fn from_yaml(yaml: &str) -> Result<Vec<u8>, CodecError> {
+    let value: serde_json::Value =
+        serde_yaml::from_str(yaml).map_err(|e| CodecError::BadDocument(e.to_string()))?;
+    Ok(to_bytes(&value))
+}
+
+#[cfg(feature = "yaml")]
+pub fn to_yaml(data: &[u8]) -> Result<String, CodecError> {
+    serde_yaml::to_string(&from_bytes(data)?).map_err(|e| CodecError::BadDocument(e.to_string()))
+}
+
+#[cfg(feature = "toml")]
+pub fn from_toml(toml: &str) -> Result<Vec<u8>, CodecError> {
+    let value: serde_json::Value =
+        toml::from_str(toml).map_err(|e| CodecError::BadDocument(e.to_string()))?;
+    Ok(to_bytes(&value))
+}
+
+// Fails on values TOML has no representation for, such as null or a top level array.
+#[cfg(feature = "toml")]
+pub fn to_toml(data: &[u8]) -> Result<String, CodecError> {
+    toml::to_string(&from_bytes(data)?).map_err(|e| CodecError::BadDocument(e.to_string()))
+}
+
+// JSON5 (comments, trailing commas, unquoted keys...) in, decoded as plain JSON by from_bytes.
+#[cfg(feature = "json5")]
+pub fn from_json5(json5: &str) -> Result<Vec<u8>, CodecError> {
+    let value: serde_json::Value =
+        json5::from_str(json5).map_err(|e| CodecError::BadDocument(e.to_string()))?;
+    Ok(to_bytes(&value))
+}
+
+// BSON document to the nearest codec values: ObjectIds become their hex string, dates their
+// milliseconds since the epoch. The types without a close match (binary, regex, decimal128...)
+// keep their relaxed extended JSON form, e.g. {"$binary": {...}}.
+#[cfg(feature = "bson")]
+pub fn from_bson(bson: &[u8]) -> Result<EncodedData, CodecError> {
+    let document =
+        bson::Document::from_reader(bson).map_err(|e| CodecError::BadDocument(e.to_string()))?;
+    Ok(bson_to_data(bson::Bson::Document(document)))
+}
+
+#[cfg(feature = "bson")]
+fn bson_to_data(value: bson::Bson) -> EncodedData {
+    use crate::encoded_data::{EncodedInteger, EncodedSpecial};
+    use bson::Bson;
+    let integer = |n: i64| {
+        EncodedData::Integer(if n < 0 {
+            EncodedInteger::Negative(n.unsigned_abs())
+        } else {
+            EncodedInteger::Positive(n as u64)
+        })
+    };
+    match value {
+        Bson::Null | Bson::Undefined => EncodedData::Special(EncodedSpecial::Null),
+        Bson::Boolean(b) => EncodedData::Integer(EncodedInteger::Bool(b)),
+        Bson::Int32(n) => integer(n as i64),
+        Bson::Int64(n) => integer(n),
+        Bson::Double(f) => EncodedData::Float(f),
+        Bson::String(s) => EncodedData::String(s),
+        Bson::ObjectId(id) => EncodedData::String(id.to_hex()),
+        Bson::DateTime(date) => integer(date.timestamp_millis()),
+        Bson::Array(list) => EncodedData::Array(list.into_iter().map(bson_to_data).collect()),
+        Bson::Document(document) => EncodedData::Object(
+            document
+                .into_iter()
+                .map(|(k, o)| (k, bson_to_data(o)))
+                .collect(),
+        ),
+        o => EncodedData::from(o.into_relaxed_extjson()),
+    }
+}
+
+// to_bytes followed by gzip. This mostly helps with long or repeated strings: the codec already
+// removes the JSON syntax and shortens numbers, so small documents of numbers may even grow.
+#[cfg(feature = "flate2")]
+pub fn to_bytes_gzip(value: &serde_json::Value) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&to_bytes(value)).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(feature = "flate2")]
+pub fn from_bytes_gzip(data: &[u8]) -> Result<serde_json::Value, CodecError> {
+    use std::io::Read;
+    let mut bytes = vec![];
+    flate2::read::GzDecoder::new(data)
+        .read_to_end(&mut bytes)
+        .map_err(|e| CodecError::BadGzip(e.to_string()))?;
+    from_bytes(&bytes)
+}
+
+// Same as decoding with EncodedData::decode_resolved and converting to JSON, but containers are
+// built as serde_json::Value directly.
+pub fn decode_to_json(data: &[u8]) -> Result<(serde_json::Value, usize), CodecError> {
+    decode_json(data, &mut vec![], encoded_data::MAX_DEPTH)
+}
+
+// Makes the DecodeError offsets of decoding `data[by..]` relative to `data`.
+fn shifted(by: usize) -> impl Fn(CodecError) -> CodecError {
+    move |e| match e {
+        CodecError::BadFormat(e) => CodecError::BadFormat(e.shifted(by)),
+        e => e,
+    }
+}
+
+// decode_to_json of a value allowed `depth` more levels of nesting.
+fn decode_json(
+    data: &[u8],
+    aliases: &mut Vec<serde_json::Value>,
+    depth: usize,
+) -> Result<(serde_json::Value, usize), CodecError> {
+    let bad_format = CodecError::BadFormat;
+    let depth = depth
+        .checked_sub(1)
+        .ok_or(bad_format(DecodeError::TooDeep))?;
+    let ctrl = match data.first() {
+        Some(ctrl) => *ctrl,
+        None => return Err(bad_format(DecodeError::MissingBytes(1))),
+    };
+    Ok(match ctrl >> 5 {
+        data_type::ARRAY => {
+            let (length, mut tot_size) =
+                encoded_data::decode_data_type_length(data).map_err(bad_format)?;
+            let mut list = vec![];
+            for _ in 0..length {
+                let (o, size) =
+                    decode_json(&data[tot_size..], aliases, depth).map_err(shifted(tot_size))?;
+                list.push(o);
+                tot_size += size;
+            }
+            (serde_json::Value::Array(list), tot_size)
+        }
+        data_type::OBJECT => {
+            let (length, mut tot_size) =
+                encoded_data::decode_data_type_length(data).map_err(bad_format)?;
+            let mut map = serde_json::Map::new();
+            for _ in 0..length {
+                let (k_length, size) =
+                    encoded_data::decode_varint(&data[tot_size..]).map_err(bad_format)?;
+                let k_length = k_length as usize;
+                tot_size += size;
+                if data.len() - tot_size < k_length {
+                    return Err(bad_format(DecodeError::MissingBytes(
+                        k_length - (data.len() - tot_size),
+                    )));
+                }
+                let k =
+                    std::str::from_utf8(&data[tot_size..tot_size + k_length]).map_err(|error| {
+                        bad_format(DecodeError::BadUtf8 {
+                            offset: tot_size + error.valid_up_to(),
+                            error,
+                        })
+                    })?;
+                tot_size += k_length;
+                let (o, size) =
+                    decode_json(&data[tot_size..], aliases, depth).map_err(shifted(tot_size))?;
+                map.insert(k.to_string(), o);
+                tot_size += size;
+            }
+            (serde_json::Value::Object(map), tot_size)
+        }
+        data_type::SPECIAL if ctrl & 0x1F == special_type::DEFINE => {
+            let (o, size) = decode_json(&data[1..], aliases, depth).map_err(shifted(1))?;
+            aliases.push(o.clone());
+            (o, 1 + size)
+        }
+        data_type::SPECIAL if ctrl & 0x1F == special_type::EXTEND => {
+            let (base, size) = decode_json(&data[1..], aliases, depth).map_err(shifted(1))?;
+            let start = 1 + size;
+            let (tail, size) =
+                decode_json(&data[start..], aliases, depth).map_err(shifted(start))?;
+            match (base, tail) {
+                (serde_json::Value::Object(mut map), serde_json::Value::Object(tail)) => {
+                    map.extend(tail);
+                    (serde_json::Value::Object(map), start + size)
+                }
+                (base, tail) => {
+                    let o = if base.is_object() { tail } else { base };
+                    let kind = crate::schema::kind(&EncodedData::from(o));
+                    return Err(bad_format(DecodeError::BadExtend(kind)));
+                }
+            }
+        }
+        data_type::SPECIAL if ctrl & 0x1F == special_type::NULL_RUNS => {
+            let (nb_values, mut tot_size) =
+                encoded_data::decode_varint(&data[1..]).map_err(bad_format)?;
+            tot_size += 1;
+            let mut list = vec![];
+            let push_nulls = |list: &mut Vec<serde_json::Value>, tot_size: &mut usize| {
+                let nb_nulls = encoded_data::decode_null_run(data, tot_size, list.len())
+                    .map_err(bad_format)?;
+                for _ in 0..nb_nulls {
+                    list.push(serde_json::Value::Null);
+                }
+                Ok(())
+            };
+            for _ in 0..nb_values {
+                push_nulls(&mut list, &mut tot_size)?;
+                let (o, size) =
+                    decode_json(&data[tot_size..], aliases, depth).map_err(shifted(tot_size))?;
+                list.push(o);
+                tot_size += size;
+            }
+            push_nulls(&mut list, &mut tot_size)?;
+            (serde_json::Value::Array(list), tot_size)
+        }
+        data_type::SPECIAL if ctrl & 0x1F == special_type::SIZED => {
+            let (length, size) = encoded_data::decode_varint(&data[1..]).map_err(bad_format)?;
+            let length = length as usize;
+            if data.len() - 1 - size < length {
+                return Err(bad_format(DecodeError::MissingBytes(
+                    length - (data.len() - 1 - size),
+                )));
+            }
+            let (o, o_size) = decode_json(&data[1 + size..1 + size + length], aliases, depth)
+                .map_err(|e| match e {
+                    CodecError::BadFormat(e) => CodecError::BadFormat(e.in_sized(length)),
+                    e => e,
+                })
+                .map_err(shifted(1 + size))?;
+            if o_size != length {
+                return Err(bad_format(DecodeError::BadSizedLength(length)));
+            }
+            (o, 1 + size + length)
+        }
+        // Scalars and packed arrays.
+        _ => {
+            let (o, size) = EncodedData::decode(data).map_err(bad_format)?;
+            let o = match o {
+                EncodedData::Alias(id) => match aliases.get(id as usize) {
+                    Some(o) => o.clone(),
+                    None => return Err(bad_format(DecodeError::UnknownAlias(id))),
+                },
+                o => o.try_into().map_err(CodecError::Json)?,
+            };
+            (o, size)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoded_data::{EncodedInteger, EncodedSpecial};
+    use serde_json::json;
+
+    #[test]
+    fn stats() {
+        let value = json!({
+            "name": "sensor",
+            "values": [1, 2, 3, 4, 5],
+            "enabled": true,
+        });
+        let (bytes, stats) = to_bytes_with_stats(&value);
+        assert_eq!(stats.compressed_len, bytes.len());
+        assert_eq!(stats.json_len, serde_json::to_vec(&value).unwrap().len());
+        assert!(stats.ratio < 1.0);
+        assert_eq!(from_bytes(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn compress_str() {
+        let json = r#"{
+            "name": "sensor",
+            "values": [1, -2, 3.5, null, true, "x"],
+            "nested": {"b": {"c": [], "d": {}}, "a": -9223372036854775808},
+            "big": 18446744073709551615
+        }"#;
+        let expected = to_bytes(&serde_json::from_str(json).unwrap());
+        assert_eq!(super::compress_str(json).unwrap(), expected);
+        assert!(matches!(
+            super::compress_str("{\"a\": 1"),
+            Err(CodecError::BadJson(_))
+        ));
+        assert!(matches!(
+            super::compress_str("1 2"),
+            Err(CodecError::BadJson(_))
+        ));
+    }
+
+    #[test]
+    fn decode_to_json() {
+        let values = [
+            json!(null),
+            json!(true),
+            json!(-12345),
+            json!(18446744073709551615u64),
+            json!(1.5),
+            json!("abc"),
+            json!("1234567890ABCDEF1234567890ABCDEF"),
+            json!([]),
+            json!({}),
+            json!([1, "a", [null, false], {"b": 2.5}]),
+            json!({"nested": {"array": [1, 2, 3], "s": "x"}, "n": -1}),
+        ];
+        for value in values.iter() {
+            let bytes = to_bytes(value);
+            assert_eq!(
+                super::decode_to_json(&bytes).unwrap(),
+                (from_bytes(&bytes).unwrap(), bytes.len())
+            );
+        }
+
+        let values = values.to_vec();
+        let batch_value = serde_json::Value::Array(values.clone());
+        let data = crate::stream_compressor::encode_batch(&[batch_value.clone(), batch_value]);
+        let (first, size) = super::decode_to_json(&data).unwrap();
+        assert_eq!(first, serde_json::Value::Array(values));
+        let (resolved, _) = EncodedData::decode_resolved(&data).unwrap();
+        let resolved: serde_json::Value = resolved.try_into().unwrap();
+        assert_eq!(first, resolved);
+        assert!(size < data.len());
+
+        assert_eq!(
+            super::decode_to_json(&EncodedData::Alias(0).encode()),
+            Err(CodecError::BadFormat(DecodeError::UnknownAlias(0)))
+        );
+        let bytes = to_bytes(&json!({"key": "value"}));
+        assert_eq!(
+            super::decode_to_json(&bytes[..4]),
+            Err(CodecError::BadFormat(DecodeError::MissingBytes(1)))
+        );
+
+        let mut deep = vec![data_type::ARRAY << 5 | 1; encoded_data::MAX_DEPTH];
+        deep.push(data_type::ARRAY << 5);
+        assert_eq!(
+            super::decode_to_json(&deep),
+            Err(CodecError::BadFormat(DecodeError::TooDeep))
+        );
+    }
+
+    #[test]
+    fn bare_scalars() {
+        let scalars = [
+            (
+                EncodedData::Integer(EncodedInteger::Positive(42)),
+                json!(42),
+            ),
+            (EncodedData::Integer(EncodedInteger::Negative(7)), json!(-7)),
+            (EncodedData::Float(-0.25), json!(-0.25)),
+            (EncodedData::String("bare".to_string()), json!("bare")),
+            (EncodedData::Special(EncodedSpecial::Null), json!(null)),
+        ];
+        for (o, value) in scalars.iter() {
+            let bytes = o.encode();
+            assert_eq!(bytes, to_bytes(value));
+            assert_eq!(EncodedData::decode(&bytes), Ok((o.clone(), bytes.len())));
+            assert_eq!(EncodedData::skip(&bytes), Ok(bytes.len()));
+            assert_eq!(
+                EncodedData::decode_borrowed(&bytes).map(|(o, size)| (o.into_owned(), size)),
+                Ok((o.clone(), bytes.len()))
+            );
+            assert_eq!(from_bytes(&bytes), Ok(value.clone()));
+            assert_eq!(
+                super::decode_to_json(&bytes),
+                Ok((value.clone(), bytes.len()))
+            );
+            assert_eq!(super::compress_str(&value.to_string()).as_ref(), Ok(&bytes));
+
+            let trailing = [bytes.clone(), vec![0]].concat();
+            assert_eq!(from_bytes(&trailing), Err(CodecError::TrailingBytes(1)));
+            assert_eq!(EncodedData::decode(&trailing), Ok((o.clone(), bytes.len())));
+        }
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml() {
+        let yaml = "name: sensor\nvalues:\n  - 1\n  - -2.5\n  - null\nnested:\n  enabled: true\n";
+        let bytes = from_yaml(yaml).unwrap();
+        let expected = json!({
+            "name": "sensor",
+            "values": [1, -2.5, null],
+            "nested": {"enabled": true},
+        });
+        assert_eq!(from_bytes(&bytes).unwrap(), expected);
+        let rendered = to_yaml(&bytes).unwrap();
+        assert_eq!(from_yaml(&rendered).unwrap(), bytes);
+        assert!(matches!(
+            from_yaml("a: [1"),
+            Err(CodecError::BadDocument(_))
+        ));
+    }
+
+    #[cfg(feature = "bson")]
+    #[test]
+    fn bson() {
+        use crate::stream_compressor::{Conf, StreamCompressor, StreamDecoder};
+        let id = bson::oid::ObjectId::parse_str("65a1b2c3d4e5f60718293a4b").unwrap();
+        let document = bson::doc! {
+            "_id": id,
+            "name": "sensor",
+            "count": 3i64,
+            "ratio": -0.5,
+            "created": bson::DateTime::from_millis(1_700_000_000_000),
+            "tags": ["a", bson::Bson::Null, 7],
+            "nested": {"enabled": true, "low": -2},
+        };
+        let mut bytes = vec![];
+        document.to_writer(&mut bytes).unwrap();
+
+        let value = from_bson(&bytes).unwrap();
+        let expected = EncodedData::from(serde_json::json!({
+            "_id": "65a1b2c3d4e5f60718293a4b",
+            "name": "sensor",
+            "count": 3,
+            "ratio": -0.5,
+            "created": 1_700_000_000_000u64,
+            "tags": ["a", null, 7],
+            "nested": {"enabled": true, "low": -2},
+        }));
+        assert_eq!(value, expected);
+
+        let data = StreamCompressor::new(Conf::default()).compress(&value);
+        assert!(data.len() < bytes.len());
+        assert_eq!(
+            StreamDecoder::new().decompress(&data),
+            Ok((value, data.len()))
+        );
+        assert!(matches!(
+            from_bson(&bytes[..bytes.len() - 1]),
+            Err(CodecError::BadDocument(_))
+        ));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml() {
+        let toml = "name = \"sensor\"\nvalues = [1, 2]\n\n[nested]\nratio = 0.5\n";
+        let bytes = from_toml(toml).unwrap();
+        let expected = json!({
+            "name": "sensor",
+            "values": [1, 2],
+            "nested": {"ratio": 0.5},
+        });
+        assert_eq!(from_bytes(&bytes).unwrap(), expected);
+        let rendered = to_toml(&bytes).unwrap();
+        assert_eq!(from_toml(&rendered).unwrap(), bytes);
+        assert!(matches!(
+            to_toml(&to_bytes(&json!([1]))),
+            Err(CodecError::BadDocument(_))
+        ));
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn gzip() {
+        let value = serde_json::Value::Array(
+            (0..200)
+                .map(|i| json!({"event": "connection-established", "id": i % 7}))
+                .collect(),
+        );
+        let data = to_bytes_gzip(&value);
+        assert_eq!(from_bytes_gzip(&data).unwrap(), value);
+        assert!(data.len() * 4 < to_bytes(&value).len());
+
+        assert!(matches!(
+            from_bytes_gzip(&to_bytes(&value)),
+            Err(CodecError::BadGzip(_))
+        ));
+        assert!(matches!(
+            from_bytes_gzip(&data[..data.len() / 2]),
+            Err(CodecError::BadGzip(_))
+        ));
+    }
+
+    #[cfg(feature = "json5")]
+    #[test]
+    fn json5() {
+        let json5 = "{
+            // Service settings.
+            name: 'gateway',
+            ports: [80, 443,],
+            /* Disabled for now. */
+            tls: {enabled: false, ciphers: null},
+        }";
+        let bytes = from_json5(json5).unwrap();
+        let expected = json!({
+            "name": "gateway",
+            "ports": [80, 443],
+            "tls": {"enabled": false, "ciphers": null},
+        });
+        assert_eq!(from_bytes(&bytes).unwrap(), expected);
+        assert_eq!(bytes, to_bytes(&expected));
+        assert!(matches!(
+            from_json5("{a: 1,, }"),
+            Err(CodecError::BadDocument(_))
+        ));
+    }
+}
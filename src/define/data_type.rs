@@ -6,8 +6,7 @@ pub const ARRAY: u8 = 4;
 pub const OBJECT: u8 = 5;
 pub const ALIAS: u8 = 6;
 
-#[repr(u8)]
-pub enum DataType {
+byte_enum!(DataType {
     Special = SPECIAL,
     // TODO first size bit is sign
     // TODO size: 0, sign: 0 => boolean
@@ -19,18 +18,28 @@ pub enum DataType {
     Array = ARRAY,
     Object = OBJECT,
     Alias = ALIAS,
-}
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl DataType {
-    pub fn from(n: u8) -> Option<Self> {
-        Some(match n {
-            SPECIAL => Self::Special,
-            INTEGER => Self::Integer,
-            FLOAT => Self::Float,
-            STRING => Self::String,
-            ARRAY => Self::Array,
-            OBJECT => Self::Object,
-            _ => return None,
-        })
+    #[test]
+    fn from() {
+        assert_eq!(
+            (0..=7).filter_map(DataType::from).collect::<Vec<_>>(),
+            vec![
+                DataType::Special,
+                DataType::Integer,
+                DataType::Float,
+                DataType::String,
+                DataType::Array,
+                DataType::Object,
+                DataType::Alias,
+            ]
+        );
+        for t in (0..=7).filter_map(DataType::from) {
+            assert_eq!(DataType::from(t as u8), Some(t));
+        }
     }
 }
@@ -1,2 +1,23 @@
+// Declares a #[repr(u8)] enum from its `Variant = CONSTANT` list, with the matching `from`, so
+// that a variant can't be left out of the decoding.
+macro_rules! byte_enum {
+    ($name:ident { $($(#[$meta:meta])* $variant:ident = $value:ident,)* }) => {
+        #[repr(u8)]
+        #[derive(Debug, PartialEq, Clone, Copy)]
+        pub enum $name {
+            $($(#[$meta])* $variant = $value,)*
+        }
+
+        impl $name {
+            pub fn from(n: u8) -> Option<Self> {
+                match n {
+                    $($value => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
 pub mod data_type;
 pub mod special_type;
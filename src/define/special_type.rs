@@ -2,23 +2,65 @@ pub const NONE: u8 = 0;
 pub const NULL: u8 = 1;
 pub const DEFINE: u8 = 2;
 pub const FORGET: u8 = 3;
+pub const SIZED: u8 = 4;
+pub const BOOL_ARRAY: u8 = 5;
+pub const INTEGER_ARRAY: u8 = 6;
+pub const DECIMAL: u8 = 7;
+pub const DELTA_ARRAY: u8 = 8;
+pub const NULL_RUNS: u8 = 9;
+// Object of a SchemaRegistry shape: its varint schema_id, then its values in key order. Left out
+// of SpecialType, as only SchemaRegistry::decode knows how many values follow.
+pub const SHAPED: u8 = 10;
+pub const EXTEND: u8 = 11;
+pub const RESET: u8 = 12;
+pub const SORTED_ARRAY: u8 = 13;
+// String stored by a StringCodecs codec: its varint codec id, then the payload of the codec. Left
+// out of SpecialType, as only the codec knows the size of its payload.
+pub const CODEC_STRING: u8 = 14;
 
-#[repr(u8)]
-pub enum SpecialType {
+// Special types from SKIPPABLE on are followed by a varint byte length and their payload, so that
+// decoders that do not know them can still skip them.
+pub const SKIPPABLE: u8 = 16;
+
+byte_enum!(SpecialType {
     None = NONE,
     Null = NULL,
     Define = DEFINE,
     Forget = FORGET,
-}
+    // Byte length prefixed value, transparent to the decoded data.
+    Sized = SIZED,
+    // Packed arrays, decoded as plain arrays of EncodedData::Integer.
+    BoolArray = BOOL_ARRAY,
+    IntegerArray = INTEGER_ARRAY,
+    // Varint length, then the JSON number text.
+    Decimal = DECIMAL,
+    // Array of integers stored as its varint length, then its first value and the differences
+    // between consecutive values, as zigzag varints.
+    DeltaArray = DELTA_ARRAY,
+    // Array stored as its varint number of non null values, each preceded by the varint number
+    // of nulls before it, then the varint number of trailing nulls.
+    NullRuns = NULL_RUNS,
+    // Object made of the entries of two objects following it, e.g. an aliased common prefix and
+    // the remaining entries.
+    Extend = EXTEND,
+    // Start of a stream from another compressor: the aliases defined so far are dropped.
+    Reset = RESET,
+    // Array of integers stored sorted, as a DeltaArray with unsigned differences, followed by the
+    // position of each original element in the sorted array, as zigzag varint differences.
+    SortedArray = SORTED_ARRAY,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl SpecialType {
-    pub fn from(n: u8) -> Option<Self> {
-        Some(match n {
-            NONE => Self::None,
-            NULL => Self::Null,
-            DEFINE => Self::Define,
-            FORGET => Self::Forget,
-            _ => return None,
-        })
+    #[test]
+    fn from() {
+        let all: Vec<_> = (0..=0x1F).filter_map(SpecialType::from).collect();
+        assert_eq!(all.len(), 13);
+        for t in all {
+            assert_eq!(SpecialType::from(t as u8), Some(t));
+            assert!((t as u8) < SKIPPABLE);
+        }
     }
 }
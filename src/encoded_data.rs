@@ -2,9 +2,10 @@ use crate::define::{
     data_type::{self, DataType},
     special_type::{self, SpecialType},
 };
+use crate::key_map::KeyMap;
 use crate::varint;
 use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum EncodedSpecial {
@@ -12,6 +13,7 @@ pub enum EncodedSpecial {
     Null,
     Define(Box<EncodedData>),
     Forget(u64),
+    Reset,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -29,31 +31,217 @@ pub enum EncodedData {
     String(String),
     Array(Vec<EncodedData>),
     Object(HashMap<String, EncodedData>),
+    // Object entries in the order they are encoded in, e.g. already sorted by from_json_sorted.
+    // Encodes as an Object.
+    OrderedObject(Vec<(String, EncodedData)>),
     Alias(u64),
+    // JSON number text, only produced with the decimal feature for numbers that an f64 would not
+    // reproduce exactly, e.g. 1.100.
+    Decimal(String),
 }
 
 impl From<serde_json::Value> for EncodedData {
     fn from(v: serde_json::Value) -> Self {
-        match v {
-            serde_json::Value::Null => Self::Special(EncodedSpecial::Null),
-            serde_json::Value::Bool(b) => Self::Integer(EncodedInteger::Bool(b)),
-            serde_json::Value::Number(n) => {
-                if let Some(n) = n.as_u64() {
-                    Self::Integer(EncodedInteger::Positive(n))
-                } else if let Some(n) = n.as_i64() {
-                    Self::Integer(EncodedInteger::Negative(-n as u64))
-                } else {
-                    Self::Float(n.as_f64().unwrap())
-                }
+        Self::from_json_with_key_map(v, &KeyMap::default())
+    }
+}
+
+// Builds EncodedData straight from a serde Deserializer (e.g. JSON text), with the same mapping as
+// From<serde_json::Value> but without the intermediate Value tree.
+impl<'de> serde::Deserialize<'de> for EncodedData {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(EncodedDataVisitor {
+            ordered: false,
+            spans: None,
+        })
+    }
+}
+
+// With `ordered`, objects become OrderedObject entries in input order, duplicate keys included.
+#[derive(Clone, Copy)]
+struct EncodedDataVisitor<'a> {
+    ordered: bool,
+    spans: Option<&'a SpanRecorder<'a>>,
+}
+
+// Input of from_json_str_with_spans, fed to serde_json one byte at a time so that `pos` is always
+// the number of bytes it consumed.
+struct SpanReader<'a> {
+    json: &'a [u8],
+    pos: &'a std::cell::Cell<usize>,
+}
+
+impl std::io::Read for SpanReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let pos = self.pos.get();
+        match (self.json.get(pos), buf.first_mut()) {
+            (Some(b), Some(out)) => {
+                *out = *b;
+                self.pos.set(pos + 1);
+                Ok(1)
             }
-            serde_json::Value::String(s) => Self::String(s),
-            serde_json::Value::Array(list) => {
-                Self::Array(list.into_iter().map(|o| o.into()).collect())
+            _ => Ok(0),
+        }
+    }
+}
+
+struct SpanRecorder<'a> {
+    json: &'a [u8],
+    pos: &'a std::cell::Cell<usize>,
+    spans: std::cell::RefCell<Vec<std::ops::Range<usize>>>,
+}
+
+impl SpanRecorder<'_> {
+    // serde_json may have peeked at the first byte of the value already, unless it just read
+    // the `:` of an object entry.
+    fn start(&self) -> usize {
+        let pos = self.pos.get();
+        let mut start = match pos.checked_sub(1).map(|i| self.json[i]) {
+            Some(b':') | None => pos,
+            Some(_) => pos - 1,
+        };
+        while self.json.get(start).is_some_and(u8::is_ascii_whitespace) {
+            start += 1;
+        }
+        start
+    }
+
+    // Containers end on their consumed closing bracket, scalars may be followed by the peeked
+    // byte after a number.
+    fn end(&self, start: usize) -> usize {
+        let mut end = self.pos.get();
+        if !matches!(self.json[start], b'[' | b'{') {
+            while end > start
+                && (matches!(self.json[end - 1], b',' | b']' | b'}')
+                    || self.json[end - 1].is_ascii_whitespace())
+            {
+                end -= 1;
             }
-            serde_json::Value::Object(map) => {
-                Self::Object(map.into_iter().map(|(k, o)| (k, o.into())).collect())
+        }
+        end
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for EncodedDataVisitor<'_> {
+    type Value = EncodedData;
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, d: D) -> Result<EncodedData, D::Error> {
+        let recorder = match self.spans {
+            Some(recorder) => recorder,
+            None => return d.deserialize_any(self),
+        };
+        // Reserved before the children, for preorder ids.
+        let id = recorder.spans.borrow().len();
+        recorder.spans.borrow_mut().push(0..0);
+        let start = recorder.start();
+        let o = d.deserialize_any(self)?;
+        recorder.spans.borrow_mut()[id] = start..recorder.end(start);
+        Ok(o)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for EncodedDataVisitor<'_> {
+    type Value = EncodedData;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<EncodedData, E> {
+        Ok(EncodedData::Special(EncodedSpecial::Null))
+    }
+
+    fn visit_none<E>(self) -> Result<EncodedData, E> {
+        Ok(EncodedData::Special(EncodedSpecial::Null))
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(self, d: D) -> Result<EncodedData, D::Error> {
+        d.deserialize_any(self)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<EncodedData, E> {
+        Ok(EncodedData::Integer(EncodedInteger::Bool(b)))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<EncodedData, E> {
+        Ok(EncodedData::Integer(EncodedInteger::Positive(n)))
+    }
+
+    fn visit_i64<E>(self, n: i64) -> Result<EncodedData, E> {
+        if n >= 0 {
+            Ok(EncodedData::Integer(EncodedInteger::Positive(n as u64)))
+        } else {
+            Ok(EncodedData::Integer(EncodedInteger::Negative(
+                n.unsigned_abs(),
+            )))
+        }
+    }
+
+    fn visit_f64<E>(self, f: f64) -> Result<EncodedData, E> {
+        Ok(EncodedData::Float(f))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<EncodedData, E> {
+        Ok(EncodedData::String(s.to_string()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<EncodedData, E> {
+        Ok(EncodedData::String(s))
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<EncodedData, A::Error> {
+        let mut list = vec![];
+        while let Some(o) = seq.next_element_seed(self)? {
+            list.push(o);
+        }
+        Ok(EncodedData::Array(list))
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(
+        self,
+        mut access: A,
+    ) -> Result<EncodedData, A::Error> {
+        let mut map = HashMap::new();
+        let mut entries = vec![];
+        while let Some(k) = access.next_key::<String>()? {
+            // serde_json's arbitrary_precision hands numbers over as this single entry map.
+            #[cfg(feature = "decimal")]
+            if map.is_empty() && entries.is_empty() && k == "$serde_json::private::Number" {
+                let n: serde_json::Number = serde_json::from_str(&access.next_value::<String>()?)
+                    .map_err(serde::de::Error::custom)?;
+                return Ok(EncodedData::from(serde_json::Value::Number(n)));
+            }
+            let o = access.next_value_seed(self)?;
+            if self.ordered {
+                entries.push((k, o));
+            } else {
+                map.insert(k, o);
             }
         }
+        Ok(if self.ordered {
+            EncodedData::OrderedObject(entries)
+        } else {
+            EncodedData::Object(map)
+        })
+    }
+}
+
+// Source byte ranges of the values of a from_json_str_with_spans result, by node id: the index of
+// the value in a preorder walk of the tree, the root being 0 and object entries in input order.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceSpans(Vec<std::ops::Range<usize>>);
+
+impl SourceSpans {
+    pub fn get(&self, id: usize) -> Option<std::ops::Range<usize>> {
+        self.0.get(id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
@@ -65,482 +253,5050 @@ pub enum EncodedDataToJsonError {
     UnsupportedNoneDataType,
     UnsupportedDefineDataType,
     UnsupportedForgetDataType,
+    UnsupportedResetDataType,
+    BadDecimal(String),
 }
 
 impl TryFrom<EncodedData> for serde_json::Value {
     type Error = EncodedDataToJsonError;
     fn try_from(v: EncodedData) -> Result<Self, Self::Error> {
-        Ok(match v {
-            EncodedData::Special(EncodedSpecial::Null) => Self::Null,
-            EncodedData::Special(EncodedSpecial::None) => {
-                return Err(EncodedDataToJsonError::UnsupportedNoneDataType)
-            }
-            EncodedData::Special(EncodedSpecial::Define(_)) => {
-                return Err(EncodedDataToJsonError::UnsupportedDefineDataType)
-            }
-            EncodedData::Special(EncodedSpecial::Forget(_)) => {
-                return Err(EncodedDataToJsonError::UnsupportedForgetDataType)
-            }
-            EncodedData::Integer(EncodedInteger::Bool(b)) => Self::Bool(b),
-            EncodedData::Integer(EncodedInteger::Positive(n)) => Self::Number((n).into()),
-            EncodedData::Integer(EncodedInteger::Negative(n)) => Self::Number(
-                (-i64::try_from(n)
-                    .map_err(|_| EncodedDataToJsonError::NegativeIntegerTooBig(n))?)
-                .into(),
-            ),
-            EncodedData::Float(n) => Self::Number(
-                serde_json::Number::from_f64(n).ok_or(EncodedDataToJsonError::BadFloat(n))?,
-            ),
-            EncodedData::String(s) => Self::String(s),
-            EncodedData::Array(list) => Self::Array(
-                list.into_iter()
-                    .map(|o| o.try_into())
-                    .collect::<Result<_, _>>()?,
-            ),
-            EncodedData::Object(map) => Self::Object(
-                map.into_iter()
-                    .map(|(k, o)| o.try_into().map(|v| (k.clone(), v)))
-                    .collect::<Result<_, _>>()?,
-            ),
-            EncodedData::Alias(_) => return Err(EncodedDataToJsonError::UnsupportedAliasDataType),
-        })
+        v.into_json_with_key_map(&KeyMap::default())
     }
 }
 
+// Number of bytes encode_compact_u64 writes for `n`, from 1 to 8.
+fn compact_u64_len(n: u64) -> u8 {
+    std::cmp::max(1, (64 - n.leading_zeros() as u8).div_ceil(8))
+}
+
 fn encode_compact_u64(n: u64) -> Vec<u8> {
-    // TODO Manage the u24, u40, u48, u56 cases
-    if n <= 0xFF {
-        (n as u8).to_le_bytes().to_vec()
-    } else if n <= 0xFF_FF {
-        (n as u16).to_le_bytes().to_vec()
-    } else if n <= 0xFF_FF_FF_FF {
-        (n as u32).to_le_bytes().to_vec()
-    } else {
-        (n as u64).to_le_bytes().to_vec()
-    }
-}
-
-unsafe fn decode_compact_u64(data: &[u8], size: u8) -> u64 {
-    // TODO Manage the u24, u40, u48, u56 cases
-    if size == 1 {
-        *data.get_unchecked(0) as u64
-    } else if size == 2 {
-        let mut n_data = [0u8; 2];
-        n_data.clone_from_slice(data.get_unchecked(0..2));
-        u16::from_le_bytes(n_data) as u64
-    } else if size == 4 {
-        let mut n_data = [0u8; 4];
-        n_data.clone_from_slice(data.get_unchecked(0..4));
-        u32::from_le_bytes(n_data) as u64
-    } else {
-        let mut n_data = [0u8; 8];
-        n_data.clone_from_slice(data.get_unchecked(0..8));
-        u64::from_le_bytes(n_data)
+    n.to_le_bytes()[..compact_u64_len(n) as usize].to_vec()
+}
+
+fn decode_compact_u64(data: &[u8]) -> u64 {
+    let mut n_data = [0u8; 8];
+    n_data[..data.len()].copy_from_slice(data);
+    u64::from_le_bytes(n_data)
+}
+
+// INTEGER nibble of an integer stored as a varint instead of its compact_u64_len bytes.
+const INTEGER_VARINT: u8 = 0x0F;
+
+// Set on a FLOAT control byte when the float is integral and stored as a little endian two's
+// complement integer of the nibble's width. It still decodes as a float, so 1.0 does not become 1.
+const FLOAT_HINT: u8 = 1 << 4;
+
+// Merges the two objects of an Extend special, the entries of `tail` replacing the ones of `base`
+// with the same key. Both must be objects once resolved: decoding Extend without resolving the
+// aliases of the stream fails with BadExtend.
+fn extend_object(base: EncodedData, tail: EncodedData) -> Result<EncodedData, DecodeError> {
+    match (base, tail) {
+        (EncodedData::OrderedObject(mut entries), EncodedData::OrderedObject(tail)) => {
+            entries.extend(tail);
+            Ok(EncodedData::OrderedObject(entries))
+        }
+        (EncodedData::Object(mut map), EncodedData::Object(tail)) => {
+            map.extend(tail);
+            Ok(EncodedData::Object(map))
+        }
+        (EncodedData::Object(_), o) | (EncodedData::OrderedObject(_), o) | (o, _) => {
+            Err(DecodeError::BadExtend(crate::schema::kind(&o)))
+        }
+    }
+}
+
+// Payload width of a FLOAT control byte without FLOAT_HINT: an f64, or an f32 for decoders of
+// writers narrowing their floats. encode always writes f64s.
+fn float_width(ctrl: u8) -> Result<usize, DecodeError> {
+    match ctrl & 0x0F {
+        width @ 4 | width @ 8 => Ok(width as usize),
+        width => Err(DecodeError::BadFloatLength(width)),
+    }
+}
+
+fn encode_integral_float(f: f64) -> Option<Vec<u8>> {
+    // The range check also rejects NaN and infinities.
+    if !(i64::MIN as f64..-(i64::MIN as f64)).contains(&f)
+        || f.fract() != 0.0
+        || (f == 0.0 && f.is_sign_negative())
+    {
+        return None;
     }
+    let n = f as i64;
+    let bytes = n.to_le_bytes();
+    let width = (1..8)
+        .find(|w| decode_compact_i64(&bytes[..*w]) == n)
+        .unwrap_or(8);
+    Some(bytes[..width].to_vec())
+}
+
+fn decode_compact_i64(data: &[u8]) -> i64 {
+    let mut n_data = match data.last() {
+        Some(last) if last & 0x80 != 0 => [0xFFu8; 8],
+        _ => [0u8; 8],
+    };
+    n_data[..data.len()].copy_from_slice(data);
+    i64::from_le_bytes(n_data)
 }
 
 fn encode_data_type_length(mut n: u64, max_flag_bit: u8) -> (u8, Vec<u8>) {
     let max_flag_size = 1 << (max_flag_bit - 1);
-    let flag_mask = (max_flag_size >> 1) - 1;
+    let flag_mask = max_flag_size - 1;
     if n < max_flag_size {
         (n as u8, vec![])
     } else {
         let continue_flag = 1 << (max_flag_bit - 1);
         n -= max_flag_size;
-        let flag_value = (n & flag_mask as u64) as u8 | continue_flag;
+        let flag_value = (n & flag_mask) as u8 | continue_flag;
         n >>= max_flag_bit - 1;
         (flag_value, varint::encode(n))
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum DecodeError {
-    UnknownDataType(u8),
-    UnknownSpecialType(u8),
-    MissingBytes(usize),
-    VarintTooBig,
-    BadUtf8(std::str::Utf8Error),
+// Ids and lengths share one encoding, the header of aliases, strings and containers: values below
+// 16 are held by the low nibble (4 bits) of the control byte as is. Larger ones set the continue
+// flag (0x10), the low nibble holding the 4 low bits of the value minus 16 and a varint following
+// with the rest. The 3 high bits of the control byte are `data_type`.
+pub fn encode_id(data_type: u8, id: u64) -> Vec<u8> {
+    let (flag, head) = encode_data_type_length(id, 5);
+    [vec![data_type << 5 | flag], head].concat()
 }
 
-impl EncodedData {
-    pub fn encode(&self) -> Vec<u8> {
-        match self {
-            Self::Special(spe) => match spe {
-                EncodedSpecial::None => {
-                    vec![data_type::SPECIAL << 5 | special_type::NONE]
-                }
-                EncodedSpecial::Null => {
-                    vec![data_type::SPECIAL << 5 | special_type::NULL]
-                }
-                EncodedSpecial::Define(o) => [
-                    vec![data_type::SPECIAL << 5 | special_type::DEFINE],
-                    o.encode(),
-                ]
-                .concat(),
-                EncodedSpecial::Forget(id) => [
-                    vec![data_type::SPECIAL << 5 | special_type::FORGET],
-                    varint::encode(*id),
-                ]
-                .concat(),
-            },
-            Self::Integer(int) => match int {
-                EncodedInteger::Positive(n) => {
-                    let encoded = encode_compact_u64(*n);
-                    let data_size = encoded.len() as u8;
-                    [vec![data_type::INTEGER << 5 | data_size], encoded].concat()
-                }
-                EncodedInteger::Negative(n) => {
-                    let encoded = encode_compact_u64(*n);
-                    let data_size = encoded.len() as u8;
-                    [vec![data_type::INTEGER << 5 | 1 << 4 | data_size], encoded].concat()
-                }
-                EncodedInteger::Bool(b) => {
-                    let b_flag = if *b { 1 } else { 0 };
-                    vec![data_type::INTEGER << 5 | b_flag << 4]
-                }
-            },
-            Self::Float(f) => {
-                [vec![data_type::FLOAT << 5 | 8].as_slice(), &f.to_le_bytes()].concat()
+pub(crate) fn encode_container_header(data_type: u8, length: usize) -> Vec<u8> {
+    encode_id(data_type, length as u64)
+}
+
+pub(crate) fn encode_key(k: &str) -> Vec<u8> {
+    [varint::encode(k.len() as u64), k.as_bytes().to_vec()].concat()
+}
+
+// Encoding adding a special of its own (Shaped objects, codec strings) to the plain one, written
+// and read by encode_extended and decode_extended, which walk the plain arrays and objects and
+// hand every value to the extension first.
+pub(crate) trait Extension {
+    // Writes `o` to `out` and returns true if the extension encodes it, its children through
+    // encode_extended.
+    fn encode_special(&self, o: &EncodedData, out: &mut Vec<u8>) -> bool;
+    // Decodes the value at the start of `data` if it is the special of the extension, its
+    // children through decode_extended with `depth`.
+    fn decode_special(
+        &self,
+        data: &[u8],
+        depth: usize,
+    ) -> Option<Result<(EncodedData, usize), DecodeError>>;
+}
+
+pub(crate) fn encode_extended<E: Extension>(extension: &E, o: &EncodedData, out: &mut Vec<u8>) {
+    if extension.encode_special(o, out) {
+        return;
+    }
+    match o {
+        EncodedData::Array(list) => {
+            out.extend(encode_container_header(data_type::ARRAY, list.len()));
+            list.iter().for_each(|o| encode_extended(extension, o, out));
+        }
+        EncodedData::Object(map) => {
+            out.extend(encode_container_header(data_type::OBJECT, map.len()));
+            for (k, o) in sorted_entries(map) {
+                out.extend(encode_key(k));
+                encode_extended(extension, o, out);
             }
-            Self::String(s) => {
-                let (flag, data_type_length_data) = encode_data_type_length(s.len() as u64, 5);
-                [
-                    vec![data_type::STRING << 5 | flag].as_slice(),
-                    &data_type_length_data,
-                    s.as_bytes(),
-                ]
-                .concat()
+        }
+        EncodedData::OrderedObject(entries) => {
+            out.extend(encode_container_header(data_type::OBJECT, entries.len()));
+            for (k, o) in entries.iter() {
+                out.extend(encode_key(k));
+                encode_extended(extension, o, out);
             }
-            Self::Array(array) => {
-                let (flag, data_type_length_data) = encode_data_type_length(array.len() as u64, 5);
-                let mut ret = vec![vec![data_type::ARRAY << 5 | flag], data_type_length_data];
-                for o in array.iter() {
-                    ret.push(o.encode());
+        }
+        o => out.extend(o.encode()),
+    }
+}
+
+// Values nested deeper than `depth` fail with TooDeep.
+pub(crate) fn decode_extended<E: Extension>(
+    extension: &E,
+    data: &[u8],
+    depth: usize,
+) -> Result<(EncodedData, usize), DecodeError> {
+    let depth = depth.checked_sub(1).ok_or(DecodeError::TooDeep)?;
+    let ctrl = match data.first() {
+        Some(ctrl) => *ctrl,
+        None => return Err(DecodeError::MissingBytes(1)),
+    };
+    if let Some(ret) = extension.decode_special(data, depth) {
+        return ret;
+    }
+    let value = |tot_size: &mut usize| {
+        let (o, size) = decode_extended(extension, &data[*tot_size..], depth)
+            .map_err(|e| e.shifted(*tot_size))?;
+        *tot_size += size;
+        Ok(o)
+    };
+    match ctrl >> 5 {
+        data_type::ARRAY => {
+            let (length, mut tot_size) = decode_data_type_length(data)?;
+            let mut list = vec![];
+            for _ in 0..length {
+                list.push(value(&mut tot_size)?);
+            }
+            Ok((EncodedData::Array(list), tot_size))
+        }
+        data_type::OBJECT => {
+            let (length, mut tot_size) = decode_data_type_length(data)?;
+            let mut map = HashMap::new();
+            for _ in 0..length {
+                let (k_length, size) = decode_varint(&data[tot_size..])?;
+                tot_size += size;
+                let k_length = k_length as usize;
+                if data.len() - tot_size < k_length {
+                    return Err(DecodeError::MissingBytes(
+                        k_length - (data.len() - tot_size),
+                    ));
                 }
-                ret.concat()
+                let end = tot_size + k_length;
+                let k = std::str::from_utf8(&data[tot_size..end]).map_err(|error| {
+                    DecodeError::BadUtf8 {
+                        offset: tot_size + error.valid_up_to(),
+                        error,
+                    }
+                })?;
+                tot_size = end;
+                map.insert(k.to_string(), value(&mut tot_size)?);
             }
-            Self::Object(map) => {
-                let (flag, data_type_length_data) = encode_data_type_length(map.len() as u64, 5);
-                let mut ret = vec![vec![data_type::OBJECT << 5 | flag], data_type_length_data];
-                for (k, o) in map.iter() {
-                    ret.push(varint::encode(k.len() as u64));
-                    ret.push(k.as_bytes().to_vec());
-                    ret.push(o.encode());
+            Ok((EncodedData::Object(map), tot_size))
+        }
+        _ => EncodedData::decode(data),
+    }
+}
+
+// Objects are encoded in key order so that equal objects always produce the same bytes.
+pub(crate) fn sorted_entries(map: &HashMap<String, EncodedData>) -> Vec<(&String, &EncodedData)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+// Capacity up to which encode_pooled keeps its scratch buffer between calls.
+pub const MAX_SCRATCH_CAPACITY: usize = 64 * 1024;
+
+// Wraps the container written in `buf` from `start` on into a Sized special.
+fn sized_from(buf: &mut Vec<u8>, start: usize) {
+    let container = buf.split_off(start);
+    buf.extend(encode_sized(container));
+}
+
+fn encode_sized(container: Vec<u8>) -> Vec<u8> {
+    [
+        vec![data_type::SPECIAL << 5 | special_type::SIZED],
+        varint::encode(container.len() as u64),
+        container,
+    ]
+    .concat()
+}
+
+fn bitset_size(nb: usize) -> usize {
+    nb.div_ceil(8)
+}
+
+fn bitset_get(bits: &[u8], i: usize) -> bool {
+    bits[i / 8] & 1 << (i % 8) != 0
+}
+
+// Packs arrays made only of booleans into a bitset, and arrays made only of numeric integers into
+// a list of fixed width little endian integers. Integer widths and signs are stored once:
+//  - [SPECIAL|BOOL_ARRAY][varint length][bitset]
+//  - [SPECIAL|INTEGER_ARRAY][varint length][flags][sign bitset if mixed signs][values]
+// with flags being the value width in the low nibble, 0x10 for all negative and 0x20 for mixed
+// signs.
+fn encode_packed_array(array: &[EncodedData]) -> Option<Vec<u8>> {
+    let ints = array
+        .iter()
+        .map(|o| match o {
+            EncodedData::Integer(int) => Some(int),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if ints.len() < 2 {
+        return None;
+    }
+    let nb_bools = ints
+        .iter()
+        .filter(|int| matches!(int, EncodedInteger::Bool(_)))
+        .count();
+    if nb_bools == ints.len() {
+        let mut bits = vec![0u8; bitset_size(ints.len())];
+        for (i, int) in ints.iter().enumerate() {
+            if let EncodedInteger::Bool(true) = int {
+                bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Some(
+            [
+                vec![data_type::SPECIAL << 5 | special_type::BOOL_ARRAY],
+                varint::encode(ints.len() as u64),
+                bits,
+            ]
+            .concat(),
+        )
+    } else if nb_bools == 0 {
+        let (values, signs): (Vec<u64>, Vec<bool>) = ints
+            .iter()
+            .map(|int| match int {
+                EncodedInteger::Positive(n) => (*n, false),
+                EncodedInteger::Negative(n) => (*n, true),
+                EncodedInteger::Bool(_) => unreachable!(),
+            })
+            .unzip();
+        let width = values.iter().map(|n| compact_u64_len(*n)).max().unwrap() as usize;
+        let mut flags = width as u8;
+        let mut sign_bits = vec![];
+        if signs.iter().all(|negative| *negative) {
+            flags |= 0x10;
+        } else if signs.iter().any(|negative| *negative) {
+            flags |= 0x20;
+            sign_bits = vec![0u8; bitset_size(signs.len())];
+            for (i, negative) in signs.iter().enumerate() {
+                if *negative {
+                    sign_bits[i / 8] |= 1 << (i % 8);
                 }
-                ret.concat()
             }
-            Self::Alias(id) => {
-                let (flag, id_data) = encode_data_type_length(*id, 5);
-                [vec![data_type::ALIAS << 5 | flag], id_data].concat()
+        }
+        let mut ret = vec![
+            vec![data_type::SPECIAL << 5 | special_type::INTEGER_ARRAY],
+            varint::encode(values.len() as u64),
+            vec![flags],
+            sign_bits,
+        ];
+        for n in values.iter() {
+            ret.push(n.to_le_bytes()[..width].to_vec());
+        }
+        Some(ret.concat())
+    } else {
+        None
+    }
+}
+
+fn integer_as_i64(o: &EncodedData) -> Option<i64> {
+    match o {
+        EncodedData::Integer(EncodedInteger::Positive(n)) if *n <= i64::MAX as u64 => {
+            Some(*n as i64)
+        }
+        EncodedData::Integer(EncodedInteger::Negative(n)) => 0i64.checked_sub_unsigned(*n),
+        _ => None,
+    }
+}
+
+// Integers that all fit in an i64, with differences that fit too, as a DeltaArray special.
+fn encode_delta_array(array: &[EncodedData]) -> Option<Vec<u8>> {
+    let values = array
+        .iter()
+        .map(integer_as_i64)
+        .collect::<Option<Vec<_>>>()?;
+    if values.len() < 2 {
+        return None;
+    }
+    let mut ret = vec![
+        vec![data_type::SPECIAL << 5 | special_type::DELTA_ARRAY],
+        varint::encode(values.len() as u64),
+        varint::encode_signed(values[0]),
+    ];
+    for pair in values.windows(2) {
+        ret.push(varint::encode_signed(pair[1].checked_sub(pair[0])?));
+    }
+    Some(ret.concat())
+}
+
+fn decode_signed_varint(data: &[u8]) -> Result<(i64, usize), DecodeError> {
+    match varint::decode_signed(data) {
+        Ok((n, size)) => Ok((n, size as usize)),
+        Err(varint::DecodeError::MissingBytes) => Err(DecodeError::MissingBytes(1)),
+        Err(varint::DecodeError::ValueTooBig) => Err(DecodeError::VarintTooBig),
+    }
+}
+
+fn decode_delta_array(data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
+    let mut list = vec![];
+    let size = read_delta_array(data, Some(&mut list))?;
+    Ok((EncodedData::Array(list), size))
+}
+
+// Reads the DeltaArray at the start of `data`, pushing its elements to `list` if given, and
+// returns its size. Nothing is allocated without `list`.
+fn read_delta_array(
+    data: &[u8],
+    mut list: Option<&mut Vec<EncodedData>>,
+) -> Result<usize, DecodeError> {
+    let (nb, mut tot_size) = decode_varint(&data[1..])?;
+    tot_size += 1;
+    let mut n = 0i64;
+    for i in 0..nb {
+        let (delta, size) = decode_signed_varint(&data[tot_size..])?;
+        tot_size += size;
+        n = if i == 0 {
+            delta
+        } else {
+            // Only corrupted data can leave the i64 range.
+            n.checked_add(delta).ok_or(DecodeError::VarintTooBig)?
+        };
+        if let Some(list) = list.as_mut() {
+            list.push(integer_of_i64(n));
+        }
+    }
+    Ok(tot_size)
+}
+
+fn integer_of_i64(n: i64) -> EncodedData {
+    EncodedData::Integer(if n < 0 {
+        EncodedInteger::Negative(n.unsigned_abs())
+    } else {
+        EncodedInteger::Positive(n as u64)
+    })
+}
+
+// Integers that all fit in an i64 as a SortedArray special: the values sorted (a stable sort, so
+// that equal values keep increasing positions), then where each element went.
+fn encode_sorted_array(array: &[EncodedData]) -> Option<Vec<u8>> {
+    let values = array
+        .iter()
+        .map(integer_as_i64)
+        .collect::<Option<Vec<_>>>()?;
+    if values.len() < 2 {
+        return None;
+    }
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by_key(|i| values[*i]);
+    let mut positions = vec![0; values.len()];
+    for (position, i) in order.iter().enumerate() {
+        positions[*i] = position as i64;
+    }
+    let mut ret = vec![data_type::SPECIAL << 5 | special_type::SORTED_ARRAY];
+    varint::write(values.len() as u64, &mut ret);
+    ret.extend(varint::encode_signed(values[order[0]]));
+    for pair in order.windows(2) {
+        varint::write(values[pair[1]].abs_diff(values[pair[0]]), &mut ret);
+    }
+    ret.extend(varint::encode_signed(positions[0]));
+    for pair in positions.windows(2) {
+        ret.extend(varint::encode_signed(pair[1] - pair[0]));
+    }
+    Some(ret)
+}
+
+fn decode_sorted_array(data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
+    let mut list = vec![];
+    let size = read_sorted_array(data, Some(&mut list))?;
+    Ok((EncodedData::Array(list), size))
+}
+
+// Same as read_delta_array for a SortedArray. Without `list`, positions are only checked to be
+// in the array, so the sorted values need not be kept.
+fn read_sorted_array(
+    data: &[u8],
+    mut list: Option<&mut Vec<EncodedData>>,
+) -> Result<usize, DecodeError> {
+    let (nb, mut tot_size) = decode_varint(&data[1..])?;
+    tot_size += 1;
+    let mut sorted = vec![];
+    let mut n = 0i64;
+    for i in 0..nb {
+        n = if i == 0 {
+            let (first, size) = decode_signed_varint(&data[tot_size..])?;
+            tot_size += size;
+            first
+        } else {
+            let (delta, size) = decode_varint(&data[tot_size..])?;
+            tot_size += size;
+            // Only corrupted data can leave the i64 range.
+            n.checked_add_unsigned(delta)
+                .ok_or(DecodeError::VarintTooBig)?
+        };
+        if list.is_some() {
+            sorted.push(n);
+        }
+    }
+    // Each of the `nb` values above took a byte at least, so the bitset is bounded by `data`.
+    let mut used = vec![false; nb as usize];
+    let mut position = 0i64;
+    for i in 0..nb {
+        let (delta, size) = decode_signed_varint(&data[tot_size..])?;
+        tot_size += size;
+        position = if i == 0 {
+            delta
+        } else {
+            position
+                .checked_add(delta)
+                .ok_or(DecodeError::VarintTooBig)?
+        };
+        if position < 0 || position as u64 >= nb || used[position as usize] {
+            return Err(DecodeError::BadPermutation(position));
+        }
+        used[position as usize] = true;
+        if let Some(list) = list.as_mut() {
+            list.push(integer_of_i64(sorted[position as usize]));
+        }
+    }
+    Ok(tot_size)
+}
+
+// Nesting bound of skip, validate, decode_borrowed, decode_to_json and decode_extended, which
+// take a stack frame per level, and default DecodeOptions::max_depth. The same as serde_json's
+// recursion limit.
+pub const MAX_DEPTH: usize = 128;
+
+// Bound on the elements of a NullRuns array per byte of its encoding read so far, so that a few
+// bytes can't decode into an arbitrarily large array.
+const MAX_NULL_RUNS_EXPANSION: u64 = 256;
+
+// Arrays holding nulls as a NullRuns special, built from the encodings of the elements: `children`
+// holds them one after the other, of the given `sizes`. None for the arrays whose runs exceed
+// MAX_NULL_RUNS_EXPANSION.
+fn encode_null_runs(array: &[EncodedData], children: &[u8], sizes: &[usize]) -> Option<Vec<u8>> {
+    if !array.iter().any(EncodedData::is_null) {
+        return None;
+    }
+    let nb_values = array.iter().filter(|o| !o.is_null()).count();
+    let mut ret = vec![data_type::SPECIAL << 5 | special_type::NULL_RUNS];
+    ret.extend(varint::encode(nb_values as u64));
+    let mut nb_nulls = 0;
+    let push_run = |ret: &mut Vec<u8>, nb_nulls: u64, len: usize| {
+        ret.extend(varint::encode(nb_nulls));
+        len as u64 + nb_nulls <= MAX_NULL_RUNS_EXPANSION * ret.len() as u64
+    };
+    let mut offset = 0;
+    for ((len, o), size) in array.iter().enumerate().zip(sizes) {
+        let child = &children[offset..offset + size];
+        offset += size;
+        if o.is_null() {
+            nb_nulls += 1;
+        } else {
+            if !push_run(&mut ret, nb_nulls, len - nb_nulls as usize) {
+                return None;
             }
+            ret.extend_from_slice(child);
+            nb_nulls = 0;
         }
     }
+    if !push_run(&mut ret, nb_nulls, array.len() - nb_nulls as usize) {
+        return None;
+    }
+    Some(ret)
+}
 
-    pub fn decode(data: &[u8]) -> Result<(Self, usize), DecodeError> {
-        unsafe {
-            if data.is_empty() {
-                return Err(DecodeError::MissingBytes(1));
+// Reads the run of nulls at `data[*tot_size..]`, `len` elements being already decoded in the
+// NullRuns array starting at `data[0]`.
+pub(crate) fn decode_null_run(
+    data: &[u8],
+    tot_size: &mut usize,
+    len: usize,
+) -> Result<u64, DecodeError> {
+    let (nb_nulls, size) = decode_varint(&data[*tot_size..])?;
+    *tot_size += size;
+    if (len as u64).saturating_add(nb_nulls) > MAX_NULL_RUNS_EXPANSION * *tot_size as u64 {
+        return Err(DecodeError::TooManyNulls(nb_nulls));
+    }
+    Ok(nb_nulls)
+}
+
+struct PackedArrayLayout {
+    nb: usize,
+    // 0 for booleans.
+    width: usize,
+    negative: bool,
+    signs: Option<usize>,
+    payload: usize,
+    size: usize,
+}
+
+fn packed_array_layout(data: &[u8]) -> Result<PackedArrayLayout, DecodeError> {
+    let (nb, varint_size) = decode_varint(&data[1..])?;
+    let nb = nb as usize;
+    let mut offset = 1 + varint_size;
+    let layout = if data[0] & 0x1F == special_type::BOOL_ARRAY {
+        PackedArrayLayout {
+            nb,
+            width: 0,
+            negative: false,
+            signs: None,
+            payload: offset,
+            size: offset.saturating_add(bitset_size(nb)),
+        }
+    } else {
+        let flags = match data.get(offset) {
+            Some(flags) => *flags,
+            None => return Err(DecodeError::MissingBytes(1)),
+        };
+        offset += 1;
+        let width = flags & 0x0F;
+        if width == 0 || width > 8 {
+            return Err(DecodeError::BadIntegerLength(width));
+        }
+        let signs = if flags & 0x20 != 0 {
+            offset = offset.saturating_add(bitset_size(nb));
+            Some(offset - bitset_size(nb))
+        } else {
+            None
+        };
+        PackedArrayLayout {
+            nb,
+            width: width as usize,
+            negative: flags & 0x10 != 0,
+            signs,
+            payload: offset,
+            size: offset.saturating_add(nb.saturating_mul(width as usize)),
+        }
+    };
+    if data.len() < layout.size {
+        return Err(DecodeError::MissingBytes(layout.size - data.len()));
+    }
+    Ok(layout)
+}
+
+// Size of an unknown skippable special, without the value following it.
+fn skip_unknown_special(data: &[u8]) -> Result<usize, DecodeError> {
+    let (length, size) = decode_varint(&data[1..])?;
+    let skipped = (1 + size).saturating_add(length as usize);
+    if data.len() < skipped {
+        return Err(DecodeError::MissingBytes(skipped - data.len()));
+    }
+    Ok(skipped)
+}
+
+fn decode_packed_array(data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
+    let layout = packed_array_layout(data)?;
+    let list = (0..layout.nb)
+        .map(|i| {
+            if layout.width == 0 {
+                return EncodedData::Integer(EncodedInteger::Bool(bitset_get(
+                    &data[layout.payload..],
+                    i,
+                )));
             }
-            let ctrl = data.get_unchecked(0);
-            let data_type_value = ctrl >> 5;
-            let data_type = match DataType::from(data_type_value) {
-                Some(data_type) => data_type,
-                None => return Err(DecodeError::UnknownDataType(data_type_value)),
+            let start = layout.payload + i * layout.width;
+            let n = decode_compact_u64(&data[start..start + layout.width]);
+            let negative = match layout.signs {
+                Some(signs) => bitset_get(&data[signs..], i),
+                None => layout.negative,
             };
-            Ok(match data_type {
-                DataType::Special => {
-                    let special_type_value = ctrl & 0x1F;
-                    let special_type = match SpecialType::from(special_type_value) {
-                        Some(special_type) => special_type,
-                        None => return Err(DecodeError::UnknownSpecialType(special_type_value)),
-                    };
-                    match special_type {
-                        SpecialType::None => (Self::Special(EncodedSpecial::None), 1),
-                        SpecialType::Null => (Self::Special(EncodedSpecial::Null), 1),
-                        SpecialType::Define => {
-                            if data.len() < 2 {
-                                return Err(DecodeError::MissingBytes(1));
-                            }
-                            let (object, size) = Self::decode(&data.get_unchecked(1..))?;
-                            (
-                                Self::Special(EncodedSpecial::Define(Box::new(object))),
-                                1 + size,
-                            )
-                        }
-                        SpecialType::Forget => {
-                            let (id, size) = match varint::decode(data.get_unchecked(1..)) {
-                                Ok(e) => e,
-                                Err(varint::DecodeError::MissingBytes) => {
-                                    return Err(DecodeError::MissingBytes(1))
-                                }
-                                Err(varint::DecodeError::ValueTooBig) => {
-                                    return Err(DecodeError::VarintTooBig)
-                                }
-                            };
-                            (Self::Special(EncodedSpecial::Forget(id)), 1 + size as usize)
-                        }
-                    }
-                }
-                DataType::Integer => {
-                    let length = ctrl & 0x0F;
-                    let negative = ctrl & 0x10 != 0;
-                    if length == 0 {
-                        (Self::Integer(EncodedInteger::Bool(negative)), 1)
-                    } else {
-                        if data.len() < 1 + length as usize {
-                            return Err(DecodeError::MissingBytes(
-                                1 + length as usize - data.len(),
-                            ));
+            if negative {
+                EncodedData::Integer(EncodedInteger::Negative(n))
+            } else {
+                EncodedData::Integer(EncodedInteger::Positive(n))
+            }
+        })
+        .collect();
+    Ok((EncodedData::Array(list), layout.size))
+}
+
+pub(crate) fn decode_varint(data: &[u8]) -> Result<(u64, usize), DecodeError> {
+    match varint::decode(data) {
+        Ok((n, size)) => Ok((n, size as usize)),
+        Err(varint::DecodeError::MissingBytes) => Err(DecodeError::MissingBytes(1)),
+        Err(varint::DecodeError::ValueTooBig) => Err(DecodeError::VarintTooBig),
+    }
+}
+
+// Inverse of encode_data_type_length with a 5 bits flag: `head` is the varint following the
+// control byte, `low` its low nibble. Heads beyond the encoding of u64::MAX are VarintTooBig.
+fn join_data_type_length(head: u64, low: u8) -> Result<u64, DecodeError> {
+    head.checked_mul(0x10)
+        .and_then(|n| (n | low as u64).checked_add(0x10))
+        .ok_or(DecodeError::VarintTooBig)
+}
+
+// Decodes the length of a string or container, starting at its control byte. Returns the length
+// and the size of the header.
+pub(crate) fn decode_data_type_length(data: &[u8]) -> Result<(usize, usize), DecodeError> {
+    let (length, size) = decode_id(data)?;
+    let length = usize::try_from(length).map_err(|_| DecodeError::VarintTooBig)?;
+    Ok((length, size))
+}
+
+// Inverse of encode_id, whatever the data type: returns the id and the size of the header.
+pub fn decode_id(data: &[u8]) -> Result<(u64, usize), DecodeError> {
+    let ctrl = match data.first() {
+        Some(ctrl) => *ctrl,
+        None => return Err(DecodeError::MissingBytes(1)),
+    };
+    let low = ctrl & 0x0F;
+    if ctrl & 0x10 != 0 {
+        let (head, size) = decode_varint(&data[1..])?;
+        Ok((join_data_type_length(head, low)?, 1 + size))
+    } else {
+        Ok((low as u64, 1))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+    UnknownDataType(u8),
+    UnknownSpecialType(u8),
+    MissingBytes(usize),
+    VarintTooBig,
+    BadIntegerLength(u8),
+    // Float payload width other than 4 or 8 bytes, or 1 to 8 with FLOAT_HINT.
+    BadFloatLength(u8),
+    // `offset` is the position of the first invalid byte in the decoded buffer.
+    BadUtf8 {
+        offset: usize,
+        error: std::str::Utf8Error,
+    },
+    UnknownAlias(u64),
+    BadSizedLength(usize),
+    UnsupportedVersion(u8),
+    // JSON pointer that is malformed, does not exist, or goes through a value in an encoding
+    // patch_pointer can't edit (alias, packed array...).
+    BadPointer(String),
+    // Run of nulls expanding a NullRuns array beyond MAX_NULL_RUNS_EXPANSION.
+    TooManyNulls(u64),
+    // Shaped object whose schema_id is not in the SchemaRegistry.
+    UnknownSchema(u64),
+    // Extend special followed by this kind of value instead of two objects.
+    BadExtend(&'static str),
+    // Object key that decode_with_schema_options was told to deny, with its path.
+    UnknownField(String),
+    // Strings and keys adding up to more than DecodeOptions::max_string_bytes.
+    StringBudgetExceeded,
+    // SortedArray position outside of the array, or already taken.
+    BadPermutation(i64),
+    // Value nested more than MAX_DEPTH or DecodeOptions::max_depth levels deep.
+    TooDeep,
+    // Codec string whose codec id is not in the StringCodecs.
+    UnknownStringCodec(u64),
+    // Codec string payload its codec failed to decode.
+    BadCodecString(u64),
+    SchemaMismatch {
+        path: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+// Error of decode_located: `offset` is the position of the value that failed to decode, relative
+// to the start of the decoded buffer.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodeErrorAt {
+    pub offset: usize,
+    pub error: DecodeError,
+}
+
+impl DecodeErrorAt {
+    fn shifted(self, by: usize) -> Self {
+        Self {
+            offset: self.offset + by,
+            error: self.error.shifted(by),
+        }
+    }
+
+    fn in_sized(self, length: usize) -> Self {
+        Self {
+            offset: self.offset,
+            error: self.error.in_sized(length),
+        }
+    }
+}
+
+impl From<DecodeError> for DecodeErrorAt {
+    fn from(error: DecodeError) -> Self {
+        Self { offset: 0, error }
+    }
+}
+
+impl DecodeError {
+    // Makes the offsets of an error raised while decoding `data[by..]` relative to `data`.
+    pub(crate) fn shifted(self, by: usize) -> Self {
+        match self {
+            Self::BadUtf8 { offset, error } => Self::BadUtf8 {
+                offset: offset + by,
+                error,
+            },
+            e => e,
+        }
+    }
+
+    // Error of decoding the content of a Sized special of `length` bytes: running out of bytes
+    // there means the length is wrong, not that the data is truncated.
+    pub(crate) fn in_sized(self, length: usize) -> Self {
+        match self {
+            Self::MissingBytes(_) => Self::BadSizedLength(length),
+            e => e,
+        }
+    }
+}
+
+// Version byte written by encode_versioned. Version 1 is the current format. Its fixed width
+// fields (integers, floats, packed arrays) are little endian whatever the host.
+pub const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct EncodeOptions {
+    // Prefix arrays and objects with their byte length (Sized special) so that skip can jump over
+    // them without walking their content.
+    pub sized_containers: bool,
+    // Pack arrays of booleans or of numeric integers (see encode_packed_array) when it is smaller.
+    pub packed_arrays: bool,
+    // Store arrays of integers as differences between consecutive values (see
+    // encode_delta_array) when it is smaller, e.g. for timestamps or sorted ids.
+    pub delta_arrays: bool,
+    // Store the nulls of arrays as counts of consecutive nulls (NullRuns special) when it is
+    // smaller, e.g. for sparse arrays.
+    pub null_runs: bool,
+    // Store arrays of integers sorted, with the permutation restoring their order (see
+    // encode_sorted_array) when it is smaller, e.g. for shuffled ids of a narrow range.
+    pub sorted_arrays: bool,
+    pub integers: IntegerEncoding,
+    // Byte limit of encode_into, checked as containers are filled so that it fails before building
+    // the whole output. Arrays are checked on their plain encoding, even when an alternative one
+    // would have fit. The infallible encode functions ignore it.
+    pub max_output: Option<usize>,
+}
+
+// How integer scalars are stored. Both decode whatever the option, the control byte telling
+// which one was used.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum IntegerEncoding {
+    // Little endian bytes, as many as the value needs. Never longer than Varint.
+    #[default]
+    CompactFixed,
+    // 7 bits per byte, for consumers already decoding varints.
+    Varint,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodeError {
+    // The encoding is longer than EncodeOptions::max_output, given here.
+    OutputTooLarge(usize),
+}
+
+fn check_len(len: usize, max_output: Option<usize>) -> Result<(), EncodeError> {
+    match max_output {
+        Some(max) if len > max => Err(EncodeError::OutputTooLarge(max)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodeOptions {
+    // Replace invalid UTF-8 sequences in strings and keys with U+FFFD instead of failing with
+    // BadUtf8.
+    pub lossy_utf8: bool,
+    // Ignore unknown special types from special_type::SKIPPABLE on and decode the value following
+    // them instead of failing with UnknownSpecialType.
+    pub skip_unknown: bool,
+    // Decode objects as OrderedObject, keeping their entries in encoded order.
+    pub ordered_objects: bool,
+    // Fail with StringBudgetExceeded once the strings and keys decoded so far, those of nested
+    // and defined values included, add up to more than this many bytes.
+    pub max_string_bytes: Option<usize>,
+    // Fail with TooDeep on values nested more than this many levels, containers and the specials
+    // wrapping a value alike. Deeper values can be decoded, but dropping them recurses per level.
+    pub max_depth: usize,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            lossy_utf8: false,
+            skip_unknown: false,
+            ordered_objects: false,
+            max_string_bytes: None,
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+// Bytes of the default encoding of a value, by what they encode. Booleans count as integers,
+// Define/Forget/null markers as specials; the key lengths are part of `keys`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SizeBreakdown {
+    pub specials: usize,
+    pub integers: usize,
+    pub floats: usize,
+    pub decimals: usize,
+    pub strings: usize,
+    pub keys: usize,
+    // Array and object headers.
+    pub containers: usize,
+    pub aliases: usize,
+}
+
+impl SizeBreakdown {
+    pub fn total(&self) -> usize {
+        self.specials
+            + self.integers
+            + self.floats
+            + self.decimals
+            + self.strings
+            + self.keys
+            + self.containers
+            + self.aliases
+    }
+}
+
+// Number of arrays stored in a given alternative encoding by compress_reported, and the bytes it
+// saved over their plain encoding.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct EncodingUse {
+    pub count: usize,
+    pub saved: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CompressReport {
+    pub bool_arrays: EncodingUse,
+    pub integer_arrays: EncodingUse,
+    pub delta_arrays: EncodingUse,
+    pub null_runs: EncodingUse,
+    pub sorted_arrays: EncodingUse,
+}
+
+impl CompressReport {
+    fn add(&mut self, ctrl: u8, saved: usize) {
+        let used = match ctrl & 0x1F {
+            special_type::BOOL_ARRAY => &mut self.bool_arrays,
+            special_type::INTEGER_ARRAY => &mut self.integer_arrays,
+            special_type::DELTA_ARRAY => &mut self.delta_arrays,
+            special_type::SORTED_ARRAY => &mut self.sorted_arrays,
+            special_type::NULL_RUNS => &mut self.null_runs,
+            // Plain and sized arrays are not alternative encodings.
+            _ => return,
+        };
+        used.count += 1;
+        used.saved += saved;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct JsonOptions {
+    // Convert the negative integers below i64::MIN, which serde_json numbers cannot hold, to
+    // strings (e.g. "-18446744073709551615") instead of failing with NegativeIntegerTooBig.
+    pub big_negatives_as_strings: bool,
+}
+
+// Source of the strings, arrays and objects built by EncodedData::decode_in, e.g. to reuse the
+// buffers of a previous message instead of allocating new ones.
+pub trait DecodeArena {
+    fn alloc_string(&mut self, s: &str) -> String;
+    // `capacity` is a hint, bounded by the size of the data left to decode.
+    fn alloc_array(&mut self, capacity: usize) -> Vec<EncodedData>;
+    fn alloc_object(&mut self, capacity: usize) -> HashMap<String, EncodedData>;
+}
+
+// Allocates everything on the heap, as decode does.
+pub struct HeapArena;
+
+impl DecodeArena for HeapArena {
+    fn alloc_string(&mut self, s: &str) -> String {
+        s.to_string()
+    }
+
+    fn alloc_array(&mut self, _capacity: usize) -> Vec<EncodedData> {
+        vec![]
+    }
+
+    fn alloc_object(&mut self, _capacity: usize) -> HashMap<String, EncodedData> {
+        HashMap::new()
+    }
+}
+
+// Arena of a decode call, charging the decoded strings and keys against
+// DecodeOptions::max_string_bytes.
+pub(crate) struct Budgeted<'a, A> {
+    arena: &'a mut A,
+    left: Option<usize>,
+}
+
+impl<'a, A: DecodeArena> Budgeted<'a, A> {
+    pub(crate) fn new(arena: &'a mut A, options: &DecodeOptions) -> Self {
+        Self {
+            arena,
+            left: options.max_string_bytes,
+        }
+    }
+
+    fn charge(&mut self, length: usize) -> Result<(), DecodeError> {
+        if let Some(left) = self.left.as_mut() {
+            *left = left
+                .checked_sub(length)
+                .ok_or(DecodeError::StringBudgetExceeded)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A: DecodeArena> DecodeArena for Budgeted<'_, A> {
+    fn alloc_string(&mut self, s: &str) -> String {
+        self.arena.alloc_string(s)
+    }
+
+    fn alloc_array(&mut self, capacity: usize) -> Vec<EncodedData> {
+        self.arena.alloc_array(capacity)
+    }
+
+    fn alloc_object(&mut self, capacity: usize) -> HashMap<String, EncodedData> {
+        self.arena.alloc_object(capacity)
+    }
+}
+
+// Result of decode_node: a whole value, or the header of a container with its number of children.
+enum Node {
+    Value(EncodedData),
+    Array(usize),
+    Object(usize),
+}
+
+impl Node {
+    fn length(&self) -> usize {
+        match self {
+            Self::Value(_) => 0,
+            Self::Array(length) | Self::Object(length) => *length,
+        }
+    }
+}
+
+// Container being filled by decode_in_with.
+enum Frame {
+    Array {
+        start: usize,
+        remaining: usize,
+        list: Vec<EncodedData>,
+    },
+    Object {
+        start: usize,
+        remaining: usize,
+        key: Option<String>,
+        map: HashMap<String, EncodedData>,
+        entries: Vec<(String, EncodedData)>,
+    },
+}
+
+impl Frame {
+    fn start(&self) -> usize {
+        match self {
+            Self::Array { start, .. } | Self::Object { start, .. } => *start,
+        }
+    }
+
+    // Returns whether the container is complete.
+    fn push(&mut self, o: EncodedData, options: &DecodeOptions) -> bool {
+        match self {
+            Self::Array {
+                remaining, list, ..
+            } => {
+                list.push(o);
+                *remaining -= 1;
+                *remaining == 0
+            }
+            Self::Object {
+                remaining,
+                key,
+                map,
+                entries,
+                ..
+            } => {
+                let k = key.take().unwrap();
+                if options.ordered_objects {
+                    entries.push((k, o));
+                } else {
+                    map.insert(k, o);
+                }
+                *remaining -= 1;
+                *remaining == 0
+            }
+        }
+    }
+
+    fn into_value(self, options: &DecodeOptions) -> EncodedData {
+        match self {
+            Self::Array { list, .. } => EncodedData::Array(list),
+            Self::Object { entries, .. } if options.ordered_objects => {
+                EncodedData::OrderedObject(entries)
+            }
+            Self::Object { map, .. } => EncodedData::Object(map),
+        }
+    }
+}
+
+fn decode_key<A: DecodeArena>(
+    data: &[u8],
+    options: &DecodeOptions,
+    arena: &mut Budgeted<A>,
+) -> Result<(String, usize), DecodeError> {
+    let (length, size) = decode_varint(data)?;
+    let length = length as usize;
+    if data.len() - size < length {
+        return Err(DecodeError::MissingBytes(length - (data.len() - size)));
+    }
+    let k = decode_str(&data[size..size + length], options, arena).map_err(|e| e.shifted(size))?;
+    Ok((k, size + length))
+}
+
+// Array index of a JSON pointer token: `0`, or digits without leading zeros nor sign (RFC 6901).
+fn pointer_index(token: &str) -> Option<usize> {
+    if token.is_empty()
+        || (token.len() > 1 && token.starts_with('0'))
+        || !token.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    token.parse().ok()
+}
+
+// Resolver leaving the decoded nodes untouched.
+fn keep(o: EncodedData) -> Result<EncodedData, DecodeError> {
+    Ok(o)
+}
+
+// Strings are expected to be valid UTF-8, as encode always writes them. Input from other encoders
+// (e.g. WTF-8 with lone surrogates) can be read with DecodeOptions::lossy_utf8.
+pub(crate) fn decode_str<A: DecodeArena>(
+    payload: &[u8],
+    options: &DecodeOptions,
+    arena: &mut Budgeted<A>,
+) -> Result<String, DecodeError> {
+    if options.lossy_utf8 {
+        // Charged before the conversion, each invalid sequence growing into a 3 byte U+FFFD.
+        let length = payload
+            .utf8_chunks()
+            .map(|chunk| chunk.valid().len() + 3 * !chunk.invalid().is_empty() as usize)
+            .sum();
+        arena.charge(length)?;
+        Ok(arena.alloc_string(&String::from_utf8_lossy(payload)))
+    } else {
+        arena.charge(payload.len())?;
+        match std::str::from_utf8(payload) {
+            Ok(s) => Ok(arena.alloc_string(s)),
+            Err(error) => Err(DecodeError::BadUtf8 {
+                offset: error.valid_up_to(),
+                error,
+            }),
+        }
+    }
+}
+
+impl EncodedData {
+    // Converts from JSON, renaming object keys through `key_map`.
+    pub fn from_json_with_key_map(v: serde_json::Value, key_map: &KeyMap) -> Self {
+        match v {
+            serde_json::Value::Null => Self::Special(EncodedSpecial::Null),
+            serde_json::Value::Bool(b) => Self::Integer(EncodedInteger::Bool(b)),
+            serde_json::Value::Number(n) => {
+                // Classified on the number text, as serde_json's own classification depends on
+                // arbitrary_precision: 100 is an integer, 100.0 and 1e2 are floats either way.
+                let integral = !n.to_string().contains(['.', 'e', 'E']);
+                if let Some(n) = n.as_u64().filter(|_| integral) {
+                    Self::Integer(EncodedInteger::Positive(n))
+                } else if let Some(n) = n.as_i64().filter(|_| integral) {
+                    Self::Integer(EncodedInteger::Negative(n.unsigned_abs()))
+                } else {
+                    // Without arbitrary_precision the number text always is the f64's.
+                    match n.as_f64() {
+                        Some(f)
+                            if !cfg!(feature = "decimal")
+                                || serde_json::Number::from_f64(f).map(|f| f.to_string())
+                                    == Some(n.to_string()) =>
+                        {
+                            Self::Float(f)
                         }
-                        let n = decode_compact_u64(data.get_unchecked(1..), length);
-                        if negative {
-                            (
-                                Self::Integer(EncodedInteger::Negative(n)),
-                                1 + length as usize,
-                            )
-                        } else {
-                            (
-                                Self::Integer(EncodedInteger::Positive(n)),
-                                1 + length as usize,
-                            )
+                        _ => Self::Decimal(n.to_string()),
+                    }
+                }
+            }
+            serde_json::Value::String(s) => Self::String(s),
+            serde_json::Value::Array(list) => Self::Array(
+                list.into_iter()
+                    .map(|o| Self::from_json_with_key_map(o, key_map))
+                    .collect(),
+            ),
+            serde_json::Value::Object(map) => Self::Object(
+                map.into_iter()
+                    .map(|(k, o)| {
+                        (
+                            key_map.apply(&k).to_string(),
+                            Self::from_json_with_key_map(o, key_map),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    // Converts to JSON, undoing the key renaming of from_json_with_key_map with the same
+    // `key_map`.
+    pub fn into_json_with_key_map(
+        self,
+        key_map: &KeyMap,
+    ) -> Result<serde_json::Value, EncodedDataToJsonError> {
+        self.into_json(key_map, &JsonOptions::default())
+    }
+
+    pub fn into_json_with_options(
+        self,
+        options: &JsonOptions,
+    ) -> Result<serde_json::Value, EncodedDataToJsonError> {
+        self.into_json(&KeyMap::default(), options)
+    }
+
+    fn into_json(
+        self,
+        key_map: &KeyMap,
+        options: &JsonOptions,
+    ) -> Result<serde_json::Value, EncodedDataToJsonError> {
+        use serde_json::Value;
+        Ok(match self {
+            Self::Special(EncodedSpecial::Null) => Value::Null,
+            Self::Special(EncodedSpecial::None) => {
+                return Err(EncodedDataToJsonError::UnsupportedNoneDataType)
+            }
+            Self::Special(EncodedSpecial::Define(_)) => {
+                return Err(EncodedDataToJsonError::UnsupportedDefineDataType)
+            }
+            Self::Special(EncodedSpecial::Forget(_)) => {
+                return Err(EncodedDataToJsonError::UnsupportedForgetDataType)
+            }
+            Self::Special(EncodedSpecial::Reset) => {
+                return Err(EncodedDataToJsonError::UnsupportedResetDataType)
+            }
+            Self::Integer(EncodedInteger::Bool(b)) => Value::Bool(b),
+            Self::Integer(EncodedInteger::Positive(n)) => Value::Number((n).into()),
+            Self::Integer(EncodedInteger::Negative(n)) => match 0i64.checked_sub_unsigned(n) {
+                Some(n) => Value::Number(n.into()),
+                None if options.big_negatives_as_strings => Value::String(format!("-{}", n)),
+                None => return Err(EncodedDataToJsonError::NegativeIntegerTooBig(n)),
+            },
+            Self::Float(n) => Value::Number(
+                serde_json::Number::from_f64(n).ok_or(EncodedDataToJsonError::BadFloat(n))?,
+            ),
+            Self::String(s) => Value::String(s),
+            Self::Decimal(s) => Value::Number(
+                serde_json::from_str(&s).map_err(|_| EncodedDataToJsonError::BadDecimal(s))?,
+            ),
+            Self::Array(list) => Value::Array(
+                list.into_iter()
+                    .map(|o| o.into_json(key_map, options))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Self::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(k, o)| {
+                        o.into_json(key_map, options)
+                            .map(|v| (key_map.apply(&k).to_string(), v))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            Self::OrderedObject(entries) => Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, o)| {
+                        o.into_json(key_map, options)
+                            .map(|v| (key_map.apply(&k).to_string(), v))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            Self::Alias(_) => return Err(EncodedDataToJsonError::UnsupportedAliasDataType),
+        })
+    }
+
+    // Same as From<serde_json::Value>, with objects converted to OrderedObject entries already
+    // in key order, so that encoding them does not sort them again.
+    pub fn from_json_sorted(v: serde_json::Value) -> Self {
+        Self::from_json_entries(v, true)
+    }
+
+    // Same as From<serde_json::Value>, with objects converted to OrderedObject entries in the
+    // iteration order of serde_json's Map: insertion order with its preserve_order feature,
+    // sorted otherwise.
+    pub fn from_json_ordered(v: serde_json::Value) -> Self {
+        Self::from_json_entries(v, false)
+    }
+
+    // Parses JSON text with objects as OrderedObject entries in input order. Unlike the
+    // serde_json::Value based constructors, duplicate keys are kept as separate entries.
+    pub fn from_json_str_ordered(json: &str) -> Result<Self, serde_json::Error> {
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        let o = serde::de::DeserializeSeed::deserialize(
+            EncodedDataVisitor {
+                ordered: true,
+                spans: None,
+            },
+            &mut deserializer,
+        )?;
+        deserializer.end()?;
+        Ok(o)
+    }
+
+    // Same as from_json_str_ordered, also returning the byte range of `json` each value was
+    // parsed from, e.g. to point diagnostics at the source text.
+    pub fn from_json_str_with_spans(json: &str) -> Result<(Self, SourceSpans), serde_json::Error> {
+        let pos = std::cell::Cell::new(0);
+        let recorder = SpanRecorder {
+            json: json.as_bytes(),
+            pos: &pos,
+            spans: Default::default(),
+        };
+        let mut deserializer = serde_json::Deserializer::from_reader(SpanReader {
+            json: json.as_bytes(),
+            pos: &pos,
+        });
+        let o = serde::de::DeserializeSeed::deserialize(
+            EncodedDataVisitor {
+                ordered: true,
+                spans: Some(&recorder),
+            },
+            &mut deserializer,
+        )?;
+        deserializer.end()?;
+        Ok((o, SourceSpans(recorder.spans.into_inner())))
+    }
+
+    // JSON text of the value, writing every OrderedObject entry, duplicate keys included, which
+    // serde_json::Value cannot hold. Object keys are written sorted.
+    pub fn to_json_string(&self) -> Result<String, EncodedDataToJsonError> {
+        let mut out = String::new();
+        self.write_json(&mut out)?;
+        Ok(out)
+    }
+
+    fn write_json(&self, out: &mut String) -> Result<(), EncodedDataToJsonError> {
+        fn write_entries<'a>(
+            entries: impl Iterator<Item = (&'a String, &'a EncodedData)>,
+            out: &mut String,
+        ) -> Result<(), EncodedDataToJsonError> {
+            out.push('{');
+            for (i, (k, o)) in entries.enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::from(k.as_str()).to_string());
+                out.push(':');
+                o.write_json(out)?;
+            }
+            out.push('}');
+            Ok(())
+        }
+        match self {
+            Self::Array(list) => {
+                out.push('[');
+                for (i, o) in list.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    o.write_json(out)?;
+                }
+                out.push(']');
+            }
+            Self::Object(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                write_entries(entries.into_iter(), out)?;
+            }
+            Self::OrderedObject(entries) => {
+                write_entries(entries.iter().map(|(k, o)| (k, o)), out)?
+            }
+            o => out.push_str(&serde_json::Value::try_from(o.clone())?.to_string()),
+        }
+        Ok(())
+    }
+
+    fn from_json_entries(v: serde_json::Value, sort: bool) -> Self {
+        match v {
+            serde_json::Value::Array(list) => Self::Array(
+                list.into_iter()
+                    .map(|o| Self::from_json_entries(o, sort))
+                    .collect(),
+            ),
+            serde_json::Value::Object(map) => {
+                let mut entries: Vec<_> = map
+                    .into_iter()
+                    .map(|(k, o)| (k, Self::from_json_entries(o, sort)))
+                    .collect();
+                if sort {
+                    entries.sort_by(|a, b| a.0.cmp(&b.0));
+                }
+                Self::OrderedObject(entries)
+            }
+            v => Self::from(v),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with_options(&EncodeOptions::default())
+    }
+
+    pub fn encode_with_options(&self, options: &EncodeOptions) -> Vec<u8> {
+        self.compress_reported(options).0
+    }
+
+    // Same as encode_with_options, also telling which alternative array encodings were chosen.
+    pub fn compress_reported(&self, options: &EncodeOptions) -> (Vec<u8>, CompressReport) {
+        let mut report = CompressReport::default();
+        match self.encode_into_report(options, None, &mut report) {
+            Ok(data) => (data, report),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    // Same as encode_with_options, appending to `buf`, but gives up with OutputTooLarge as soon as
+    // the encoding is known to exceed options.max_output. `buf` is then left as it was.
+    pub fn encode_into(
+        &self,
+        options: &EncodeOptions,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), EncodeError> {
+        let start = buf.len();
+        let ret = self
+            .write_into(
+                options,
+                options.max_output,
+                &mut CompressReport::default(),
+                buf,
+            )
+            .and_then(|()| check_len(buf.len() - start, options.max_output));
+        if ret.is_err() {
+            buf.truncate(start);
+        }
+        ret
+    }
+
+    // Encodes `value` at the end of `buf`, so that a buffer of concatenated values can be grown
+    // one value at a time and read back with decode_all or decode_spans. Values compressed by a
+    // StreamCompressor go through StreamCompressor::append_to instead.
+    pub fn append_to(buf: &mut Vec<u8>, value: &EncodedData) {
+        value.write_plain(buf);
+    }
+
+    fn write_plain(&self, buf: &mut Vec<u8>) {
+        let options = EncodeOptions::default();
+        match self.write_into(&options, None, &mut CompressReport::default(), buf) {
+            Ok(()) => (),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    // Same as encode, built in a scratch buffer kept by the calling thread across calls, so that
+    // the only allocation left is the returned copy. Each thread has its own buffer, which is
+    // never shared: encode_pooled can be called from any number of threads at once. Buffers
+    // grown past MAX_SCRATCH_CAPACITY are freed after use.
+    pub fn encode_pooled(&self) -> Vec<u8> {
+        use std::cell::RefCell;
+        thread_local! {
+            static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        }
+        SCRATCH.with(|scratch| {
+            let mut buf = scratch.borrow_mut();
+            buf.clear();
+            self.write_plain(&mut buf);
+            let data = buf.to_vec();
+            // One large value does not pin its buffer for the rest of the thread.
+            if buf.capacity() > MAX_SCRATCH_CAPACITY {
+                *buf = Vec::new();
+            }
+            data
+        })
+    }
+
+    fn encode_into_report(
+        &self,
+        options: &EncodeOptions,
+        max_output: Option<usize>,
+        report: &mut CompressReport,
+    ) -> Result<Vec<u8>, EncodeError> {
+        let mut buf = vec![];
+        self.write_into(options, max_output, report, &mut buf)?;
+        Ok(buf)
+    }
+
+    // Appends the encoding of the value to `buf`, containers included, so that only the sized
+    // and alternative array encodings build intermediate vectors. max_output bounds each
+    // container on its own.
+    fn write_into(
+        &self,
+        options: &EncodeOptions,
+        max_output: Option<usize>,
+        report: &mut CompressReport,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), EncodeError> {
+        let start = buf.len();
+        match self {
+            Self::Special(spe) => match spe {
+                EncodedSpecial::None => buf.push(data_type::SPECIAL << 5 | special_type::NONE),
+                EncodedSpecial::Null => buf.push(data_type::SPECIAL << 5 | special_type::NULL),
+                EncodedSpecial::Define(o) => {
+                    buf.push(data_type::SPECIAL << 5 | special_type::DEFINE);
+                    o.write_into(options, max_output, report, buf)?;
+                }
+                EncodedSpecial::Forget(id) => {
+                    buf.push(data_type::SPECIAL << 5 | special_type::FORGET);
+                    varint::write(*id, buf);
+                }
+                EncodedSpecial::Reset => buf.push(data_type::SPECIAL << 5 | special_type::RESET),
+            },
+            Self::Integer(int) => match (int, options.integers) {
+                (EncodedInteger::Positive(n), IntegerEncoding::CompactFixed) => {
+                    buf.push(data_type::INTEGER << 5 | compact_u64_len(*n));
+                    buf.extend(encode_compact_u64(*n));
+                }
+                (EncodedInteger::Negative(n), IntegerEncoding::CompactFixed) => {
+                    buf.push(data_type::INTEGER << 5 | 1 << 4 | compact_u64_len(*n));
+                    buf.extend(encode_compact_u64(*n));
+                }
+                (EncodedInteger::Positive(n), IntegerEncoding::Varint) => {
+                    buf.push(data_type::INTEGER << 5 | INTEGER_VARINT);
+                    varint::write(*n, buf);
+                }
+                (EncodedInteger::Negative(n), IntegerEncoding::Varint) => {
+                    buf.push(data_type::INTEGER << 5 | 1 << 4 | INTEGER_VARINT);
+                    varint::write(*n, buf);
+                }
+                (EncodedInteger::Bool(b), _) => {
+                    let b_flag = if *b { 1 } else { 0 };
+                    buf.push(data_type::INTEGER << 5 | b_flag << 4);
+                }
+            },
+            Self::Float(f) => match encode_integral_float(*f) {
+                Some(n) => {
+                    buf.push(data_type::FLOAT << 5 | FLOAT_HINT | n.len() as u8);
+                    buf.extend(n);
+                }
+                None => {
+                    buf.push(data_type::FLOAT << 5 | 8);
+                    buf.extend_from_slice(&f.to_le_bytes());
+                }
+            },
+            Self::String(s) => {
+                buf.extend(encode_container_header(data_type::STRING, s.len()));
+                buf.extend_from_slice(s.as_bytes());
+            }
+            Self::Decimal(s) => {
+                buf.push(data_type::SPECIAL << 5 | special_type::DECIMAL);
+                buf.extend(encode_key(s));
+            }
+            Self::Array(array) => {
+                buf.extend(encode_container_header(data_type::ARRAY, array.len()));
+                // Sizes of the encoded elements, for encode_null_runs to reuse them.
+                let mut sizes = vec![];
+                for o in array.iter() {
+                    let child_start = buf.len();
+                    o.write_into(options, max_output, report, buf)?;
+                    if options.null_runs {
+                        sizes.push(buf.len() - child_start);
+                    }
+                    check_len(buf.len() - start, max_output)?;
+                }
+                if options.sized_containers {
+                    sized_from(buf, start);
+                }
+                let plain_len = buf.len() - start;
+                let mut best: Option<Vec<u8>> = None;
+                let mut consider = |candidate: Option<Vec<u8>>| {
+                    if let Some(candidate) = candidate {
+                        if candidate.len() < best.as_ref().map_or(plain_len, Vec::len) {
+                            best = Some(candidate);
+                        }
+                    }
+                };
+                if options.packed_arrays {
+                    consider(encode_packed_array(array));
+                }
+                if options.delta_arrays {
+                    consider(encode_delta_array(array));
+                }
+                if options.sorted_arrays {
+                    consider(encode_sorted_array(array));
+                }
+                if options.null_runs {
+                    let children = &buf[buf.len() - sizes.iter().sum::<usize>()..];
+                    consider(encode_null_runs(array, children, &sizes));
+                }
+                if let Some(best) = best {
+                    report.add(best[0], plain_len - best.len());
+                    buf.truncate(start);
+                    buf.extend(best);
+                }
+                check_len(buf.len() - start, max_output)?;
+            }
+            Self::Object(map) => {
+                buf.extend(encode_container_header(data_type::OBJECT, map.len()));
+                for (k, o) in sorted_entries(map) {
+                    buf.extend(encode_key(k));
+                    o.write_into(options, max_output, report, buf)?;
+                    check_len(buf.len() - start, max_output)?;
+                }
+                if options.sized_containers {
+                    sized_from(buf, start);
+                }
+            }
+            Self::OrderedObject(entries) => {
+                buf.extend(encode_container_header(data_type::OBJECT, entries.len()));
+                for (k, o) in entries.iter() {
+                    buf.extend(encode_key(k));
+                    o.write_into(options, max_output, report, buf)?;
+                    check_len(buf.len() - start, max_output)?;
+                }
+                if options.sized_containers {
+                    sized_from(buf, start);
+                }
+            }
+            Self::Alias(id) => buf.extend(encode_id(data_type::ALIAS, *id)),
+        }
+        Ok(())
+    }
+
+    // Where the bytes of encode() go, e.g. to see whether keys or strings dominate.
+    pub fn size_breakdown(&self) -> SizeBreakdown {
+        let mut breakdown = SizeBreakdown::default();
+        self.add_sizes(&mut breakdown);
+        breakdown
+    }
+
+    fn add_sizes(&self, breakdown: &mut SizeBreakdown) {
+        match self {
+            Self::Special(EncodedSpecial::Define(o)) => {
+                breakdown.specials += 1;
+                o.add_sizes(breakdown);
+            }
+            Self::Special(_) => breakdown.specials += self.encode().len(),
+            Self::Integer(_) => breakdown.integers += self.encode().len(),
+            Self::Float(_) => breakdown.floats += self.encode().len(),
+            Self::Decimal(_) => breakdown.decimals += self.encode().len(),
+            Self::String(_) => breakdown.strings += self.encode().len(),
+            Self::Alias(_) => breakdown.aliases += self.encode().len(),
+            Self::Array(list) => {
+                breakdown.containers += encode_container_header(data_type::ARRAY, list.len()).len();
+                for o in list.iter() {
+                    o.add_sizes(breakdown);
+                }
+            }
+            Self::Object(map) => {
+                breakdown.containers += encode_container_header(data_type::OBJECT, map.len()).len();
+                for (k, o) in map.iter() {
+                    breakdown.keys += encode_key(k).len();
+                    o.add_sizes(breakdown);
+                }
+            }
+            Self::OrderedObject(entries) => {
+                breakdown.containers +=
+                    encode_container_header(data_type::OBJECT, entries.len()).len();
+                for (k, o) in entries.iter() {
+                    breakdown.keys += encode_key(k).len();
+                    o.add_sizes(breakdown);
+                }
+            }
+        }
+    }
+
+    // Same as encode, prefixed with FORMAT_VERSION.
+    pub fn encode_versioned(&self) -> Vec<u8> {
+        [vec![FORMAT_VERSION], self.encode()].concat()
+    }
+
+    // Decodes data written by encode_versioned. The returned size includes the version byte.
+    pub fn decode_versioned(data: &[u8]) -> Result<(Self, usize), DecodeError> {
+        match data.first() {
+            Some(&FORMAT_VERSION) => {
+                let (o, size) = Self::decode(&data[1..]).map_err(|e| e.shifted(1))?;
+                Ok((o, 1 + size))
+            }
+            Some(version) => Err(DecodeError::UnsupportedVersion(*version)),
+            None => Err(DecodeError::MissingBytes(1)),
+        }
+    }
+
+    // Registers Define nodes in `aliases` and replaces them by their value, and replaces Alias
+    // nodes by the value they refer to. Meant to be used as a decode_with resolver.
+    pub fn resolve_alias(aliases: &mut Vec<Self>, o: Self) -> Result<Self, DecodeError> {
+        match o {
+            Self::Special(EncodedSpecial::Define(o)) => {
+                aliases.push(*o.clone());
+                Ok(*o)
+            }
+            Self::Alias(id) => aliases
+                .get(id as usize)
+                .cloned()
+                .ok_or(DecodeError::UnknownAlias(id)),
+            o => Ok(o),
+        }
+    }
+
+    // Decodes a value that defines its own aliases, e.g. a single StreamCompressor output, with
+    // every Define and Alias substituted by the actual value, wherever they are in the tree.
+    pub fn decode_resolved(data: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut aliases = vec![];
+        Self::decode_with(data, &DecodeOptions::default(), &mut |o| {
+            Self::resolve_alias(&mut aliases, o)
+        })
+    }
+
+    // Same as decode, also feeding `hasher` with the canonical encoding of the value (default
+    // options, sorted keys, minimal integers), so that logically equal inputs hash the same even
+    // when encoded differently.
+    pub fn decode_hashing<H: std::hash::Hasher>(
+        data: &[u8],
+        hasher: &mut H,
+    ) -> Result<(Self, usize), DecodeError> {
+        let (o, size) = Self::decode(data)?;
+        o.write_canonical(hasher);
+        Ok((o, size))
+    }
+
+    pub(crate) fn write_canonical<H: std::hash::Hasher>(&self, hasher: &mut H) {
+        match self {
+            Self::Array(list) => {
+                hasher.write(&encode_container_header(data_type::ARRAY, list.len()));
+                for o in list.iter() {
+                    o.write_canonical(hasher);
+                }
+            }
+            Self::Object(map) => {
+                hasher.write(&encode_container_header(data_type::OBJECT, map.len()));
+                for (k, o) in sorted_entries(map) {
+                    hasher.write(&encode_key(k));
+                    o.write_canonical(hasher);
+                }
+            }
+            Self::OrderedObject(entries) => {
+                hasher.write(&encode_container_header(data_type::OBJECT, entries.len()));
+                let mut entries: Vec<_> = entries.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                for (k, o) in entries {
+                    hasher.write(&encode_key(k));
+                    o.write_canonical(hasher);
+                }
+            }
+            Self::Integer(EncodedInteger::Negative(0)) => {
+                hasher.write(&Self::Integer(EncodedInteger::Positive(0)).encode())
+            }
+            o => hasher.write(&o.encode()),
+        }
+    }
+
+    // Rewrites the values having several representations of the same JSON value into a single
+    // one, so that encode -> json -> encode is stable. Only Negative(0) is rewritten, into
+    // Positive(0).
+    pub fn canonicalize(self) -> Self {
+        match self {
+            Self::Integer(EncodedInteger::Negative(0)) => {
+                Self::Integer(EncodedInteger::Positive(0))
+            }
+            Self::Special(EncodedSpecial::Define(o)) => {
+                Self::Special(EncodedSpecial::Define(Box::new(o.canonicalize())))
+            }
+            Self::Array(list) => Self::Array(list.into_iter().map(Self::canonicalize).collect()),
+            Self::Object(map) => Self::Object(
+                map.into_iter()
+                    .map(|(k, o)| (k, o.canonicalize()))
+                    .collect(),
+            ),
+            Self::OrderedObject(entries) => Self::OrderedObject(
+                entries
+                    .into_iter()
+                    .map(|(k, o)| (k, o.canonicalize()))
+                    .collect(),
+            ),
+            o => o,
+        }
+    }
+
+    // Decodes the value starting at `offset` and returns it with the offset following it.
+    pub fn decode_at(data: &[u8], offset: usize) -> Result<(Self, usize), DecodeError> {
+        if offset > data.len() {
+            return Err(DecodeError::MissingBytes(offset - data.len()));
+        }
+        let (o, size) = Self::decode(&data[offset..]).map_err(|e| e.shifted(offset))?;
+        Ok((o, offset + size))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), DecodeError> {
+        Self::decode_with_options(data, &DecodeOptions::default())
+    }
+
+    pub fn decode_with_options(
+        data: &[u8],
+        options: &DecodeOptions,
+    ) -> Result<(Self, usize), DecodeError> {
+        Self::decode_with(data, options, &mut |o| Ok(o))
+    }
+
+    // Same as decode, but every decoded node is passed through `resolve`, children first and in
+    // byte order, so that Define/Alias nodes can be substituted while the stream is read.
+    pub fn decode_with<F>(
+        data: &[u8],
+        options: &DecodeOptions,
+        resolve: &mut F,
+    ) -> Result<(Self, usize), DecodeError>
+    where
+        F: FnMut(Self) -> Result<Self, DecodeError>,
+    {
+        Self::decode_in_with(
+            data,
+            options,
+            options.max_depth,
+            resolve,
+            &mut Budgeted::new(&mut HeapArena, options),
+        )
+        .map_err(|e| e.error)
+    }
+
+    // Same as decode, with the strings and containers allocated through `arena`.
+    pub fn decode_in<A: DecodeArena>(
+        data: &[u8],
+        arena: &mut A,
+    ) -> Result<(Self, usize), DecodeError> {
+        let options = DecodeOptions::default();
+        let mut arena = Budgeted::new(arena, &options);
+        Self::decode_in_with(data, &options, options.max_depth, &mut keep, &mut arena)
+            .map_err(|e| e.error)
+    }
+
+    // Same as decode, the error also giving the position of the value that failed to decode.
+    pub fn decode_located(data: &[u8]) -> Result<(Self, usize), DecodeErrorAt> {
+        let options = DecodeOptions::default();
+        Self::decode_in_with(
+            data,
+            &options,
+            options.max_depth,
+            &mut keep,
+            &mut Budgeted::new(&mut HeapArena, &options),
+        )
+    }
+
+    // Containers are decoded with a heap allocated work stack rather than by recursion, so that
+    // the nesting depth is not bounded by the thread stack. The special encodings wrapping a value
+    // (define, sized, null runs) still recurse. `depth` is the number of levels the value may
+    // nest, wrappers included.
+    fn decode_in_with<F, A>(
+        data: &[u8],
+        options: &DecodeOptions,
+        depth: usize,
+        resolve: &mut F,
+        arena: &mut Budgeted<A>,
+    ) -> Result<(Self, usize), DecodeErrorAt>
+    where
+        F: FnMut(Self) -> Result<Self, DecodeError>,
+        A: DecodeArena,
+    {
+        let mut stack: Vec<Frame> = vec![];
+        let mut pos = 0;
+        loop {
+            if let Some(Frame::Object { start, key, .. }) = stack.last_mut() {
+                let (k, size) =
+                    decode_key(&data[pos..], options, arena).map_err(|e| DecodeErrorAt {
+                        offset: *start,
+                        error: e.shifted(pos),
+                    })?;
+                *key = Some(k);
+                pos += size;
+            }
+            let mut start = pos;
+            let (node, size) = Self::decode_node(
+                &data[pos..],
+                options,
+                depth.saturating_sub(stack.len()),
+                resolve,
+                arena,
+            )
+            .map_err(|e| e.shifted(pos))?;
+            pos += size;
+            let capacity = node.length().min(data.len() - pos);
+            let mut o = match node {
+                Node::Value(o) => o,
+                Node::Array(0) => Self::Array(arena.alloc_array(0)),
+                Node::Object(0) if options.ordered_objects => Self::OrderedObject(vec![]),
+                Node::Object(0) => Self::Object(arena.alloc_object(0)),
+                Node::Array(remaining) => {
+                    stack.push(Frame::Array {
+                        start,
+                        remaining,
+                        list: arena.alloc_array(capacity),
+                    });
+                    continue;
+                }
+                Node::Object(remaining) => {
+                    let (map, entries) = if options.ordered_objects {
+                        (HashMap::new(), Vec::with_capacity(capacity))
+                    } else {
+                        (arena.alloc_object(capacity), vec![])
+                    };
+                    stack.push(Frame::Object {
+                        start,
+                        remaining,
+                        key: None,
+                        map,
+                        entries,
+                    });
+                    continue;
+                }
+            };
+            // Hands the value to its container, climbing up the containers it completes.
+            loop {
+                o = resolve(o).map_err(|e| DecodeErrorAt::from(e).shifted(start))?;
+                let frame = match stack.last_mut() {
+                    Some(frame) => frame,
+                    None => return Ok((o, pos)),
+                };
+                if !frame.push(o, options) {
+                    break;
+                }
+                let frame = stack.pop().unwrap();
+                start = frame.start();
+                o = frame.into_value(options);
+            }
+        }
+    }
+
+    // Decodes the first node of `data`, without the children of plain containers.
+    fn decode_node<F, A>(
+        data: &[u8],
+        options: &DecodeOptions,
+        depth: usize,
+        resolve: &mut F,
+        arena: &mut Budgeted<A>,
+    ) -> Result<(Node, usize), DecodeErrorAt>
+    where
+        F: FnMut(Self) -> Result<Self, DecodeError>,
+        A: DecodeArena,
+    {
+        let depth = depth.checked_sub(1).ok_or(DecodeError::TooDeep)?;
+        unsafe {
+            if data.is_empty() {
+                return Err(DecodeError::MissingBytes(1).into());
+            }
+            let ctrl = data.get_unchecked(0);
+            let data_type_value = ctrl >> 5;
+            let data_type = match DataType::from(data_type_value) {
+                Some(data_type) => data_type,
+                None => return Err(DecodeError::UnknownDataType(data_type_value).into()),
+            };
+            let (o, size) = match data_type {
+                DataType::Special => {
+                    let special_type_value = ctrl & 0x1F;
+                    let special_type = match SpecialType::from(special_type_value) {
+                        Some(special_type) => special_type,
+                        None if options.skip_unknown
+                            && special_type_value >= special_type::SKIPPABLE =>
+                        {
+                            let skipped = skip_unknown_special(data)?;
+                            let (node, size) = Self::decode_node(
+                                data.get_unchecked(skipped..),
+                                options,
+                                depth,
+                                resolve,
+                                arena,
+                            )
+                            .map_err(|e| e.shifted(skipped))?;
+                            return Ok((node, skipped + size));
+                        }
+                        None => {
+                            return Err(DecodeError::UnknownSpecialType(special_type_value).into())
+                        }
+                    };
+                    match special_type {
+                        SpecialType::None => (Self::Special(EncodedSpecial::None), 1),
+                        SpecialType::Null => (Self::Special(EncodedSpecial::Null), 1),
+                        SpecialType::Define => {
+                            if data.len() < 2 {
+                                return Err(DecodeError::MissingBytes(1).into());
+                            }
+                            // Resolved first, so that the aliases it holds are substituted and
+                            // the Defines it holds get the ids before its own.
+                            let (object, size) = Self::decode_in_with(
+                                data.get_unchecked(1..),
+                                options,
+                                depth,
+                                resolve,
+                                arena,
+                            )
+                            .map_err(|e| e.shifted(1))?;
+                            (
+                                Self::Special(EncodedSpecial::Define(Box::new(object))),
+                                1 + size,
+                            )
+                        }
+                        SpecialType::Forget => {
+                            let (id, size) = decode_varint(data.get_unchecked(1..))?;
+                            (Self::Special(EncodedSpecial::Forget(id)), 1 + size)
+                        }
+                        SpecialType::Reset => (Self::Special(EncodedSpecial::Reset), 1),
+                        SpecialType::Sized => {
+                            let (length, size) = decode_varint(data.get_unchecked(1..))?;
+                            let length = length as usize;
+                            if data.len() - 1 - size < length {
+                                return Err(DecodeError::MissingBytes(
+                                    length - (data.len() - 1 - size),
+                                )
+                                .into());
+                            }
+                            let (object, object_size) = Self::decode_in_with(
+                                &data[1 + size..1 + size + length],
+                                options,
+                                depth,
+                                resolve,
+                                arena,
+                            )
+                            .map_err(|e| e.in_sized(length).shifted(1 + size))?;
+                            if object_size != length {
+                                return Err(DecodeError::BadSizedLength(length).into());
+                            }
+                            (object, 1 + size + length)
+                        }
+                        SpecialType::BoolArray | SpecialType::IntegerArray => {
+                            decode_packed_array(data)?
+                        }
+                        SpecialType::DeltaArray => decode_delta_array(data)?,
+                        SpecialType::SortedArray => decode_sorted_array(data)?,
+                        SpecialType::NullRuns => {
+                            let (nb_values, mut tot_size) = decode_varint(data.get_unchecked(1..))?;
+                            tot_size += 1;
+                            let mut list = arena.alloc_array(0);
+                            let push_nulls =
+                                |list: &mut Vec<Self>,
+                                 tot_size: &mut usize|
+                                 -> Result<(), DecodeError> {
+                                    let nb_nulls = decode_null_run(data, tot_size, list.len())?;
+                                    for _ in 0..nb_nulls {
+                                        list.push(Self::Special(EncodedSpecial::Null));
+                                    }
+                                    Ok(())
+                                };
+                            for _ in 0..nb_values {
+                                push_nulls(&mut list, &mut tot_size)?;
+                                let (o, size) = Self::decode_in_with(
+                                    &data[tot_size..],
+                                    options,
+                                    depth,
+                                    resolve,
+                                    arena,
+                                )
+                                .map_err(|e| e.shifted(tot_size))?;
+                                list.push(o);
+                                tot_size += size;
+                            }
+                            push_nulls(&mut list, &mut tot_size)?;
+                            (Self::Array(list), tot_size)
+                        }
+                        SpecialType::Extend => {
+                            let (base, size) = Self::decode_in_with(
+                                data.get_unchecked(1..),
+                                options,
+                                depth,
+                                resolve,
+                                arena,
+                            )
+                            .map_err(|e| e.shifted(1))?;
+                            let start = 1 + size;
+                            let (tail, size) = Self::decode_in_with(
+                                &data[start..],
+                                options,
+                                depth,
+                                resolve,
+                                arena,
+                            )
+                            .map_err(|e| e.shifted(start))?;
+                            (extend_object(base, tail)?, start + size)
+                        }
+                        SpecialType::Decimal => {
+                            let (length, size) = decode_varint(data.get_unchecked(1..))?;
+                            let length = length as usize;
+                            let start = 1 + size;
+                            if data.len() - start < length {
+                                return Err(DecodeError::MissingBytes(
+                                    length - (data.len() - start),
+                                )
+                                .into());
+                            }
+                            let s = decode_str(&data[start..start + length], options, arena)
+                                .map_err(|e| e.shifted(start))?;
+                            (Self::Decimal(s), start + length)
+                        }
+                    }
+                }
+                DataType::Integer => {
+                    let length = ctrl & 0x0F;
+                    let negative = ctrl & 0x10 != 0;
+                    if length == 0 {
+                        (Self::Integer(EncodedInteger::Bool(negative)), 1)
+                    } else if length == INTEGER_VARINT {
+                        let (n, size) = decode_varint(&data[1..])?;
+                        let int = if negative {
+                            EncodedInteger::Negative(n)
+                        } else {
+                            EncodedInteger::Positive(n)
+                        };
+                        (Self::Integer(int), 1 + size)
+                    } else {
+                        if length > 8 {
+                            return Err(DecodeError::BadIntegerLength(length).into());
+                        }
+                        if data.len() < 1 + length as usize {
+                            return Err(DecodeError::MissingBytes(
+                                1 + length as usize - data.len(),
+                            )
+                            .into());
+                        }
+                        let n = decode_compact_u64(&data[1..1 + length as usize]);
+                        if negative {
+                            (
+                                Self::Integer(EncodedInteger::Negative(n)),
+                                1 + length as usize,
+                            )
+                        } else {
+                            (
+                                Self::Integer(EncodedInteger::Positive(n)),
+                                1 + length as usize,
+                            )
+                        }
+                    }
+                }
+                DataType::Float if ctrl & FLOAT_HINT != 0 => {
+                    let length = ctrl & 0x0F;
+                    if length == 0 || length > 8 {
+                        return Err(DecodeError::BadFloatLength(length).into());
+                    }
+                    let length = length as usize;
+                    if data.len() < 1 + length {
+                        return Err(DecodeError::MissingBytes(1 + length - data.len()).into());
+                    }
+                    let n = decode_compact_i64(&data[1..1 + length]);
+                    (Self::Float(n as f64), 1 + length)
+                }
+                DataType::Float => {
+                    let width = float_width(*ctrl)?;
+                    if data.len() < 1 + width {
+                        return Err(DecodeError::MissingBytes(1 + width - data.len()).into());
+                    }
+                    let payload = &data[1..1 + width];
+                    let f = match width {
+                        4 => f32::from_le_bytes(<[u8; 4]>::try_from(payload).unwrap()) as f64,
+                        _ => f64::from_le_bytes(<[u8; 8]>::try_from(payload).unwrap()),
+                    };
+                    (Self::Float(f), 1 + width)
+                }
+                DataType::String => {
+                    let (length, size) = decode_data_type_length(data)?;
+                    if data.len() - size < length {
+                        return Err(DecodeError::MissingBytes(length - (data.len() - size)).into());
+                    }
+                    let payload = data.get_unchecked(size..size + length);
+                    let s = decode_str(payload, options, arena).map_err(|e| e.shifted(size))?;
+                    (Self::String(s), size + length)
+                }
+                DataType::Array => {
+                    let (length, size) = decode_data_type_length(data)?;
+                    return Ok((Node::Array(length), size));
+                }
+                DataType::Object => {
+                    let (length, size) = decode_data_type_length(data)?;
+                    return Ok((Node::Object(length), size));
+                }
+                DataType::Alias => {
+                    let (id, size) = decode_id(data)?;
+                    (Self::Alias(id), size)
+                }
+            };
+            Ok((Node::Value(o), size))
+        }
+    }
+
+    // Returns the encoded size of the first value of `data` without building it. Sized containers
+    // are jumped over without looking at their content.
+    pub fn skip(data: &[u8]) -> Result<usize, DecodeError> {
+        Self::skip_nested(data, MAX_DEPTH)
+    }
+
+    // skip of a value allowed `depth` more levels of nesting.
+    fn skip_nested(data: &[u8], depth: usize) -> Result<usize, DecodeError> {
+        let depth = depth.checked_sub(1).ok_or(DecodeError::TooDeep)?;
+        let rest = |i: usize| data.get(i..).unwrap_or(&[]);
+        let ctrl = match data.first() {
+            Some(ctrl) => *ctrl,
+            None => return Err(DecodeError::MissingBytes(1)),
+        };
+        let data_type_value = ctrl >> 5;
+        let data_type = match DataType::from(data_type_value) {
+            Some(data_type) => data_type,
+            None => return Err(DecodeError::UnknownDataType(data_type_value)),
+        };
+        let size = match data_type {
+            DataType::Special => {
+                let special_type_value = ctrl & 0x1F;
+                match SpecialType::from(special_type_value) {
+                    Some(SpecialType::None)
+                    | Some(SpecialType::Null)
+                    | Some(SpecialType::Reset) => 1,
+                    Some(SpecialType::Define) => 1 + Self::skip_nested(rest(1), depth)?,
+                    Some(SpecialType::Forget) => 1 + decode_varint(rest(1))?.1,
+                    Some(SpecialType::Sized) | Some(SpecialType::Decimal) => {
+                        let (length, size) = decode_varint(rest(1))?;
+                        let length = length as usize;
+                        if data.len() - 1 - size < length {
+                            return Err(DecodeError::MissingBytes(
+                                length - (data.len() - 1 - size),
+                            ));
+                        }
+                        1 + size + length
+                    }
+                    Some(SpecialType::BoolArray) | Some(SpecialType::IntegerArray) => {
+                        packed_array_layout(data)?.size
+                    }
+                    Some(SpecialType::DeltaArray) => {
+                        let (nb, mut tot_size) = decode_varint(rest(1))?;
+                        tot_size += 1;
+                        for _ in 0..nb {
+                            tot_size += decode_varint(rest(tot_size))?.1;
+                        }
+                        tot_size
+                    }
+                    Some(SpecialType::SortedArray) => {
+                        let (nb, mut tot_size) = decode_varint(rest(1))?;
+                        tot_size += 1;
+                        for _ in 0..nb.saturating_mul(2) {
+                            tot_size += decode_varint(rest(tot_size))?.1;
+                        }
+                        tot_size
+                    }
+                    Some(SpecialType::Extend) => {
+                        let base = 1 + Self::skip_nested(rest(1), depth)?;
+                        base + Self::skip_nested(rest(base), depth)?
+                    }
+                    Some(SpecialType::NullRuns) => {
+                        let (nb_values, mut tot_size) = decode_varint(rest(1))?;
+                        tot_size += 1;
+                        for _ in 0..nb_values {
+                            tot_size += decode_varint(rest(tot_size))?.1;
+                            tot_size += Self::skip_nested(rest(tot_size), depth)?;
+                        }
+                        tot_size + decode_varint(rest(tot_size))?.1
+                    }
+                    None => return Err(DecodeError::UnknownSpecialType(special_type_value)),
+                }
+            }
+            DataType::Integer => {
+                let length = ctrl & 0x0F;
+                if length == INTEGER_VARINT {
+                    return Ok(1 + decode_varint(rest(1))?.1);
+                }
+                if length > 8 {
+                    return Err(DecodeError::BadIntegerLength(length));
+                }
+                1 + length as usize
+            }
+            DataType::Float if ctrl & FLOAT_HINT != 0 => {
+                let length = ctrl & 0x0F;
+                if length == 0 || length > 8 {
+                    return Err(DecodeError::BadFloatLength(length));
+                }
+                1 + length as usize
+            }
+            DataType::Float => 1 + float_width(ctrl)?,
+            DataType::String => {
+                let (length, size) = decode_data_type_length(data)?;
+                if data.len() - size < length {
+                    return Err(DecodeError::MissingBytes(length - (data.len() - size)));
+                }
+                size + length
+            }
+            DataType::Array => {
+                let (length, mut tot_size) = decode_data_type_length(data)?;
+                for _ in 0..length {
+                    tot_size += Self::skip_nested(rest(tot_size), depth)?;
+                }
+                tot_size
+            }
+            DataType::Object => {
+                let (length, mut tot_size) = decode_data_type_length(data)?;
+                for _ in 0..length {
+                    let (k_length, size) = decode_varint(rest(tot_size))?;
+                    tot_size += size;
+                    let k_length = k_length as usize;
+                    if data.len() - tot_size < k_length {
+                        return Err(DecodeError::MissingBytes(
+                            k_length - (data.len() - tot_size),
+                        ));
+                    }
+                    tot_size += k_length;
+                    tot_size += Self::skip_nested(rest(tot_size), depth)?;
+                }
+                tot_size
+            }
+            // Checked as decode does, ids past u64::MAX being VarintTooBig.
+            DataType::Alias => decode_id(data)?.1,
+        };
+        if data.len() < size {
+            return Err(DecodeError::MissingBytes(size - data.len()));
+        }
+        Ok(size)
+    }
+
+    // Same as skip, but checks the whole value as decode would (UTF-8 strings and keys, Sized
+    // lengths...) without building it.
+    pub fn validate(data: &[u8]) -> Result<usize, DecodeError> {
+        Self::validate_nested(data, MAX_DEPTH)
+    }
+
+    // validate of a value allowed `depth` more levels of nesting.
+    fn validate_nested(data: &[u8], depth: usize) -> Result<usize, DecodeError> {
+        let depth = depth.checked_sub(1).ok_or(DecodeError::TooDeep)?;
+        let rest = |i: usize| data.get(i..).unwrap_or(&[]);
+        let check_str = |start: usize, length: usize| match data
+            .get(start..)
+            .filter(|payload| payload.len() >= length)
+        {
+            Some(payload) => match std::str::from_utf8(&payload[..length]) {
+                Ok(_) => Ok(start + length),
+                Err(error) => Err(DecodeError::BadUtf8 {
+                    offset: start + error.valid_up_to(),
+                    error,
+                }),
+            },
+            None => Err(DecodeError::MissingBytes(
+                match data.len().checked_sub(start) {
+                    Some(left) => length - left,
+                    None => start.saturating_add(length) - data.len(),
+                },
+            )),
+        };
+        let ctrl = match data.first() {
+            Some(ctrl) => *ctrl,
+            None => return Err(DecodeError::MissingBytes(1)),
+        };
+        let size = match ctrl >> 5 {
+            data_type::SPECIAL => match SpecialType::from(ctrl & 0x1F) {
+                Some(SpecialType::Define) => {
+                    1 + Self::validate_nested(rest(1), depth).map_err(|e| e.shifted(1))?
+                }
+                Some(SpecialType::Sized) => {
+                    let (length, size) = decode_varint(rest(1))?;
+                    let length = length as usize;
+                    if data.len() - 1 - size < length {
+                        return Err(DecodeError::MissingBytes(length - (data.len() - 1 - size)));
+                    }
+                    let inner = Self::validate_nested(&data[1 + size..1 + size + length], depth)
+                        .map_err(|e| e.in_sized(length).shifted(1 + size))?;
+                    if inner != length {
+                        return Err(DecodeError::BadSizedLength(length));
+                    }
+                    1 + size + length
+                }
+                Some(SpecialType::Decimal) => {
+                    let (length, size) = decode_varint(rest(1))?;
+                    check_str(1 + size, length as usize)?
+                }
+                Some(SpecialType::DeltaArray) => read_delta_array(data, None)?,
+                Some(SpecialType::SortedArray) => read_sorted_array(data, None)?,
+                Some(SpecialType::NullRuns) => {
+                    let (nb_values, mut tot_size) = decode_varint(rest(1))?;
+                    tot_size += 1;
+                    let mut len = 0;
+                    for _ in 0..nb_values {
+                        len += decode_null_run(data, &mut tot_size, len)? as usize + 1;
+                        tot_size += Self::validate_nested(rest(tot_size), depth)
+                            .map_err(|e| e.shifted(tot_size))?;
+                    }
+                    decode_null_run(data, &mut tot_size, len)?;
+                    tot_size
+                }
+                Some(SpecialType::Extend) => {
+                    let base =
+                        1 + Self::validate_nested(rest(1), depth).map_err(|e| e.shifted(1))?;
+                    base + Self::validate_nested(rest(base), depth).map_err(|e| e.shifted(base))?
+                }
+                _ => Self::skip(data)?,
+            },
+            data_type::STRING => {
+                let (length, size) = decode_data_type_length(data)?;
+                check_str(size, length)?
+            }
+            data_type::ARRAY => {
+                let (length, mut tot_size) = decode_data_type_length(data)?;
+                for _ in 0..length {
+                    tot_size += Self::validate_nested(rest(tot_size), depth)
+                        .map_err(|e| e.shifted(tot_size))?;
+                }
+                tot_size
+            }
+            data_type::OBJECT => {
+                let (length, mut tot_size) = decode_data_type_length(data)?;
+                for _ in 0..length {
+                    let (k_length, size) = decode_varint(rest(tot_size))?;
+                    tot_size = check_str(tot_size + size, k_length as usize)?;
+                    tot_size += Self::validate_nested(rest(tot_size), depth)
+                        .map_err(|e| e.shifted(tot_size))?;
+                }
+                tot_size
+            }
+            // Scalars hold nothing more to check.
+            _ => Self::skip(data)?,
+        };
+        Ok(size)
+    }
+
+    // Returns the encoded bytes of the first value of `data`, e.g. to forward it verbatim.
+    pub fn slice_value(data: &[u8]) -> Result<&[u8], DecodeError> {
+        let size = Self::skip(data)?;
+        Ok(&data[..size])
+    }
+
+    // Returns `data` with the value at the JSON `pointer` (e.g. "/items/0/name") replaced by
+    // `new_value`. Only the bytes around the target are rewritten: container headers hold their
+    // number of children, only the byte lengths of the enclosing sized containers change.
+    pub fn patch_pointer(
+        data: &[u8],
+        pointer: &str,
+        new_value: &EncodedData,
+    ) -> Result<Vec<u8>, DecodeError> {
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(DecodeError::BadPointer(pointer.to_string()));
+        }
+        let tokens: Vec<_> = pointer
+            .split('/')
+            .skip(1)
+            .map(|t| t.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        let (patched, size) = Self::patch_at(data, &tokens, &new_value.encode(), pointer)?;
+        Ok([&patched[..], &data[size..]].concat())
+    }
+
+    // Returns the new encoding of the first value of `data` and the size of the old one.
+    fn patch_at(
+        data: &[u8],
+        tokens: &[String],
+        new: &[u8],
+        pointer: &str,
+    ) -> Result<(Vec<u8>, usize), DecodeError> {
+        let size = Self::skip(data)?;
+        let (token, rest) = match tokens.split_first() {
+            Some(split) => split,
+            None => return Ok((new.to_vec(), size)),
+        };
+        let not_found = || DecodeError::BadPointer(pointer.to_string());
+        let ctrl = data[0];
+        let child = match ctrl >> 5 {
+            data_type::SPECIAL if ctrl & 0x1F == special_type::SIZED => {
+                let start = 1 + decode_varint(&data[1..])?.1;
+                let (patched, old) = Self::patch_at(&data[start..size], tokens, new, pointer)
+                    .map_err(|e| e.shifted(start))?;
+                let inner = [&patched[..], &data[start + old..size]].concat();
+                return Ok((
+                    [&[ctrl][..], &varint::encode(inner.len() as u64), &inner].concat(),
+                    size,
+                ));
+            }
+            data_type::ARRAY => {
+                let index = pointer_index(token).ok_or_else(not_found)?;
+                let (length, mut offset) = decode_data_type_length(data)?;
+                if index >= length {
+                    return Err(not_found());
+                }
+                for _ in 0..index {
+                    offset += Self::skip(&data[offset..]).map_err(|e| e.shifted(offset))?;
+                }
+                offset
+            }
+            data_type::OBJECT => {
+                let (length, mut offset) = decode_data_type_length(data)?;
+                let mut found = None;
+                for _ in 0..length {
+                    // skip checked that the keys fit in `size`.
+                    let (k_length, k_size) = decode_varint(&data[offset..])?;
+                    let key = offset + k_size..offset + k_size + k_length as usize;
+                    offset = key.end;
+                    if data[key] == *token.as_bytes() {
+                        found = Some(offset);
+                        break;
+                    }
+                    offset += Self::skip(&data[offset..]).map_err(|e| e.shifted(offset))?;
+                }
+                found.ok_or_else(not_found)?
+            }
+            _ => return Err(not_found()),
+        };
+        let (patched, old) =
+            Self::patch_at(&data[child..size], rest, new, pointer).map_err(|e| e.shifted(child))?;
+        Ok((
+            [&data[..child], &patched[..], &data[child + old..size]].concat(),
+            size,
+        ))
+    }
+
+    pub fn decode_all(mut data: &[u8]) -> Result<(Vec<Self>, usize), DecodeError> {
+        let mut ret = vec![];
+        let mut tot_size = 0;
+        while !data.is_empty() {
+            let (o, size) = Self::decode(data).map_err(|e| e.shifted(tot_size))?;
+            tot_size += size;
+            ret.push(o);
+            data = &data[size..];
+        }
+        Ok((ret, tot_size))
+    }
+
+    // Decodes the elements of the array encoded at the start of `data` in parallel, once their
+    // boundaries are found with skip. The result is the one of decode: aliases are left
+    // unresolved, as resolving them depends on the elements before. Packed or sized arrays are
+    // decoded sequentially.
+    #[cfg(feature = "rayon")]
+    pub fn decode_array_parallel(data: &[u8]) -> Result<Vec<Self>, DecodeError> {
+        use rayon::prelude::*;
+        if data.first().map(|ctrl| ctrl >> 5) != Some(data_type::ARRAY) {
+            return match Self::decode(data)? {
+                (Self::Array(list), _) => Ok(list),
+                (o, _) => Err(DecodeError::SchemaMismatch {
+                    path: "$".to_string(),
+                    expected: "array",
+                    found: crate::schema::kind(&o),
+                }),
+            };
+        }
+        let (length, mut offset) = decode_data_type_length(data)?;
+        let mut offsets = vec![offset];
+        for _ in 0..length {
+            offset += Self::skip(&data[offset..]).map_err(|e| e.shifted(offset))?;
+            offsets.push(offset);
+        }
+        offsets
+            .par_windows(2)
+            .map(|w| {
+                Self::decode(&data[w[0]..w[1]])
+                    .map(|(o, _)| o)
+                    .map_err(|e| e.shifted(w[0]))
+            })
+            .collect()
+    }
+
+    // Decodes the concatenated values of `data` one by one, with the range of bytes each one was
+    // decoded from. Stops after the first error.
+    pub fn decode_spans(
+        data: &[u8],
+    ) -> impl Iterator<Item = Result<(Self, std::ops::Range<usize>), DecodeError>> + '_ {
+        let mut start = 0;
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed || start == data.len() {
+                return None;
+            }
+            match Self::decode_at(data, start) {
+                Ok((o, end)) => {
+                    let span = start..end;
+                    start = end;
+                    Some(Ok((o, span)))
+                }
+                Err(e) => {
+                    failed = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
+
+    // Value equality, independent of how the value was encoded (integer widths, object key
+    // order). Unlike the derived PartialEq, NaN equals NaN and Negative(0) equals Positive(0);
+    // 0.0 and -0.0 are equal as numbers. Logically equal values may still encode to different
+    // bytes, e.g. Negative(0) and Positive(0).
+    pub fn eq_logical(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Float(a), Self::Float(b)) => a == b || (a.is_nan() && b.is_nan()),
+            (
+                Self::Integer(EncodedInteger::Positive(0)),
+                Self::Integer(EncodedInteger::Negative(0)),
+            )
+            | (
+                Self::Integer(EncodedInteger::Negative(0)),
+                Self::Integer(EncodedInteger::Positive(0)),
+            ) => true,
+            (
+                Self::Special(EncodedSpecial::Define(a)),
+                Self::Special(EncodedSpecial::Define(b)),
+            ) => a.eq_logical(b),
+            (Self::Array(a), Self::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.eq_logical(b))
+            }
+            (
+                Self::Object(_) | Self::OrderedObject(_),
+                Self::Object(_) | Self::OrderedObject(_),
+            ) => {
+                let (a, b) = (self.object_entries(), other.object_entries());
+                a.len() == b.len()
+                    && a.iter()
+                        .all(|(k, a)| b.get(k).is_some_and(|b| a.eq_logical(b)))
+            }
+            _ => self == other,
+        }
+    }
+
+    fn object_entries(&self) -> HashMap<&str, &Self> {
+        match self {
+            Self::Object(map) => map.iter().map(|(k, o)| (k.as_str(), o)).collect(),
+            Self::OrderedObject(entries) => entries.iter().map(|(k, o)| (k.as_str(), o)).collect(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+// Accessors in the manner of serde_json::Value. The as_ ones borrow the value of the matching
+// variant, the into_ ones move it out.
+impl EncodedData {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Special(EncodedSpecial::Null))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Integer(EncodedInteger::Bool(b)) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Integer(EncodedInteger::Positive(n)) => Some(*n),
+            Self::Integer(EncodedInteger::Negative(0)) => Some(0),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        integer_as_i64(self)
+    }
+
+    // Integers are converted, possibly losing precision, as decimals are.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Float(f) => Some(*f),
+            Self::Integer(EncodedInteger::Positive(n)) => Some(*n as f64),
+            Self::Integer(EncodedInteger::Negative(n)) => Some(-(*n as f64)),
+            Self::Decimal(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Self>> {
+        match self {
+            Self::Array(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    // None for OrderedObject, whose entries are a list.
+    pub fn as_object(&self) -> Option<&HashMap<String, Self>> {
+        match self {
+            Self::Object(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn into_array(self) -> Option<Vec<Self>> {
+        match self {
+            Self::Array(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn into_object(self) -> Option<HashMap<String, Self>> {
+        match self {
+            Self::Object(map) => Some(map),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn consistency() {
+        fn check(object: EncodedData, size: usize) {
+            let data = object.encode();
+            assert_eq!(data.len(), size);
+            let (decoded, decoded_size) = EncodedData::decode(&data).unwrap();
+            assert_eq!(decoded, object);
+            assert_eq!(decoded_size, size);
+        }
+
+        fn check_w_json(object: EncodedData, size: usize) {
+            check(object.clone(), size);
+            let json: serde_json::Value = object.clone().try_into().unwrap();
+            let reencoded: EncodedData = json.into();
+            assert_eq!(reencoded, object);
+        }
+
+        check(EncodedData::Special(EncodedSpecial::None), 1);
+        check_w_json(EncodedData::Special(EncodedSpecial::Null), 1);
+        check(
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::Special(
+                EncodedSpecial::Null,
+            )))),
+            2,
+        );
+        check(EncodedData::Special(EncodedSpecial::Forget(4)), 2);
+        check(EncodedData::Special(EncodedSpecial::Reset), 1);
+        check_w_json(EncodedData::Integer(EncodedInteger::Positive(0)), 2);
+        check(EncodedData::Integer(EncodedInteger::Negative(0)), 2);
+        check_w_json(EncodedData::Integer(EncodedInteger::Positive(1)), 2);
+        check_w_json(EncodedData::Integer(EncodedInteger::Positive(0xFF_FF)), 3);
+        check_w_json(
+            EncodedData::Integer(EncodedInteger::Negative(0xFF_FF_FF_FF)),
+            5,
+        );
+        check_w_json(EncodedData::Integer(EncodedInteger::Bool(true)), 1);
+        check_w_json(EncodedData::Integer(EncodedInteger::Bool(false)), 1);
+        check_w_json(EncodedData::Float(1.2f64), 9);
+        check_w_json(EncodedData::String("abc".to_string()), 4);
+        check_w_json(
+            EncodedData::String("1234567890ABCDEF".to_string()),
+            1 + 1 + 16,
+        );
+        check_w_json(
+            EncodedData::String("1234567890ABCDEF1234567890ABCDEF".to_string()),
+            1 + 1 + 32,
+        );
+        let array = EncodedData::Array(vec![
+            EncodedData::Special(EncodedSpecial::Null),
+            EncodedData::Integer(EncodedInteger::Positive(5)),
+            EncodedData::String("abc".to_string()),
+        ]);
+        check_w_json(array.clone(), 1 + 1 + 2 + 4);
+        let mut map = HashMap::new();
+        map.insert(
+            "null".to_string(),
+            EncodedData::Special(EncodedSpecial::Null),
+        );
+        map.insert(
+            "positive".to_string(),
+            EncodedData::Integer(EncodedInteger::Positive(5)),
+        );
+        map.insert("string".to_string(), EncodedData::String("abc".to_string()));
+        check_w_json(EncodedData::Object(map.clone()), 1 + 5 + 1 + 9 + 2 + 7 + 4);
+
+        let mut new_map = HashMap::new();
+        new_map.insert(
+            "null".to_string(),
+            EncodedData::Special(EncodedSpecial::Null),
+        );
+        new_map.insert(
+            "positive".to_string(),
+            EncodedData::Integer(EncodedInteger::Positive(5)),
+        );
+        new_map.insert("map".to_string(), EncodedData::Object(map));
+        new_map.insert("array".to_string(), array);
+        check_w_json(
+            EncodedData::Object(new_map),
+            1 + 5 + 1 + 9 + 2 + 4 + 1 + 5 + 1 + 9 + 2 + 7 + 4 + 6 + 1 + 1 + 2 + 4,
+        );
+    }
+
+    #[test]
+    fn compact_integer_widths() {
+        assert_eq!(
+            EncodedData::decode(&[data_type::INTEGER << 5 | 3, 0x01, 0x02, 0x03]).unwrap(),
+            (
+                EncodedData::Integer(EncodedInteger::Positive(0x03_02_01)),
+                4
+            )
+        );
+        assert_eq!(
+            EncodedData::decode(&[data_type::INTEGER << 5 | 1 << 4 | 3, 0xFF, 0xFF, 0xFF]).unwrap(),
+            (
+                EncodedData::Integer(EncodedInteger::Negative(0xFF_FF_FF)),
+                4
+            )
+        );
+        assert_eq!(
+            EncodedData::decode(&[data_type::INTEGER << 5 | 3, 0x01, 0x02]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        assert_eq!(
+            EncodedData::decode(&[data_type::INTEGER << 5 | 9, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::BadIntegerLength(9))
+        );
+    }
+
+    #[test]
+    fn ids() {
+        for (id, size) in [
+            (0, 1),
+            (15, 1),
+            (16, 2),
+            (2048, 2),
+            (u64::MAX - 1, 10),
+            (u64::MAX, 10),
+        ] {
+            let data = encode_id(data_type::ALIAS, id);
+            assert_eq!(data.len(), size);
+            assert_eq!(data, EncodedData::Alias(id).encode());
+            assert_eq!(decode_id(&data), Ok((id, size)));
+            // The data type does not matter.
+            let header = encode_id(data_type::ARRAY, id);
+            assert_eq!(header[0] & 0x1F, data[0] & 0x1F);
+            assert_eq!(decode_id(&header), Ok((id, size)));
+        }
+        assert_eq!(decode_id(&[]), Err(DecodeError::MissingBytes(1)));
+        assert_eq!(
+            decode_id(&[data_type::ALIAS << 5 | 0x10]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+
+    // Every reader of the crate must fail with MissingBytes(missing) on `data`.
+    fn assert_missing_everywhere(data: &[u8], missing: usize) {
+        use crate::codec::{self, CodecError};
+        use crate::schema::Schema;
+        let error = DecodeError::MissingBytes(missing);
+        assert_eq!(EncodedData::decode(data), Err(error.clone()));
+        assert_eq!(EncodedData::decode_borrowed(data), Err(error.clone()));
+        assert_eq!(EncodedData::validate(data), Err(error.clone()));
+        assert_eq!(EncodedData::skip(data), Err(error.clone()));
+        assert_eq!(
+            EncodedData::slice_value(data).map(|_| ()),
+            Err(error.clone())
+        );
+        assert_eq!(
+            EncodedData::patch_pointer(data, "/1.5", &EncodedData::Alias(0)),
+            Err(error.clone())
+        );
+        assert_eq!(
+            codec::decode_to_json(data),
+            Err(CodecError::BadFormat(error.clone()))
+        );
+        assert!(
+            crate::event_reader::EventReader::new(data).any(|event| event == Err(error.clone()))
+        );
+        let schema = match data[0] >> 5 {
+            data_type::OBJECT => Schema::Object(&[]),
+            _ => Schema::Any,
+        };
+        assert_eq!(
+            EncodedData::decode_with_schema(data, &schema),
+            Err(error.clone())
+        );
+        assert_eq!(
+            crate::schema_registry::SchemaRegistry::new().decode(data),
+            Err(error.clone())
+        );
+        assert_eq!(
+            crate::string_codec::StringCodecs::new().decode(data),
+            Err(error)
+        );
+    }
+
+    #[test]
+    fn extreme_lengths() {
+        for id in [15, 16, 17, 1 << 32, u64::MAX - 16, u64::MAX - 1, u64::MAX] {
+            let data = EncodedData::Alias(id).encode();
+            assert_eq!(
+                EncodedData::decode(&data),
+                Ok((EncodedData::Alias(id), data.len()))
+            );
+            assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+        }
+        let (flag, head) = encode_data_type_length(u64::MAX, 5);
+        assert_eq!(flag, 0x1F);
+        // The low nibble goes to the flag, the first 16 values to the flag alone.
+        assert_eq!(decode_varint(&head), Ok(((u64::MAX >> 4) - 1, head.len())));
+
+        // A string header claiming usize::MAX bytes.
+        let header = encode_container_header(data_type::STRING, usize::MAX);
+        assert_eq!(
+            decode_data_type_length(&header),
+            Ok((usize::MAX, header.len()))
+        );
+        let data = [header.clone(), b"abc".to_vec()].concat();
+        let missing = Err(DecodeError::MissingBytes(usize::MAX - 3));
+        assert_eq!(EncodedData::decode(&data).map(|_| ()), missing.clone());
+        assert_eq!(EncodedData::validate(&data).map(|_| ()), missing);
+        assert!(matches!(
+            EncodedData::skip(&data),
+            Err(DecodeError::MissingBytes(_))
+        ));
+
+        // Keys, Decimal and Sized payloads of 3 bytes, claiming up to about usize::MAX bytes.
+        for length in [4, u64::MAX >> 1, u64::MAX - 1, u64::MAX] {
+            for ctrl in [
+                data_type::OBJECT << 5 | 1,
+                data_type::SPECIAL << 5 | special_type::DECIMAL,
+                data_type::SPECIAL << 5 | special_type::SIZED,
+            ] {
+                let data = [vec![ctrl], varint::encode(length), b"1.5".to_vec()].concat();
+                assert_missing_everywhere(&data, length as usize - 3);
+            }
+        }
+
+        // Heads past the encoding of u64::MAX.
+        for head in [u64::MAX >> 4, (u64::MAX >> 4) + 1, u64::MAX] {
+            for data_type in [data_type::ALIAS, data_type::STRING] {
+                let data = [vec![data_type << 5 | 0x1F], varint::encode(head)].concat();
+                assert_eq!(
+                    EncodedData::decode(&data).map(|_| ()),
+                    Err(DecodeError::VarintTooBig)
+                );
+                assert_eq!(EncodedData::skip(&data), Err(DecodeError::VarintTooBig));
+                assert_eq!(EncodedData::validate(&data), Err(DecodeError::VarintTooBig));
+            }
+        }
+    }
+
+    #[test]
+    fn integer_encoding() {
+        let varint = EncodeOptions {
+            integers: IntegerEncoding::Varint,
+            ..Default::default()
+        };
+        let values = [0, 1, 127, 128, 255, 300, 1 << 20, 1 << 40, u64::MAX];
+        for n in values.iter() {
+            for int in [EncodedInteger::Positive(*n), EncodedInteger::Negative(*n)] {
+                let o = EncodedData::Integer(int);
+                let fixed = o.encode();
+                let data = o.encode_with_options(&varint);
+                assert_eq!(data[0] & 0x0F, INTEGER_VARINT);
+                assert_eq!(EncodedData::decode(&data), Ok((o.clone(), data.len())));
+                assert_eq!(EncodedData::validate(&data), Ok(data.len()));
+                // Both use 8 bits per byte at most, the varint only 7.
+                assert!(fixed.len() <= data.len(), "{}", n);
+            }
+        }
+        let size = |n: u64, options: &EncodeOptions| {
+            EncodedData::Integer(EncodedInteger::Positive(n))
+                .encode_with_options(options)
+                .len()
+        };
+        assert_eq!((size(300, &Default::default()), size(300, &varint)), (3, 3));
+        assert_eq!((size(200, &Default::default()), size(200, &varint)), (2, 3));
+        assert_eq!(
+            (size(u64::MAX, &Default::default()), size(u64::MAX, &varint)),
+            (9, 11)
+        );
+        // Booleans have no payload to encode.
+        let b = EncodedData::Integer(EncodedInteger::Bool(true));
+        assert_eq!(b.encode_with_options(&varint), b.encode());
+
+        let data = EncodedData::Integer(EncodedInteger::Positive(300)).encode_with_options(&varint);
+        assert_eq!(
+            EncodedData::decode(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+
+    #[test]
+    fn lossy_utf8() {
+        let data = [vec![data_type::STRING << 5 | 4], b"a\xFFbc".to_vec()].concat();
+        assert!(matches!(
+            EncodedData::decode(&data),
+            Err(DecodeError::BadUtf8 { .. })
+        ));
+
+        let options = DecodeOptions {
+            lossy_utf8: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            EncodedData::decode_with_options(&data, &options).unwrap(),
+            (EncodedData::String("a\u{FFFD}bc".to_string()), 5)
+        );
+
+        let object = [
+            vec![data_type::OBJECT << 5 | 1, 2],
+            b"\xFFk".to_vec(),
+            EncodedData::Special(EncodedSpecial::Null).encode(),
+        ]
+        .concat();
+        let (decoded, _) = EncodedData::decode_with_options(&object, &options).unwrap();
+        let mut map = HashMap::new();
+        map.insert(
+            "\u{FFFD}k".to_string(),
+            EncodedData::Special(EncodedSpecial::Null),
+        );
+        assert_eq!(decoded, EncodedData::Object(map));
+    }
+
+    #[test]
+    fn max_string_bytes() {
+        // 4 + 4 bytes of keys, 6 + 2 + 1 bytes of strings.
+        let o = EncodedData::from(serde_json::json!({"name": "abcdef", "tags": ["xy", "z"]}));
+        let data = o.encode();
+        let options = |max| DecodeOptions {
+            max_string_bytes: Some(max),
+            ..Default::default()
+        };
+        assert_eq!(
+            EncodedData::decode_with_options(&data, &options(17)),
+            Ok((o, data.len()))
+        );
+        assert_eq!(
+            EncodedData::decode_with_options(&data, &options(16)),
+            Err(DecodeError::StringBudgetExceeded)
+        );
+
+        // Strings nested in specials count too.
+        let define = EncodedData::Array(vec![
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::String(
+                "abc".to_string(),
+            )))),
+            EncodedData::Decimal("1.5".to_string()),
+        ])
+        .encode();
+        assert!(EncodedData::decode_with_options(&define, &options(6)).is_ok());
+        assert_eq!(
+            EncodedData::decode_with_options(&define, &options(5)),
+            Err(DecodeError::StringBudgetExceeded)
+        );
+
+        // Lossy strings are charged their decoded size: 4 invalid bytes give 12 bytes.
+        let invalid = [vec![data_type::STRING << 5 | 4], vec![0xFF; 4]].concat();
+        let lossy = |max| DecodeOptions {
+            lossy_utf8: true,
+            ..options(max)
+        };
+        assert_eq!(
+            EncodedData::decode_with_options(&invalid, &lossy(12)),
+            Ok((EncodedData::String("\u{FFFD}".repeat(4)), invalid.len()))
+        );
+        assert_eq!(
+            EncodedData::decode_with_options(&invalid, &lossy(11)),
+            Err(DecodeError::StringBudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn logical_equality() {
+        let minimal = EncodedData::Integer(EncodedInteger::Positive(5)).encode();
+        let wide = vec![data_type::INTEGER << 5 | 4, 5, 0, 0, 0];
+        let array =
+            |int: &[u8]| [vec![data_type::ARRAY << 5 | 2], int.to_vec(), int.to_vec()].concat();
+        let object = |int: &[u8]| {
+            [
+                vec![data_type::OBJECT << 5 | 1],
+                encode_key("n"),
+                int.to_vec(),
+            ]
+            .concat()
+        };
+
+        for (a, b) in [
+            (minimal.clone(), wide.clone()),
+            (array(&minimal), array(&wide)),
+            (object(&minimal), object(&wide)),
+        ] {
+            assert_ne!(a, b);
+            let (a, _) = EncodedData::decode(&a).unwrap();
+            let (b, _) = EncodedData::decode(&b).unwrap();
+            assert!(a.eq_logical(&b));
+        }
+
+        assert!(EncodedData::Float(f64::NAN).eq_logical(&EncodedData::Float(f64::NAN)));
+        assert!(EncodedData::Float(0.0).eq_logical(&EncodedData::Float(-0.0)));
+        assert!(!EncodedData::Float(1.0).eq_logical(&EncodedData::Float(2.0)));
+        assert!(EncodedData::Integer(EncodedInteger::Negative(0))
+            .eq_logical(&EncodedData::Integer(EncodedInteger::Positive(0))));
+        assert!(!EncodedData::Integer(EncodedInteger::Negative(1))
+            .eq_logical(&EncodedData::Integer(EncodedInteger::Positive(1))));
+        assert!(!EncodedData::Integer(EncodedInteger::Bool(false))
+            .eq_logical(&EncodedData::Integer(EncodedInteger::Positive(0))));
+        assert!(!EncodedData::Array(vec![EncodedData::Float(1.0)])
+            .eq_logical(&EncodedData::Array(vec![])));
+    }
+
+    #[test]
+    fn sized_containers() {
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), EncodedData::String("abc".to_string()));
+        let nested = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Positive(1)),
+            EncodedData::Array(vec![
+                EncodedData::Float(1.5),
+                EncodedData::Object(map),
+                EncodedData::Array(vec![]),
+            ]),
+            EncodedData::Special(EncodedSpecial::Null),
+        ]);
+        let sized_options = EncodeOptions {
+            sized_containers: true,
+            ..Default::default()
+        };
+
+        for options in [EncodeOptions::default(), sized_options.clone()] {
+            let data = nested.encode_with_options(&options);
+            let (decoded, size) = EncodedData::decode(&data).unwrap();
+            assert_eq!(decoded, nested);
+            assert_eq!(size, data.len());
+            assert_eq!(EncodedData::skip(&data).unwrap(), data.len());
+            assert_eq!(
+                EncodedData::skip(&data[..data.len() - 1]),
+                Err(DecodeError::MissingBytes(1))
+            );
+        }
+
+        // Skipping a sized container does not look at its content.
+        let mut data = nested.encode_with_options(&sized_options);
+        let size = data.len();
+        data[3] = 7 << 5;
+        assert_eq!(EncodedData::skip(&data).unwrap(), size);
+        assert_eq!(
+            EncodedData::decode(&data),
+            Err(DecodeError::UnknownDataType(7))
+        );
+    }
+
+    #[test]
+    fn object_key_overhead() {
+        // Keys only cost their length varint, which is a single byte below 0x80 bytes.
+        assert_eq!(encode_key(&"k".repeat(0x7F)).len(), 1 + 0x7F);
+        assert_eq!(encode_key(&"k".repeat(0x80)).len(), 2 + 0x80);
+        assert_eq!(encode_key("").len(), 1);
+
+        let map: HashMap<String, EncodedData> = (0..100)
+            .map(|i| {
+                (
+                    format!("{:x}", i),
+                    EncodedData::Integer(EncodedInteger::Bool(true)),
+                )
+            })
+            .collect();
+        let keys_size: usize = map.keys().map(|k| 1 + k.len()).sum();
+        let object = EncodedData::Object(map);
+        let header_size = encode_container_header(data_type::OBJECT, 100).len();
+        assert_eq!(object.encode().len(), header_size + keys_size + 100);
+        assert_eq!(EncodedData::decode(&object.encode()).unwrap().0, object);
+    }
+
+    #[test]
+    fn slice_value() {
+        let first = EncodedData::Array(vec![
+            EncodedData::String("abc".to_string()),
+            EncodedData::Integer(EncodedInteger::Positive(0xFF_FF)),
+        ]);
+        let second = EncodedData::Float(1.5);
+        let data = [first.encode(), second.encode()].concat();
+        assert_eq!(
+            EncodedData::slice_value(&data).unwrap(),
+            &first.encode()[..]
+        );
+        assert_eq!(
+            EncodedData::slice_value(&data[first.encode().len()..]).unwrap(),
+            &second.encode()[..]
+        );
+        assert_eq!(
+            EncodedData::slice_value(&data[..3]),
+            Err(DecodeError::MissingBytes(2))
+        );
+    }
+
+    #[test]
+    fn patch_pointer() {
+        let data = EncodedData::from(serde_json::json!({"a": 1, "b": 2})).encode();
+        let new_value = EncodedData::String("two".to_string());
+        let patched = EncodedData::patch_pointer(&data, "/b", &new_value).unwrap();
+        assert_eq!(patched.len(), data.len() + 2);
+        assert_eq!(
+            EncodedData::decode(&patched),
+            Ok((
+                EncodedData::from(serde_json::json!({"a": 1, "b": "two"})),
+                patched.len()
+            ))
+        );
+
+        let value = serde_json::json!({"x/y": [0, {"k": null}, 2], "z": "end"});
+        for sized_containers in [false, true].iter() {
+            let options = EncodeOptions {
+                sized_containers: *sized_containers,
+                ..Default::default()
+            };
+            let data = [
+                EncodedData::from(value.clone()).encode_with_options(&options),
+                EncodedData::Float(1.5).encode(),
+            ]
+            .concat();
+            let patched =
+                EncodedData::patch_pointer(&data, "/x~1y/1/k", &EncodedData::Float(-0.5)).unwrap();
+            let mut expected = value.clone();
+            expected["x/y"][1]["k"] = serde_json::json!(-0.5);
+            assert_eq!(
+                EncodedData::decode_all(&patched).unwrap().0,
+                vec![EncodedData::from(expected), EncodedData::Float(1.5)]
+            );
+            assert_eq!(EncodedData::validate(&patched), Ok(patched.len() - 9));
+        }
+
+        assert_eq!(
+            EncodedData::patch_pointer(&data, "", &new_value),
+            Ok(new_value.encode())
+        );
+        for pointer in ["/c", "/a/0", "b", "/a/b"].iter() {
+            assert_eq!(
+                EncodedData::patch_pointer(&data, pointer, &new_value),
+                Err(DecodeError::BadPointer(pointer.to_string()))
+            );
+        }
+
+        let data = EncodedData::from(serde_json::json!([0, 1, 2])).encode();
+        for pointer in ["/0", "/1", "/2"].iter() {
+            assert!(EncodedData::patch_pointer(&data, pointer, &new_value).is_ok());
+        }
+        // Only digits are indices, without leading zeros.
+        for pointer in ["/+1", "/01", "/00", "/-0", "/ 1", "/1 ", "/", "/3"].iter() {
+            assert_eq!(
+                EncodedData::patch_pointer(&data, pointer, &new_value),
+                Err(DecodeError::BadPointer(pointer.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn compress_reported() {
+        let options = EncodeOptions {
+            packed_arrays: true,
+            delta_arrays: true,
+            null_runs: true,
+            ..Default::default()
+        };
+        let value = EncodedData::from(serde_json::json!({
+            "flags": [true, false, true, true, false, true, true, true, false, false],
+            "ids": [1000, 1001, 1002, 1003],
+            "inner": [[false, true, true, false]],
+            "text": ["a", "b"],
+        }));
+        let (data, report) = value.compress_reported(&options);
+        assert_eq!(data, value.encode_with_options(&options));
+        assert_eq!(report.bool_arrays.count, 2);
+        let flags = EncodedData::from(serde_json::json!([
+            true, false, true, true, false, true, true, true, false, false
+        ]));
+        let inner = EncodedData::from(serde_json::json!([false, true, true, false]));
+        assert_eq!(
+            report.bool_arrays.saved,
+            flags.encode().len() - flags.encode_with_options(&options).len() + inner.encode().len()
+                - inner.encode_with_options(&options).len()
+        );
+        assert_eq!(report.delta_arrays.count, 1);
+        assert!(report.delta_arrays.saved > 0);
+        assert_eq!(report.integer_arrays, EncodingUse::default());
+        assert_eq!(report.null_runs, EncodingUse::default());
+
+        let (_, report) = value.compress_reported(&EncodeOptions::default());
+        assert_eq!(report, CompressReport::default());
+    }
+
+    #[test]
+    fn packed_arrays() {
+        let options = EncodeOptions {
+            packed_arrays: true,
+            ..Default::default()
+        };
+        let int = |n: i64| {
+            if n < 0 {
+                EncodedData::Integer(EncodedInteger::Negative(n.unsigned_abs()))
+            } else {
+                EncodedData::Integer(EncodedInteger::Positive(n as u64))
+            }
+        };
+        let check = |array: Vec<EncodedData>| {
+            let array = EncodedData::Array(array);
+            let data = array.encode_with_options(&options);
+            assert_eq!(EncodedData::decode(&data), Ok((array, data.len())));
+            assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+            data
+        };
+
+        // Bools and numbers share the INTEGER data type but are not homogeneous.
+        let mixed = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Bool(true)),
+            int(1),
+            EncodedData::Integer(EncodedInteger::Bool(false)),
+            int(2),
+        ]);
+        assert_eq!(mixed.encode_with_options(&options), mixed.encode());
+
+        let bools: Vec<_> = (0..10)
+            .map(|i| EncodedData::Integer(EncodedInteger::Bool(i % 3 == 0)))
+            .collect();
+        let data = check(bools);
+        assert_eq!(
+            data,
+            vec![
+                data_type::SPECIAL << 5 | special_type::BOOL_ARRAY,
+                10,
+                0b0100_1001,
+                0b10
+            ]
+        );
+
+        let data = check((0..5).map(|i| int(i * 300)).collect());
+        assert_eq!(
+            data[0],
+            data_type::SPECIAL << 5 | special_type::INTEGER_ARRAY
+        );
+        assert_eq!(&data[1..3], &[5, 2]);
+        assert_eq!(data.len(), 3 + 5 * 2);
+
+        let data = check((1..4).map(|i| int(-i)).collect());
+        assert_eq!(&data[1..], &[3, 0x11, 1, 2, 3]);
+
+        let data = check(vec![int(-1), int(2), int(-3), int(4)]);
+        assert_eq!(&data[1..], &[4, 0x21, 0b0101, 1, 2, 3, 4]);
+
+        // Not packed when the elements widths differ too much.
+        let wide = EncodedData::Array(vec![int(1), int(1), int(1 << 40)]);
+        assert_eq!(wide.encode_with_options(&options), wide.encode());
+
+        let data = check((0..4).map(int).collect());
+        assert_eq!(
+            EncodedData::decode(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        let mut bad_width = data.clone();
+        bad_width[2] = 9;
+        assert_eq!(
+            EncodedData::decode(&bad_width),
+            Err(DecodeError::BadIntegerLength(9))
+        );
+        let (borrowed, _) = EncodedData::decode_borrowed(&data).unwrap();
+        assert_eq!(
+            borrowed,
+            crate::encoded_data_ref::EncodedDataRef::Array(
+                (0..4)
+                    .map(|i| crate::encoded_data_ref::EncodedDataRef::Integer(
+                        EncodedInteger::Positive(i)
+                    ))
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn float_hint() {
+        let value: serde_json::Value = serde_json::from_str("1.0").unwrap();
+        let data = EncodedData::from(value).encode();
+        assert_eq!(data, vec![data_type::FLOAT << 5 | FLOAT_HINT | 1, 1]);
+        let (decoded, _) = EncodedData::decode(&data).unwrap();
+        let decoded: serde_json::Value = decoded.try_into().unwrap();
+        assert_eq!(decoded.to_string(), "1.0");
+
+        for f in [0.0, -1.0, 127.0, 128.0, -128.0, -129.0, 1e15, -1e12] {
+            let data = EncodedData::Float(f).encode();
+            assert!(data.len() < 9, "{}", f);
+            assert_eq!(
+                EncodedData::decode(&data),
+                Ok((EncodedData::Float(f), data.len()))
+            );
+            assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+        }
+        assert_eq!(EncodedData::Float(128.0).encode().len(), 3);
+        let data = EncodedData::Float(i64::MIN as f64).encode();
+        assert_eq!(data[0], data_type::FLOAT << 5 | FLOAT_HINT | 8);
+        assert_eq!(
+            EncodedData::decode(&data),
+            Ok((EncodedData::Float(i64::MIN as f64), 9))
+        );
+        for f in [-0.0, 0.5, -(i64::MIN as f64), f64::INFINITY, f64::NAN] {
+            assert_eq!(EncodedData::Float(f).encode().len(), 9, "{}", f);
+        }
+        assert_eq!(
+            EncodedData::decode(&[data_type::FLOAT << 5 | FLOAT_HINT | 2, 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+
+    #[test]
+    fn append_to() {
+        use serde_json::json;
+        let values: Vec<_> = [
+            json!({"level": "info", "n": 1}),
+            json!([1.5, null]),
+            json!("end"),
+        ]
+        .iter()
+        .map(|v| EncodedData::from(v.clone()))
+        .collect();
+        let mut buf = vec![];
+        for o in values.iter() {
+            let len = buf.len();
+            EncodedData::append_to(&mut buf, o);
+            assert_eq!(&buf[len..], &o.encode()[..]);
+        }
+        assert_eq!(
+            EncodedData::decode_all(&buf),
+            Ok((values.clone(), buf.len()))
+        );
+        let decoded: Vec<_> = EncodedData::decode_spans(&buf)
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn max_output() {
+        let array = EncodedData::Array(
+            (0..1000)
+                .map(|i| EncodedData::String(format!("record-{:04}", i)))
+                .collect(),
+        );
+        let data = array.encode();
+        let capped = |max| {
+            let mut buf = b"head".to_vec();
+            let options = EncodeOptions {
+                max_output: Some(max),
+                ..Default::default()
+            };
+            array
+                .encode_into(&options, &mut buf)
+                .map(|()| buf[4..].to_vec())
+        };
+        assert_eq!(capped(data.len()), Ok(data.clone()));
+        assert_eq!(
+            capped(data.len() - 1),
+            Err(EncodeError::OutputTooLarge(data.len() - 1))
+        );
+        assert_eq!(capped(100), Err(EncodeError::OutputTooLarge(100)));
+        // Nested containers count against the same limit.
+        let nested = EncodedData::OrderedObject(vec![("records".to_string(), array.clone())]);
+        let mut buf = b"head".to_vec();
+        let options = EncodeOptions {
+            max_output: Some(data.len()),
+            ..Default::default()
+        };
+        assert_eq!(
+            nested.encode_into(&options, &mut buf),
+            Err(EncodeError::OutputTooLarge(data.len()))
+        );
+        assert_eq!(buf, b"head");
+        assert_eq!(array.encode_into(&Default::default(), &mut buf), Ok(()));
+        assert_eq!(buf, [&b"head"[..], &data].concat());
+
+        // Arrays fail as soon as their plain encoding is too large, even if the packed one fits.
+        let ints = EncodedData::Array(
+            (0..100)
+                .map(|_| EncodedData::Integer(EncodedInteger::Bool(true)))
+                .collect(),
+        );
+        let options = EncodeOptions {
+            packed_arrays: true,
+            ..Default::default()
+        };
+        let packed = ints.encode_with_options(&options);
+        assert!(packed.len() < ints.encode().len());
+        let capped = |max| {
+            let options = EncodeOptions {
+                max_output: Some(max),
+                ..options.clone()
+            };
+            ints.encode_into(&options, &mut vec![])
+        };
+        assert_eq!(capped(ints.encode().len()), Ok(()));
+        assert_eq!(
+            capped(packed.len()),
+            Err(EncodeError::OutputTooLarge(packed.len()))
+        );
+    }
+
+    #[test]
+    fn encode_pooled() {
+        use serde_json::json;
+        let values: Vec<_> = [
+            json!({"name": "sensor", "values": [1, -2, 2.5, 3.0, null, true, false, "x"]}),
+            json!([u64::MAX, i64::MIN, -0.0, 1e300, "a".repeat(300)]),
+            json!({"nested": {"empty": [], "object": {}}, "k".repeat(200): 0}),
+            // Past MAX_SCRATCH_CAPACITY, so that the buffer is dropped and grown again.
+            json!("b".repeat(MAX_SCRATCH_CAPACITY + 1)),
+            json!("end"),
+        ]
+        .iter()
+        .map(|v| EncodedData::from(v.clone()))
+        .chain(vec![
+            EncodedData::OrderedObject(vec![("z".to_string(), EncodedData::Alias(3))]),
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::Decimal(
+                "1.10".to_string(),
+            )))),
+            EncodedData::Array(vec![EncodedData::Special(EncodedSpecial::Forget(2))]),
+        ])
+        .collect();
+        let expected: Vec<_> = values.iter().map(|o| o.encode()).collect();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let values = values.clone();
+                let expected = expected.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        for (o, data) in values.iter().zip(expected.iter()) {
+                            assert_eq!(&o.encode_pooled(), data);
                         }
                     }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut buf = vec![];
+        for o in values.iter() {
+            EncodedData::append_to(&mut buf, o);
+        }
+        assert_eq!(buf, expected.concat());
+    }
+
+    #[test]
+    fn float_width() {
+        let data = EncodedData::Float(0.1).encode();
+        assert_eq!(
+            EncodedData::decode(&data[..4]),
+            Err(DecodeError::MissingBytes(5))
+        );
+        assert_eq!(
+            EncodedData::skip(&data[..4]),
+            Err(DecodeError::MissingBytes(5))
+        );
+        assert_eq!(
+            EncodedData::validate(&data[..4]),
+            Err(DecodeError::MissingBytes(5))
+        );
+
+        let f32_data = [
+            vec![data_type::FLOAT << 5 | 4],
+            0.1f32.to_le_bytes().to_vec(),
+        ]
+        .concat();
+        assert_eq!(
+            EncodedData::decode(&f32_data),
+            Ok((EncodedData::Float(0.1f32 as f64), 5))
+        );
+        assert_eq!(EncodedData::skip(&f32_data), Ok(5));
+        assert_eq!(
+            EncodedData::decode(&f32_data[..3]),
+            Err(DecodeError::MissingBytes(2))
+        );
+        assert_eq!(
+            EncodedData::decode(&[data_type::FLOAT << 5 | 3, 0, 0, 0]),
+            Err(DecodeError::BadFloatLength(3))
+        );
+    }
+
+    #[test]
+    fn skip_unknown_specials() {
+        let unknown = [
+            vec![data_type::SPECIAL << 5 | (special_type::SKIPPABLE + 3)],
+            varint::encode(3),
+            vec![1, 2, 3],
+        ]
+        .concat();
+        let array = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Positive(1)),
+            EncodedData::String("a".to_string()),
+        ]);
+        let encoded = array.encode();
+        // The unknown special precedes the string inside the array.
+        let data = [&encoded[..3], &unknown, &encoded[3..]].concat();
+
+        assert_eq!(
+            EncodedData::decode(&data),
+            Err(DecodeError::UnknownSpecialType(special_type::SKIPPABLE + 3))
+        );
+        let options = DecodeOptions {
+            skip_unknown: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            EncodedData::decode_with_options(&data, &options),
+            Ok((array, data.len()))
+        );
+        assert_eq!(
+            EncodedData::decode_with_options(&data[..6], &options),
+            Err(DecodeError::MissingBytes(2))
+        );
+
+        // Unknown non skippable types still fail.
+        assert_eq!(
+            EncodedData::decode_with_options(&[data_type::SPECIAL << 5 | 15, 0], &options),
+            Err(DecodeError::UnknownSpecialType(15))
+        );
+    }
+
+    // Reuses the buffers of the previously decoded messages.
+    #[derive(Default)]
+    struct Pool {
+        strings: Vec<String>,
+        arrays: Vec<Vec<EncodedData>>,
+        objects: Vec<HashMap<String, EncodedData>>,
+        nb_allocated: usize,
+    }
+
+    impl Pool {
+        fn reclaim(&mut self, o: EncodedData) {
+            match o {
+                EncodedData::String(mut s) => {
+                    s.clear();
+                    self.strings.push(s);
+                }
+                EncodedData::Array(mut list) => {
+                    for o in list.drain(..) {
+                        self.reclaim(o);
+                    }
+                    self.arrays.push(list);
                 }
-                DataType::Float => {
-                    let mut f_data = [0u8; 8];
-                    f_data.clone_from_slice(&data.get_unchecked(1..));
-                    (Self::Float(f64::from_le_bytes(f_data)), 1 + 8)
+                EncodedData::Object(mut map) => {
+                    for (mut k, o) in map.drain() {
+                        k.clear();
+                        self.strings.push(k);
+                        self.reclaim(o);
+                    }
+                    self.objects.push(map);
                 }
-                DataType::String => {
-                    let length = ctrl & 0x0F;
-                    let length_continue = ctrl & 0x10 != 0;
-                    let (length, size) = if length_continue {
-                        let (length_head, size) = match varint::decode(data.get_unchecked(1..)) {
-                            Ok(e) => e,
-                            Err(varint::DecodeError::MissingBytes) => {
-                                return Err(DecodeError::MissingBytes(1))
-                            }
-                            Err(varint::DecodeError::ValueTooBig) => {
-                                return Err(DecodeError::VarintTooBig)
-                            }
+                EncodedData::Special(EncodedSpecial::Define(o)) => self.reclaim(*o),
+                _ => {}
+            }
+        }
+    }
+
+    impl DecodeArena for Pool {
+        fn alloc_string(&mut self, s: &str) -> String {
+            let mut ret = self.strings.pop().unwrap_or_else(|| {
+                self.nb_allocated += 1;
+                String::new()
+            });
+            ret.push_str(s);
+            ret
+        }
+
+        fn alloc_array(&mut self, capacity: usize) -> Vec<EncodedData> {
+            self.arrays.pop().unwrap_or_else(|| {
+                self.nb_allocated += 1;
+                Vec::with_capacity(capacity)
+            })
+        }
+
+        fn alloc_object(&mut self, capacity: usize) -> HashMap<String, EncodedData> {
+            self.objects.pop().unwrap_or_else(|| {
+                self.nb_allocated += 1;
+                HashMap::with_capacity(capacity)
+            })
+        }
+    }
+
+    #[test]
+    fn decode_in_arena() {
+        let object = EncodedData::Object(
+            (0..100)
+                .map(|i| {
+                    (
+                        format!("key{}", i),
+                        EncodedData::Array(vec![
+                            EncodedData::String(format!("value{}", i)),
+                            EncodedData::Integer(EncodedInteger::Positive(i)),
+                            EncodedData::Special(EncodedSpecial::Define(Box::new(
+                                EncodedData::String("defined".to_string()),
+                            ))),
+                        ]),
+                    )
+                })
+                .collect(),
+        );
+        let data = object.encode();
+        let mut pool = Pool::default();
+        let decoded = EncodedData::decode_in(&data, &mut pool).unwrap();
+        assert_eq!(decoded, (object.clone(), data.len()));
+        // 100 keys, 100 arrays of 2 strings and the object.
+        assert_eq!(pool.nb_allocated, 100 + 100 * 3 + 1);
+
+        pool.reclaim(decoded.0);
+        let decoded = EncodedData::decode_in(&data, &mut pool).unwrap();
+        assert_eq!(decoded, (object, data.len()));
+        assert_eq!(pool.nb_allocated, 100 + 100 * 3 + 1);
+    }
+
+    #[test]
+    fn empty_containers() {
+        for (o, ctrl) in [
+            (EncodedData::Array(vec![]), data_type::ARRAY << 5),
+            (EncodedData::Object(HashMap::new()), data_type::OBJECT << 5),
+        ] {
+            let data = o.encode();
+            assert_eq!(data, vec![ctrl]);
+            assert_eq!(EncodedData::decode(&data), Ok((o.clone(), 1)));
+            assert_eq!(EncodedData::skip(&data), Ok(1));
+            assert_eq!(EncodedData::slice_value(&data), Ok(&data[..]));
+
+            // Followed by another value.
+            let next = EncodedData::Integer(EncodedInteger::Positive(3));
+            let data = [data, next.encode()].concat();
+            assert_eq!(
+                EncodedData::decode_all(&data).unwrap().0,
+                vec![o.clone(), next]
+            );
+        }
+        let (borrowed, size) = EncodedData::decode_borrowed(&[data_type::ARRAY << 5]).unwrap();
+        assert_eq!(
+            borrowed,
+            crate::encoded_data_ref::EncodedDataRef::Array(vec![])
+        );
+        assert_eq!(size, 1);
+    }
+
+    #[test]
+    fn versioned() {
+        let o = EncodedData::String("abc".to_string());
+        let data = o.encode_versioned();
+        assert_eq!(data, [vec![FORMAT_VERSION], o.encode()].concat());
+        assert_eq!(EncodedData::decode_versioned(&data), Ok((o, data.len())));
+
+        let mut v2 = data.clone();
+        v2[0] = 2;
+        assert_eq!(
+            EncodedData::decode_versioned(&v2),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            EncodedData::decode_versioned(&[]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        assert_eq!(
+            EncodedData::decode_versioned(&data[..1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+
+    #[test]
+    fn decode_at() {
+        let values = [
+            EncodedData::Integer(EncodedInteger::Positive(1000)),
+            EncodedData::String("abc".to_string()),
+            EncodedData::Float(0.5),
+        ];
+        let data: Vec<u8> = values.iter().flat_map(|o| o.encode()).collect();
+        let (_, first_size) = EncodedData::decode(&data).unwrap();
+        let (o, offset) = EncodedData::decode_at(&data, first_size).unwrap();
+        assert_eq!(o, values[1]);
+        assert_eq!(offset, first_size + values[1].encode().len());
+        assert_eq!(
+            EncodedData::decode_at(&data, offset),
+            Ok((values[2].clone(), data.len()))
+        );
+        assert_eq!(
+            EncodedData::decode_at(&data, data.len()),
+            Err(DecodeError::MissingBytes(1))
+        );
+        assert_eq!(
+            EncodedData::decode_at(&data, data.len() + 2),
+            Err(DecodeError::MissingBytes(2))
+        );
+    }
+
+    // Builds a minimal valid encoding for every control byte and checks what it decodes to.
+    #[test]
+    fn all_control_bytes() {
+        let null = data_type::SPECIAL << 5 | special_type::NULL;
+        let int = |n| EncodedData::Integer(EncodedInteger::Positive(n));
+        for ctrl in 0..=255u8 {
+            let nibble = (ctrl & 0x0F) as usize;
+            let continued = ctrl & 0x10 != 0;
+            // Length/id from the nibble and the continuation flag, followed by a 0 varint.
+            let length = if continued { 0x10 + nibble } else { nibble };
+            let header = if continued { vec![ctrl, 0] } else { vec![ctrl] };
+            let expected: Result<(Vec<u8>, EncodedData), DecodeError> = match ctrl >> 5 {
+                data_type::SPECIAL => match ctrl & 0x1F {
+                    special_type::NONE => {
+                        Ok((vec![ctrl], EncodedData::Special(EncodedSpecial::None)))
+                    }
+                    special_type::NULL => {
+                        Ok((vec![ctrl], EncodedData::Special(EncodedSpecial::Null)))
+                    }
+                    special_type::DEFINE => Ok((
+                        vec![ctrl, null],
+                        EncodedData::Special(EncodedSpecial::Define(Box::new(
+                            EncodedData::Special(EncodedSpecial::Null),
+                        ))),
+                    )),
+                    special_type::FORGET => Ok((
+                        vec![ctrl, 5],
+                        EncodedData::Special(EncodedSpecial::Forget(5)),
+                    )),
+                    special_type::RESET => {
+                        Ok((vec![ctrl], EncodedData::Special(EncodedSpecial::Reset)))
+                    }
+                    special_type::SIZED => Ok((
+                        vec![ctrl, 1, null],
+                        EncodedData::Special(EncodedSpecial::Null),
+                    )),
+                    special_type::BOOL_ARRAY => Ok((
+                        vec![ctrl, 2, 0b10],
+                        EncodedData::Array(vec![
+                            EncodedData::Integer(EncodedInteger::Bool(false)),
+                            EncodedData::Integer(EncodedInteger::Bool(true)),
+                        ]),
+                    )),
+                    special_type::INTEGER_ARRAY => {
+                        Ok((vec![ctrl, 1, 1, 7], EncodedData::Array(vec![int(7)])))
+                    }
+                    special_type::DECIMAL => Ok((
+                        vec![ctrl, 3, b'1', b'.', b'0'],
+                        EncodedData::Decimal("1.0".to_string()),
+                    )),
+                    special_type::DELTA_ARRAY => Ok((
+                        vec![ctrl, 2, 14, 1],
+                        EncodedData::Array(vec![int(7), int(6)]),
+                    )),
+                    special_type::SORTED_ARRAY => Ok((
+                        vec![ctrl, 2, 12, 1, 2, 1],
+                        EncodedData::Array(vec![int(7), int(6)]),
+                    )),
+                    special_type::NULL_RUNS => Ok((
+                        vec![ctrl, 1, 2, int(7).encode()[0], 7, 1],
+                        EncodedData::Array(vec![
+                            EncodedData::Special(EncodedSpecial::Null),
+                            EncodedData::Special(EncodedSpecial::Null),
+                            int(7),
+                            EncodedData::Special(EncodedSpecial::Null),
+                        ]),
+                    )),
+                    special_type::EXTEND => {
+                        let tail = EncodedData::OrderedObject(vec![(
+                            "a".to_string(),
+                            EncodedData::Special(EncodedSpecial::Null),
+                        )]);
+                        Ok((
+                            [vec![ctrl, data_type::OBJECT << 5], tail.encode()].concat(),
+                            EncodedData::Object(
+                                vec![("a".to_string(), EncodedData::Special(EncodedSpecial::Null))]
+                                    .into_iter()
+                                    .collect(),
+                            ),
+                        ))
+                    }
+                    special => Err(DecodeError::UnknownSpecialType(special)),
+                },
+                data_type::INTEGER => match nibble {
+                    0 => Ok((
+                        vec![ctrl],
+                        EncodedData::Integer(EncodedInteger::Bool(continued)),
+                    )),
+                    1..=8 => {
+                        let mut data = vec![0u8; 1 + nibble];
+                        data[0] = ctrl;
+                        data[1] = 1;
+                        let int = if continued {
+                            EncodedInteger::Negative(1)
+                        } else {
+                            EncodedInteger::Positive(1)
                         };
-                        (
-                            ((length_head as usize) << 4 | length as usize) + 0x10,
-                            1 + size as usize,
-                        )
-                    } else {
-                        (length as usize, 1)
-                    };
-                    let payload = data.get_unchecked(size..size + length);
-                    let s = match std::str::from_utf8(payload) {
-                        Ok(s) => s.to_string(),
-                        Err(e) => return Err(DecodeError::BadUtf8(e)),
-                    };
-                    (Self::String(s), size + length)
-                }
-                DataType::Array => {
-                    let length = ctrl & 0x0F;
-                    let length_continue = ctrl & 0x10 != 0;
-                    let (length, size) = if length_continue {
-                        let (length_head, size) = match varint::decode(data.get_unchecked(1..)) {
-                            Ok(e) => e,
-                            Err(varint::DecodeError::MissingBytes) => {
-                                return Err(DecodeError::MissingBytes(1))
-                            }
-                            Err(varint::DecodeError::ValueTooBig) => {
-                                return Err(DecodeError::VarintTooBig)
-                            }
+                        Ok((data, EncodedData::Integer(int)))
+                    }
+                    15 => {
+                        let int = if continued {
+                            EncodedInteger::Negative(1)
+                        } else {
+                            EncodedInteger::Positive(1)
                         };
-                        (
-                            ((length_head as usize) << 4 | length as usize) + 0x10,
-                            1 + size as usize,
-                        )
-                    } else {
-                        (length as usize, 1)
-                    };
-                    let mut list = vec![];
-                    let mut data_ref = data.get_unchecked(size..);
-                    let mut tot_size = size;
-                    for _ in 0..length {
-                        let (o, size) = Self::decode(data_ref)?;
-                        list.push(o);
-                        data_ref = data_ref.get_unchecked(size..);
-                        tot_size += size;
+                        Ok((vec![ctrl, 1], EncodedData::Integer(int)))
+                    }
+                    _ => Err(DecodeError::BadIntegerLength(nibble as u8)),
+                },
+                data_type::FLOAT => match (continued, nibble) {
+                    (false, 8) => Ok((
+                        [vec![ctrl], 0.5f64.to_le_bytes().to_vec()].concat(),
+                        EncodedData::Float(0.5),
+                    )),
+                    (false, 4) => Ok((
+                        [vec![ctrl], 0.5f32.to_le_bytes().to_vec()].concat(),
+                        EncodedData::Float(0.5),
+                    )),
+                    (true, 1..=8) => {
+                        let mut data = vec![0u8; 1 + nibble];
+                        data[0] = ctrl;
+                        data[1] = 0xFF;
+                        // Sign extended, unless the 0xFF byte is the most significant one.
+                        let f = if nibble == 1 { -1.0 } else { 255.0 };
+                        Ok((data, EncodedData::Float(f)))
                     }
+                    _ => Err(DecodeError::BadFloatLength(nibble as u8)),
+                },
+                data_type::STRING => Ok((
+                    [header, vec![b'a'; length]].concat(),
+                    EncodedData::String("a".repeat(length)),
+                )),
+                data_type::ARRAY => Ok((
+                    [header, vec![null; length]].concat(),
+                    EncodedData::Array(vec![EncodedData::Special(EncodedSpecial::Null); length]),
+                )),
+                data_type::OBJECT => {
+                    let keys: Vec<String> = (0..length)
+                        .map(|i| ((b'A' + i as u8) as char).to_string())
+                        .collect();
+                    let entries: Vec<u8> = keys
+                        .iter()
+                        .flat_map(|k| [encode_key(k), vec![null]].concat())
+                        .collect();
+                    Ok((
+                        [header, entries].concat(),
+                        EncodedData::Object(
+                            keys.into_iter()
+                                .map(|k| (k, EncodedData::Special(EncodedSpecial::Null)))
+                                .collect(),
+                        ),
+                    ))
+                }
+                data_type::ALIAS => Ok((header, EncodedData::Alias(length as u64))),
+                data_type => Err(DecodeError::UnknownDataType(data_type)),
+            };
+            match expected {
+                Ok((data, o)) => {
+                    assert_eq!(
+                        EncodedData::decode(&data),
+                        Ok((o.clone(), data.len())),
+                        "{:#04x}",
+                        ctrl
+                    );
+                    assert_eq!(EncodedData::skip(&data), Ok(data.len()), "{:#04x}", ctrl);
+                    assert_eq!(
+                        EncodedData::validate(&data),
+                        Ok(data.len()),
+                        "{:#04x}",
+                        ctrl
+                    );
+                    if o.encode() != data {
+                        // Only the non canonical encodings may differ.
+                        assert!(
+                            (ctrl >> 5 == data_type::INTEGER && nibble > 1)
+                                || (ctrl >> 5 == data_type::FLOAT && continued && nibble > 2)
+                                || (ctrl >> 5 == data_type::FLOAT && nibble == 4)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::SIZED)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::BOOL_ARRAY)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::INTEGER_ARRAY)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::DELTA_ARRAY)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::SORTED_ARRAY)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::NULL_RUNS)
+                                || (ctrl >> 5 == data_type::SPECIAL
+                                    && ctrl & 0x1F == special_type::EXTEND),
+                            "{:#04x}",
+                            ctrl
+                        );
+                    }
+                }
+                Err(e) => {
+                    let data = [vec![ctrl], vec![0u8; 16]].concat();
+                    assert_eq!(EncodedData::decode(&data), Err(e.clone()), "{:#04x}", ctrl);
+                    assert_eq!(EncodedData::skip(&data), Err(e.clone()), "{:#04x}", ctrl);
+                    assert_eq!(EncodedData::validate(&data), Err(e), "{:#04x}", ctrl);
+                }
+            }
+        }
+
+        // Lengths and ids around the continuation boundaries.
+        for n in (0..600).chain([0x7FF, 0x800, 0x80F, 0x810, 0x4010, 0x4011]) {
+            let alias = EncodedData::Alias(n);
+            assert_eq!(
+                EncodedData::decode(&alias.encode()),
+                Ok((alias.clone(), alias.encode().len())),
+                "{}",
+                n
+            );
+            let header = encode_container_header(data_type::ARRAY, n as usize);
+            assert_eq!(
+                decode_data_type_length(&header),
+                Ok((n as usize, header.len())),
+                "{}",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn decimal() {
+        let o = EncodedData::Decimal("1.100".to_string());
+        let data = o.encode();
+        assert_eq!(
+            &data[..2],
+            &[data_type::SPECIAL << 5 | special_type::DECIMAL, 5]
+        );
+        assert_eq!(EncodedData::decode(&data), Ok((o.clone(), data.len())));
+        assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+        assert_eq!(
+            serde_json::Value::try_from(EncodedData::Decimal("1.1.".to_string())),
+            Err(EncodedDataToJsonError::BadDecimal("1.1.".to_string()))
+        );
+
+        let value: serde_json::Value = serde_json::from_str("1.100").unwrap();
+        let expected = if cfg!(feature = "decimal") {
+            o
+        } else {
+            EncodedData::Float(1.1)
+        };
+        assert_eq!(EncodedData::from(value), expected);
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn decimal_round_trip() {
+        for text in [
+            "1.100",
+            "1e+400",
+            "123456789012345678901234567890",
+            "0.1",
+            "-2.5",
+        ] {
+            let value: serde_json::Value = serde_json::from_str(text).unwrap();
+            let data = EncodedData::from(value).encode();
+            let (decoded, _) = EncodedData::decode(&data).unwrap();
+            let decoded: serde_json::Value = decoded.try_into().unwrap();
+            assert_eq!(decoded.to_string(), text);
+        }
+        let data = crate::codec::compress_str("[1.100, 0.1]").unwrap();
+        assert_eq!(
+            EncodedData::decode(&data).unwrap().0,
+            EncodedData::Array(vec![
+                EncodedData::Decimal("1.100".to_string()),
+                EncodedData::Float(0.1),
+            ])
+        );
+    }
+
+    #[test]
+    fn decode_resolved() {
+        let abc = EncodedData::String("abc".to_string());
+        let mut map = HashMap::new();
+        map.insert(
+            "a".to_string(),
+            EncodedData::Special(EncodedSpecial::Define(Box::new(abc.clone()))),
+        );
+        map.insert("b".to_string(), EncodedData::Alias(0));
+        map.insert(
+            "c".to_string(),
+            EncodedData::Array(vec![EncodedData::Alias(0), EncodedData::Float(0.5)]),
+        );
+        let data = EncodedData::Object(map).encode();
+
+        let mut expected = HashMap::new();
+        expected.insert("a".to_string(), abc.clone());
+        expected.insert("b".to_string(), abc.clone());
+        expected.insert(
+            "c".to_string(),
+            EncodedData::Array(vec![abc, EncodedData::Float(0.5)]),
+        );
+        assert_eq!(
+            EncodedData::decode_resolved(&data),
+            Ok((EncodedData::Object(expected), data.len()))
+        );
+        assert_eq!(
+            EncodedData::decode_resolved(&EncodedData::Alias(1).encode()),
+            Err(DecodeError::UnknownAlias(1))
+        );
+
+        // Aliases and Defines inside a Define, the inner Defines getting the first ids.
+        let define = |o| EncodedData::Special(EncodedSpecial::Define(Box::new(o)));
+        let abc = EncodedData::String("abc".to_string());
+        let x = EncodedData::String("x".to_string());
+        let data = EncodedData::Array(vec![
+            define(abc.clone()),
+            define(EncodedData::Array(vec![
+                EncodedData::Alias(0),
+                define(x.clone()),
+            ])),
+            EncodedData::Alias(1),
+            EncodedData::Alias(2),
+        ])
+        .encode();
+        let pair = EncodedData::Array(vec![abc.clone(), x.clone()]);
+        let expected = EncodedData::Array(vec![abc, pair.clone(), x, pair]);
+        assert_eq!(
+            EncodedData::decode_resolved(&data),
+            Ok((expected.clone(), data.len()))
+        );
+        let mut decoder = crate::stream_compressor::StreamDecoder::new();
+        assert_eq!(decoder.decompress(&data), Ok((expected, data.len())));
+    }
+
+    #[test]
+    fn decode_hashing() {
+        use std::hash::Hasher;
+        let hash = |data: &[u8]| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            let (o, size) = EncodedData::decode_hashing(data, &mut hasher).unwrap();
+            assert_eq!(size, data.len());
+            (o, hasher.finish())
+        };
+        let mut map = HashMap::new();
+        map.insert(
+            "bools".to_string(),
+            EncodedData::Array(vec![EncodedData::Integer(EncodedInteger::Bool(true)); 20]),
+        );
+        map.insert(
+            "n".to_string(),
+            EncodedData::Integer(EncodedInteger::Positive(5)),
+        );
+        let o = EncodedData::Object(map);
+        let canonical = o.encode();
+        let options = EncodeOptions {
+            sized_containers: true,
+            packed_arrays: true,
+            ..Default::default()
+        };
+        let packed = o.encode_with_options(&options);
+        assert_ne!(canonical, packed);
+        // Same object with a 2 bytes wide integer.
+        let n_pos = canonical.len() - 2;
+        assert_eq!(
+            &canonical[n_pos..],
+            &EncodedData::Integer(EncodedInteger::Positive(5)).encode()[..]
+        );
+        let wide = [
+            &canonical[..n_pos],
+            &[data_type::INTEGER << 5 | 2, 5, 0][..],
+        ]
+        .concat();
 
-                    (Self::Array(list), tot_size)
-                }
-                DataType::Object => {
-                    let length = ctrl & 0x0F;
-                    let length_continue = ctrl & 0x10 != 0;
-                    let (length, size) = if length_continue {
-                        let (length_head, size) = match varint::decode(data.get_unchecked(1..)) {
-                            Ok(e) => e,
-                            Err(varint::DecodeError::MissingBytes) => {
-                                return Err(DecodeError::MissingBytes(1))
-                            }
-                            Err(varint::DecodeError::ValueTooBig) => {
-                                return Err(DecodeError::VarintTooBig)
-                            }
-                        };
-                        (
-                            ((length_head as usize) << 4 | length as usize) + 0x10,
-                            1 + size as usize,
-                        )
-                    } else {
-                        (length as usize, 1)
-                    };
-                    let mut map = HashMap::new();
-                    let mut data_ref = data.get_unchecked(size..);
-                    let mut tot_size = size;
-                    for _ in 0..length {
-                        let (k_length, size) = match varint::decode(data_ref) {
-                            Ok(e) => e,
-                            Err(varint::DecodeError::MissingBytes) => {
-                                return Err(DecodeError::MissingBytes(1))
-                            }
-                            Err(varint::DecodeError::ValueTooBig) => {
-                                return Err(DecodeError::VarintTooBig)
-                            }
-                        };
-                        let (k_length, size) = (k_length as usize, size as usize);
-                        tot_size += size;
-                        data_ref = data_ref.get_unchecked(size..);
-                        if data_ref.len() < k_length {
-                            return Err(DecodeError::MissingBytes(k_length - data_ref.len()));
-                        }
-                        tot_size += k_length;
-                        let k = match std::str::from_utf8(data_ref.get_unchecked(..k_length)) {
-                            Ok(k) => k,
-                            Err(e) => return Err(DecodeError::BadUtf8(e)),
-                        };
-                        data_ref = data_ref.get_unchecked(k_length..);
-                        let (o, size) = Self::decode(data_ref)?;
-                        map.insert(k.to_string(), o);
-                        data_ref = data_ref.get_unchecked(size..);
-                        tot_size += size;
-                    }
+        let (decoded, expected) = hash(&canonical);
+        assert_eq!(decoded, o);
+        assert_eq!(hash(&packed), (o.clone(), expected));
+        assert_eq!(hash(&wide), (o, expected));
+        assert_ne!(hash(&EncodedData::Array(vec![]).encode()).1, expected);
+    }
 
-                    (Self::Object(map), tot_size)
-                }
-                DataType::Alias => {
-                    let id = ctrl & 0x0F;
-                    let id_continue = ctrl & 0x10 != 0;
-                    let (id, size) = if id_continue {
-                        let (id_head, size) = match varint::decode(data.get_unchecked(1..)) {
-                            Ok(e) => e,
-                            Err(varint::DecodeError::MissingBytes) => {
-                                return Err(DecodeError::MissingBytes(1))
-                            }
-                            Err(varint::DecodeError::ValueTooBig) => {
-                                return Err(DecodeError::VarintTooBig)
+    #[test]
+    fn from_json_sorted() {
+        let value = serde_json::json!({"b": 1, "c": {"z": null, "y": [{"k": 2}]}, "a": "x"});
+        let sorted = EncodedData::from_json_sorted(value.clone());
+        let expected = EncodedData::OrderedObject(vec![
+            ("a".to_string(), EncodedData::String("x".to_string())),
+            (
+                "b".to_string(),
+                EncodedData::Integer(EncodedInteger::Positive(1)),
+            ),
+            (
+                "c".to_string(),
+                EncodedData::OrderedObject(vec![
+                    (
+                        "y".to_string(),
+                        EncodedData::Array(vec![EncodedData::OrderedObject(vec![(
+                            "k".to_string(),
+                            EncodedData::Integer(EncodedInteger::Positive(2)),
+                        )])]),
+                    ),
+                    ("z".to_string(), EncodedData::Special(EncodedSpecial::Null)),
+                ]),
+            ),
+        ]);
+        assert_eq!(sorted, expected);
+        assert_eq!(sorted.encode(), EncodedData::from(value.clone()).encode());
+        assert!(sorted.eq_logical(&EncodedData::from(value.clone())));
+        let json: serde_json::Value = sorted.try_into().unwrap();
+        assert_eq!(json, value);
+    }
+
+    #[test]
+    fn canonicalize_negative_zero() {
+        let o = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Negative(0)),
+            EncodedData::Integer(EncodedInteger::Negative(1)),
+            EncodedData::Object(
+                vec![(
+                    "a".to_string(),
+                    EncodedData::Integer(EncodedInteger::Negative(0)),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+        ]);
+        let round_trip = |o: EncodedData| {
+            let json: serde_json::Value = o.try_into().unwrap();
+            EncodedData::from(json)
+        };
+        assert_ne!(round_trip(o.clone()).encode(), o.encode());
+
+        let canonical = o.clone().canonicalize();
+        assert!(canonical.eq_logical(&o));
+        assert_eq!(round_trip(canonical.clone()).encode(), canonical.encode());
+        assert_eq!(canonical.clone().canonicalize(), canonical);
+
+        let hash = |o: &EncodedData| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            EncodedData::decode_hashing(&o.encode(), &mut hasher).unwrap();
+            std::hash::Hasher::finish(&hasher)
+        };
+        assert_eq!(hash(&o), hash(&canonical));
+    }
+
+    #[test]
+    fn size_breakdown() {
+        let value = serde_json::json!({
+            "name": "sensor",
+            "values": [1, -20000, 2.5, 3.0, null, true],
+            "nested": {"key": "value", "list": []},
+        });
+        let o = EncodedData::from(value);
+        let breakdown = o.size_breakdown();
+        assert_eq!(breakdown.total(), o.encode().len());
+        assert_eq!(breakdown.keys, 5 + 7 + 7 + 4 + 5);
+        assert_eq!(breakdown.strings, 7 + 6);
+        assert_eq!(breakdown.integers, 2 + 3 + 1);
+        assert_eq!(breakdown.floats, 9 + 2);
+        assert_eq!(breakdown.specials, 1);
+        assert_eq!(breakdown.containers, 4);
+
+        let o = EncodedData::Array(vec![
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::String(
+                "abc".to_string(),
+            )))),
+            EncodedData::Alias(0x10),
+            EncodedData::Special(EncodedSpecial::Forget(0x10)),
+        ]);
+        let breakdown = o.size_breakdown();
+        assert_eq!(breakdown.total(), o.encode().len());
+        assert_eq!(breakdown.strings, 4);
+        assert_eq!(breakdown.aliases, o.encode().len() - 1 - 1 - 4 - 2);
+    }
+
+    #[test]
+    fn bad_utf8_offset() {
+        let value = serde_json::json!({"a": [1, "xxxx"], "b": {"c": "ok"}});
+        let mut data = EncodedData::from(value.clone()).encode();
+        // Object header, key "a", array header, 1, string header, 'x'.
+        let bad = 1 + 2 + 1 + 2 + 1 + 1;
+        assert_eq!(data[bad], b'x');
+        data[bad] = 0xFF;
+        let expect_offset = |r: Result<(EncodedData, usize), DecodeError>, offset: usize| match r {
+            Err(DecodeError::BadUtf8 { offset: o, .. }) => assert_eq!(o, offset),
+            r => panic!("unexpected {:?}", r),
+        };
+        expect_offset(EncodedData::decode(&data), bad);
+        expect_offset(EncodedData::decode_resolved(&data), bad);
+        match EncodedData::decode_borrowed(&data) {
+            Err(DecodeError::BadUtf8 { offset, .. }) => assert_eq!(offset, bad),
+            r => panic!("unexpected {:?}", r),
+        }
+        match crate::codec::decode_to_json(&data) {
+            Err(crate::codec::CodecError::BadFormat(DecodeError::BadUtf8 { offset, .. })) => {
+                assert_eq!(offset, bad)
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let versioned = [vec![FORMAT_VERSION], data.clone()].concat();
+        expect_offset(EncodedData::decode_versioned(&versioned), bad + 1);
+        let sized = EncodedData::from(value).encode_with_options(&EncodeOptions {
+            sized_containers: true,
+            ..Default::default()
+        });
+        let mut data = [
+            EncodedData::Integer(EncodedInteger::Positive(7)).encode(),
+            sized,
+        ]
+        .concat();
+        let bad = data.iter().position(|b| *b == b'o').unwrap();
+        data[bad + 1] = 0xC0;
+        match EncodedData::decode_all(&data) {
+            Err(DecodeError::BadUtf8 { offset, .. }) => assert_eq!(offset, bad + 1),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        // Run with a small stack: the nesting only grows the heap allocated work stack.
+        let depth = 10000;
+        let mut arrays = vec![(data_type::ARRAY << 5) | 1; depth];
+        arrays.push(data_type::ARRAY << 5);
+        let mut objects = [(data_type::OBJECT << 5) | 1, 1, b'a'].repeat(depth);
+        objects.push(data_type::OBJECT << 5);
+        std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                let options = DecodeOptions {
+                    max_depth: depth + 1,
+                    ..Default::default()
+                };
+                for data in [arrays, objects].iter() {
+                    assert_eq!(EncodedData::decode(data), Err(DecodeError::TooDeep));
+                    let (mut o, size) = EncodedData::decode_with_options(data, &options).unwrap();
+                    assert_eq!(size, data.len());
+                    // Taken apart level by level, as dropping it would recurse.
+                    let mut levels = 0;
+                    loop {
+                        o = match o {
+                            EncodedData::Array(mut list) if !list.is_empty() => list.pop().unwrap(),
+                            EncodedData::Object(mut map) if !map.is_empty() => {
+                                map.remove("a").unwrap()
                             }
+                            _ => break,
                         };
-                        ((id_head as u64) << 3 | id as u64, 1 + size as usize)
-                    } else {
-                        (id as u64, 1)
-                    };
-                    (Self::Alias(id), size)
+                        levels += 1;
+                    }
+                    assert_eq!(levels, depth);
                 }
             })
-        }
+            .unwrap()
+            .join()
+            .unwrap();
     }
 
-    pub fn decode_all(mut data: &[u8]) -> Result<(Vec<Self>, usize), DecodeError> {
-        let mut ret = vec![];
-        let mut tot_size = 0;
-        while !data.is_empty() {
-            let (o, size) = Self::decode(data)?;
-            tot_size += size;
-            ret.push(o);
-            data = &data[size..];
+    #[test]
+    fn recursive_walks_depth() {
+        let nested = |depth: usize| {
+            let mut data = vec![(data_type::ARRAY << 5) | 1; depth - 1];
+            data.push(data_type::ARRAY << 5);
+            data
+        };
+        let data = nested(MAX_DEPTH);
+        assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+        assert_eq!(EncodedData::validate(&data), Ok(data.len()));
+        assert_eq!(
+            EncodedData::decode_borrowed(&data).map(|(_, size)| size),
+            Ok(data.len())
+        );
+
+        let data = nested(MAX_DEPTH + 1);
+        assert_eq!(EncodedData::skip(&data), Err(DecodeError::TooDeep));
+        assert_eq!(EncodedData::validate(&data), Err(DecodeError::TooDeep));
+        assert_eq!(
+            EncodedData::decode_borrowed(&data).map(|(_, size)| size),
+            Err(DecodeError::TooDeep)
+        );
+        // Defines and Sized specials are levels too.
+        let define = data_type::SPECIAL << 5 | special_type::DEFINE;
+        let mut data = vec![define; MAX_DEPTH];
+        data.push(data_type::SPECIAL << 5 | special_type::NULL);
+        assert_eq!(EncodedData::validate(&data), Err(DecodeError::TooDeep));
+        // decode recurses on wrappers with large frames in debug builds, hence the main thread's
+        // stack size.
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(move || assert_eq!(EncodedData::decode(&data), Err(DecodeError::TooDeep)))
+            .unwrap()
+            .join()
+            .unwrap();
+        let data = nested(MAX_DEPTH);
+        assert_eq!(
+            EncodedData::decode(&data).map(|(_, size)| size),
+            Ok(data.len())
+        );
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn decode_array_parallel() {
+        let values: Vec<_> = (0..1000)
+            .map(|i| serde_json::json!({"id": i, "name": format!("n{}", i % 10), "v": [i, -1.5]}))
+            .collect();
+        let data = EncodedData::from(serde_json::Value::Array(values)).encode();
+        match EncodedData::decode(&data).unwrap().0 {
+            EncodedData::Array(list) => {
+                assert_eq!(EncodedData::decode_array_parallel(&data), Ok(list))
+            }
+            o => panic!("unexpected {:?}", o),
         }
-        Ok((ret, tot_size))
+
+        // Aliases are decoded as is.
+        let mut compressor = crate::stream_compressor::StreamCompressor::new(Default::default());
+        let data = compressor.compress(&EncodedData::Array(vec![
+            EncodedData::String(
+                "abcd".to_string()
+            );
+            8
+        ]));
+        assert_eq!(
+            EncodedData::decode_array_parallel(&data).map(EncodedData::Array),
+            EncodedData::decode(&data).map(|(o, _)| o)
+        );
+
+        let packed = EncodedData::from(serde_json::json!([true, false])).encode_with_options(
+            &EncodeOptions {
+                packed_arrays: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            EncodedData::decode_array_parallel(&packed),
+            Ok(vec![
+                EncodedData::Integer(EncodedInteger::Bool(true)),
+                EncodedData::Integer(EncodedInteger::Bool(false)),
+            ])
+        );
+        assert!(matches!(
+            EncodedData::decode_array_parallel(&EncodedData::Float(1.0).encode()),
+            Err(DecodeError::SchemaMismatch { .. })
+        ));
+        assert_eq!(
+            EncodedData::decode_array_parallel(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn accessors() {
+        let null = EncodedData::Special(EncodedSpecial::Null);
+        let t = EncodedData::Integer(EncodedInteger::Bool(true));
+        let positive = EncodedData::Integer(EncodedInteger::Positive(u64::MAX));
+        let negative = EncodedData::Integer(EncodedInteger::Negative(3));
+        let float = EncodedData::Float(-1.5);
+        let string = EncodedData::String("abc".to_string());
+        let array = EncodedData::Array(vec![null.clone()]);
+        let object = EncodedData::from(serde_json::json!({"a": 1}));
+        let ordered = EncodedData::OrderedObject(vec![("a".to_string(), t.clone())]);
+
+        assert!(null.is_null());
+        assert!(!t.is_null() && !EncodedData::Special(EncodedSpecial::None).is_null());
+        assert_eq!(t.as_bool(), Some(true));
+        assert_eq!(positive.as_bool(), None);
+        assert_eq!(positive.as_u64(), Some(u64::MAX));
+        assert_eq!(negative.as_u64(), None);
+        assert_eq!(t.as_u64(), None);
+        assert_eq!(negative.as_i64(), Some(-3));
+        assert_eq!(positive.as_i64(), None);
+        assert_eq!(float.as_i64(), None);
+        assert_eq!(float.as_f64(), Some(-1.5));
+        assert_eq!(negative.as_f64(), Some(-3.0));
+        assert_eq!(
+            EncodedData::Decimal("0.25".to_string()).as_f64(),
+            Some(0.25)
+        );
+        assert_eq!(string.as_f64(), None);
+        assert_eq!(string.as_str(), Some("abc"));
+        assert_eq!(null.as_str(), None);
+        assert_eq!(array.as_array(), Some(&vec![null.clone()]));
+        assert_eq!(object.as_array(), None);
+        assert_eq!(
+            object.as_object().and_then(|map| map.get("a")),
+            Some(&EncodedData::Integer(EncodedInteger::Positive(1)))
+        );
+        assert_eq!(ordered.as_object(), None);
+        assert_eq!(array.as_object(), None);
+
+        assert_eq!(string.clone().into_string(), Some("abc".to_string()));
+        assert_eq!(float.clone().into_string(), None);
+        assert_eq!(array.clone().into_array(), Some(vec![null]));
+        assert_eq!(string.into_array(), None);
+        assert_eq!(object.clone().into_object(), object.as_object().cloned());
+        assert_eq!(ordered.into_object(), None);
+        assert_eq!(array.into_object(), None);
+    }
 
+    // Entry points decoding a single value fail with MissingBytes(1) on empty input, the ones
+    // decoding a sequence of values return none.
     #[test]
-    fn consistency() {
-        fn check(object: EncodedData, size: usize) {
-            let data = object.encode();
-            assert_eq!(data.len(), size);
-            let (decoded, decoded_size) = EncodedData::decode(&data).unwrap();
-            assert_eq!(decoded, object);
-            assert_eq!(decoded_size, size);
+    fn empty_input() {
+        let missing = Err(DecodeError::MissingBytes(1));
+        assert_eq!(EncodedData::decode(&[]), missing);
+        assert_eq!(EncodedData::decode_resolved(&[]), missing);
+        assert_eq!(EncodedData::decode_versioned(&[]), missing);
+        assert_eq!(EncodedData::decode_at(&[], 0), missing);
+        assert_eq!(EncodedData::decode_at(&[1], 1), missing);
+        assert_eq!(
+            EncodedData::decode_located(&[]),
+            Err(DecodeErrorAt {
+                offset: 0,
+                error: DecodeError::MissingBytes(1)
+            })
+        );
+        assert_eq!(
+            EncodedData::decode_with_schema(&[], &crate::schema::Schema::Any),
+            missing
+        );
+        assert_eq!(
+            EncodedData::decode_borrowed(&[]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        assert_eq!(EncodedData::skip(&[]), Err(DecodeError::MissingBytes(1)));
+        assert_eq!(
+            EncodedData::validate(&[]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        assert_eq!(
+            EncodedData::slice_value(&[]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        assert_eq!(
+            EncodedData::patch_pointer(&[], "", &EncodedData::Special(EncodedSpecial::Null)),
+            Err(DecodeError::MissingBytes(1))
+        );
+        let bad_format = crate::codec::CodecError::BadFormat(DecodeError::MissingBytes(1));
+        assert_eq!(crate::codec::from_bytes(&[]), Err(bad_format.clone()));
+        assert_eq!(crate::codec::decode_to_json(&[]), Err(bad_format));
+        let mut decoder = crate::stream_compressor::StreamDecoder::new();
+        assert_eq!(
+            decoder.decompress(&[]),
+            Err(crate::stream_compressor::DecodeError::BadFormat(
+                DecodeError::MissingBytes(1)
+            ))
+        );
+
+        assert_eq!(EncodedData::decode_all(&[]), Ok((vec![], 0)));
+        assert_eq!(EncodedData::decode_spans(&[]).count(), 0);
+        assert_eq!(decoder.filter_decode(&[], |_| true).count(), 0);
+        assert_eq!(crate::stream_compressor::decode_batch(&[]), Ok(vec![]));
+        assert!(crate::stream_compressor::encode_batch(&[]).is_empty());
+        let mut out = vec![];
+        assert_eq!(crate::cli::decompress_stream(&[][..], &mut out).unwrap(), 0);
+        assert_eq!(crate::cli::compress_stream(&[][..], &mut out).unwrap(), 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn decode_located() {
+        let value = serde_json::json!({"outer": {"items": [1, 2, 3]}});
+        let mut data = EncodedData::from(value).encode();
+        // Object header, key "outer", object header, key "items", array header, 1.
+        let bad = 1 + 6 + 1 + 6 + 1 + 2;
+        assert_eq!(bad, 17);
+        assert_eq!(data[bad..bad + 2], [0x21, 2]);
+        data[bad] = 0xE0;
+        assert_eq!(
+            EncodedData::decode_located(&data),
+            Err(DecodeErrorAt {
+                offset: 17,
+                error: DecodeError::UnknownDataType(7),
+            })
+        );
+        assert_eq!(
+            EncodedData::decode(&data),
+            Err(DecodeError::UnknownDataType(7))
+        );
+
+        data[bad] = 0x21;
+        assert_eq!(
+            EncodedData::decode_located(&data[..bad + 1]),
+            Err(DecodeErrorAt {
+                offset: 17,
+                error: DecodeError::MissingBytes(1),
+            })
+        );
+        assert!(EncodedData::decode_located(&data).is_ok());
+    }
+
+    #[test]
+    fn delta_arrays() {
+        let options = EncodeOptions {
+            delta_arrays: true,
+            ..Default::default()
+        };
+        let timestamps = EncodedData::Array(
+            (1000..1100)
+                .map(|n| EncodedData::Integer(EncodedInteger::Positive(n)))
+                .collect(),
+        );
+        let data = timestamps.encode_with_options(&options);
+        // Header, count, first value, then a single byte per delta.
+        assert_eq!(data.len(), 1 + 1 + 2 + 99);
+        assert!(data.len() < timestamps.encode().len() / 2);
+        assert_eq!(EncodedData::decode(&data), Ok((timestamps, data.len())));
+        assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+
+        let int = |n: i64| match n.try_into() {
+            Ok(n) => EncodedData::Integer(EncodedInteger::Positive(n)),
+            Err(_) => EncodedData::Integer(EncodedInteger::Negative(n.unsigned_abs())),
+        };
+        let mixed = EncodedData::Array(vec![int(-5), int(3), int(-200), int(-190), int(0)]);
+        let data = mixed.encode_with_options(&options);
+        assert_eq!(data[0], data_type::SPECIAL << 5 | special_type::DELTA_ARRAY);
+        assert_eq!(EncodedData::decode(&data), Ok((mixed, data.len())));
+
+        // Kept as is when deltas do not pay off, or do not fit in an i64.
+        let scattered = EncodedData::Array(vec![int(1), int(i64::MAX), int(i64::MIN)]);
+        assert_eq!(scattered.encode_with_options(&options), scattered.encode());
+        let big = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Positive(u64::MAX)),
+            EncodedData::Integer(EncodedInteger::Positive(u64::MAX)),
+        ]);
+        assert_eq!(big.encode_with_options(&options), big.encode());
+
+        assert_eq!(
+            EncodedData::decode(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+        let overflow = [
+            vec![data_type::SPECIAL << 5 | special_type::DELTA_ARRAY, 2],
+            varint::encode_signed(i64::MAX),
+            varint::encode_signed(1),
+        ]
+        .concat();
+        assert_eq!(
+            EncodedData::decode(&overflow),
+            Err(DecodeError::VarintTooBig)
+        );
+    }
+
+    #[test]
+    fn sorted_arrays() {
+        let options = EncodeOptions {
+            sorted_arrays: true,
+            ..Default::default()
+        };
+        // Every id of a range, shuffled.
+        let ids = EncodedData::Array(
+            (0..500)
+                .map(|i| EncodedData::Integer(EncodedInteger::Positive(100_000 + i * 7919 % 500)))
+                .collect(),
+        );
+        let (data, report) = ids.compress_reported(&options);
+        assert_eq!(
+            data[0],
+            data_type::SPECIAL << 5 | special_type::SORTED_ARRAY
+        );
+        assert!(data.len() < ids.encode().len() * 4 / 5);
+        assert_eq!(report.sorted_arrays.count, 1);
+        assert_eq!(EncodedData::decode(&data), Ok((ids, data.len())));
+        assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+        assert_eq!(EncodedData::validate(&data), Ok(data.len()));
+
+        // Duplicates and negative values.
+        let int = |n: i64| match n.try_into() {
+            Ok(n) => EncodedData::Integer(EncodedInteger::Positive(n)),
+            Err(_) => EncodedData::Integer(EncodedInteger::Negative(n.unsigned_abs())),
+        };
+        let mixed = EncodedData::Array(
+            [3, -1, 3, i64::MIN, i64::MAX, -1, 0]
+                .iter()
+                .map(|n| int(*n))
+                .collect(),
+        );
+        let data = encode_sorted_array(mixed.as_array().unwrap()).unwrap();
+        assert_eq!(EncodedData::decode(&data), Ok((mixed, data.len())));
+
+        // Kept as is when it does not pay off.
+        let ordered = EncodedData::Array((0..10).map(int).collect());
+        assert_eq!(ordered.encode_with_options(&options), ordered.encode());
+
+        let bad_position = [
+            vec![
+                data_type::SPECIAL << 5 | special_type::SORTED_ARRAY,
+                2,
+                0,
+                0,
+            ],
+            varint::encode_signed(0),
+            varint::encode_signed(2),
+        ]
+        .concat();
+        assert_eq!(
+            EncodedData::decode(&bad_position),
+            Err(DecodeError::BadPermutation(2))
+        );
+        assert_eq!(
+            EncodedData::validate(&bad_position),
+            Err(DecodeError::BadPermutation(2))
+        );
+        // Positions 1 then 1 again: the array would not be a permutation of the sorted values.
+        let repeated = [
+            vec![
+                data_type::SPECIAL << 5 | special_type::SORTED_ARRAY,
+                2,
+                0,
+                0,
+            ],
+            varint::encode_signed(1),
+            varint::encode_signed(0),
+        ]
+        .concat();
+        let error = Err(DecodeError::BadPermutation(1));
+        assert_eq!(EncodedData::decode(&repeated).map(|_| ()), error);
+        assert_eq!(EncodedData::validate(&repeated).map(|_| ()), error);
+        assert_eq!(EncodedData::decode_borrowed(&repeated).map(|_| ()), error);
+        assert_eq!(
+            EncodedData::decode(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+
+    #[test]
+    fn decode_spans() {
+        let values = [
+            EncodedData::from(serde_json::json!({"a": [1, 2], "b": "text"})),
+            EncodedData::Integer(EncodedInteger::Negative(300)),
+            EncodedData::String("end".to_string()),
+        ];
+        let data: Vec<u8> = values.iter().flat_map(|o| o.encode()).collect();
+        let spans: Vec<_> = EncodedData::decode_spans(&data)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(spans.len(), 3);
+        let mut end = 0;
+        for ((o, span), expected) in spans.iter().zip(values.iter()) {
+            assert_eq!(o, expected);
+            assert_eq!(span.start, end);
+            assert_eq!(&data[span.clone()], &expected.encode()[..]);
+            end = span.end;
         }
+        assert_eq!(end, data.len());
 
-        fn check_w_json(object: EncodedData, size: usize) {
-            check(object.clone(), size);
-            let json: serde_json::Value = object.clone().try_into().unwrap();
-            let reencoded: EncodedData = json.into();
-            assert_eq!(reencoded, object);
+        let mut spans = EncodedData::decode_spans(&data[..data.len() - 1]);
+        assert!(spans.next().unwrap().is_ok());
+        assert!(spans.next().unwrap().is_ok());
+        assert_eq!(spans.next(), Some(Err(DecodeError::MissingBytes(1))));
+        assert_eq!(spans.next(), None);
+    }
+
+    #[test]
+    fn ordered_objects() {
+        let entries: Vec<_> = ["zeta", "alpha", "mid", "beta", "omega"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| {
+                (
+                    k.to_string(),
+                    EncodedData::Integer(EncodedInteger::Positive(i as u64)),
+                )
+            })
+            .collect();
+        let o = EncodedData::Array(vec![EncodedData::OrderedObject(entries.clone())]);
+        let data = o.encode();
+        let options = DecodeOptions {
+            ordered_objects: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            EncodedData::decode_with_options(&data, &options),
+            Ok((o.clone(), data.len()))
+        );
+        let keys: Vec<_> = match EncodedData::decode_with_options(&data, &options) {
+            Ok((EncodedData::Array(list), _)) => match &list[0] {
+                EncodedData::OrderedObject(entries) => {
+                    entries.iter().map(|(k, _)| k.clone()).collect()
+                }
+                o => panic!("unexpected {:?}", o),
+            },
+            r => panic!("unexpected {:?}", r),
+        };
+        assert_eq!(keys, vec!["zeta", "alpha", "mid", "beta", "omega"]);
+
+        // Decoded as a plain Object by default.
+        let (decoded, _) = EncodedData::decode(&data).unwrap();
+        assert!(decoded.eq_logical(&o));
+        let first_is_object = match &decoded {
+            EncodedData::Array(list) => matches!(list[0], EncodedData::Object(_)),
+            _ => false,
+        };
+        assert!(first_is_object);
+
+        let json: serde_json::Value = o.try_into().unwrap();
+        let value = serde_json::json!([{"zeta": 0, "alpha": 1, "mid": 2, "beta": 3, "omega": 4}]);
+        assert_eq!(json, value);
+        let ordered = EncodedData::from_json_ordered(value.clone());
+        assert!(ordered.eq_logical(&EncodedData::from(value)));
+    }
+
+    #[test]
+    fn number_classification() {
+        let convert = |json: &str| {
+            EncodedData::from(serde_json::from_str::<serde_json::Value>(json).unwrap())
+        };
+        for json in ["100", "-100"].iter() {
+            assert!(matches!(convert(json), EncodedData::Integer(_)), "{}", json);
         }
+        for json in ["100.0", "1e2", "1E2", "-1e2", "1.5e300"].iter() {
+            let o = convert(json);
+            assert!(
+                matches!(o, EncodedData::Float(_) | EncodedData::Decimal(_)),
+                "{}",
+                json
+            );
+            let f: f64 = json.parse().unwrap();
+            assert_eq!(o.as_f64(), Some(f), "{}", json);
+        }
+        assert_eq!(convert("100.0"), EncodedData::Float(100.0));
+        #[cfg(not(feature = "decimal"))]
+        assert_eq!(convert("1e2"), EncodedData::Float(100.0));
+        // Kept as text, which is not the 100.0 of the f64.
+        #[cfg(feature = "decimal")]
+        assert!(matches!(convert("1e2"), EncodedData::Decimal(_)));
+    }
 
-        check(EncodedData::Special(EncodedSpecial::None), 1);
-        check_w_json(EncodedData::Special(EncodedSpecial::Null), 1);
-        check(
-            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::Special(
-                EncodedSpecial::Null,
-            )))),
-            2,
+    #[test]
+    fn source_spans() {
+        let json =
+            r#" {"name": "sensor", "values": [1, 2.5 ,true], "nested": {"n": null}, "e": []} "#;
+        let (o, spans) = EncodedData::from_json_str_with_spans(json).unwrap();
+        assert!(o.eq_logical(&EncodedData::from(
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        )));
+        let text = |id: usize| &json[spans.get(id).unwrap()];
+        // Preorder: root, name, values, 1, 2.5, true, nested, n, e.
+        assert_eq!(spans.len(), 9);
+        assert_eq!(text(0), json.trim());
+        assert_eq!(
+            spans.get(1),
+            Some(json.find("\"sensor\"").unwrap()..json.find(", \"values").unwrap())
         );
-        check(EncodedData::Special(EncodedSpecial::Forget(4)), 2);
-        check_w_json(EncodedData::Integer(EncodedInteger::Positive(0)), 2);
-        check(EncodedData::Integer(EncodedInteger::Negative(0)), 2);
-        check_w_json(EncodedData::Integer(EncodedInteger::Positive(1)), 2);
-        check_w_json(EncodedData::Integer(EncodedInteger::Positive(0xFF_FF)), 3);
-        check_w_json(
-            EncodedData::Integer(EncodedInteger::Negative(0xFF_FF_FF_FF)),
-            5,
+        assert_eq!(text(2), "[1, 2.5 ,true]");
+        assert_eq!(text(3), "1");
+        assert_eq!(text(4), "2.5");
+        assert_eq!(text(5), "true");
+        assert_eq!(text(6), r#"{"n": null}"#);
+        assert_eq!(text(7), "null");
+        assert_eq!(text(8), "[]");
+        assert_eq!(spans.get(9), None);
+
+        let (_, spans) = EncodedData::from_json_str_with_spans("42").unwrap();
+        assert_eq!(spans.get(0), Some(0..2));
+        assert!(EncodedData::from_json_str_with_spans("[1,").is_err());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let json = r#"{"a":1,"a":2,"b":[{"c":null,"c":"x"}]}"#;
+        let o = EncodedData::from_json_str_ordered(json).unwrap();
+        let a = |n| {
+            (
+                "a".to_string(),
+                EncodedData::Integer(EncodedInteger::Positive(n)),
+            )
+        };
+        assert!(matches!(&o, EncodedData::OrderedObject(entries) if entries[..2] == [a(1), a(2)]));
+
+        let data = o.encode();
+        let options = DecodeOptions {
+            ordered_objects: true,
+            ..Default::default()
+        };
+        let (decoded, _) = EncodedData::decode_with_options(&data, &options).unwrap();
+        assert_eq!(decoded, o);
+        assert_eq!(decoded.to_json_string(), Ok(json.to_string()));
+
+        // The other ingests keep the last value.
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(value["a"], 2);
+        assert_eq!(
+            EncodedData::from_json_str_ordered("{\"a\": 1} 2").map_err(|e| e.is_syntax()),
+            Err(true)
         );
-        check_w_json(EncodedData::Integer(EncodedInteger::Bool(true)), 1);
-        check_w_json(EncodedData::Integer(EncodedInteger::Bool(false)), 1);
-        check_w_json(EncodedData::Float(1.2f64), 9);
-        check_w_json(EncodedData::String("abc".to_string()), 4);
-        check_w_json(
-            EncodedData::String("1234567890ABCDEF".to_string()),
-            1 + 1 + 16,
+    }
+
+    #[test]
+    fn validate() {
+        let value = serde_json::json!({"name": "sensor", "values": [1, -2.5, null, "abc"]});
+        let data = EncodedData::from(value.clone()).encode();
+        assert_eq!(EncodedData::validate(&data), Ok(data.len()));
+        let sized = EncodedData::from(value).encode_with_options(&EncodeOptions {
+            sized_containers: true,
+            packed_arrays: true,
+            delta_arrays: true,
+            null_runs: true,
+            sorted_arrays: true,
+            integers: IntegerEncoding::Varint,
+            max_output: None,
+        });
+        assert_eq!(EncodedData::validate(&sized), Ok(sized.len()));
+
+        // Every truncation is reported the same way as decode does.
+        for end in 0..data.len() {
+            assert_eq!(
+                EncodedData::validate(&data[..end]),
+                EncodedData::decode(&data[..end]).map(|(_, size)| size),
+                "{}",
+                end
+            );
+        }
+        let truncated = [vec![data_type::STRING << 5 | 5], b"abc".to_vec()].concat();
+        assert_eq!(
+            EncodedData::validate(&truncated),
+            Err(DecodeError::MissingBytes(2))
         );
-        check_w_json(
-            EncodedData::String("1234567890ABCDEF1234567890ABCDEF".to_string()),
-            1 + 1 + 32,
+
+        let bad_varint = [
+            vec![data_type::ARRAY << 5 | 1],
+            vec![data_type::SPECIAL << 5 | special_type::FORGET],
+            vec![0xFF; 11],
+        ]
+        .concat();
+        assert_eq!(
+            EncodedData::validate(&bad_varint),
+            Err(DecodeError::VarintTooBig)
         );
-        let array = EncodedData::Array(vec![
-            EncodedData::Special(EncodedSpecial::Null),
-            EncodedData::Integer(EncodedInteger::Positive(5)),
-            EncodedData::String("abc".to_string()),
+
+        // skip does not look at strings, validate does.
+        let mut bad_utf8 = data.clone();
+        let pos = bad_utf8.windows(3).position(|w| w == b"abc").unwrap();
+        bad_utf8[pos + 1] = 0xFF;
+        assert_eq!(EncodedData::skip(&bad_utf8), Ok(data.len()));
+        match EncodedData::validate(&bad_utf8) {
+            Err(DecodeError::BadUtf8 { offset, .. }) => assert_eq!(offset, pos + 1),
+            r => panic!("unexpected {:?}", r),
+        }
+        let mut bad_key = data;
+        bad_key[2] = 0xFF;
+        match EncodedData::validate(&bad_key) {
+            Err(DecodeError::BadUtf8 { offset, .. }) => assert_eq!(offset, 2),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn big_negatives_as_strings() {
+        let o = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Negative(u64::MAX)),
+            EncodedData::Integer(EncodedInteger::Negative(1 << 63)),
+            EncodedData::Integer(EncodedInteger::Negative((1 << 63) + 1)),
         ]);
-        check_w_json(array.clone(), 1 + 1 + 2 + 4);
-        let mut map = HashMap::new();
-        map.insert(
-            "null".to_string(),
-            EncodedData::Special(EncodedSpecial::Null),
+        assert_eq!(
+            serde_json::Value::try_from(o.clone()),
+            Err(EncodedDataToJsonError::NegativeIntegerTooBig(u64::MAX))
         );
-        map.insert(
-            "positive".to_string(),
-            EncodedData::Integer(EncodedInteger::Positive(5)),
+        let options = JsonOptions {
+            big_negatives_as_strings: true,
+        };
+        assert_eq!(
+            o.into_json_with_options(&options),
+            Ok(serde_json::json!([
+                "-18446744073709551615",
+                i64::MIN,
+                "-9223372036854775809"
+            ]))
         );
-        map.insert("string".to_string(), EncodedData::String("abc".to_string()));
-        check_w_json(EncodedData::Object(map.clone()), 1 + 5 + 1 + 9 + 2 + 7 + 4);
+    }
 
-        let mut new_map = HashMap::new();
-        new_map.insert(
-            "null".to_string(),
-            EncodedData::Special(EncodedSpecial::Null),
+    #[test]
+    fn little_endian_bytes() {
+        // Hard coded so that a big endian host catches a switch to native endianness.
+        let cases = vec![
+            (
+                EncodedData::Float(1.5),
+                vec![0x48, 0, 0, 0, 0, 0, 0, 0xF8, 0x3F],
+            ),
+            (EncodedData::Float(300.0), vec![0x52, 0x2C, 0x01]),
+            (EncodedData::Float(-2.0), vec![0x51, 0xFE]),
+            (
+                EncodedData::Integer(EncodedInteger::Positive(0x0102)),
+                vec![0x22, 0x02, 0x01],
+            ),
+            (
+                EncodedData::Integer(EncodedInteger::Negative(0x01020304)),
+                vec![0x34, 0x04, 0x03, 0x02, 0x01],
+            ),
+            (
+                EncodedData::Integer(EncodedInteger::Positive(0x0102030405060708)),
+                vec![0x28, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+            ),
+        ];
+        for (o, data) in cases {
+            assert_eq!(o.encode(), data, "{:?}", o);
+            assert_eq!(EncodedData::decode(&data), Ok((o, data.len())));
+        }
+
+        let packed = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Positive(0x0102)),
+            EncodedData::Integer(EncodedInteger::Positive(0x0304)),
+            EncodedData::Integer(EncodedInteger::Positive(0x0506)),
+        ]);
+        let data = vec![0x06, 3, 0x02, 0x02, 0x01, 0x04, 0x03, 0x06, 0x05];
+        let options = EncodeOptions {
+            packed_arrays: true,
+            ..Default::default()
+        };
+        assert_eq!(packed.encode_with_options(&options), data);
+        assert_eq!(EncodedData::decode(&data), Ok((packed, data.len())));
+    }
+
+    #[test]
+    fn null_runs() {
+        let options = EncodeOptions {
+            null_runs: true,
+            ..Default::default()
+        };
+        let null = || EncodedData::Special(EncodedSpecial::Null);
+        let nulls = EncodedData::Array((0..1000).map(|_| null()).collect());
+        let data = nulls.encode_with_options(&options);
+        assert_eq!(data.len(), 4);
+        assert_eq!(EncodedData::decode(&data), Ok((nulls, data.len())));
+
+        let mut sparse: Vec<_> = (0..300).map(|_| null()).collect();
+        sparse[0] = EncodedData::String("first".to_string());
+        sparse[150] = EncodedData::Array(vec![null(), null(), null()]);
+        sparse[151] = EncodedData::Integer(EncodedInteger::Positive(1));
+        let sparse = EncodedData::Array(sparse);
+        let data = sparse.encode_with_options(&options);
+        assert!(data.len() < 20);
+        assert_eq!(EncodedData::decode(&data), Ok((sparse.clone(), data.len())));
+        assert_eq!(EncodedData::skip(&data), Ok(data.len()));
+        assert_eq!(EncodedData::validate(&data), Ok(data.len()));
+        assert_eq!(
+            EncodedData::decode_borrowed(&data).unwrap().0.into_owned(),
+            sparse
         );
-        new_map.insert(
-            "positive".to_string(),
-            EncodedData::Integer(EncodedInteger::Positive(5)),
+        let json: serde_json::Value = sparse.clone().try_into().unwrap();
+        assert_eq!(crate::codec::decode_to_json(&data), Ok((json, data.len())));
+
+        // Arrays without nulls, or with isolated ones, are kept as is.
+        let dense = EncodedData::Array(vec![null(), EncodedData::Float(1.5)]);
+        assert_eq!(dense.encode_with_options(&options), dense.encode());
+
+        assert_eq!(
+            EncodedData::decode(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
         );
-        new_map.insert("map".to_string(), EncodedData::Object(map));
-        new_map.insert("array".to_string(), array);
-        check_w_json(
-            EncodedData::Object(new_map),
-            1 + 5 + 1 + 9 + 2 + 4 + 1 + 5 + 1 + 9 + 2 + 7 + 4 + 6 + 1 + 1 + 2 + 4,
+
+        // Runs too long for their encoded size are stored as plain arrays, and rejected by the
+        // decoders.
+        let nulls = EncodedData::Array((0..2000).map(|_| null()).collect());
+        assert_eq!(nulls.encode_with_options(&options), nulls.encode());
+        let bomb = [
+            vec![data_type::SPECIAL << 5 | special_type::NULL_RUNS, 0],
+            varint::encode(0xFF_FF_FF_FF),
+        ]
+        .concat();
+        let error = DecodeError::TooManyNulls(0xFF_FF_FF_FF);
+        assert_eq!(EncodedData::decode(&bomb), Err(error.clone()));
+        assert_eq!(EncodedData::validate(&bomb), Err(error.clone()));
+        assert_eq!(EncodedData::decode_borrowed(&bomb), Err(error.clone()));
+        assert_eq!(
+            crate::codec::decode_to_json(&bomb),
+            Err(crate::codec::CodecError::BadFormat(error))
         );
+
+        // Each level reuses the encoding of the one below instead of encoding it again, which
+        // took twice as long per level.
+        let mut deep = EncodedData::String("x".to_string());
+        for _ in 0..100 {
+            let mut level = vec![deep];
+            level.extend((0..5).map(|_| null()));
+            deep = EncodedData::Array(level);
+        }
+        let data = deep.encode_with_options(&options);
+        assert!(data.len() < deep.encode().len());
+        assert_eq!(EncodedData::decode(&data), Ok((deep, data.len())));
+    }
+
+    #[test]
+    fn compact_u64_len() {
+        let boundaries = [
+            (0, 1),
+            (0xFF, 1),
+            (0x100, 2),
+            (0xFFFF, 2),
+            (0x1_0000, 3),
+            (0xFF_FFFF, 3),
+            (0x100_0000, 4),
+            (0xFFFF_FFFF, 4),
+            (0x1_0000_0000, 5),
+            (0xFF_FFFF_FFFF, 5),
+            (0x100_0000_0000, 6),
+            (0xFFFF_FFFF_FFFF, 6),
+            (0x1_0000_0000_0000, 7),
+            (0xFF_FFFF_FFFF_FFFF, 7),
+            (0x100_0000_0000_0000, 8),
+            (u64::MAX, 8),
+        ];
+        for (n, len) in boundaries.iter() {
+            assert_eq!(super::compact_u64_len(*n), *len, "{:#x}", n);
+            assert_eq!(encode_compact_u64(*n).len(), *len as usize, "{:#x}", n);
+            assert_eq!(decode_compact_u64(&encode_compact_u64(*n)), *n);
+            let o = EncodedData::Integer(EncodedInteger::Negative(*n));
+            assert_eq!(o.encode().len(), 1 + *len as usize, "{:#x}", n);
+            assert_eq!(o.encode()[0] & 0x0F, *len);
+        }
     }
 }
@@ -0,0 +1,343 @@
+use crate::define::{data_type::DataType, special_type};
+use crate::encoded_data::{self, DecodeError, EncodedData, EncodedInteger, EncodedSpecial};
+use std::collections::HashMap;
+
+// Borrowing counterparts of EncodedSpecial/EncodedData: strings and object keys point into the
+// decoded buffer instead of being copied.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodedSpecialRef<'a> {
+    None,
+    Null,
+    Define(Box<EncodedDataRef<'a>>),
+    Forget(u64),
+    Reset,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodedDataRef<'a> {
+    Special(EncodedSpecialRef<'a>),
+    Integer(EncodedInteger),
+    Float(f64),
+    String(&'a str),
+    Array(Vec<EncodedDataRef<'a>>),
+    Object(HashMap<&'a str, EncodedDataRef<'a>>),
+    Alias(u64),
+    Decimal(&'a str),
+}
+
+impl From<EncodedDataRef<'_>> for EncodedData {
+    fn from(o: EncodedDataRef<'_>) -> Self {
+        match o {
+            EncodedDataRef::Special(EncodedSpecialRef::None) => Self::Special(EncodedSpecial::None),
+            EncodedDataRef::Special(EncodedSpecialRef::Null) => Self::Special(EncodedSpecial::Null),
+            EncodedDataRef::Special(EncodedSpecialRef::Define(o)) => {
+                Self::Special(EncodedSpecial::Define(Box::new((*o).into())))
+            }
+            EncodedDataRef::Special(EncodedSpecialRef::Forget(id)) => {
+                Self::Special(EncodedSpecial::Forget(id))
+            }
+            EncodedDataRef::Special(EncodedSpecialRef::Reset) => {
+                Self::Special(EncodedSpecial::Reset)
+            }
+            EncodedDataRef::Integer(int) => Self::Integer(int),
+            EncodedDataRef::Float(f) => Self::Float(f),
+            EncodedDataRef::String(s) => Self::String(s.to_string()),
+            EncodedDataRef::Array(list) => Self::Array(list.into_iter().map(Self::from).collect()),
+            EncodedDataRef::Object(map) => Self::Object(
+                map.into_iter()
+                    .map(|(k, o)| (k.to_string(), o.into()))
+                    .collect(),
+            ),
+            EncodedDataRef::Alias(id) => Self::Alias(id),
+            EncodedDataRef::Decimal(s) => Self::Decimal(s.to_string()),
+        }
+    }
+}
+
+impl EncodedDataRef<'_> {
+    // Copies the borrowed strings, to keep the value past the decoded buffer. Named after
+    // Cow::into_owned, as the ToOwned::to_owned of the derived Clone already exists.
+    pub fn into_owned(self) -> EncodedData {
+        self.into()
+    }
+}
+
+pub(crate) fn borrow_str(data: &[u8], length: usize) -> Result<&str, DecodeError> {
+    if data.len() < length {
+        return Err(DecodeError::MissingBytes(length - data.len()));
+    }
+    std::str::from_utf8(&data[..length]).map_err(|error| DecodeError::BadUtf8 {
+        offset: error.valid_up_to(),
+        error,
+    })
+}
+
+impl EncodedData {
+    pub fn decode_borrowed(data: &[u8]) -> Result<(EncodedDataRef<'_>, usize), DecodeError> {
+        Self::decode_borrowed_nested(data, encoded_data::MAX_DEPTH)
+    }
+
+    // decode_borrowed of a value allowed `depth` more levels of nesting.
+    fn decode_borrowed_nested(
+        data: &[u8],
+        depth: usize,
+    ) -> Result<(EncodedDataRef<'_>, usize), DecodeError> {
+        let depth = depth.checked_sub(1).ok_or(DecodeError::TooDeep)?;
+        let ctrl = match data.first() {
+            Some(ctrl) => *ctrl,
+            None => return Err(DecodeError::MissingBytes(1)),
+        };
+        Ok(match DataType::from(ctrl >> 5) {
+            Some(DataType::String) => {
+                let (length, size) = encoded_data::decode_data_type_length(data)?;
+                let s = borrow_str(&data[size..], length).map_err(|e| e.shifted(size))?;
+                (EncodedDataRef::String(s), size + length)
+            }
+            Some(DataType::Special) if ctrl & 0x1F == special_type::NULL_RUNS => {
+                let (nb_values, mut tot_size) = encoded_data::decode_varint(&data[1..])?;
+                tot_size += 1;
+                let mut list = vec![];
+                let push_nulls = |list: &mut Vec<EncodedDataRef<'_>>, tot_size: &mut usize| {
+                    let nb_nulls = encoded_data::decode_null_run(data, tot_size, list.len())?;
+                    for _ in 0..nb_nulls {
+                        list.push(EncodedDataRef::Special(EncodedSpecialRef::Null));
+                    }
+                    Ok(())
+                };
+                for _ in 0..nb_values {
+                    push_nulls(&mut list, &mut tot_size)?;
+                    let (o, size) = Self::decode_borrowed_nested(&data[tot_size..], depth)
+                        .map_err(|e| e.shifted(tot_size))?;
+                    list.push(o);
+                    tot_size += size;
+                }
+                push_nulls(&mut list, &mut tot_size)?;
+                (EncodedDataRef::Array(list), tot_size)
+            }
+            Some(DataType::Special) if ctrl & 0x1F == special_type::DECIMAL => {
+                let (length, size) = encoded_data::decode_varint(&data[1..])?;
+                let s = borrow_str(&data[1 + size..], length as usize)
+                    .map_err(|e| e.shifted(1 + size))?;
+                (EncodedDataRef::Decimal(s), 1 + size + length as usize)
+            }
+            Some(DataType::Array) => {
+                let (length, mut tot_size) = encoded_data::decode_data_type_length(data)?;
+                let mut list = vec![];
+                for _ in 0..length {
+                    let (o, size) = Self::decode_borrowed_nested(&data[tot_size..], depth)
+                        .map_err(|e| e.shifted(tot_size))?;
+                    list.push(o);
+                    tot_size += size;
+                }
+                (EncodedDataRef::Array(list), tot_size)
+            }
+            Some(DataType::Object) => {
+                let (length, mut tot_size) = encoded_data::decode_data_type_length(data)?;
+                let mut map = HashMap::new();
+                for _ in 0..length {
+                    let (k_length, size) = encoded_data::decode_varint(&data[tot_size..])?;
+                    tot_size += size;
+                    let k = borrow_str(&data[tot_size..], k_length as usize)
+                        .map_err(|e| e.shifted(tot_size))?;
+                    tot_size += k_length as usize;
+                    let (o, size) = Self::decode_borrowed_nested(&data[tot_size..], depth)
+                        .map_err(|e| e.shifted(tot_size))?;
+                    map.insert(k, o);
+                    tot_size += size;
+                }
+                (EncodedDataRef::Object(map), tot_size)
+            }
+            Some(DataType::Special) if ctrl & 0x1F == special_type::DEFINE => {
+                let (o, size) =
+                    Self::decode_borrowed_nested(&data[1..], depth).map_err(|e| e.shifted(1))?;
+                (
+                    EncodedDataRef::Special(EncodedSpecialRef::Define(Box::new(o))),
+                    1 + size,
+                )
+            }
+            Some(DataType::Special) if ctrl & 0x1F == special_type::SIZED => {
+                let (length, size) = encoded_data::decode_varint(&data[1..])?;
+                let length = length as usize;
+                if data.len() - 1 - size < length {
+                    return Err(DecodeError::MissingBytes(length - (data.len() - 1 - size)));
+                }
+                let (o, o_size) =
+                    Self::decode_borrowed_nested(&data[1 + size..1 + size + length], depth)
+                        .map_err(|e| e.in_sized(length).shifted(1 + size))?;
+                if o_size != length {
+                    return Err(DecodeError::BadSizedLength(length));
+                }
+                (o, 1 + size + length)
+            }
+            Some(DataType::Special) if ctrl & 0x1F == special_type::EXTEND => {
+                let (base, size) =
+                    Self::decode_borrowed_nested(&data[1..], depth).map_err(|e| e.shifted(1))?;
+                let start = 1 + size;
+                let (tail, size) = Self::decode_borrowed_nested(&data[start..], depth)
+                    .map_err(|e| e.shifted(start))?;
+                match (base, tail) {
+                    (EncodedDataRef::Object(mut map), EncodedDataRef::Object(tail)) => {
+                        map.extend(tail);
+                        (EncodedDataRef::Object(map), start + size)
+                    }
+                    (EncodedDataRef::Object(_), o) | (o, _) => {
+                        return Err(DecodeError::BadExtend(crate::schema::kind(&o.into_owned())))
+                    }
+                }
+            }
+            // Everything else holds no borrowable data.
+            _ => {
+                let (o, size) = Self::decode(data)?;
+                let o = match o {
+                    EncodedData::Special(EncodedSpecial::None) => {
+                        EncodedDataRef::Special(EncodedSpecialRef::None)
+                    }
+                    EncodedData::Special(EncodedSpecial::Null) => {
+                        EncodedDataRef::Special(EncodedSpecialRef::Null)
+                    }
+                    EncodedData::Special(EncodedSpecial::Forget(id)) => {
+                        EncodedDataRef::Special(EncodedSpecialRef::Forget(id))
+                    }
+                    EncodedData::Special(EncodedSpecial::Reset) => {
+                        EncodedDataRef::Special(EncodedSpecialRef::Reset)
+                    }
+                    EncodedData::Integer(int) => EncodedDataRef::Integer(int),
+                    EncodedData::Float(f) => EncodedDataRef::Float(f),
+                    EncodedData::Alias(id) => EncodedDataRef::Alias(id),
+                    // Packed arrays.
+                    EncodedData::Array(list) => EncodedDataRef::Array(
+                        list.into_iter()
+                            .map(|o| match o {
+                                EncodedData::Integer(int) => EncodedDataRef::Integer(int),
+                                _ => unreachable!(),
+                            })
+                            .collect(),
+                    ),
+                    _ => unreachable!(),
+                };
+                (o, size)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrowed() {
+        let mut map = HashMap::new();
+        map.insert("name".to_string(), EncodedData::String("abc".to_string()));
+        map.insert(
+            "values".to_string(),
+            EncodedData::Array(vec![
+                EncodedData::Integer(EncodedInteger::Positive(5)),
+                EncodedData::Float(1.5),
+                EncodedData::Special(EncodedSpecial::Null),
+                EncodedData::String("def".to_string()),
+            ]),
+        );
+        let data = EncodedData::Object(map).encode();
+        let (decoded, size) = EncodedData::decode_borrowed(&data).unwrap();
+        assert_eq!(size, data.len());
+
+        let mut expected = HashMap::new();
+        expected.insert("name", EncodedDataRef::String("abc"));
+        expected.insert(
+            "values",
+            EncodedDataRef::Array(vec![
+                EncodedDataRef::Integer(EncodedInteger::Positive(5)),
+                EncodedDataRef::Float(1.5),
+                EncodedDataRef::Special(EncodedSpecialRef::Null),
+                EncodedDataRef::String("def"),
+            ]),
+        );
+        assert_eq!(decoded, EncodedDataRef::Object(expected));
+
+        let range = data.as_ptr_range();
+        match decoded {
+            EncodedDataRef::Object(map) => {
+                for (k, o) in map.iter() {
+                    assert!(range.contains(&k.as_ptr()));
+                    if let EncodedDataRef::String(s) = o {
+                        assert!(range.contains(&s.as_ptr()));
+                    }
+                }
+            }
+            _ => panic!("expected an object"),
+        }
+
+        assert_eq!(
+            EncodedData::decode_borrowed(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+
+    #[test]
+    fn into_owned() {
+        let value = serde_json::json!({
+            "name": "sensor",
+            "values": [1, -2, 2.5, null, true, "x"],
+            "nested": {"empty": [], "object": {}},
+        });
+        let data = EncodedData::from(value).encode();
+        let owned = {
+            let copy = data.clone();
+            let (borrowed, _) = EncodedData::decode_borrowed(&copy).unwrap();
+            borrowed.into_owned()
+        };
+        assert_eq!(owned, EncodedData::decode(&data).unwrap().0);
+
+        let define = EncodedData::Array(vec![
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::String(
+                "abc".to_string(),
+            )))),
+            EncodedData::Alias(0),
+            EncodedData::Special(EncodedSpecial::Forget(0)),
+            EncodedData::Special(EncodedSpecial::Reset),
+            EncodedData::Special(EncodedSpecial::None),
+        ]);
+        let data = define.encode();
+        let (borrowed, _) = EncodedData::decode_borrowed(&data).unwrap();
+        assert_eq!(EncodedData::from(borrowed), define);
+    }
+
+    // Strings borrowed from a buffer that is neither 'static nor owned by the decoded values.
+    fn names(archive: &[u8]) -> Vec<&str> {
+        EncodedData::decode_spans(archive)
+            .map(|r| {
+                let (_, span) = r.unwrap();
+                let record = EncodedData::slice_value(&archive[span.start..]).unwrap();
+                match EncodedData::decode_borrowed(record).unwrap().0 {
+                    EncodedDataRef::Object(mut map) => match map.remove("name") {
+                        Some(EncodedDataRef::String(s)) => s,
+                        o => panic!("unexpected {:?}", o),
+                    },
+                    o => panic!("unexpected {:?}", o),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mapped_bytes() {
+        let path = std::env::temp_dir().join(format!("mapped_bytes_{}.bin", std::process::id()));
+        let archive: Vec<u8> = (0..3)
+            .flat_map(|i| {
+                EncodedData::from(serde_json::json!({"name": format!("record-{}", i), "n": i}))
+                    .encode()
+            })
+            .collect();
+        std::fs::write(&path, &archive).unwrap();
+        // Stands for the mapping: the file content lives only as long as this buffer.
+        let mapped = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let names = names(&mapped);
+        assert_eq!(names, vec!["record-0", "record-1", "record-2"]);
+        let range = mapped.as_ptr_range();
+        assert!(names.iter().all(|s| range.contains(&s.as_ptr())));
+        assert_eq!(EncodedData::validate(&mapped[..]), Ok(archive.len() / 3));
+    }
+}
@@ -0,0 +1,445 @@
+use crate::define::{data_type::DataType, special_type};
+use crate::encoded_data::{self, DecodeError, EncodedData, EncodedInteger, EncodedSpecial};
+use crate::encoded_data_ref::borrow_str;
+use std::collections::VecDeque;
+
+// Flat view of decoded values, for transforms that never need the whole tree. Define is followed
+// by the events of its value, and Extend by those of its base and tail objects. Sized values are
+// transparent.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event<'a> {
+    None,
+    Null,
+    Integer(EncodedInteger),
+    Float(f64),
+    Str(&'a str),
+    Decimal(&'a str),
+    Alias(u64),
+    Define,
+    Forget(u64),
+    Reset,
+    Extend,
+    StartArray(usize),
+    EndArray,
+    StartObject(usize),
+    Key(&'a str),
+    EndObject,
+}
+
+enum Frame {
+    // Number of elements left to read.
+    Array(usize),
+    // Number of entries left to read, and whether the last key is waiting for its value.
+    Object {
+        left: usize,
+        key: bool,
+    },
+    // A NullRuns array starting at `start`: elements already returned, nulls left in the current
+    // run, values left to read, and whether the run before the next value (or the last one) is
+    // still to be read.
+    NullRuns {
+        start: usize,
+        len: usize,
+        nulls: u64,
+        values: u64,
+        run: bool,
+    },
+    // Values of a Define or an Extend, which end without any event.
+    Wrapped(usize),
+    // A Sized value of `length` bytes ending at `end`, and whether it was read.
+    Sized {
+        end: usize,
+        length: usize,
+        read: bool,
+    },
+}
+
+// Iterates over the events of the values of `data`, one after the other, strings and keys
+// borrowing from it. Stops after the first error.
+pub struct EventReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    stack: Vec<Frame>,
+    // Events of an already decoded packed array.
+    queue: VecDeque<Event<'a>>,
+    failed: bool,
+}
+
+impl<'a> EventReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            stack: vec![],
+            queue: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    // Number of bytes of `data` read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn step(&mut self) -> Result<Option<Event<'a>>, DecodeError> {
+        loop {
+            match self.stack.last_mut() {
+                None if self.pos == self.data.len() => return Ok(None),
+                None => {}
+                Some(Frame::Array(0)) => {
+                    self.stack.pop();
+                    return Ok(Some(Event::EndArray));
+                }
+                Some(Frame::Array(left)) => *left -= 1,
+                Some(Frame::Object { left: 0, .. }) => {
+                    self.stack.pop();
+                    return Ok(Some(Event::EndObject));
+                }
+                Some(Frame::Object {
+                    key: key @ false, ..
+                }) => {
+                    *key = true;
+                    return self.key().map(Some);
+                }
+                Some(Frame::Object { left, key }) => {
+                    *left -= 1;
+                    *key = false;
+                }
+                Some(Frame::NullRuns { nulls, len, .. }) if *nulls > 0 => {
+                    *nulls -= 1;
+                    *len += 1;
+                    return Ok(Some(Event::Null));
+                }
+                Some(Frame::NullRuns {
+                    start,
+                    len,
+                    nulls,
+                    run: run @ true,
+                    ..
+                }) => {
+                    let start = *start;
+                    let mut tot_size = self.pos - start;
+                    *nulls =
+                        encoded_data::decode_null_run(&self.data[start..], &mut tot_size, *len)
+                            .map_err(|e| e.shifted(start))?;
+                    *run = false;
+                    self.pos = start + tot_size;
+                    continue;
+                }
+                Some(Frame::NullRuns { values: 0, .. }) => {
+                    self.stack.pop();
+                    return Ok(Some(Event::EndArray));
+                }
+                Some(Frame::NullRuns {
+                    len, values, run, ..
+                }) => {
+                    *len += 1;
+                    *values -= 1;
+                    *run = true;
+                }
+                Some(Frame::Wrapped(0)) => {
+                    self.stack.pop();
+                    continue;
+                }
+                Some(Frame::Wrapped(left)) => *left -= 1,
+                Some(Frame::Sized { end, length, read }) => {
+                    if !*read {
+                        *read = true;
+                    } else if self.pos != *end {
+                        return Err(DecodeError::BadSizedLength(*length));
+                    } else {
+                        self.stack.pop();
+                        continue;
+                    }
+                }
+            }
+            let pos = self.pos;
+            if let Some(event) = self.value().map_err(|e| e.shifted(pos))? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<Event<'a>, DecodeError> {
+        let data = &self.data[self.pos..];
+        let (length, size) = encoded_data::decode_varint(data)?;
+        let k =
+            borrow_str(&data[size..], length as usize).map_err(|e| e.shifted(self.pos + size))?;
+        self.pos += size + length as usize;
+        Ok(Event::Key(k))
+    }
+
+    // Reads the value at `pos`, returning None for the values that have no event of their own.
+    // Errors are relative to `pos`.
+    fn value(&mut self) -> Result<Option<Event<'a>>, DecodeError> {
+        let data: &'a [u8] = &self.data[self.pos..];
+        let ctrl = match data.first() {
+            Some(ctrl) => *ctrl,
+            None => return Err(DecodeError::MissingBytes(1)),
+        };
+        let special = ctrl & 0x1F;
+        let event = match DataType::from(ctrl >> 5) {
+            Some(DataType::String) => {
+                let (length, size) = encoded_data::decode_data_type_length(data)?;
+                let s = borrow_str(&data[size..], length).map_err(|e| e.shifted(size))?;
+                self.pos += size + length;
+                Event::Str(s)
+            }
+            Some(DataType::Array) => {
+                let (length, size) = encoded_data::decode_data_type_length(data)?;
+                self.pos += size;
+                self.stack.push(Frame::Array(length));
+                Event::StartArray(length)
+            }
+            Some(DataType::Object) => {
+                let (length, size) = encoded_data::decode_data_type_length(data)?;
+                self.pos += size;
+                self.stack.push(Frame::Object {
+                    left: length,
+                    key: false,
+                });
+                Event::StartObject(length)
+            }
+            Some(DataType::Special) if special == special_type::DECIMAL => {
+                let (length, size) = encoded_data::decode_varint(&data[1..])?;
+                let s = borrow_str(&data[1 + size..], length as usize)
+                    .map_err(|e| e.shifted(1 + size))?;
+                self.pos += 1 + size + length as usize;
+                Event::Decimal(s)
+            }
+            Some(DataType::Special) if special == special_type::DEFINE => {
+                self.pos += 1;
+                self.stack.push(Frame::Wrapped(1));
+                Event::Define
+            }
+            Some(DataType::Special) if special == special_type::EXTEND => {
+                self.pos += 1;
+                self.stack.push(Frame::Wrapped(2));
+                Event::Extend
+            }
+            Some(DataType::Special) if special == special_type::SIZED => {
+                let (length, size) = encoded_data::decode_varint(&data[1..])?;
+                let length = length as usize;
+                if (data.len() - 1 - size) < length {
+                    return Err(DecodeError::MissingBytes(length - (data.len() - 1 - size)));
+                }
+                self.pos += 1 + size;
+                self.stack.push(Frame::Sized {
+                    end: self.pos + length,
+                    length,
+                    read: false,
+                });
+                return Ok(None);
+            }
+            Some(DataType::Special) if special == special_type::NULL_RUNS => {
+                let (values, size) = encoded_data::decode_varint(&data[1..])?;
+                let len = null_runs_len(data, values, 1 + size)?;
+                self.pos += 1 + size;
+                self.stack.push(Frame::NullRuns {
+                    start: self.pos - 1 - size,
+                    len: 0,
+                    nulls: 0,
+                    values,
+                    run: true,
+                });
+                Event::StartArray(len)
+            }
+            // Everything else holds neither borrowable data nor nested values.
+            _ => {
+                let (o, size) = EncodedData::decode(data)?;
+                self.pos += size;
+                match o {
+                    EncodedData::Special(EncodedSpecial::None) => Event::None,
+                    EncodedData::Special(EncodedSpecial::Null) => Event::Null,
+                    EncodedData::Special(EncodedSpecial::Forget(id)) => Event::Forget(id),
+                    EncodedData::Special(EncodedSpecial::Reset) => Event::Reset,
+                    EncodedData::Integer(int) => Event::Integer(int),
+                    EncodedData::Float(f) => Event::Float(f),
+                    EncodedData::Alias(id) => Event::Alias(id),
+                    // Packed arrays, only made of integers.
+                    EncodedData::Array(list) => {
+                        let len = list.len();
+                        for o in list {
+                            match o {
+                                EncodedData::Integer(int) => {
+                                    self.queue.push_back(Event::Integer(int))
+                                }
+                                _ => unreachable!(),
+                            }
+                        }
+                        self.queue.push_back(Event::EndArray);
+                        Event::StartArray(len)
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        };
+        Ok(Some(event))
+    }
+}
+
+// Counts the elements of the NullRuns array at `data[0]`, its `values` values starting after its
+// `tot_size` bytes header.
+fn null_runs_len(data: &[u8], values: u64, mut tot_size: usize) -> Result<usize, DecodeError> {
+    let mut len = 0;
+    for i in 0..=values {
+        let nb_nulls = encoded_data::decode_null_run(data, &mut tot_size, len)?;
+        len += nb_nulls as usize;
+        if i == values {
+            break;
+        }
+        let size = EncodedData::skip(&data[tot_size..]).map_err(|e| e.shifted(tot_size))?;
+        tot_size = tot_size.saturating_add(size);
+        if tot_size > data.len() {
+            return Err(DecodeError::MissingBytes(tot_size - data.len()));
+        }
+        len += 1;
+    }
+    Ok(len)
+}
+
+impl<'a> Iterator for EventReader<'a> {
+    type Item = Result<Event<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.queue.pop_front() {
+            return Some(Ok(event));
+        }
+        if self.failed {
+            return None;
+        }
+        match self.step() {
+            Ok(event) => event.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoded_data::EncodeOptions;
+
+    fn events(data: &[u8]) -> Vec<Result<Event<'_>, DecodeError>> {
+        EventReader::new(data).collect()
+    }
+
+    #[test]
+    fn nested() {
+        let value = EncodedData::from(serde_json::json!({
+            "name": "sensor",
+            "values": [1, -2, 2.5, null, true, {"unit": "C"}],
+            "empty": [],
+        }));
+        let data = value.encode();
+        let mut expected = vec![Ok(Event::StartObject(3))];
+        // Keys are encoded in sorted order.
+        expected.extend(vec![
+            Ok(Event::Key("empty")),
+            Ok(Event::StartArray(0)),
+            Ok(Event::EndArray),
+            Ok(Event::Key("name")),
+            Ok(Event::Str("sensor")),
+            Ok(Event::Key("values")),
+            Ok(Event::StartArray(6)),
+            Ok(Event::Integer(EncodedInteger::Positive(1))),
+            Ok(Event::Integer(EncodedInteger::Negative(2))),
+            Ok(Event::Float(2.5)),
+            Ok(Event::Null),
+            Ok(Event::Integer(EncodedInteger::Bool(true))),
+            Ok(Event::StartObject(1)),
+            Ok(Event::Key("unit")),
+            Ok(Event::Str("C")),
+            Ok(Event::EndObject),
+            Ok(Event::EndArray),
+            Ok(Event::EndObject),
+        ]);
+        assert_eq!(events(&data), expected);
+
+        let range = data.as_ptr_range();
+        for event in EventReader::new(&data) {
+            if let Ok(Event::Key(s)) | Ok(Event::Str(s)) = event {
+                assert!(range.contains(&s.as_ptr()));
+            }
+        }
+    }
+
+    #[test]
+    fn specials() {
+        let value = EncodedData::Array(vec![
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::String(
+                "abc".to_string(),
+            )))),
+            EncodedData::Alias(0),
+            EncodedData::Special(EncodedSpecial::Forget(0)),
+            EncodedData::from(serde_json::json!([1, 2, 3, 4, 5, 6, 7, 8])),
+            EncodedData::from(serde_json::json!([null, null, null, null, null, "x", null])),
+        ]);
+        let options = EncodeOptions {
+            packed_arrays: true,
+            null_runs: true,
+            sized_containers: true,
+            ..Default::default()
+        };
+        let data = value.encode_with_options(&options);
+        let mut expected = vec![
+            Ok(Event::StartArray(5)),
+            Ok(Event::Define),
+            Ok(Event::Str("abc")),
+            Ok(Event::Alias(0)),
+            Ok(Event::Forget(0)),
+            Ok(Event::StartArray(8)),
+        ];
+        expected.extend((1..=8).map(|i| Ok(Event::Integer(EncodedInteger::Positive(i)))));
+        expected.push(Ok(Event::EndArray));
+        expected.push(Ok(Event::StartArray(7)));
+        expected.extend((0..5).map(|_| Ok(Event::Null)));
+        expected.push(Ok(Event::Str("x")));
+        expected.push(Ok(Event::Null));
+        expected.push(Ok(Event::EndArray));
+        expected.push(Ok(Event::EndArray));
+        assert_eq!(events(&data), expected);
+    }
+
+    #[test]
+    fn successive_values() {
+        let data = [
+            EncodedData::from(serde_json::json!([1])).encode(),
+            EncodedData::from(serde_json::json!("a")).encode(),
+        ]
+        .concat();
+        assert_eq!(
+            events(&data),
+            vec![
+                Ok(Event::StartArray(1)),
+                Ok(Event::Integer(EncodedInteger::Positive(1))),
+                Ok(Event::EndArray),
+                Ok(Event::Str("a")),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let data = EncodedData::from(serde_json::json!({"a": [1, "bc"]})).encode();
+        let truncated = events(&data[..data.len() - 1]);
+        assert_eq!(
+            truncated,
+            vec![
+                Ok(Event::StartObject(1)),
+                Ok(Event::Key("a")),
+                Ok(Event::StartArray(2)),
+                Ok(Event::Integer(EncodedInteger::Positive(1))),
+                Err(DecodeError::MissingBytes(1)),
+            ]
+        );
+
+        // Nothing is read after the error.
+        let mut reader = EventReader::new(&[0xFF, 0x00]);
+        assert!(matches!(reader.next(), Some(Err(_))));
+        assert_eq!(reader.next(), None);
+    }
+}
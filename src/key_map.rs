@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+// Bijective renaming of object keys, e.g. to shorten the keys of a known schema.
+// Every inserted pair is swapped both ways, so the same map undoes its own renaming and keys
+// that are not mapped are left untouched.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct KeyMap {
+    keys: HashMap<String, String>,
+}
+
+impl KeyMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false, leaving the map untouched, if either key is already mapped.
+    pub fn insert(&mut self, key: &str, short: &str) -> bool {
+        if key == short || self.keys.contains_key(key) || self.keys.contains_key(short) {
+            return false;
+        }
+        self.keys.insert(key.to_string(), short.to_string());
+        self.keys.insert(short.to_string(), key.to_string());
+        true
+    }
+
+    pub fn apply<'a>(&'a self, key: &'a str) -> &'a str {
+        self.keys.get(key).map(|k| k.as_str()).unwrap_or(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoded_data::EncodedData;
+    use serde_json::json;
+
+    #[test]
+    fn round_trip() {
+        let mut key_map = KeyMap::new();
+        assert!(key_map.insert("temperature", "t"));
+        assert!(!key_map.insert("time", "t"));
+        assert!(!key_map.insert("temperature", "x"));
+
+        let value = json!({"temperature": 1});
+        let data = EncodedData::from_json_with_key_map(value.clone(), &key_map).encode();
+        assert_eq!(data.len(), 1 + 1 + 1 + 2);
+        assert_eq!(
+            data.len() + "temperature".len() - 1,
+            EncodedData::from(value.clone()).encode().len()
+        );
+        let (decoded, _) = EncodedData::decode(&data).unwrap();
+        assert_eq!(decoded.into_json_with_key_map(&key_map).unwrap(), value);
+
+        // Unmapped keys that collide with a short key are swapped too, so nothing is lost.
+        let value = json!({"t": {"temperature": 2}, "other": 3});
+        let data = EncodedData::from_json_with_key_map(value.clone(), &key_map).encode();
+        let (decoded, _) = EncodedData::decode(&data).unwrap();
+        assert_eq!(decoded.into_json_with_key_map(&key_map).unwrap(), value);
+    }
+}
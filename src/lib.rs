@@ -1,4 +1,16 @@
+pub mod cas;
+pub mod cli;
+pub mod codec;
 pub mod define;
 pub mod encoded_data;
+pub mod encoded_data_ref;
+pub mod event_reader;
+pub mod key_map;
+pub mod log;
+pub mod schema;
+pub mod schema_registry;
+pub mod serde_format;
 pub mod stream_compressor;
+pub mod stream_encoder;
+pub mod string_codec;
 pub mod varint;
@@ -0,0 +1,171 @@
+use crate::encoded_data::{self, EncodedData};
+use crate::stream_compressor::{Conf, StreamCompressor, StreamDecoder};
+use crate::varint;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+// Append-only log: a header made of MAGIC, VERSION and a dictionary of strings aliased from the
+// start, then records framed as [varint length][compressed value]. All records share the same
+// StreamCompressor, so a record may alias strings defined by the previous ones.
+pub const MAGIC: &[u8; 4] = b"CJCL";
+pub const VERSION: u8 = 1;
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+pub struct LogWriter<W: Write> {
+    w: W,
+    compressor: StreamCompressor,
+}
+
+impl<W: Write> LogWriter<W> {
+    pub fn new(mut w: W, dictionary: &[String]) -> io::Result<Self> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        w.write_all(&varint::encode(dictionary.len() as u64))?;
+        for s in dictionary.iter() {
+            w.write_all(&encoded_data::encode_key(s))?;
+        }
+        Ok(Self {
+            w,
+            compressor: StreamCompressor::with_dictionary(Conf::default(), dictionary),
+        })
+    }
+
+    pub fn write(&mut self, value: &serde_json::Value) -> io::Result<()> {
+        let data = self.compressor.compress(&EncodedData::from(value.clone()));
+        self.w.write_all(&varint::encode(data.len() as u64))?;
+        self.w.write_all(&data)
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+pub struct LogReader<R: Read> {
+    r: R,
+    dictionary: Vec<String>,
+    decoder: StreamDecoder,
+}
+
+fn read_bytes<R: Read>(r: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    r.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(data)
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(mut r: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        r.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_data("not a log"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data(format!("unsupported version {}", header[4])));
+        }
+        let nb = varint::read_from(&mut r)?;
+        let mut dictionary = vec![];
+        for _ in 0..nb {
+            let length = varint::read_from(&mut r)?;
+            let s = String::from_utf8(read_bytes(&mut r, length)?).map_err(invalid_data)?;
+            dictionary.push(s);
+        }
+        Ok(Self {
+            r,
+            decoder: StreamDecoder::with_dictionary(&dictionary),
+            dictionary,
+        })
+    }
+
+    pub fn dictionary(&self) -> &[String] {
+        &self.dictionary
+    }
+
+    // Returns None at the end of the log.
+    pub fn read(&mut self) -> io::Result<Option<serde_json::Value>> {
+        let mut first = [0u8; 1];
+        if self.r.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let length = varint::read_from(&mut (&first[..]).chain(&mut self.r))?;
+        let data = read_bytes(&mut self.r, length)?;
+        let (o, size) = self
+            .decoder
+            .decompress(&data)
+            .map_err(|e| invalid_data(format!("{:?}", e)))?;
+        if size != data.len() {
+            return Err(invalid_data("trailing bytes in record"));
+        }
+        o.try_into()
+            .map(Some)
+            .map_err(|e| invalid_data(format!("{:?}", e)))
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = io::Result<serde_json::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn round_trip() {
+        let dictionary = vec!["sensor".to_string(), "celsius".to_string()];
+        let records = vec![
+            json!({"name": "sensor", "unit": "celsius", "value": 1}),
+            json!({"name": "sensor", "unit": "kelvin", "value": 2}),
+            json!({"name": "sensor", "unit": "kelvin", "value": 3}),
+            json!({"name": "sensor", "unit": "kelvin", "value": 4}),
+        ];
+        let mut writer = LogWriter::new(vec![], &dictionary).unwrap();
+        let mut record_sizes = vec![];
+        for record in records.iter() {
+            let before = writer.w.len();
+            writer.write(record).unwrap();
+            record_sizes.push(writer.w.len() - before);
+        }
+        let log = writer.into_inner().unwrap();
+
+        // Dictionary strings are aliased from the first record on, "kelvin" is defined by the
+        // third one.
+        let plain: Vec<_> = records
+            .iter()
+            .map(|r| EncodedData::from(r.clone()).encode().len() + 1)
+            .collect();
+        assert_eq!(record_sizes[0], plain[0] - 6 - 7);
+        assert_eq!(record_sizes[1], plain[1] - 6);
+        assert_eq!(record_sizes[2], plain[2] - 6 + 1);
+        assert_eq!(record_sizes[3], plain[3] - 6 - 6);
+
+        let mut reader = LogReader::new(&log[..]).unwrap();
+        assert_eq!(reader.dictionary(), &dictionary[..]);
+        let decoded: Vec<_> = reader.by_ref().collect::<io::Result<_>>().unwrap();
+        assert_eq!(decoded, records);
+        assert!(reader.read().unwrap().is_none());
+
+        let mut bad = log.clone();
+        bad[0] = b'X';
+        assert!(LogReader::new(&bad[..]).is_err());
+        bad = log.clone();
+        bad[4] = VERSION + 1;
+        assert!(LogReader::new(&bad[..]).is_err());
+        let truncated = LogReader::new(&log[..log.len() - 1])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>();
+        assert_eq!(truncated.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
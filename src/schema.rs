@@ -0,0 +1,581 @@
+use crate::define::data_type;
+use crate::encoded_data::{
+    self, Budgeted, DecodeError, DecodeOptions, EncodedData, EncodedInteger, EncodedSpecial,
+    HeapArena,
+};
+use crate::stream_compressor::Dictionary;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Expected shape of a decoded value. Object fields must all be present, other keys are skipped
+// (see SchemaOptions).
+#[derive(Debug, PartialEq, Clone)]
+pub enum Schema<'a> {
+    Any,
+    Null,
+    Bool,
+    Integer,
+    Float,
+    // Integer or float.
+    Number,
+    // Number with this many decimals, stored as an integer scaled by 10^scale once passed through
+    // EncodedData::to_fixed_point. decode_with_schema turns it back into a float.
+    Fixed(u8),
+    String,
+    // String taking one of the listed values.
+    Enum(&'a [&'a str]),
+    Array(Box<Schema<'a>>),
+    Object(&'a [(&'a str, Schema<'a>)]),
+}
+
+impl Schema<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Any => "any",
+            Self::Null => "null",
+            Self::Bool => "bool",
+            Self::Integer => "integer",
+            Self::Float => "float",
+            Self::Number => "number",
+            Self::Fixed(_) => "fixed",
+            Self::String => "string",
+            Self::Enum(_) => "enum",
+            Self::Array(_) => "array",
+            Self::Object(_) => "object",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SchemaOptions {
+    // Fail with UnknownField on object keys the schema does not list, instead of leaving them out
+    // of the decoded value.
+    pub deny_unknown_fields: bool,
+}
+
+pub(crate) fn kind(o: &EncodedData) -> &'static str {
+    match o {
+        EncodedData::Special(EncodedSpecial::None) => "none",
+        EncodedData::Special(EncodedSpecial::Null) => "null",
+        EncodedData::Special(EncodedSpecial::Define(_)) => "define",
+        EncodedData::Special(EncodedSpecial::Forget(_)) => "forget",
+        EncodedData::Special(EncodedSpecial::Reset) => "reset",
+        EncodedData::Integer(EncodedInteger::Bool(_)) => "bool",
+        EncodedData::Integer(_) => "integer",
+        EncodedData::Float(_) => "float",
+        EncodedData::Decimal(_) => "decimal",
+        EncodedData::String(_) => "string",
+        EncodedData::Array(_) => "array",
+        EncodedData::Object(_) | EncodedData::OrderedObject(_) => "object",
+        EncodedData::Alias(_) => "alias",
+    }
+}
+
+fn mismatch(path: &str, schema: &Schema, found: &'static str) -> DecodeError {
+    DecodeError::SchemaMismatch {
+        path: path.to_string(),
+        expected: schema.name(),
+        found,
+    }
+}
+
+fn get_field<'a>(o: &'a EncodedData, k: &str) -> Option<&'a EncodedData> {
+    match o {
+        EncodedData::Object(map) => map.get(k),
+        EncodedData::OrderedObject(entries) => {
+            entries.iter().find(|(name, _)| name == k).map(|(_, o)| o)
+        }
+        _ => None,
+    }
+}
+
+// Checks an already decoded value, for the encodings decode_schema does not walk itself (sized
+// or packed containers).
+fn check(
+    o: &EncodedData,
+    schema: &Schema,
+    path: &mut String,
+    options: &SchemaOptions,
+) -> Result<(), DecodeError> {
+    let ok = match (schema, o) {
+        (Schema::Any, _)
+        | (Schema::Null, EncodedData::Special(EncodedSpecial::Null))
+        | (Schema::Bool, EncodedData::Integer(EncodedInteger::Bool(_)))
+        | (Schema::String, EncodedData::String(_)) => true,
+        (Schema::Enum(values), EncodedData::String(s)) => values.contains(&s.as_str()),
+        (Schema::Integer, EncodedData::Integer(int))
+        | (Schema::Number, EncodedData::Integer(int)) => !matches!(int, EncodedInteger::Bool(_)),
+        (Schema::Fixed(_), EncodedData::Integer(int)) => !matches!(int, EncodedInteger::Bool(_)),
+        (Schema::Float, EncodedData::Float(_))
+        | (Schema::Float, EncodedData::Decimal(_))
+        | (Schema::Number, EncodedData::Float(_))
+        | (Schema::Number, EncodedData::Decimal(_))
+        | (Schema::Fixed(_), EncodedData::Float(_)) => true,
+        (Schema::Array(item), EncodedData::Array(list)) => {
+            for (i, o) in list.iter().enumerate() {
+                let len = path.len();
+                write!(path, "[{}]", i).unwrap();
+                check(o, item, path, options)?;
+                path.truncate(len);
+            }
+            true
+        }
+        (Schema::Object(fields), EncodedData::Object(_))
+        | (Schema::Object(fields), EncodedData::OrderedObject(_)) => {
+            for (k, field) in fields.iter() {
+                let len = path.len();
+                write!(path, ".{}", k).unwrap();
+                match get_field(o, k) {
+                    Some(o) => check(o, field, path, options)?,
+                    None => return Err(mismatch(path, field, "missing")),
+                }
+                path.truncate(len);
+            }
+            if options.deny_unknown_fields {
+                let keys: Vec<&String> = match o {
+                    EncodedData::Object(map) => map.keys().collect(),
+                    EncodedData::OrderedObject(entries) => entries.iter().map(|(k, _)| k).collect(),
+                    _ => vec![],
+                };
+                if let Some(k) = keys
+                    .iter()
+                    .find(|k| !fields.iter().any(|(name, _)| name == *k))
+                {
+                    return Err(DecodeError::UnknownField(format!("{}.{}", path, k)));
+                }
+            }
+            true
+        }
+        _ => false,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(mismatch(path, schema, kind(o)))
+    }
+}
+
+// Leaves out the object keys `schema` does not list, for the values check walks instead of
+// decode_schema.
+fn strip_unknown(o: &mut EncodedData, schema: &Schema) {
+    let field =
+        |fields: &[(&str, Schema<'_>)], k: &str| fields.iter().position(|(name, _)| *name == k);
+    match (schema, o) {
+        (Schema::Array(item), EncodedData::Array(list)) => {
+            list.iter_mut().for_each(|o| strip_unknown(o, item))
+        }
+        (Schema::Object(fields), EncodedData::Object(map)) => {
+            map.retain(|k, _| field(fields, k).is_some());
+            for (k, o) in map.iter_mut() {
+                strip_unknown(o, &fields[field(fields, k).unwrap()].1);
+            }
+        }
+        (Schema::Object(fields), EncodedData::OrderedObject(entries)) => {
+            entries.retain(|(k, _)| field(fields, k).is_some());
+            for (k, o) in entries.iter_mut() {
+                strip_unknown(o, &fields[field(fields, k).unwrap()].1);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn scale(scale: u8) -> f64 {
+    10f64.powi(scale as i32)
+}
+
+// Entry of an object given to to_fixed_point, converted by the schema of its field if any.
+fn fixed_entry(fields: &[(&str, Schema)], (k, o): (String, EncodedData)) -> (String, EncodedData) {
+    let o = match fields.iter().find(|(name, _)| *name == k) {
+        Some((_, field)) => o.to_fixed_point(field),
+        None => o,
+    };
+    (k, o)
+}
+
+impl EncodedData {
+    // Rewrites the numbers of the Fixed fields of `schema` into integers scaled by 10^scale, which
+    // are shorter than floats. The ones that would not decode back to the same float (more
+    // decimals than the scale, too large) are kept as floats.
+    pub fn to_fixed_point(self, schema: &Schema) -> Self {
+        match (schema, self) {
+            (Schema::Fixed(n), o) => {
+                let f = match &o {
+                    Self::Float(f) => *f,
+                    Self::Integer(EncodedInteger::Positive(i)) => *i as f64,
+                    Self::Integer(EncodedInteger::Negative(i)) => -(*i as f64),
+                    _ => return o,
+                };
+                let scaled = (f * scale(*n)).round();
+                // Past 2^53 integers are no longer exact in the decoded f64.
+                if scaled.abs() < (1u64 << 53) as f64 && scaled / scale(*n) == f {
+                    Self::Integer(if scaled < 0.0 {
+                        EncodedInteger::Negative(-scaled as u64)
+                    } else {
+                        EncodedInteger::Positive(scaled as u64)
+                    })
+                } else {
+                    Self::Float(f)
+                }
+            }
+            (Schema::Array(item), Self::Array(list)) => {
+                Self::Array(list.into_iter().map(|o| o.to_fixed_point(item)).collect())
+            }
+            (Schema::Object(fields), Self::Object(map)) => {
+                Self::Object(map.into_iter().map(|e| fixed_entry(fields, e)).collect())
+            }
+            (Schema::Object(fields), Self::OrderedObject(entries)) => Self::OrderedObject(
+                entries
+                    .into_iter()
+                    .map(|e| fixed_entry(fields, e))
+                    .collect(),
+            ),
+            (_, o) => o,
+        }
+    }
+
+    // Same as decode, failing with SchemaMismatch as soon as a value does not match `schema`.
+    // Paths look like `$.field[2]`.
+    pub fn decode_with_schema(data: &[u8], schema: &Schema) -> Result<(Self, usize), DecodeError> {
+        Self::decode_with_schema_options(data, schema, &SchemaOptions::default())
+    }
+
+    pub fn decode_with_schema_options(
+        data: &[u8],
+        schema: &Schema,
+        options: &SchemaOptions,
+    ) -> Result<(Self, usize), DecodeError> {
+        Self::decode_schema(data, schema, &mut "$".to_string(), options)
+    }
+
+    fn decode_schema(
+        data: &[u8],
+        schema: &Schema,
+        path: &mut String,
+        options: &SchemaOptions,
+    ) -> Result<(Self, usize), DecodeError> {
+        let ctrl = match data.first() {
+            Some(ctrl) => *ctrl,
+            None => return Err(DecodeError::MissingBytes(1)),
+        };
+        match (schema, ctrl >> 5) {
+            (Schema::Array(item), data_type::ARRAY) => {
+                let (length, mut tot_size) = encoded_data::decode_data_type_length(data)?;
+                let mut list = vec![];
+                for i in 0..length {
+                    let len = path.len();
+                    write!(path, "[{}]", i).unwrap();
+                    let (o, size) = Self::decode_schema(&data[tot_size..], item, path, options)
+                        .map_err(|e| e.shifted(tot_size))?;
+                    path.truncate(len);
+                    list.push(o);
+                    tot_size += size;
+                }
+                Ok((Self::Array(list), tot_size))
+            }
+            (Schema::Object(fields), data_type::OBJECT) => {
+                let (length, mut tot_size) = encoded_data::decode_data_type_length(data)?;
+                let mut map = HashMap::new();
+                for _ in 0..length {
+                    let (k_length, size) = encoded_data::decode_varint(&data[tot_size..])?;
+                    let k_length = k_length as usize;
+                    tot_size += size;
+                    if data.len() - tot_size < k_length {
+                        return Err(DecodeError::MissingBytes(
+                            k_length - (data.len() - tot_size),
+                        ));
+                    }
+                    let k_data = &data[tot_size..tot_size + k_length];
+                    let field = match fields.iter().find(|(name, _)| name.as_bytes() == k_data) {
+                        Some((_, field)) => field,
+                        None if options.deny_unknown_fields => {
+                            return Err(DecodeError::UnknownField(format!(
+                                "{}.{}",
+                                path,
+                                String::from_utf8_lossy(k_data)
+                            )))
+                        }
+                        // Jumped over without building the key or the value.
+                        None => {
+                            tot_size += k_length;
+                            tot_size +=
+                                Self::skip(&data[tot_size..]).map_err(|e| e.shifted(tot_size))?;
+                            continue;
+                        }
+                    };
+                    let k = encoded_data::decode_str(
+                        k_data,
+                        &DecodeOptions::default(),
+                        &mut Budgeted::new(&mut HeapArena, &DecodeOptions::default()),
+                    )
+                    .map_err(|e| e.shifted(tot_size))?;
+                    tot_size += k_length;
+                    let len = path.len();
+                    write!(path, ".{}", k).unwrap();
+                    let (o, size) = Self::decode_schema(&data[tot_size..], field, path, options)
+                        .map_err(|e| e.shifted(tot_size))?;
+                    path.truncate(len);
+                    map.insert(k, o);
+                    tot_size += size;
+                }
+                for (k, field) in fields.iter() {
+                    if !map.contains_key(*k) {
+                        return Err(mismatch(&format!("{}.{}", path, k), field, "missing"));
+                    }
+                }
+                Ok((Self::Object(map), tot_size))
+            }
+            _ => {
+                let (mut o, size) = Self::decode(data)?;
+                check(&o, schema, path, options)?;
+                strip_unknown(&mut o, schema);
+                let o = match (schema, o) {
+                    (Schema::Fixed(n), Self::Integer(EncodedInteger::Positive(i))) => {
+                        Self::Float(i as f64 / scale(*n))
+                    }
+                    (Schema::Fixed(n), Self::Integer(EncodedInteger::Negative(i))) => {
+                        Self::Float(-(i as f64) / scale(*n))
+                    }
+                    (_, o) => o,
+                };
+                Ok((o, size))
+            }
+        }
+    }
+}
+
+fn collect_enums<'a>(schema: &Schema<'a>, out: &mut Vec<&'a str>) {
+    match schema {
+        Schema::Enum(values) => {
+            for s in values.iter() {
+                if !out.contains(s) {
+                    out.push(s);
+                }
+            }
+        }
+        Schema::Array(item) => collect_enums(item, out),
+        Schema::Object(fields) => {
+            for (_, field) in fields.iter() {
+                collect_enums(field, out);
+            }
+        }
+        _ => (),
+    }
+}
+
+impl Dictionary {
+    // Dictionary of the Enum values of `schema`, so that even their first occurrence is an alias.
+    pub fn from_schema(schema: &Schema) -> Self {
+        let mut values = vec![];
+        collect_enums(schema, &mut values);
+        Self {
+            strings: values.into_iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn schema() {
+        let point = [("x", Schema::Integer), ("y", Schema::Number)];
+        let fields = [
+            ("name", Schema::String),
+            ("points", Schema::Array(Box::new(Schema::Object(&point)))),
+            ("enabled", Schema::Bool),
+        ];
+        let schema = Schema::Object(&fields);
+
+        let value = json!({
+            "name": "path",
+            "points": [{"x": 1, "y": 2.5}, {"x": -3, "y": 4}],
+            "enabled": true,
+            "extra": null,
+        });
+        let data = EncodedData::from(value.clone()).encode();
+        let mut known = value.clone();
+        known.as_object_mut().unwrap().remove("extra");
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Ok((EncodedData::from(known), data.len()))
+        );
+
+        let mut bad = value.clone();
+        bad["points"][1]["x"] = json!("3");
+        let data = EncodedData::from(bad).encode();
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Err(DecodeError::SchemaMismatch {
+                path: "$.points[1].x".to_string(),
+                expected: "integer",
+                found: "string",
+            })
+        );
+
+        let mut missing = value.clone();
+        missing.as_object_mut().unwrap().remove("enabled");
+        let data = EncodedData::from(missing).encode();
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Err(DecodeError::SchemaMismatch {
+                path: "$.enabled".to_string(),
+                expected: "bool",
+                found: "missing",
+            })
+        );
+
+        // Packed arrays are checked once decoded.
+        let options = encoded_data::EncodeOptions {
+            packed_arrays: true,
+            ..Default::default()
+        };
+        let data = EncodedData::from(json!([true, false, true])).encode_with_options(&options);
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &Schema::Array(Box::new(Schema::Integer))),
+            Err(DecodeError::SchemaMismatch {
+                path: "$[0]".to_string(),
+                expected: "integer",
+                found: "bool",
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let fields = [
+            ("id", Schema::Integer),
+            ("tags", Schema::Array(Box::new(Schema::String))),
+        ];
+        let schema = Schema::Object(&fields);
+        let value = json!({
+            "id": 7,
+            "tags": ["a"],
+            "payload": {"nested": [1, 2, {"deep": "x"}], "text": "ignored"},
+            "note": null,
+        });
+        let expected = EncodedData::from(json!({"id": 7, "tags": ["a"]}));
+        let deny = SchemaOptions {
+            deny_unknown_fields: true,
+        };
+
+        let data = EncodedData::from(value.clone()).encode();
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Ok((expected.clone(), data.len()))
+        );
+        assert_eq!(
+            EncodedData::decode_with_schema_options(&data, &schema, &deny),
+            Err(DecodeError::UnknownField("$.note".to_string()))
+        );
+
+        // Sized objects are decoded before being checked, with the same result.
+        let sized = EncodedData::from(value).encode_with_options(&encoded_data::EncodeOptions {
+            sized_containers: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            EncodedData::decode_with_schema(&sized, &schema),
+            Ok((expected.clone(), sized.len()))
+        );
+        assert!(matches!(
+            EncodedData::decode_with_schema_options(&sized, &schema, &deny),
+            Err(DecodeError::UnknownField(_))
+        ));
+
+        let data = expected.encode();
+        assert_eq!(
+            EncodedData::decode_with_schema_options(&data, &schema, &deny),
+            Ok((expected, data.len()))
+        );
+    }
+
+    #[test]
+    fn enum_dictionary() {
+        let status = ["active", "inactive"];
+        let fields = [
+            ("status", Schema::Enum(&status)),
+            (
+                "tags",
+                Schema::Array(Box::new(Schema::Enum(&["new", "active"]))),
+            ),
+        ];
+        let schema = Schema::Object(&fields);
+        let dictionary = Dictionary::from_schema(&schema);
+        assert_eq!(dictionary.strings, vec!["active", "inactive", "new"]);
+
+        let value = EncodedData::from(json!({"status": "inactive"}));
+        let data = dictionary
+            .compressor(crate::stream_compressor::Conf::default())
+            .compress(&value);
+        // Object header, key "status", then the alias of "inactive".
+        assert_eq!(data.len(), 1 + 7 + 1);
+        assert_eq!(data[8], EncodedData::Alias(1).encode()[0]);
+        assert_eq!(
+            dictionary.decoder().decompress(&data),
+            Ok((value, data.len()))
+        );
+
+        let data = EncodedData::from(json!({"status": "deleted", "tags": []})).encode();
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Err(DecodeError::SchemaMismatch {
+                path: "$.status".to_string(),
+                expected: "enum",
+                found: "string",
+            })
+        );
+    }
+
+    #[test]
+    fn fixed_point() {
+        let price = Schema::Fixed(2);
+        let data = EncodedData::Float(19.99).to_fixed_point(&price).encode();
+        assert!(data.len() <= 4);
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &price),
+            Ok((EncodedData::Float(19.99), data.len()))
+        );
+        // Not rounded to the scale.
+        assert_eq!(
+            EncodedData::Float(0.125).to_fixed_point(&price),
+            EncodedData::Float(0.125)
+        );
+
+        let fields = [
+            ("name", Schema::String),
+            ("prices", Schema::Array(Box::new(price))),
+        ];
+        let schema = Schema::Object(&fields);
+        let value = EncodedData::from(json!({
+            "name": "x",
+            "prices": [0.1, -3.07, 12, 1e300, 0.125, 2.0 / 3.0],
+        }));
+        let data = value.clone().to_fixed_point(&schema).encode();
+        assert!(data.len() < value.encode().len());
+        let expected = EncodedData::from(json!({
+            "name": "x",
+            "prices": [0.1, -3.07, 12.0, 1e300, 0.125, 2.0 / 3.0],
+        }));
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Ok((expected.clone(), data.len()))
+        );
+
+        // Objects decoded with ordered_objects too.
+        let options = DecodeOptions {
+            ordered_objects: true,
+            ..Default::default()
+        };
+        let (ordered, _) = EncodedData::decode_with_options(&value.encode(), &options).unwrap();
+        assert!(matches!(ordered, EncodedData::OrderedObject(_)));
+        let data = ordered.to_fixed_point(&schema).encode();
+        assert!(data.len() < value.encode().len());
+        assert_eq!(
+            EncodedData::decode_with_schema(&data, &schema),
+            Ok((expected, data.len()))
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::define::{data_type, special_type};
+use crate::encoded_data::{self, DecodeError, EncodedData, Extension};
+use crate::varint;
+use std::collections::HashMap;
+
+// Object shapes (sets of keys) shared by both ends, e.g. the record types of a log. An object of
+// a registered shape is encoded as its schema_id then its values in key order, without its keys.
+// Registering shapes in the same order on both ends gives the same ids, or the registry itself
+// can be sent ahead of the data with encode_header.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SchemaRegistry {
+    shapes: Vec<Vec<String>>,
+    ids: HashMap<Vec<String>, u64>,
+}
+
+fn object_entries(o: &EncodedData) -> Option<Vec<(&String, &EncodedData)>> {
+    match o {
+        EncodedData::Object(map) => Some(encoded_data::sorted_entries(map)),
+        EncodedData::OrderedObject(entries) => {
+            let mut entries: Vec<_> = entries.iter().map(|(k, o)| (k, o)).collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            Some(entries)
+        }
+        _ => None,
+    }
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the schema_id of the shape made of `keys`, registering it if it is new. Duplicate
+    // keys are ignored.
+    pub fn register(&mut self, keys: &[&str]) -> u64 {
+        let mut shape: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        shape.sort();
+        shape.dedup();
+        if let Some(id) = self.ids.get(&shape) {
+            return *id;
+        }
+        let id = self.shapes.len() as u64;
+        self.shapes.push(shape.clone());
+        self.ids.insert(shape, id);
+        id
+    }
+
+    // Registers the shape of every object of `o`, nested ones included.
+    pub fn learn(&mut self, o: &EncodedData) {
+        match o {
+            EncodedData::Array(list) => list.iter().for_each(|o| self.learn(o)),
+            o => {
+                if let Some(entries) = object_entries(o) {
+                    let keys: Vec<&str> = entries.iter().map(|(k, _)| k.as_str()).collect();
+                    self.register(&keys);
+                    entries.iter().for_each(|(_, o)| self.learn(o));
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    fn id_of(&self, entries: &[(&String, &EncodedData)]) -> Option<u64> {
+        let shape: Vec<String> = entries.iter().map(|(k, _)| k.to_string()).collect();
+        self.ids.get(&shape).copied()
+    }
+
+    // Same as EncodedData::encode, with the objects of a registered shape stored as a Shaped
+    // special. The result can only be decoded by SchemaRegistry::decode with the same shapes.
+    pub fn encode(&self, o: &EncodedData) -> Vec<u8> {
+        let mut out = vec![];
+        encoded_data::encode_extended(self, o, &mut out);
+        out
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
+        encoded_data::decode_extended(self, data, encoded_data::MAX_DEPTH)
+    }
+
+    // The registered shapes as an encoded array of key arrays, for decoders that do not share
+    // the registry.
+    pub fn encode_header(&self) -> Vec<u8> {
+        EncodedData::Array(
+            self.shapes
+                .iter()
+                .map(|shape| {
+                    EncodedData::Array(
+                        shape
+                            .iter()
+                            .map(|k| EncodedData::String(k.clone()))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        )
+        .encode()
+    }
+
+    pub fn decode_header(data: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let (o, size) = EncodedData::decode(data)?;
+        let mut registry = Self::new();
+        let bad = || DecodeError::SchemaMismatch {
+            path: String::new(),
+            expected: "array of string arrays",
+            found: crate::schema::kind(&o),
+        };
+        for shape in o.as_array().ok_or_else(bad)? {
+            let keys = shape
+                .as_array()
+                .and_then(|keys| keys.iter().map(|k| k.as_str()).collect::<Option<Vec<_>>>())
+                .ok_or_else(bad)?;
+            registry.register(&keys);
+        }
+        Ok((registry, size))
+    }
+}
+
+impl Extension for SchemaRegistry {
+    fn encode_special(&self, o: &EncodedData, out: &mut Vec<u8>) -> bool {
+        let entries = match object_entries(o) {
+            Some(entries) => entries,
+            None => return false,
+        };
+        let id = match self.id_of(&entries) {
+            Some(id) => id,
+            None => return false,
+        };
+        out.push(data_type::SPECIAL << 5 | special_type::SHAPED);
+        out.extend(varint::encode(id));
+        for (_, o) in entries {
+            encoded_data::encode_extended(self, o, out);
+        }
+        true
+    }
+
+    fn decode_special(
+        &self,
+        data: &[u8],
+        depth: usize,
+    ) -> Option<Result<(EncodedData, usize), DecodeError>> {
+        if data[0] != data_type::SPECIAL << 5 | special_type::SHAPED {
+            return None;
+        }
+        Some((|| {
+            let (id, size) = encoded_data::decode_varint(&data[1..])?;
+            let shape = self
+                .shapes
+                .get(id as usize)
+                .ok_or(DecodeError::UnknownSchema(id))?;
+            let mut tot_size = 1 + size;
+            let mut map = HashMap::new();
+            for k in shape.iter() {
+                let (o, size) = encoded_data::decode_extended(self, &data[tot_size..], depth)
+                    .map_err(|e| e.shifted(tot_size))?;
+                map.insert(k.clone(), o);
+                tot_size += size;
+            }
+            Ok((EncodedData::Object(map), tot_size))
+        })())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn schema_registry() {
+        let records = EncodedData::from(serde_json::Value::Array(
+            (0..1000)
+                .map(|i| match i % 3 {
+                    0 => json!({"temperature": i, "humidity": 40}),
+                    1 => json!({"wind_speed": 2.5, "wind_direction": "north"}),
+                    _ => json!({"battery": i, "position": {"latitude": 1.5, "longitude": 2.5}}),
+                })
+                .collect(),
+        ));
+        let mut registry = SchemaRegistry::new();
+        registry.learn(&records);
+        assert_eq!(registry.len(), 4);
+
+        let data = registry.encode(&records);
+        assert_eq!(registry.decode(&data), Ok((records.clone(), data.len())));
+        for k in [
+            "temperature",
+            "humidity",
+            "wind_speed",
+            "battery",
+            "latitude",
+        ]
+        .iter()
+        {
+            assert!(!data.windows(k.len()).any(|w| w == k.as_bytes()), "{}", k);
+        }
+        assert!(data.len() * 2 < records.encode().len());
+
+        // Decoding needs the registry.
+        assert_eq!(
+            EncodedData::decode(&data),
+            Err(DecodeError::UnknownSpecialType(special_type::SHAPED))
+        );
+        assert_eq!(
+            SchemaRegistry::new().decode(&data),
+            Err(DecodeError::UnknownSchema(0))
+        );
+        let header = registry.encode_header();
+        let (shared, size) = SchemaRegistry::decode_header(&header).unwrap();
+        assert_eq!(size, header.len());
+        assert_eq!(shared.decode(&data), Ok((records, data.len())));
+
+        // Unregistered shapes keep their keys.
+        let other = EncodedData::from(json!([{"a": 1, "temperature": 2}, "x", null]));
+        let data = registry.encode(&other);
+        assert_eq!(data, other.encode());
+        assert_eq!(registry.decode(&data), Ok((other, data.len())));
+        assert_eq!(
+            registry.decode(&data[..data.len() - 1]),
+            Err(DecodeError::MissingBytes(1))
+        );
+    }
+}
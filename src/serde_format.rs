@@ -0,0 +1,699 @@
+use crate::encoded_data::{DecodeError, EncodedData, EncodedInteger, EncodedSpecial};
+use serde::de::{self, DeserializeOwned, IntoDeserializer};
+use serde::ser::{self, Serialize};
+use std::collections::HashMap;
+
+// Serde data format over EncodedData, for Rust types that are not serde_json::Value. Structs and
+// maps are objects, sequences and tuples arrays, enums serde_json's externally tagged objects.
+// Option::None is the None special: a struct field holding it is left out of its object, and
+// deserializing treats None values as absent fields, so that #[serde(default)] applies. Units are
+// nulls, which deserialize into options as None, as in JSON.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SerdeError {
+    // Raised by a Serialize or Deserialize impl, e.g. on a type mismatch.
+    Message(String),
+    // Map key serializing to something else than a string or an integer.
+    KeyMustBeAString,
+    // Special (Define, Alias...) that only a StreamDecoder can resolve.
+    Unsupported(&'static str),
+    BadFormat(DecodeError),
+}
+
+impl std::fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Message(message) => f.write_str(message),
+            Self::KeyMustBeAString => f.write_str("map key must be a string"),
+            Self::Unsupported(kind) => write!(f, "unsupported {}", kind),
+            Self::BadFormat(e) => write!(f, "bad format: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Self::Message(message.to_string())
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Self::Message(message.to_string())
+    }
+}
+
+pub fn to_encoded<T: Serialize + ?Sized>(value: &T) -> Result<EncodedData, SerdeError> {
+    value.serialize(Serializer)
+}
+
+pub fn from_encoded<T: DeserializeOwned>(o: EncodedData) -> Result<T, SerdeError> {
+    T::deserialize(o)
+}
+
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerdeError> {
+    Ok(to_encoded(value)?.encode())
+}
+
+// Decodes the first value of `data` into a T and returns it with its encoded size.
+pub fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<(T, usize), SerdeError> {
+    let (o, size) = EncodedData::decode(data).map_err(SerdeError::BadFormat)?;
+    Ok((from_encoded(o)?, size))
+}
+
+fn integer(n: i64) -> EncodedData {
+    EncodedData::Integer(if n < 0 {
+        EncodedInteger::Negative(n.unsigned_abs())
+    } else {
+        EncodedInteger::Positive(n as u64)
+    })
+}
+
+fn is_none(o: &EncodedData) -> bool {
+    matches!(o, EncodedData::Special(EncodedSpecial::None))
+}
+
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeObject;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeObject;
+
+    fn serialize_bool(self, b: bool) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::Integer(EncodedInteger::Bool(b)))
+    }
+
+    fn serialize_i8(self, n: i8) -> Result<EncodedData, SerdeError> {
+        self.serialize_i64(n as i64)
+    }
+
+    fn serialize_i16(self, n: i16) -> Result<EncodedData, SerdeError> {
+        self.serialize_i64(n as i64)
+    }
+
+    fn serialize_i32(self, n: i32) -> Result<EncodedData, SerdeError> {
+        self.serialize_i64(n as i64)
+    }
+
+    fn serialize_i64(self, n: i64) -> Result<EncodedData, SerdeError> {
+        Ok(integer(n))
+    }
+
+    fn serialize_u8(self, n: u8) -> Result<EncodedData, SerdeError> {
+        self.serialize_u64(n as u64)
+    }
+
+    fn serialize_u16(self, n: u16) -> Result<EncodedData, SerdeError> {
+        self.serialize_u64(n as u64)
+    }
+
+    fn serialize_u32(self, n: u32) -> Result<EncodedData, SerdeError> {
+        self.serialize_u64(n as u64)
+    }
+
+    fn serialize_u64(self, n: u64) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::Integer(EncodedInteger::Positive(n)))
+    }
+
+    fn serialize_f32(self, f: f32) -> Result<EncodedData, SerdeError> {
+        self.serialize_f64(f as f64)
+    }
+
+    fn serialize_f64(self, f: f64) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::Float(f))
+    }
+
+    fn serialize_char(self, c: char) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::String(c.to_string()))
+    }
+
+    fn serialize_str(self, s: &str) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::String(s.to_string()))
+    }
+
+    fn serialize_bytes(self, bytes: &[u8]) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::Array(
+            bytes
+                .iter()
+                .map(|b| EncodedData::Integer(EncodedInteger::Positive(*b as u64)))
+                .collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::Special(EncodedSpecial::None))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<EncodedData, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<EncodedData, SerdeError> {
+        Ok(EncodedData::Special(EncodedSpecial::Null))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<EncodedData, SerdeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<EncodedData, SerdeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<EncodedData, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<EncodedData, SerdeError> {
+        let mut map = HashMap::new();
+        map.insert(variant.to_string(), value.serialize(self)?);
+        Ok(EncodedData::Object(map))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, SerdeError> {
+        Ok(SerializeArray {
+            variant: None,
+            list: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, SerdeError> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            list: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeObject, SerdeError> {
+        Ok(SerializeObject {
+            variant: None,
+            map: HashMap::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeObject, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeObject, SerdeError> {
+        Ok(SerializeObject {
+            variant: Some(variant),
+            map: HashMap::with_capacity(len),
+            key: None,
+        })
+    }
+}
+
+// Wraps the value of an enum variant into its externally tagged object.
+fn tagged(variant: Option<&'static str>, o: EncodedData) -> EncodedData {
+    match variant {
+        Some(variant) => {
+            let mut map = HashMap::new();
+            map.insert(variant.to_string(), o);
+            EncodedData::Object(map)
+        }
+        None => o,
+    }
+}
+
+pub struct SerializeArray {
+    variant: Option<&'static str>,
+    list: Vec<EncodedData>,
+}
+
+impl SerializeArray {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.list.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        Ok(tagged(self.variant, EncodedData::Array(self.list)))
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeArray::end(self)
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeArray::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeArray::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeArray::end(self)
+    }
+}
+
+pub struct SerializeObject {
+    variant: Option<&'static str>,
+    map: HashMap<String, EncodedData>,
+    // Key of the map entry whose value comes next.
+    key: Option<String>,
+}
+
+impl SerializeObject {
+    // None fields are left out, decoding gives them back as absent.
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), SerdeError> {
+        let o = value.serialize(Serializer)?;
+        if !is_none(&o) {
+            self.map.insert(key.to_string(), o);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        Ok(tagged(self.variant, EncodedData::Object(self.map)))
+    }
+}
+
+impl ser::SerializeMap for SerializeObject {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        self.key = Some(match key.serialize(Serializer)? {
+            EncodedData::String(s) => s,
+            EncodedData::Integer(EncodedInteger::Positive(n)) => n.to_string(),
+            EncodedData::Integer(EncodedInteger::Negative(n)) => format!("-{}", n),
+            _ => return Err(SerdeError::KeyMustBeAString),
+        });
+        Ok(())
+    }
+
+    // Map values are kept whatever they are: a None value is a key mapped to None.
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| SerdeError::Message("map value without a key".to_string()))?;
+        self.map.insert(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeObject::end(self)
+    }
+}
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeObject::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeObject {
+    type Ok = EncodedData;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<EncodedData, SerdeError> {
+        SerializeObject::end(self)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for EncodedData {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            Self::Special(EncodedSpecial::None) => visitor.visit_none(),
+            Self::Special(EncodedSpecial::Null) => visitor.visit_unit(),
+            Self::Special(EncodedSpecial::Define(_)) => Err(SerdeError::Unsupported("define")),
+            Self::Special(EncodedSpecial::Forget(_)) => Err(SerdeError::Unsupported("forget")),
+            Self::Special(EncodedSpecial::Reset) => Err(SerdeError::Unsupported("reset")),
+            Self::Alias(_) => Err(SerdeError::Unsupported("alias")),
+            Self::Integer(EncodedInteger::Bool(b)) => visitor.visit_bool(b),
+            Self::Integer(EncodedInteger::Positive(n)) => visitor.visit_u64(n),
+            Self::Integer(EncodedInteger::Negative(n)) => match 0i64.checked_sub_unsigned(n) {
+                Some(n) => visitor.visit_i64(n),
+                None => visitor.visit_i128(-(n as i128)),
+            },
+            Self::Float(f) => visitor.visit_f64(f),
+            Self::Decimal(s) => match s.parse() {
+                Ok(f) => visitor.visit_f64(f),
+                Err(_) => Err(SerdeError::Message(format!("bad decimal {}", s))),
+            },
+            Self::String(s) => visitor.visit_string(s),
+            Self::Array(list) => {
+                visitor.visit_seq(de::value::SeqDeserializer::new(list.into_iter()))
+            }
+            Self::Object(map) => visitor.visit_map(de::value::MapDeserializer::new(
+                map.into_iter().filter(|(_, o)| !is_none(o)),
+            )),
+            Self::OrderedObject(entries) => visitor.visit_map(de::value::MapDeserializer::new(
+                entries.into_iter().filter(|(_, o)| !is_none(o)),
+            )),
+        }
+    }
+
+    // Nulls are None too, as in JSON.
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            Self::Special(EncodedSpecial::None) | Self::Special(EncodedSpecial::Null) => {
+                visitor.visit_none()
+            }
+            o => visitor.visit_some(o),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        let (variant, value) = match self {
+            Self::String(variant) => (variant, None),
+            Self::Object(map) if map.len() == 1 => {
+                let (variant, value) = map.into_iter().next().unwrap();
+                (variant, Some(value))
+            }
+            Self::OrderedObject(mut entries) if entries.len() == 1 => {
+                let (variant, value) = entries.pop().unwrap();
+                (variant, Some(value))
+            }
+            o => {
+                return Err(SerdeError::Message(format!(
+                    "expected an enum, found a {}",
+                    crate::schema::kind(&o)
+                )))
+            }
+        };
+        visitor.visit_enum(Enum { variant, value })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for EncodedData {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+// Enum variant being deserialized, with its value unless it is a unit variant.
+struct Enum {
+    variant: String,
+    value: Option<EncodedData>,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum {
+    type Error = SerdeError;
+    type Variant = Variant;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Variant), SerdeError> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, Variant(self.value)))
+    }
+}
+
+// Value of an enum variant, None for unit variants.
+struct Variant(Option<EncodedData>);
+
+impl Variant {
+    fn value(self) -> EncodedData {
+        self.0.unwrap_or(EncodedData::Special(EncodedSpecial::Null))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        match self.0 {
+            None | Some(EncodedData::Special(EncodedSpecial::Null)) => Ok(()),
+            Some(o) => Err(SerdeError::Message(format!(
+                "expected a unit variant, found a {}",
+                crate::schema::kind(&o)
+            ))),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, SerdeError> {
+        seed.deserialize(self.value())
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self.value(), visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self.value(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: Option<i32>,
+        #[serde(default)]
+        count: u32,
+        unit: (),
+        kind: Kind,
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+    enum Kind {
+        Plain,
+        Scaled(f64),
+        Range { min: i64, max: i64 },
+    }
+
+    #[test]
+    fn option_fields() {
+        let reading = Reading {
+            sensor: "a".to_string(),
+            value: None,
+            count: 3,
+            unit: (),
+            kind: Kind::Plain,
+        };
+        let o = to_encoded(&reading).unwrap();
+        let keys: Vec<_> = o.as_object().unwrap().keys().cloned().collect();
+        assert!(!keys.contains(&"value".to_string()));
+        assert_eq!(
+            o.as_object().unwrap().get("unit"),
+            Some(&EncodedData::Special(EncodedSpecial::Null))
+        );
+        let data = to_vec(&reading).unwrap();
+        // Smaller than going through serde_json, which writes a null.
+        let json = EncodedData::from(serde_json::to_value(&reading).unwrap()).encode();
+        assert!(data.len() < json.len());
+        assert_eq!(from_slice(&data), Ok((reading, data.len())));
+        assert_eq!(
+            from_encoded::<Option<i32>>(EncodedData::Special(EncodedSpecial::Null)),
+            Ok(None)
+        );
+
+        // Fields holding the None special are absent, defaults apply.
+        let mut map = HashMap::new();
+        map.insert("sensor".to_string(), EncodedData::String("b".to_string()));
+        map.insert(
+            "value".to_string(),
+            EncodedData::Special(EncodedSpecial::None),
+        );
+        map.insert(
+            "count".to_string(),
+            EncodedData::Special(EncodedSpecial::None),
+        );
+        map.insert(
+            "unit".to_string(),
+            EncodedData::Special(EncodedSpecial::Null),
+        );
+        map.insert("kind".to_string(), EncodedData::String("Plain".to_string()));
+        assert_eq!(
+            from_encoded(EncodedData::Object(map)),
+            Ok(Reading {
+                sensor: "b".to_string(),
+                value: None,
+                count: 0,
+                unit: (),
+                kind: Kind::Plain,
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let readings = vec![
+            Reading {
+                sensor: "x".to_string(),
+                value: Some(-5),
+                count: u32::MAX,
+                unit: (),
+                kind: Kind::Scaled(0.5),
+            },
+            Reading {
+                sensor: String::new(),
+                value: Some(i32::MIN),
+                count: 0,
+                unit: (),
+                kind: Kind::Range { min: -1, max: 1 },
+            },
+        ];
+        let data = to_vec(&readings).unwrap();
+        assert_eq!(from_slice(&data), Ok((readings, data.len())));
+
+        let mut map = std::collections::BTreeMap::new();
+        map.insert(1u8, vec![Some(1.5), None]);
+        let mut expected = HashMap::new();
+        expected.insert(
+            "1".to_string(),
+            EncodedData::Array(vec![
+                EncodedData::Float(1.5),
+                EncodedData::Special(EncodedSpecial::None),
+            ]),
+        );
+        assert_eq!(to_encoded(&map), Ok(EncodedData::Object(expected)));
+        assert_eq!(
+            to_encoded(&[((), 1)].iter().cloned().collect::<HashMap<_, _>>()),
+            Err(SerdeError::KeyMustBeAString)
+        );
+        assert_eq!(
+            from_encoded::<Reading>(EncodedData::Alias(0)),
+            Err(SerdeError::Unsupported("alias"))
+        );
+    }
+}
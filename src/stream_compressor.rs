@@ -1,29 +1,1832 @@
-use crate::encoded_data::{self, EncodedData};
+use crate::define::{data_type, special_type};
+use crate::encoded_data::{
+    self, DecodeOptions, EncodedData, EncodedDataToJsonError, EncodedSpecial,
+};
+use std::collections::HashMap;
+use std::convert::TryInto;
 
-pub struct Conf {}
+// Cost of the Define special prefixed to a value the first time it is cached.
+const DEFINE_COST: usize = 1;
 
+pub struct Conf {
+    // Maximum number of aliases the compressor may define.
+    pub max_cache: usize,
+    // Maximum number of not yet aliased strings tracked as alias candidates.
+    pub max_future_cache: usize,
+    // Maximum total byte length of the strings tracked as alias candidates. When a new candidate
+    // does not fit, the ones with the lowest gain so far are dropped to make room.
+    pub max_future_cache_bytes: usize,
+    // Number of compressed values after which the use counts of the alias candidates are halved,
+    // dropping the candidates that were not seen again. 0 disables the decay.
+    pub decay_period: usize,
+    // Alias the arrays and objects repeated within a compressed value, at the cost of encoding
+    // every container of the value once more to find them.
+    pub dedup_subtrees: bool,
+    // Alias the entries a compressed object shares with the previous one (a common header), the
+    // object being written as an Extend of that alias and of its remaining entries. Decoding
+    // it requires resolving the aliases, e.g. with a StreamDecoder.
+    pub share_prefixes: bool,
+}
+
+impl Default for Conf {
+    fn default() -> Self {
+        Self {
+            max_cache: 1024,
+            max_future_cache: 4096,
+            max_future_cache_bytes: 1 << 20,
+            decay_period: 256,
+            dedup_subtrees: false,
+            share_prefixes: false,
+        }
+    }
+}
+
+// Starts a new alias numbering, e.g. between the outputs of two compressors that are concatenated.
+pub const RESET_MARKER: u8 = data_type::SPECIAL << 5 | special_type::RESET;
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum DecodeError {
     BadFormat(encoded_data::DecodeError),
+    Json(EncodedDataToJsonError),
 }
 
-pub struct StreamCompressor {
-    // TODO Object caching
-    aliases: Vec<EncodedData>,
-    // string_map: HashMap<&str, usize>,
+// Floats are keyed by their bits rather than by value so that 0.0 and -0.0 get distinct aliases
+// and NaN can be aliased at all.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum CacheKey {
+    String(String),
+    Float(u64),
+    // Canonical encoding of an array or object.
+    Subtree(Vec<u8>),
+}
+
+struct CacheEntry {
+    index: u64,
+    nb_use: usize,
+}
+
+fn string_size(s: &str) -> usize {
+    encoded_data::encode_container_header(data_type::STRING, s.len()).len() + s.len()
+}
+
+fn for_each_container(o: &EncodedData, f: &mut impl FnMut(&EncodedData)) {
+    match o {
+        EncodedData::Array(list) => {
+            f(o);
+            list.iter().for_each(|o| for_each_container(o, f));
+        }
+        EncodedData::Object(map) => {
+            f(o);
+            map.values().for_each(|o| for_each_container(o, f));
+        }
+        EncodedData::OrderedObject(entries) => {
+            f(o);
+            entries.iter().for_each(|(_, o)| for_each_container(o, f));
+        }
+        _ => {}
+    }
+}
+
+// Strings and containers of `o`, `o` included.
+fn for_each_candidate(o: &EncodedData, f: &mut impl FnMut(&EncodedData)) {
+    for_each_container(o, &mut |o| {
+        f(o);
+        let mut strings = |o: &EncodedData| {
+            if let EncodedData::String(_) = o {
+                f(o)
+            }
+        };
+        match o {
+            EncodedData::Array(list) => list.iter().for_each(&mut strings),
+            EncodedData::Object(map) => map.values().for_each(&mut strings),
+            EncodedData::OrderedObject(entries) => entries.iter().for_each(|(_, o)| strings(o)),
+            _ => {}
+        }
+    });
+    if let EncodedData::String(_) = o {
+        f(o)
+    }
+}
+
+fn for_each_string<'a>(value: &'a serde_json::Value, f: &mut impl FnMut(&'a str)) {
+    match value {
+        serde_json::Value::String(s) => f(s),
+        serde_json::Value::Array(list) => list.iter().for_each(|o| for_each_string(o, f)),
+        serde_json::Value::Object(map) => map.values().for_each(|o| for_each_string(o, f)),
+        _ => {}
+    }
+}
+
+fn unique(dictionary: &[String]) -> Vec<&str> {
+    let mut seen = std::collections::HashSet::new();
+    dictionary
+        .iter()
+        .map(|s| s.as_str())
+        .filter(|s| seen.insert(*s))
+        .collect()
+}
+
+// Decides which strings a StreamCompressor aliases. Alias ids are allocated by the compressor, in
+// admission order, while Conf::max_cache allows it.
+pub trait CachePolicy {
+    // Called for each occurrence of a string that is not aliased yet, with its encoded size and
+    // the size of the alias it would get.
+    fn on_seen(&mut self, s: &str, encoded_size: usize, alias_size: usize) -> Admission;
+    // Called once per compressed value.
+    fn tick(&mut self) {}
+    // Called by StreamCompressor::reset.
+    fn reset(&mut self);
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Admission {
+    // Define an alias on this occurrence.
+    Define,
+    Inline,
+}
+
+// Default policy: a string is aliased once its previous occurrences show that aliasing it would
+// have paid for its definition.
+pub struct GainPolicy {
+    // String -> number of times it was seen without being aliased.
+    future_cache: HashMap<String, usize>,
+    available_future_cache: usize,
+    // Bytes of the strings of future_cache.
+    future_cache_bytes: usize,
+    max_future_cache_bytes: usize,
+    decay_period: usize,
+    ticks_before_decay: usize,
+}
+
+impl GainPolicy {
+    pub fn new(conf: &Conf) -> Self {
+        Self {
+            future_cache: HashMap::new(),
+            available_future_cache: conf.max_future_cache,
+            future_cache_bytes: 0,
+            max_future_cache_bytes: conf.max_future_cache_bytes,
+            decay_period: conf.decay_period,
+            ticks_before_decay: conf.decay_period,
+        }
+    }
+
+    // Halves the use counts, so that candidates only survive if they keep being seen, and frees
+    // the slots of the ones that drop to 0.
+    fn decay(&mut self) {
+        let before = self.future_cache.len();
+        let future_cache_bytes = &mut self.future_cache_bytes;
+        self.future_cache.retain(|s, nb_use| {
+            *nb_use /= 2;
+            if *nb_use == 0 {
+                *future_cache_bytes -= s.len();
+            }
+            *nb_use > 0
+        });
+        self.available_future_cache += before - self.future_cache.len();
+    }
+
+    fn forget(&mut self, s: &str) {
+        if self.future_cache.remove(s).is_some() {
+            self.future_cache_bytes -= s.len();
+            self.available_future_cache += 1;
+        }
+    }
+
+    // Drops the candidates with the lowest gain until `size` more bytes fit in the byte budget.
+    // Returns false if they can't.
+    fn make_room(&mut self, size: usize) -> bool {
+        if size > self.max_future_cache_bytes {
+            return false;
+        }
+        while self.future_cache_bytes + size > self.max_future_cache_bytes {
+            let lowest = self
+                .future_cache
+                .iter()
+                .min_by(|a, b| {
+                    (a.1 * string_size(a.0))
+                        .cmp(&(b.1 * string_size(b.0)))
+                        .then_with(|| a.0.cmp(b.0))
+                })
+                .map(|(s, _)| s.clone())
+                .unwrap();
+            self.forget(&lowest);
+        }
+        true
+    }
+
+    // Bytes that aliasing each tracked string would have saved over its recorded uses, the first
+    // one paying for the definition, best first.
+    fn get_best_gains(&self, alias_size: usize) -> Vec<(&str, usize)> {
+        let mut gains: Vec<_> = self
+            .future_cache
+            .iter()
+            .filter_map(|(s, nb_use)| {
+                let saved = string_size(s).saturating_sub(alias_size) * (nb_use - 1);
+                match saved.saturating_sub(DEFINE_COST) {
+                    0 => None,
+                    gain => Some((s.as_str(), gain)),
+                }
+            })
+            .collect();
+        // Ties are broken on the string so that the ranking does not depend on the map order.
+        gains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        gains
+    }
+}
+
+impl CachePolicy for GainPolicy {
+    // Records an occurrence of `s` and admits it if aliasing it from now on would have paid for
+    // its definition, given the previous occurrences.
+    fn on_seen(&mut self, s: &str, encoded_size: usize, alias_size: usize) -> Admission {
+        if encoded_size <= alias_size {
+            return Admission::Inline;
+        }
+        match self.future_cache.get_mut(s) {
+            Some(nb_use) => {
+                if *nb_use * (encoded_size - alias_size) > DEFINE_COST {
+                    self.forget(s);
+                    Admission::Define
+                } else {
+                    *nb_use += 1;
+                    Admission::Inline
+                }
+            }
+            None => {
+                if self.available_future_cache > 0 && self.make_room(s.len()) {
+                    self.future_cache.insert(s.to_string(), 1);
+                    self.future_cache_bytes += s.len();
+                    self.available_future_cache -= 1;
+                }
+                Admission::Inline
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.decay_period == 0 {
+            return;
+        }
+        self.ticks_before_decay -= 1;
+        if self.ticks_before_decay == 0 {
+            self.decay();
+            self.ticks_before_decay = self.decay_period;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.available_future_cache += self.future_cache.len();
+        self.future_cache.clear();
+        self.future_cache_bytes = 0;
+        self.ticks_before_decay = self.decay_period;
+    }
+}
+
+struct Cache<P> {
+    entries: HashMap<CacheKey, CacheEntry>,
+    policy: P,
+    available_cache: usize,
+}
+
+impl<P: CachePolicy> Cache<P> {
+    fn new(conf: &Conf, policy: P) -> Self {
+        Self {
+            entries: HashMap::new(),
+            policy,
+            available_cache: conf.max_cache,
+        }
+    }
+
+    // Returns the Define or Alias to emit in place of `data`, if it is cacheable.
+    fn get_cached(&mut self, data: &EncodedData) -> Option<EncodedData> {
+        let key = match data {
+            EncodedData::String(s) => CacheKey::String(s.clone()),
+            EncodedData::Float(f) => CacheKey::Float(f.to_bits()),
+            _ => return None,
+        };
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.nb_use += 1;
+            return Some(EncodedData::Alias(entry.index));
+        }
+        if self.available_cache == 0 {
+            return None;
+        }
+
+        let index = self.entries.len() as u64;
+        let encoded_size = data.encode().len();
+        let alias_size = EncodedData::Alias(index).encode().len();
+        let admitted = match data {
+            EncodedData::String(s) => {
+                self.policy.on_seen(s, encoded_size, alias_size) == Admission::Define
+            }
+            _ => encoded_size > alias_size + DEFINE_COST,
+        };
+        if !admitted {
+            return None;
+        }
+
+        self.entries.insert(key, CacheEntry { index, nb_use: 0 });
+        self.available_cache -= 1;
+        Some(EncodedData::Special(EncodedSpecial::Define(Box::new(
+            data.clone(),
+        ))))
+    }
+}
+
+pub struct StreamCompressor<P = GainPolicy> {
+    conf: Conf,
+    cache: Cache<P>,
+    // Canonical encoding of the arrays and objects known to be repeated in what is left to
+    // compress -> number of occurrences left. Those are defined on their first occurrence.
+    subtrees: HashMap<Vec<u8>, usize>,
+    // Entries, with their canonical encoding, of the last compressed object when
+    // Conf::share_prefixes is set, and of the shared prefixes aliased so far.
+    previous: Vec<(String, Vec<u8>)>,
+    prefixes: Vec<Vec<(String, Vec<u8>)>>,
 }
 
 impl StreamCompressor {
     pub fn new(conf: Conf) -> Self {
-        Self { aliases: vec![] }
+        let policy = GainPolicy::new(&conf);
+        Self::with_policy(conf, policy)
+    }
+
+    // Starts with the strings of `dictionary` already aliased, in order and without duplicates,
+    // as StreamDecoder::with_dictionary expects them.
+    pub fn with_dictionary(conf: Conf, dictionary: &[String]) -> Self {
+        let mut ret = Self::new(conf);
+        for s in unique(dictionary) {
+            let index = ret.cache.entries.len() as u64;
+            ret.cache.entries.insert(
+                CacheKey::String(s.to_string()),
+                CacheEntry { index, nb_use: 0 },
+            );
+            ret.cache.available_cache = ret.cache.available_cache.saturating_sub(1);
+        }
+        ret
+    }
+
+    // Picks the strings and subtrees (arrays and objects) repeated in `value` that save the most
+    // bytes when aliased from the start, best first, up to `max_entries`: the single document
+    // counterpart of Dictionary::train. The strings and subtrees nested in a picked subtree only
+    // count their occurrences outside of it.
+    pub fn optimal_dictionary(value: &serde_json::Value, max_entries: usize) -> Vec<EncodedData> {
+        let root = EncodedData::from(value.clone());
+        // Occurrences of each candidate, by encoding, and how many times each other candidate
+        // appears in one occurrence of it.
+        let mut candidates: HashMap<Vec<u8>, (EncodedData, usize)> = HashMap::new();
+        let mut nested: HashMap<Vec<u8>, HashMap<Vec<u8>, usize>> = HashMap::new();
+        for_each_candidate(&root, &mut |o| {
+            candidates.entry(o.encode()).or_insert((o.clone(), 0)).1 += 1;
+        });
+        for (encoded, (o, nb_use)) in candidates.iter() {
+            if *nb_use < 2 || !matches!(o, EncodedData::Array(_) | EncodedData::Object(_)) {
+                continue;
+            }
+            let mut inner: HashMap<Vec<u8>, usize> = HashMap::new();
+            for_each_candidate(o, &mut |o| *inner.entry(o.encode()).or_insert(0) += 1);
+            inner.remove(encoded);
+            nested.insert(encoded.clone(), inner);
+        }
+
+        // The largest alias the dictionary may need, so that the gain is never overestimated.
+        let alias_size = EncodedData::Alias(max_entries.saturating_sub(1) as u64)
+            .encode()
+            .len();
+        let gain = |encoded: &[u8], nb_use: usize| match nb_use {
+            0 | 1 => 0,
+            _ => encoded.len().saturating_sub(alias_size) * nb_use,
+        };
+        let mut dictionary = vec![];
+        while dictionary.len() < max_entries {
+            let best = candidates
+                .iter()
+                .map(|(encoded, (_, nb_use))| (gain(encoded, *nb_use), encoded))
+                .filter(|(gain, _)| *gain > 0)
+                .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)))
+                .map(|(_, encoded)| encoded.clone());
+            let encoded = match best {
+                Some(encoded) => encoded,
+                None => break,
+            };
+            let (o, nb_use) = candidates.remove(&encoded).unwrap();
+            for (inner, nb) in nested.get(&encoded).into_iter().flatten() {
+                if let Some((_, inner_use)) = candidates.get_mut(inner) {
+                    *inner_use = inner_use.saturating_sub(nb * nb_use);
+                }
+            }
+            dictionary.push(o);
+        }
+        dictionary
+    }
+
+    // Number of occurrences of each object key in `value`, e.g. to pick the keys worth shortening
+    // with a KeyMap: a key's every occurrence is written in full.
+    pub fn key_stats(value: &serde_json::Value) -> HashMap<String, usize> {
+        let mut ret = HashMap::new();
+        let mut stack = vec![value];
+        while let Some(o) = stack.pop() {
+            match o {
+                serde_json::Value::Array(list) => stack.extend(list.iter()),
+                serde_json::Value::Object(map) => {
+                    for (k, o) in map.iter() {
+                        *ret.entry(k.clone()).or_insert(0) += 1;
+                        stack.push(o);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ret
+    }
+}
+
+// Lists the aliases by id, one per line, as `id -> value (used n times)`, the uses not counting
+// the definition.
+impl<P: CachePolicy> std::fmt::Display for StreamCompressor<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut entries: Vec<_> = self.cache.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.index);
+        for (key, entry) in entries {
+            write!(f, "{} -> ", entry.index)?;
+            match key {
+                CacheKey::String(s) => write!(f, "{:?}", s)?,
+                CacheKey::Float(bits) => write!(f, "{:?}", f64::from_bits(*bits))?,
+                CacheKey::Subtree(data) => write!(f, "subtree of {} bytes", data.len())?,
+            }
+            writeln!(f, " (used {} times)", entry.nb_use)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: CachePolicy> StreamCompressor<P> {
+    pub fn with_policy(conf: Conf, policy: P) -> Self {
+        Self {
+            cache: Cache::new(&conf, policy),
+            subtrees: HashMap::new(),
+            previous: vec![],
+            prefixes: vec![],
+            conf,
+        }
+    }
+
+    // Forgets every alias and alias candidate, including the ones of a with_dictionary
+    // dictionary, so that the next value can be decoded by a new (or reset) StreamDecoder.
+    pub fn reset(&mut self) {
+        self.cache.entries.clear();
+        self.cache.available_cache = self.conf.max_cache;
+        self.cache.policy.reset();
+        self.subtrees.clear();
+        self.previous.clear();
+        self.prefixes.clear();
+    }
+
+    // Same as reset, also writing a reset marker at the end of `buf`, so that a single
+    // StreamDecoder can read the values compressed before and after it.
+    pub fn append_reset(&mut self, buf: &mut Vec<u8>) {
+        self.reset();
+        buf.push(RESET_MARKER);
+    }
+
+    // Aliased strings in alias order, to seed another compressor/decoder pair with.
+    pub fn dictionary(&self) -> Vec<String> {
+        let mut strings: Vec<_> = self
+            .cache
+            .entries
+            .iter()
+            .filter_map(|(key, entry)| match key {
+                CacheKey::String(s) => Some((entry.index, s.clone())),
+                CacheKey::Float(_) | CacheKey::Subtree(_) => None,
+            })
+            .collect();
+        strings.sort();
+        strings.into_iter().map(|(_, s)| s).collect()
+    }
+
+    // Number of times each alias id was referenced so far, not counting its definition.
+    pub fn alias_usage(&self) -> HashMap<u64, usize> {
+        self.cache
+            .entries
+            .values()
+            .map(|entry| (entry.index, entry.nb_use))
+            .collect()
+    }
+
+    pub fn compress(&mut self, object: &EncodedData) -> Vec<u8> {
+        let mut ret = vec![];
+        self.append_to(&mut ret, object);
+        ret
+    }
+
+    // Compresses `object` at the end of `buf`, e.g. a log being appended to. The values of the
+    // buffer are read back in order by a single StreamDecoder.
+    pub fn append_to(&mut self, buf: &mut Vec<u8>, object: &EncodedData) {
+        if self.conf.dedup_subtrees {
+            self.expect_subtrees(std::slice::from_ref(object));
+        }
+        match object {
+            EncodedData::Object(_) | EncodedData::OrderedObject(_) if self.conf.share_prefixes => {
+                self.compress_prefixed(object, buf)
+            }
+            _ => self.compress_into(object, buf),
+        }
+        // Occurrences nested in an aliased subtree are never reached.
+        self.subtrees.clear();
+        self.cache.policy.tick();
+    }
+
+    // Estimates the compressed/JSON size ratio of `value` from the string aliases the compressor
+    // would use, given its current state, without compressing it. Candidates are ranked as
+    // GainPolicy would, whatever the policy of the compressor.
+    pub fn estimate_ratio(&self, value: &serde_json::Value) -> f64 {
+        let mut strings = GainPolicy::new(&Conf {
+            max_future_cache: usize::MAX,
+            decay_period: 0,
+            ..Conf::default()
+        });
+        let mut saved = 0;
+        for_each_string(value, &mut |s| match self
+            .cache
+            .entries
+            .get(&CacheKey::String(s.to_string()))
+        {
+            Some(entry) => {
+                let alias_size = EncodedData::Alias(entry.index).encode().len();
+                saved += string_size(s).saturating_sub(alias_size);
+            }
+            None => *strings.future_cache.entry(s.to_string()).or_insert(0) += 1,
+        });
+        let alias_size = EncodedData::Alias(self.cache.entries.len() as u64)
+            .encode()
+            .len();
+        saved += strings
+            .get_best_gains(alias_size)
+            .iter()
+            .take(self.cache.available_cache)
+            .map(|(_, gain)| gain)
+            .sum::<usize>();
+
+        let size = EncodedData::from(value.clone()).encode().len() - saved;
+        size as f64 / serde_json::to_vec(value).unwrap().len() as f64
+    }
+
+    // Counts the arrays and objects of `values`, so that the ones that are repeated are aliased as
+    // a whole.
+    fn expect_subtrees(&mut self, values: &[EncodedData]) {
+        for value in values.iter() {
+            for_each_container(value, &mut |o| {
+                *self.subtrees.entry(o.encode()).or_insert(0) += 1;
+            });
+        }
+        self.subtrees.retain(|_, nb| *nb > 1);
+    }
+
+    // Returns the Define or Alias to emit in place of the container `object`, if it is repeated.
+    fn get_cached_subtree(&mut self, object: &EncodedData) -> Option<Vec<u8>> {
+        if self.subtrees.is_empty() {
+            return None;
+        }
+        let encoded = object.encode();
+        let nb_left = self.subtrees.get_mut(&encoded)?;
+        *nb_left -= 1;
+        let nb_left = *nb_left;
+        let encoded_size = encoded.len();
+        let key = CacheKey::Subtree(encoded);
+        if let Some(entry) = self.cache.entries.get_mut(&key) {
+            entry.nb_use += 1;
+            return Some(EncodedData::Alias(entry.index).encode());
+        }
+        let index = self.cache.entries.len() as u64;
+        let alias_size = EncodedData::Alias(index).encode().len();
+        if self.cache.available_cache == 0
+            || nb_left == 0
+            || encoded_size <= alias_size + DEFINE_COST
+        {
+            return None;
+        }
+        self.cache
+            .entries
+            .insert(key, CacheEntry { index, nb_use: 0 });
+        self.cache.available_cache -= 1;
+        let define = EncodedData::Special(EncodedSpecial::Define(Box::new(object.clone())));
+        Some(define.encode())
+    }
+
+    // Compresses the object `object` as an Extend of an aliased prefix, when it shares one with
+    // the previous object or starts with an already aliased one. Ordered objects must share a
+    // leading run of entries, plain objects (whose entries are sorted) any subset of them.
+    fn compress_prefixed(&mut self, object: &EncodedData, out: &mut Vec<u8>) {
+        let (entries, ordered): (Vec<_>, _) = match object {
+            EncodedData::Object(map) => (encoded_data::sorted_entries(map), false),
+            EncodedData::OrderedObject(entries) => {
+                (entries.iter().map(|(k, o)| (k, o)).collect(), true)
+            }
+            _ => unreachable!(),
+        };
+        let encoded: Vec<_> = entries
+            .iter()
+            .map(|(k, o)| (k.to_string(), o.encode()))
+            .collect();
+        let shared = |prefix: &[(String, Vec<u8>)]| {
+            if ordered {
+                encoded.starts_with(prefix)
+            } else {
+                prefix.iter().all(|entry| encoded.contains(entry))
+            }
+        };
+        let prefix = match self
+            .prefixes
+            .iter()
+            .filter(|p| shared(p))
+            .max_by_key(|p| p.len())
+        {
+            Some(prefix) => prefix.clone(),
+            None if ordered => encoded
+                .iter()
+                .zip(self.previous.iter())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a.clone())
+                .collect(),
+            None => encoded
+                .iter()
+                .filter(|entry| self.previous.contains(entry))
+                .cloned()
+                .collect(),
+        };
+        self.previous = encoded;
+
+        let mut prefix_data =
+            encoded_data::encode_container_header(data_type::OBJECT, prefix.len());
+        for (k, o) in prefix.iter() {
+            prefix_data.extend(encoded_data::encode_key(k));
+            prefix_data.extend(o);
+        }
+        let key = CacheKey::Subtree(prefix_data.clone());
+        let index = self.cache.entries.len() as u64;
+        let base = match self.cache.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.nb_use += 1;
+                EncodedData::Alias(entry.index).encode()
+            }
+            // Worth defining if the alias of the next occurrence, with its Extend byte, is
+            // smaller than the prefix.
+            None if !prefix.is_empty()
+                && self.cache.available_cache > 0
+                && EncodedData::Alias(index).encode().len() + 1 < prefix_data.len() =>
+            {
+                self.cache
+                    .entries
+                    .insert(key, CacheEntry { index, nb_use: 0 });
+                self.cache.available_cache -= 1;
+                self.prefixes.push(prefix.clone());
+                [
+                    vec![data_type::SPECIAL << 5 | special_type::DEFINE],
+                    prefix_data,
+                ]
+                .concat()
+            }
+            None => return self.compress_into(object, out),
+        };
+        out.push(data_type::SPECIAL << 5 | special_type::EXTEND);
+        out.extend(base);
+        // The prefix of an ordered object is its leading entries, which may repeat later keys.
+        let tail: Vec<_> = if ordered {
+            entries[prefix.len()..].to_vec()
+        } else {
+            entries
+                .into_iter()
+                .filter(|(k, _)| !prefix.iter().any(|(name, _)| name == *k))
+                .collect()
+        };
+        let tail = tail
+            .into_iter()
+            .map(|(k, o)| (k.clone(), o.clone()))
+            .collect();
+        self.compress_into(&EncodedData::OrderedObject(tail), out);
+    }
+
+    fn compress_into(&mut self, object: &EncodedData, out: &mut Vec<u8>) {
+        if let EncodedData::Array(_) | EncodedData::Object(_) | EncodedData::OrderedObject(_) =
+            object
+        {
+            if let Some(cached) = self.get_cached_subtree(object) {
+                out.extend(cached);
+                return;
+            }
+        }
+        match object {
+            EncodedData::Array(list) => {
+                out.extend(encoded_data::encode_container_header(
+                    data_type::ARRAY,
+                    list.len(),
+                ));
+                for o in list.iter() {
+                    self.compress_into(o, out);
+                }
+            }
+            EncodedData::Object(map) => {
+                out.extend(encoded_data::encode_container_header(
+                    data_type::OBJECT,
+                    map.len(),
+                ));
+                for (k, o) in encoded_data::sorted_entries(map) {
+                    out.extend(encoded_data::encode_key(k));
+                    self.compress_into(o, out);
+                }
+            }
+            EncodedData::OrderedObject(entries) => {
+                out.extend(encoded_data::encode_container_header(
+                    data_type::OBJECT,
+                    entries.len(),
+                ));
+                for (k, o) in entries.iter() {
+                    out.extend(encoded_data::encode_key(k));
+                    self.compress_into(o, out);
+                }
+            }
+            _ => match self.cache.get_cached(object) {
+                Some(cached) => out.extend(cached.encode()),
+                None => out.extend(object.encode()),
+            },
+        }
+    }
+}
+
+// Strings aliased from the start by the compressor/decoder pairs built from it.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Dictionary {
+    pub strings: Vec<String>,
+}
+
+impl Dictionary {
+    // Picks the strings repeated across `samples` that save the most bytes when aliased from the
+    // start, best first, up to `max_entries`.
+    pub fn train(samples: &[serde_json::Value], max_entries: usize) -> Self {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for sample in samples.iter() {
+            for_each_string(sample, &mut |s| *counts.entry(s).or_insert(0) += 1);
+        }
+        // The largest alias the dictionary may need, so that the gain is never overestimated.
+        let alias_size = EncodedData::Alias(max_entries.saturating_sub(1) as u64)
+            .encode()
+            .len();
+        let mut gains: Vec<_> = counts
+            .into_iter()
+            .filter(|(_, nb_use)| *nb_use > 1)
+            .filter_map(
+                |(s, nb_use)| match string_size(s).saturating_sub(alias_size) * nb_use {
+                    0 => None,
+                    gain => Some((s, gain)),
+                },
+            )
+            .collect();
+        gains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        Self {
+            strings: gains
+                .into_iter()
+                .take(max_entries)
+                .map(|(s, _)| s.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn compressor(&self, conf: Conf) -> StreamCompressor {
+        StreamCompressor::with_dictionary(conf, &self.strings)
+    }
+
+    pub fn decoder(&self) -> StreamDecoder {
+        StreamDecoder::with_dictionary(&self.strings)
+    }
+}
+
+// Result of StreamDecoder::next_value.
+#[derive(Debug, PartialEq, Clone)]
+pub enum NextValue {
+    Value(EncodedData),
+    // The fed bytes end inside the next value, which needs at least this many more bytes.
+    NeedBytes(usize),
+    // Every fed byte was consumed.
+    Empty,
+}
+
+pub struct StreamDecoder {
+    aliases: Vec<EncodedData>,
+    // Bytes given to feed, those before `offset` already consumed by next_value.
+    buffer: Vec<u8>,
+    offset: usize,
+    // Whether top level Defines only fill the aliases, see consume_defines.
+    standalone_defines: bool,
+    options: DecodeOptions,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self {
+            aliases: vec![],
+            buffer: vec![],
+            offset: 0,
+            standalone_defines: false,
+            options: DecodeOptions::default(),
+        }
     }
 
-    pub fn compress(object: &EncodedData) -> Vec<u8> {
-        object.encode()
+    pub fn with_dictionary(dictionary: &[String]) -> Self {
+        Self {
+            aliases: unique(dictionary)
+                .into_iter()
+                .map(|s| EncodedData::String(s.to_string()))
+                .collect(),
+            buffer: vec![],
+            offset: 0,
+            standalone_defines: false,
+            options: DecodeOptions::default(),
+        }
     }
 
-    pub fn decompress(data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
-        let (decoded, size) = EncodedData::decode(data).map_err(DecodeError::BadFormat)?;
+    // When enabled, top level Defines, e.g. written ahead of the values to warm the dictionary,
+    // are consumed into the aliases and decoding goes on with the next value, instead of being
+    // decoded as that value. Only for streams written that way: a StreamCompressor defines the
+    // aliases of repeated top level values in place.
+    pub fn consume_defines(&mut self, enabled: bool) {
+        self.standalone_defines = enabled;
+    }
+
+    // Options each value is decoded with, e.g. max_string_bytes to bound the strings of every
+    // value. Values taken from the aliases were charged when defined.
+    pub fn set_decode_options(&mut self, options: DecodeOptions) {
+        self.options = options;
+    }
+
+    // Counterpart of StreamCompressor::reset. Bytes buffered by feed are kept.
+    pub fn reset(&mut self) {
+        self.aliases.clear();
+    }
+
+    // Buffers bytes of the stream as they arrive, e.g. from a socket, for next_value.
+    pub fn feed(&mut self, data: &[u8]) {
+        // Consumed bytes are dropped once they make up half of the buffer, so that each byte is
+        // only moved a few times.
+        if self.offset > self.buffer.len() / 2 {
+            self.buffer.drain(..self.offset);
+            self.offset = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Number of fed bytes not consumed by next_value yet. Non zero once the stream has ended means
+    // its last value is truncated.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
+    // Decodes the next value of the fed bytes. When they end before it does, it is decoded again
+    // from its first byte on the next call, once more bytes are fed, and the aliases defined by
+    // the incomplete value are dropped until then. Corrupted values fail even if more bytes could
+    // follow.
+    pub fn next_value(&mut self) -> Result<NextValue, DecodeError> {
+        if self.buffered() == 0 {
+            return Ok(NextValue::Empty);
+        }
+        let nb_aliases = self.aliases.len();
+        let buffer = std::mem::take(&mut self.buffer);
+        let ret = self.decompress(&buffer[self.offset..]);
+        self.buffer = buffer;
+        match ret {
+            Ok((o, size)) => {
+                self.offset += size;
+                Ok(NextValue::Value(o))
+            }
+            Err(DecodeError::BadFormat(encoded_data::DecodeError::MissingBytes(missing))) => {
+                self.aliases.truncate(nb_aliases);
+                Ok(NextValue::NeedBytes(missing))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Reset markers before the value are consumed, each one dropping the aliases defined so far,
+    // as well as the Defines before and between them if consume_defines is enabled.
+    pub fn decompress(&mut self, data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
+        let mut offset = 0;
+        loop {
+            let skipped = data[offset..]
+                .iter()
+                .take_while(|ctrl| **ctrl == RESET_MARKER)
+                .count();
+            if skipped > 0 {
+                self.aliases.clear();
+            }
+            offset += skipped;
+            let standalone = self.standalone_defines
+                && data.get(offset) == Some(&(data_type::SPECIAL << 5 | special_type::DEFINE));
+            let aliases = &mut self.aliases;
+            let (o, size) = EncodedData::decode_with(&data[offset..], &self.options, &mut |o| {
+                EncodedData::resolve_alias(aliases, o)
+            })
+            .map_err(|e| DecodeError::BadFormat(e.shifted(offset)))?;
+            offset += size;
+            if !standalone {
+                return Ok((o, offset));
+            }
+        }
+    }
+
+    // Decodes the values concatenated in `data`, as written by encode_batch, yielding only the
+    // ones matching `pred`. The others are decoded too, for the aliases they define. Stops after
+    // the first error.
+    pub fn filter_decode<'a, F>(
+        &'a mut self,
+        mut data: &'a [u8],
+        pred: F,
+    ) -> impl Iterator<Item = Result<EncodedData, DecodeError>> + 'a
+    where
+        F: Fn(&EncodedData) -> bool + 'a,
+    {
+        std::iter::from_fn(move || {
+            while !data.is_empty() {
+                match self.decompress(data) {
+                    Ok((o, size)) => {
+                        data = &data[size..];
+                        if pred(&o) {
+                            return Some(Ok(o));
+                        }
+                    }
+                    Err(e) => {
+                        data = &[];
+                        return Some(Err(e));
+                    }
+                }
+            }
+            None
+        })
+    }
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Compresses all values with a single StreamCompressor, so that strings repeated across values
+// are aliased, and concatenates them.
+pub fn encode_batch(values: &[serde_json::Value]) -> Vec<u8> {
+    let values: Vec<_> = values.iter().cloned().map(EncodedData::from).collect();
+    let mut compressor = StreamCompressor::new(Conf::default());
+    compressor.expect_subtrees(&values);
+    let mut ret = vec![];
+    for value in values.iter() {
+        compressor.compress_into(value, &mut ret);
+        compressor.cache.policy.tick();
+    }
+    ret
+}
+
+pub fn decode_batch(mut data: &[u8]) -> Result<Vec<serde_json::Value>, DecodeError> {
+    let mut decoder = StreamDecoder::new();
+    let mut ret = vec![];
+    while !data.is_empty() {
+        let (o, size) = decoder.decompress(data)?;
+        ret.push(o.try_into().map_err(DecodeError::Json)?);
+        data = &data[size..];
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoded_data::EncodedInteger;
+
+    #[test]
+    fn float_aliases() {
+        let array = EncodedData::Array(vec![EncodedData::Float(21.5); 20]);
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let data = compressor.compress(&array);
+
+        let (raw, size) = EncodedData::decode(&data).unwrap();
+        assert_eq!(size, data.len());
+        let mut expected = vec![EncodedData::Special(EncodedSpecial::Define(Box::new(
+            EncodedData::Float(21.5),
+        )))];
+        expected.extend(vec![EncodedData::Alias(0); 19]);
+        assert_eq!(raw, EncodedData::Array(expected));
+        assert_eq!(data.len(), 2 + 1 + 9 + 19);
+
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(decoder.decompress(&data).unwrap(), (array, data.len()));
+    }
+
+    #[test]
+    fn float_aliases_keep_sign_of_zero() {
+        let array = EncodedData::Array(vec![EncodedData::Float(0.0), EncodedData::Float(-0.0)]);
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let data = compressor.compress(&array);
+        let (decoded, _) = StreamDecoder::new().decompress(&data).unwrap();
+        match decoded {
+            EncodedData::Array(list) => {
+                assert_eq!(list.len(), 2);
+                match (&list[0], &list[1]) {
+                    (EncodedData::Float(a), EncodedData::Float(b)) => {
+                        assert!(a.is_sign_positive());
+                        assert!(b.is_sign_negative());
+                    }
+                    _ => panic!("expected floats"),
+                }
+            }
+            _ => panic!("expected an array"),
+        }
+    }
+
+    #[test]
+    fn string_aliases() {
+        let s = EncodedData::String("abc".to_string());
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let data: Vec<u8> = (0..3).flat_map(|_| compressor.compress(&s)).collect();
+        assert_eq!(
+            EncodedData::decode_all(&data).unwrap().0,
+            vec![
+                s.clone(),
+                EncodedData::Special(EncodedSpecial::Define(Box::new(s.clone()))),
+                EncodedData::Alias(0),
+            ]
+        );
+
+        let mut decoder = StreamDecoder::new();
+        let mut data_ref = &data[..];
+        for _ in 0..3 {
+            let (o, size) = decoder.decompress(data_ref).unwrap();
+            assert_eq!(o, s);
+            data_ref = &data_ref[size..];
+        }
+        assert!(data_ref.is_empty());
+    }
+
+    #[test]
+    fn non_cacheable_values() {
+        let array = EncodedData::Array(vec![
+            EncodedData::Integer(EncodedInteger::Positive(1234)),
+            EncodedData::Integer(EncodedInteger::Positive(1234)),
+            EncodedData::String("a".to_string()),
+            EncodedData::String("a".to_string()),
+        ]);
+        let mut compressor = StreamCompressor::new(Conf::default());
+        assert_eq!(compressor.compress(&array), array.encode());
+    }
+
+    #[test]
+    fn unknown_alias() {
+        assert_eq!(
+            StreamDecoder::new().decompress(&EncodedData::Alias(3).encode()),
+            Err(DecodeError::BadFormat(
+                encoded_data::DecodeError::UnknownAlias(3)
+            ))
+        );
+    }
+
+    #[test]
+    fn batch() {
+        let values: Vec<serde_json::Value> = (0..100)
+            .map(|i| {
+                serde_json::json!({
+                    "sensor": "kitchen-thermometer",
+                    "unit": "celsius",
+                    "value": i,
+                })
+            })
+            .collect();
+        let data = encode_batch(&values);
+        let independent: usize = values
+            .iter()
+            .map(|v| EncodedData::from(v.clone()).encode().len())
+            .sum();
+        assert!(data.len() < independent);
+        assert_eq!(decode_batch(&data).unwrap(), values);
+        assert!(decode_batch(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn future_cache_decay() {
+        let conf = Conf {
+            decay_period: 16,
+            ..Conf::default()
+        };
+        let mut compressor = StreamCompressor::new(conf);
+        let repeated = EncodedData::String("repeated".to_string());
+        let mut nb_alias = 0;
+        for i in 0..10_000 {
+            let data = compressor.compress(&EncodedData::Array(vec![
+                EncodedData::String(format!("unique-{}", i)),
+                repeated.clone(),
+            ]));
+            if let (EncodedData::Array(list), _) = EncodedData::decode(&data).unwrap() {
+                if list[1] == EncodedData::Alias(0) {
+                    nb_alias += 1;
+                }
+            }
+            assert!(compressor.cache.policy.future_cache.len() <= 16);
+        }
+        assert_eq!(nb_alias, 10_000 - 2);
+        assert_eq!(
+            compressor.cache.policy.available_future_cache
+                + compressor.cache.policy.future_cache.len(),
+            Conf::default().max_future_cache
+        );
+    }
+
+    #[test]
+    fn future_cache_bytes() {
+        let conf = Conf {
+            max_future_cache_bytes: 64 * 1024,
+            decay_period: 0,
+            ..Conf::default()
+        };
+        let mut compressor = StreamCompressor::new(conf);
+        let repeated = EncodedData::String("repeated".to_string());
+        for i in 0..1000 {
+            let large = format!("{:010000}", i);
+            compressor.compress(&EncodedData::Array(vec![
+                EncodedData::String(large),
+                repeated.clone(),
+            ]));
+            let policy = &compressor.cache.policy;
+            let bytes: usize = policy.future_cache.keys().map(|s| s.len()).sum();
+            assert_eq!(bytes, policy.future_cache_bytes);
+            assert!(bytes <= 64 * 1024);
+        }
+        // Evictions don't prevent the repeated string from being aliased.
+        assert_eq!(compressor.dictionary(), vec!["repeated"]);
+
+        let policy = &mut compressor.cache.policy;
+        policy.reset();
+        assert_eq!(policy.future_cache_bytes, 0);
+        // Strings larger than the whole budget are not tracked.
+        let huge = "x".repeat(64 * 1024 + 1);
+        assert_eq!(policy.on_seen(&huge, huge.len() + 4, 1), Admission::Inline);
+        assert!(policy.future_cache.is_empty());
+    }
+
+    #[test]
+    fn estimate_ratio() {
+        let repetitive = serde_json::Value::Array(
+            (0..100)
+                .map(|_| serde_json::json!({"status": "connection-established"}))
+                .collect(),
+        );
+        let random = serde_json::Value::Array(
+            (0..100)
+                .map(|i| serde_json::json!({"status": format!("connection-{:012}", i * 7919)}))
+                .collect(),
+        );
+        let compressor = StreamCompressor::new(Conf::default());
+        let repetitive_ratio = compressor.estimate_ratio(&repetitive);
+        let random_ratio = compressor.estimate_ratio(&random);
+        assert!(repetitive_ratio < random_ratio);
+
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let data = compressor.compress(&EncodedData::from(repetitive.clone()));
+        let json_len = serde_json::to_vec(&repetitive).unwrap().len() as f64;
+        assert!((repetitive_ratio - data.len() as f64 / json_len).abs() < 0.01);
+        // Strings already aliased by the compressor only cost their alias.
+        assert!(compressor.estimate_ratio(&repetitive) < repetitive_ratio);
+    }
+
+    #[test]
+    fn best_gains_ties() {
+        for _ in 0..10 {
+            let mut strings = GainPolicy::new(&Conf::default());
+            for s in ["bbbb", "aaaa", "cccc", "long string"] {
+                strings.future_cache.insert(s.to_string(), 3);
+            }
+            strings.future_cache.insert("dddd".to_string(), 2);
+            assert_eq!(
+                strings.get_best_gains(1),
+                vec![
+                    ("long string", 21),
+                    ("aaaa", 7),
+                    ("bbbb", 7),
+                    ("cccc", 7),
+                    ("dddd", 3),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn dictionary() {
+        let dictionary = vec!["abc".to_string(), "def".to_string(), "abc".to_string()];
+        let mut compressor = StreamCompressor::with_dictionary(Conf::default(), &dictionary);
+        let s = EncodedData::String("ghi".to_string());
+        for _ in 0..2 {
+            compressor.compress(&s);
+        }
+        assert_eq!(compressor.dictionary(), vec!["abc", "def", "ghi"]);
+
+        let data = compressor.compress(&EncodedData::String("def".to_string()));
+        assert_eq!(data, EncodedData::Alias(1).encode());
+        let mut decoder = StreamDecoder::with_dictionary(&dictionary);
+        assert_eq!(
+            decoder.decompress(&data),
+            Ok((EncodedData::String("def".to_string()), data.len()))
+        );
+    }
+
+    #[test]
+    fn alias_usage() {
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let array = EncodedData::Array(vec![
+            EncodedData::Float(1.5),
+            EncodedData::String("abc".to_string()),
+            EncodedData::Float(1.5),
+            EncodedData::String("abc".to_string()),
+            EncodedData::Float(1.5),
+            EncodedData::String("abc".to_string()),
+            EncodedData::Float(2.5),
+            EncodedData::Float(1.5),
+        ]);
+        compressor.compress(&array);
+        // 1.5 is defined at once, "abc" on its second occurrence.
+        let mut expected = HashMap::new();
+        expected.insert(0, 3);
+        expected.insert(1, 1);
+        expected.insert(2, 0);
+        assert_eq!(compressor.alias_usage(), expected);
+
+        compressor.compress(&EncodedData::Float(2.5));
+        assert_eq!(compressor.alias_usage()[&2], 1);
+    }
+
+    #[test]
+    fn batch_subtrees() {
+        let metadata = serde_json::json!({
+            "f0": 0, "f1": 1.5, "f2": "two", "f3": true, "f4": null,
+            "f5": [5], "f6": {"six": 6}, "f7": -7, "f8": "eight", "f9": 9,
+        });
+        let values: Vec<serde_json::Value> = (0..10)
+            .map(|i| serde_json::json!({"metadata": metadata, "value": format!("unique-{}", i)}))
+            .collect();
+        let data = encode_batch(&values);
+        assert_eq!(decode_batch(&data).unwrap(), values);
+
+        let metadata = EncodedData::from(metadata).encode();
+        let nb_copies = data
+            .windows(metadata.len())
+            .filter(|w| *w == &metadata[..])
+            .count();
+        assert_eq!(nb_copies, 1);
+        let raw: usize = values
+            .iter()
+            .map(|v| EncodedData::from(v.clone()).encode().len())
+            .sum();
+        assert!(data.len() < raw - 8 * metadata.len());
+    }
+
+    #[test]
+    fn document_subtrees() {
+        let point = serde_json::json!({"x": 1.5, "y": -2, "z": 3, "label": "corner", "ok": true});
+        let value = serde_json::json!({"from": point, "to": point, "other": {"x": 1}});
+        let value = EncodedData::from(value);
+        let mut compressor = StreamCompressor::new(Conf {
+            dedup_subtrees: true,
+            ..Conf::default()
+        });
+        let data = compressor.compress(&value);
+        let plain = StreamCompressor::new(Conf::default()).compress(&value);
+        assert!(data.len() < plain.len());
+
+        let point = EncodedData::from(point).encode();
+        let nb_copies = data
+            .windows(point.len())
+            .filter(|w| *w == &point[..])
+            .count();
+        assert_eq!(nb_copies, 1);
+        // The first copy gets a Define prefix, the second one is a 1 byte alias.
+        assert_eq!(data.len(), value.encode().len() - point.len() + 2);
+        assert_eq!(
+            StreamDecoder::new().decompress(&data),
+            Ok((value.clone(), data.len()))
+        );
+
+        // Later values alias both copies.
+        let second = compressor.compress(&value);
+        assert_eq!(second.len(), data.len() - point.len());
+    }
+
+    #[test]
+    fn reset() {
+        let mut compressor = StreamCompressor::new(Conf {
+            max_cache: 2,
+            ..Conf::default()
+        });
+        let mut decoder = StreamDecoder::new();
+        let values: Vec<_> = ["abc", "def", "ghi"]
+            .iter()
+            .map(|s| EncodedData::String(s.to_string()))
+            .collect();
+        for _ in 0..2 {
+            let data: Vec<u8> = values
+                .iter()
+                .chain(values.iter())
+                .flat_map(|o| compressor.compress(o))
+                .collect();
+            let (decoded, _) = EncodedData::decode_all(&data).unwrap();
+            // "abc" and "def" fill the cache on their second occurrence, "ghi" is never aliased.
+            assert_eq!(
+                decoded[3..],
+                [
+                    EncodedData::Special(EncodedSpecial::Define(Box::new(values[0].clone()))),
+                    EncodedData::Special(EncodedSpecial::Define(Box::new(values[1].clone()))),
+                    values[2].clone(),
+                ]
+            );
+            assert_eq!(compressor.dictionary(), vec!["abc", "def"]);
+
+            let mut data_ref = &data[..];
+            for o in values.iter().chain(values.iter()) {
+                let (decoded, size) = decoder.decompress(data_ref).unwrap();
+                assert_eq!(&decoded, o);
+                data_ref = &data_ref[size..];
+            }
+            assert_eq!(
+                compressor.compress(&values[1]),
+                EncodedData::Alias(1).encode()
+            );
+
+            compressor.reset();
+            decoder.reset();
+            assert!(compressor.dictionary().is_empty());
+            assert!(compressor.alias_usage().is_empty());
+        }
+    }
+
+    #[test]
+    fn standalone_defines() {
+        let define = |s: &str| {
+            EncodedData::Special(EncodedSpecial::Define(Box::new(EncodedData::String(
+                s.to_string(),
+            ))))
+            .encode()
+        };
+        let value = EncodedData::Array(vec![EncodedData::Alias(1), EncodedData::Alias(0)]);
+        let data = [define("first"), define("second"), value.encode()].concat();
+        let expected = EncodedData::from(serde_json::json!(["second", "first"]));
+
+        let mut decoder = StreamDecoder::new();
+        decoder.consume_defines(true);
+        assert_eq!(
+            decoder.decompress(&data),
+            Ok((expected.clone(), data.len()))
+        );
+        assert_eq!(
+            decoder.decompress(&[define("third"), vec![RESET_MARKER]].concat()),
+            Err(DecodeError::BadFormat(
+                encoded_data::DecodeError::MissingBytes(1)
+            ))
+        );
+
+        // Incomplete Defines are read again once the value arrives.
+        let mut decoder = StreamDecoder::new();
+        decoder.consume_defines(true);
+        decoder.feed(&data[..data.len() - 1]);
+        assert_eq!(decoder.next_value(), Ok(NextValue::NeedBytes(1)));
+        decoder.feed(&data[data.len() - 1..]);
+        assert_eq!(decoder.next_value(), Ok(NextValue::Value(expected)));
+        assert_eq!(decoder.buffered(), 0);
+
+        // Otherwise a top level Define is the value itself.
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(
+            decoder.decompress(&data),
+            Ok((
+                EncodedData::String("first".to_string()),
+                define("first").len()
+            ))
+        );
+    }
+
+    #[test]
+    fn reset_marker() {
+        let values: Vec<_> = [
+            "sensor-a", "sensor-b", "sensor-a", "sensor-b", "sensor-a", "sensor-b",
+        ]
+        .iter()
+        .map(|s| EncodedData::String(s.to_string()))
+        .collect();
+        let stream = |values: &[EncodedData]| {
+            let mut compressor = StreamCompressor::new(Conf::default());
+            let data: Vec<u8> = values.iter().flat_map(|o| compressor.compress(o)).collect();
+            data
+        };
+        // Both streams define alias 0, as a different string.
+        let first = stream(&values);
+        let second = stream(&values[1..]);
+        let expected: Vec<_> = values.iter().chain(values[1..].iter()).cloned().collect();
+        let decode = |data: &[u8]| {
+            let mut decoder = StreamDecoder::new();
+            let mut offset = 0;
+            let mut decoded = vec![];
+            while offset < data.len() {
+                let (o, size) = decoder.decompress(&data[offset..]).unwrap();
+                decoded.push(o);
+                offset += size;
+            }
+            decoded
+        };
+        assert_ne!(decode(&[first.clone(), second.clone()].concat()), expected);
+        let data = [first, vec![RESET_MARKER], second].concat();
+        assert_eq!(decode(&data), expected);
+
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let mut appended = vec![];
+        values
+            .iter()
+            .for_each(|o| compressor.append_to(&mut appended, o));
+        compressor.append_reset(&mut appended);
+        values[1..]
+            .iter()
+            .for_each(|o| compressor.append_to(&mut appended, o));
+        assert_eq!(appended, data);
+
+        // Without a StreamDecoder, the marker is a value of its own.
+        assert_eq!(
+            EncodedData::decode(&[RESET_MARKER]),
+            Ok((EncodedData::Special(EncodedSpecial::Reset), 1))
+        );
+        assert_eq!(
+            StreamDecoder::new().decompress(&[RESET_MARKER]),
+            Err(DecodeError::BadFormat(
+                encoded_data::DecodeError::MissingBytes(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn next_value() {
+        let records: Vec<_> = (0..2)
+            .map(|i| EncodedData::from(serde_json::json!({"sensor": "sensor-a", "value": i})))
+            .collect();
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let first = compressor.compress(&records[0]);
+        let second = compressor.compress(&records[1]);
+        assert!(second.contains(&(data_type::SPECIAL << 5 | special_type::DEFINE)));
+        let split = second.len() - 2;
+
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(decoder.next_value(), Ok(NextValue::Empty));
+        decoder.feed(&first);
+        decoder.feed(&second[..split]);
+        assert_eq!(
+            decoder.next_value(),
+            Ok(NextValue::Value(records[0].clone()))
+        );
+        assert!(matches!(decoder.next_value(), Ok(NextValue::NeedBytes(_))));
+        assert!(matches!(decoder.next_value(), Ok(NextValue::NeedBytes(_))));
+        assert_eq!(decoder.buffered(), split);
+        decoder.feed(&second[split..]);
+        assert_eq!(
+            decoder.next_value(),
+            Ok(NextValue::Value(records[1].clone()))
+        );
+        assert_eq!(decoder.next_value(), Ok(NextValue::Empty));
+        assert_eq!(decoder.buffered(), 0);
+
+        // The alias was only defined once, the next one gets the next id.
+        let other = EncodedData::from(serde_json::json!({"sensor": "sensor-b", "value": 0}));
+        for o in [&other, &other, &other, &records[0]].iter() {
+            decoder.feed(&compressor.compress(o));
+            assert_eq!(decoder.next_value(), Ok(NextValue::Value((*o).clone())));
+        }
+
+        // Many values fed at once are read in place.
+        let values: Vec<_> = (0..1000)
+            .map(|i| EncodedData::from(serde_json::json!(i)))
+            .collect();
+        decoder.feed(
+            &values
+                .iter()
+                .flat_map(EncodedData::encode)
+                .collect::<Vec<_>>(),
+        );
+        for o in values.iter() {
+            assert_eq!(decoder.next_value(), Ok(NextValue::Value(o.clone())));
+        }
+        assert_eq!(decoder.next_value(), Ok(NextValue::Empty));
+
+        // A Sized length cutting its content short is not a truncated stream.
+        decoder.feed(&[
+            data_type::SPECIAL << 5 | special_type::SIZED,
+            1,
+            0x82,
+            0x21,
+            1,
+        ]);
+        decoder.feed(&[0x21; 100]);
+        for _ in 0..2 {
+            assert_eq!(
+                decoder.next_value(),
+                Err(DecodeError::BadFormat(
+                    encoded_data::DecodeError::BadSizedLength(1)
+                ))
+            );
+        }
+
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&[0xFF]);
+        assert!(decoder.next_value().is_err());
+
+        // Each value gets the whole string budget.
+        let mut decoder = StreamDecoder::new();
+        decoder.set_decode_options(encoded_data::DecodeOptions {
+            max_string_bytes: Some(19),
+            ..Default::default()
+        });
+        let mut compressor = StreamCompressor::new(Conf::default());
+        for _ in 0..3 {
+            decoder.feed(&compressor.compress(&records[0]));
+            assert_eq!(
+                decoder.next_value(),
+                Ok(NextValue::Value(records[0].clone()))
+            );
+        }
+        decoder.feed(&compressor.compress(&EncodedData::String("a".repeat(20))));
+        assert_eq!(
+            decoder.next_value(),
+            Err(DecodeError::BadFormat(
+                encoded_data::DecodeError::StringBudgetExceeded
+            ))
+        );
+    }
+
+    #[test]
+    fn key_stats() {
+        let value = serde_json::json!({
+            "events": (0..100)
+                .map(|i| serde_json::json!({"id": i, "tags": {"id": "nested"}}))
+                .collect::<Vec<_>>(),
+        });
+        let stats = StreamCompressor::key_stats(&value);
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats["events"], 1);
+        assert_eq!(stats["tags"], 100);
+        // Both the element and the nested object keys.
+        assert_eq!(stats["id"], 200);
+        assert!(StreamCompressor::key_stats(&serde_json::json!([1, "a"])).is_empty());
+    }
+
+    #[test]
+    fn optimal_dictionary() {
+        let value = serde_json::json!({
+            "events": (0..100)
+                .map(|i| serde_json::json!({"kind": "temperature-reading", "value": i}))
+                .collect::<Vec<_>>(),
+            "units": [["celsius", "kelvin"], ["celsius", "kelvin"], ["celsius", "kelvin"]],
+            "once": "not repeated at all",
+        });
+        let dictionary = StreamCompressor::optimal_dictionary(&value, 8);
+        assert_eq!(
+            dictionary[0],
+            EncodedData::String("temperature-reading".to_string())
+        );
+        // The strings of the repeated array only appear in it.
+        let units = EncodedData::from(serde_json::json!(["celsius", "kelvin"]));
+        assert_eq!(dictionary[1..], [units]);
+        assert!(StreamCompressor::optimal_dictionary(&value, 0).is_empty());
+
+        let strings: Vec<_> = dictionary
+            .iter()
+            .filter_map(|o| o.as_str().map(str::to_string))
+            .collect();
+        let mut compressor = StreamCompressor::with_dictionary(Conf::default(), &strings);
+        let mut plain = StreamCompressor::new(Conf::default());
+        let o = EncodedData::from(value);
+        assert!(compressor.compress(&o).len() < plain.compress(&o).len());
+    }
+
+    #[test]
+    fn custom_policy() {
+        struct NeverCache;
+
+        impl CachePolicy for NeverCache {
+            fn on_seen(&mut self, _: &str, _: usize, _: usize) -> Admission {
+                Admission::Inline
+            }
+
+            fn reset(&mut self) {}
+        }
+
+        let value = EncodedData::from(serde_json::json!([
+            {"name": "a long repeated string", "n": 1},
+            {"name": "a long repeated string", "n": null},
+            ["a long repeated string", "a long repeated string"],
+        ]));
+        let mut compressor = StreamCompressor::with_policy(Conf::default(), NeverCache);
+        for _ in 0..3 {
+            assert_eq!(compressor.compress(&value), value.encode());
+        }
+        assert!(compressor.dictionary().is_empty());
+        assert!(
+            StreamCompressor::new(Conf::default())
+                .compress(&value)
+                .len()
+                < value.encode().len()
+        );
+    }
+
+    #[test]
+    fn train_dictionary() {
+        let samples: Vec<_> = (0..20)
+            .map(|i| {
+                serde_json::json!({
+                    "status": "connection-established",
+                    "id": format!("unique-{}", i),
+                    "kind": if i % 2 == 0 { "request" } else { "x" },
+                })
+            })
+            .collect();
+        let dictionary = Dictionary::train(&samples, 16);
+        assert_eq!(
+            dictionary.strings,
+            vec!["connection-established", "request", "x"]
+        );
+        assert_eq!(
+            Dictionary::train(&samples, 1).strings,
+            vec!["connection-established"]
+        );
+
+        let mut compressor = dictionary.compressor(Conf::default());
+        let mut decoder = dictionary.decoder();
+        let mut plain = StreamCompressor::new(Conf::default());
+        let value = EncodedData::from(samples[0].clone());
+        let data = compressor.compress(&value);
+        assert!(data.len() < plain.compress(&value).len());
+        assert_eq!(decoder.decompress(&data), Ok((value, data.len())));
+    }
+
+    #[test]
+    fn filter_decode() {
+        let records: Vec<_> = (0..100)
+            .map(|i| {
+                if i % 7 == 0 {
+                    serde_json::json!({"level": "error", "id": i, "error": "timeout"})
+                } else {
+                    serde_json::json!({"level": "info", "id": i})
+                }
+            })
+            .collect();
+        let data = encode_batch(&records);
+
+        let mut decoder = StreamDecoder::new();
+        let errors: Vec<_> = decoder
+            .filter_decode(&data, |o| match o {
+                EncodedData::Object(map) => map.contains_key("error"),
+                _ => false,
+            })
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected: Vec<_> = records
+            .iter()
+            .filter(|r| r.get("error").is_some())
+            .cloned()
+            .map(EncodedData::from)
+            .collect();
+        assert_eq!(expected.len(), 15);
+        assert_eq!(errors, expected);
+
+        let mut decoder = StreamDecoder::new();
+        let mut truncated = decoder.filter_decode(&data[..data.len() - 1], |_| false);
+        assert!(matches!(
+            truncated.next(),
+            Some(Err(DecodeError::BadFormat(_)))
+        ));
+        assert_eq!(truncated.next(), None);
+    }
+
+    #[test]
+    fn append_to() {
+        let values: Vec<_> = (0..3)
+            .map(|i| EncodedData::from(serde_json::json!({"sensor": "sensor-a", "value": i})))
+            .collect();
+        let mut compressor = StreamCompressor::new(Conf::default());
+        let mut buf = vec![];
+        for o in values.iter() {
+            compressor.append_to(&mut buf, o);
+        }
+        assert!(buf.len() < values.iter().map(|o| o.encode().len()).sum());
+
+        let mut decoder = StreamDecoder::new();
+        let mut offset = 0;
+        let mut decoded = vec![];
+        while offset < buf.len() {
+            let (o, size) = decoder.decompress(&buf[offset..]).unwrap();
+            decoded.push(o);
+            offset += size;
+        }
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn shared_prefixes() {
+        let conf = || Conf {
+            share_prefixes: true,
+            ..Conf::default()
+        };
+        let events: Vec<_> = (0..20)
+            .map(|i| {
+                EncodedData::from(serde_json::json!({
+                    "type": "event",
+                    "v": 1,
+                    "id": i,
+                    "payload": format!("payload-{}", i),
+                }))
+            })
+            .collect();
+        let mut compressor = StreamCompressor::new(conf());
+        let mut data = vec![];
+        for o in events.iter() {
+            compressor.append_to(&mut data, o);
+        }
+        let count = |data: &[u8], s: &[u8]| data.windows(s.len()).filter(|w| w == &s).count();
+        // In the first event, which has nothing to share with yet, then in the prefix definition.
+        assert_eq!(count(&data, b"event"), 2);
+        assert_eq!(count(&data, b"type"), 2);
+        let mut plain = StreamCompressor::new(Conf::default());
+        let plain: Vec<u8> = events.iter().flat_map(|o| plain.compress(o)).collect();
+        assert!(data.len() < plain.len());
+
+        let mut decoder = StreamDecoder::new();
+        let mut offset = 0;
+        for o in events.iter() {
+            let (decoded, size) = decoder.decompress(&data[offset..]).unwrap();
+            assert_eq!(&decoded, o);
+            offset += size;
+        }
+        assert_eq!(offset, data.len());
+        // The aliased prefix can't be merged without the stream's aliases.
+        assert!(matches!(
+            EncodedData::decode_all(&data),
+            Err(encoded_data::DecodeError::BadExtend("define"))
+        ));
+
+        // Ordered objects share their leading entries, in order.
+        let ordered: Vec<_> = (0..5)
+            .map(|i| {
+                EncodedData::from_json_str_ordered(&format!(
+                    r#"{{"type":"event","v":1,"id":{},"type2":"x{}"}}"#,
+                    i, i
+                ))
+                .unwrap()
+            })
+            .collect();
+        let mut compressor = StreamCompressor::new(conf());
+        let data: Vec<u8> = ordered
+            .iter()
+            .flat_map(|o| compressor.compress(o))
+            .collect();
+        assert_eq!(count(&data, b"event"), 2);
+        let mut decoder = StreamDecoder::new();
+        let mut offset = 0;
+        for o in ordered.iter() {
+            let (decoded, size) = decoder.decompress(&data[offset..]).unwrap();
+            assert!(decoded.eq_logical(o));
+            offset += size;
+        }
+
+        // Keys of the prefix repeated after it are kept.
+        let repeated: Vec<_> = (0..5)
+            .map(|i| {
+                EncodedData::from_json_str_ordered(&format!(
+                    r#"{{"type":"event","v":1,"x":{},"type":"dup"}}"#,
+                    i
+                ))
+                .unwrap()
+            })
+            .collect();
+        let mut compressor = StreamCompressor::new(conf());
+        let data: Vec<u8> = repeated
+            .iter()
+            .flat_map(|o| compressor.compress(o))
+            .collect();
+        assert_eq!(count(&data, b"event"), 2);
+        let mut decoder = StreamDecoder::new();
+        let mut offset = 0;
+        for o in repeated.iter() {
+            let (decoded, size) = decoder.decompress(&data[offset..]).unwrap();
+            assert!(decoded.eq_logical(o));
+            offset += size;
+        }
+    }
 
-        Ok((decoded, size))
+    #[test]
+    fn display() {
+        let mut compressor = StreamCompressor::new(Conf::default());
+        assert_eq!(compressor.to_string(), "");
+        let value = EncodedData::from(serde_json::json!(["sensor-a", 1.5, "sensor-a", 1.5]));
+        for _ in 0..3 {
+            compressor.compress(&value);
+        }
+        assert_eq!(
+            compressor.to_string(),
+            "0 -> 1.5 (used 5 times)\n1 -> \"sensor-a\" (used 4 times)\n"
+        );
     }
 }
@@ -0,0 +1,629 @@
+use crate::define::data_type;
+use crate::encoded_data::{self, EncodedData};
+use crate::stream_compressor::{StreamCompressor, RESET_MARKER};
+use std::io::{self, Write};
+
+// Writes values to `w` as they are produced, arrays and objects included: begin_array and
+// begin_object declare the number of elements or entries, which are then written one at a time,
+// so that the container is never held in memory. The output decodes as if the whole values had
+// been encoded by EncodedData::encode, object entries being in written order. Objects whose values
+// arrive out of order can be buffered instead, see begin_unordered_object.
+pub struct StreamEncoder<W: Write> {
+    w: W,
+    // Open containers, innermost last.
+    open: Vec<Open>,
+    buf: Vec<u8>,
+    // Compresses the written values, for a StreamDecoder to read, see with_compressor.
+    compressor: Option<StreamCompressor>,
+}
+
+enum Open {
+    // Number of elements left to write.
+    Array(u64),
+    // Number of entries left to start, and whether the last key is waiting for its value.
+    Object {
+        left: u64,
+        key: bool,
+    },
+    // Entries written so far, with the encoding of their value, and whether the last key is
+    // waiting for its value. Whatever is written in the object goes to the last entry.
+    Unordered {
+        entries: Vec<(String, Vec<Part>)>,
+        key: bool,
+    },
+}
+
+// Piece of a buffered entry. The compressor defines strings and subtrees at their first
+// occurrence, so the values it compresses are kept until the entries are sorted and written.
+enum Part {
+    Data(Vec<u8>),
+    Value(EncodedData),
+}
+
+fn invalid_input(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+impl<W: Write> StreamEncoder<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            open: vec![],
+            buf: vec![],
+            compressor: None,
+        }
+    }
+
+    // Same as new, the values given to write_element and write_value being compressed by
+    // `compressor`, so that the strings and subtrees repeated across them are aliased. The output
+    // is then read by a StreamDecoder.
+    pub fn with_compressor(w: W, compressor: StreamCompressor) -> Self {
+        Self {
+            compressor: Some(compressor),
+            ..Self::new(w)
+        }
+    }
+
+    // Counts a value about to be written in the innermost container, if any: the next element of
+    // an array, or the value of the last key of an object.
+    fn value(&mut self) -> io::Result<()> {
+        match self.open.last_mut() {
+            Some(Open::Array(0)) => Err(invalid_input(
+                "more elements than the declared array length",
+            )),
+            Some(Open::Array(left)) => {
+                *left -= 1;
+                Ok(())
+            }
+            Some(Open::Object { key: false, .. }) | Some(Open::Unordered { key: false, .. }) => {
+                Err(invalid_input("object value without key"))
+            }
+            Some(Open::Object { key, .. }) | Some(Open::Unordered { key, .. }) => {
+                *key = false;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // The last entry of the innermost unordered object, if any.
+    fn buffered(&mut self) -> Option<&mut Vec<Part>> {
+        self.open.iter_mut().rev().find_map(|open| match open {
+            // Nothing is written in an unordered object before its first key.
+            Open::Unordered { entries, .. } => Some(&mut entries.last_mut().unwrap().1),
+            _ => None,
+        })
+    }
+
+    // Sends `data` to the innermost unordered object if any, or to `w`.
+    fn emit(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.buffered() {
+            Some(parts) => {
+                match parts.last_mut() {
+                    Some(Part::Data(buffered)) => buffered.extend_from_slice(data),
+                    _ => parts.push(Part::Data(data.to_vec())),
+                }
+                Ok(())
+            }
+            None => self.w.write_all(data),
+        }
+    }
+
+    // Encodes `value` to `w`, or to the innermost unordered object if any. Compressed values are
+    // buffered as is, to be compressed in the order they are written to `w`.
+    fn emit_value(&mut self, value: &EncodedData) -> io::Result<()> {
+        if self.compressor.is_some() {
+            if let Some(parts) = self.buffered() {
+                parts.push(Part::Value(value.clone()));
+                return Ok(());
+            }
+        }
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        match self.compressor.as_mut() {
+            Some(compressor) => compressor.append_to(&mut buf, value),
+            None => EncodedData::append_to(&mut buf, value),
+        }
+        let ret = self.emit(&buf);
+        self.buf = buf;
+        ret
+    }
+
+    fn write(&mut self, value: &EncodedData) -> io::Result<()> {
+        self.value()?;
+        self.emit_value(value)
+    }
+
+    // Starts an array of `len` elements, as a value of the innermost container if any.
+    pub fn begin_array(&mut self, len: u64) -> io::Result<()> {
+        self.value()?;
+        let header = encoded_data::encode_container_header(data_type::ARRAY, len as usize);
+        self.emit(&header)?;
+        self.open.push(Open::Array(len));
+        Ok(())
+    }
+
+    // Writes `value` as the next element of the innermost array, or as a value of its own when no
+    // container is open.
+    pub fn write_element(&mut self, value: &EncodedData) -> io::Result<()> {
+        if let Some(Open::Object { .. }) | Some(Open::Unordered { .. }) = self.open.last() {
+            return Err(invalid_input("array element written in an object"));
+        }
+        self.write(value)
+    }
+
+    // Closes the innermost array, failing if fewer elements than declared were written.
+    pub fn end_array(&mut self) -> io::Result<()> {
+        match self.open.last() {
+            Some(Open::Array(0)) => {
+                self.open.pop();
+                Ok(())
+            }
+            Some(Open::Array(left)) => Err(invalid_input(format!(
+                "{} elements missing from the array",
+                left
+            ))),
+            _ => Err(invalid_input("no array to end")),
+        }
+    }
+
+    // Starts an object of `len` entries, as a value of the innermost container if any. Each entry
+    // is a write_key followed by its value: write_value, or a nested begin_array/begin_object.
+    pub fn begin_object(&mut self, len: u64) -> io::Result<()> {
+        self.value()?;
+        let header = encoded_data::encode_container_header(data_type::OBJECT, len as usize);
+        self.emit(&header)?;
+        self.open.push(Open::Object {
+            left: len,
+            key: false,
+        });
+        Ok(())
+    }
+
+    // Starts an object whose entries are buffered until end_object, as a value of the innermost
+    // container if any, e.g. for values computed out of order. The object is then written in key
+    // order, as EncodedData::encode does, whatever the order of the write_key calls, and its
+    // length is the number of entries written. With a compressor, the values are compressed in
+    // that order too, once the outermost unordered object ends.
+    pub fn begin_unordered_object(&mut self) -> io::Result<()> {
+        self.value()?;
+        self.open.push(Open::Unordered {
+            entries: vec![],
+            key: false,
+        });
+        Ok(())
+    }
+
+    // Starts the next entry of the innermost object. Duplicate keys are written as is, the last
+    // one winning once decoded.
+    pub fn write_key(&mut self, k: &str) -> io::Result<()> {
+        match self.open.last_mut() {
+            Some(Open::Object { key: true, .. }) | Some(Open::Unordered { key: true, .. }) => {
+                return Err(invalid_input("object key without value"))
+            }
+            Some(Open::Unordered { entries, key }) => {
+                entries.push((k.to_string(), vec![]));
+                *key = true;
+                return Ok(());
+            }
+            Some(Open::Object { left: 0, .. }) => {
+                return Err(invalid_input(
+                    "more entries than the declared object length",
+                ))
+            }
+            Some(Open::Object { left, key }) => {
+                *left -= 1;
+                *key = true;
+            }
+            _ => return Err(invalid_input("object key written outside of an object")),
+        }
+        self.emit(&encoded_data::encode_key(k))
+    }
+
+    // Writes `value` as the value of the last key of the innermost object.
+    pub fn write_value(&mut self, value: &EncodedData) -> io::Result<()> {
+        match self.open.last() {
+            Some(Open::Object { .. }) | Some(Open::Unordered { .. }) => self.write(value),
+            _ => Err(invalid_input("object value written outside of an object")),
+        }
+    }
+
+    // Closes the innermost object, failing if fewer entries than declared were written.
+    pub fn end_object(&mut self) -> io::Result<()> {
+        match self.open.last() {
+            Some(Open::Object {
+                left: 0,
+                key: false,
+            }) => {
+                self.open.pop();
+                Ok(())
+            }
+            Some(Open::Unordered { key: false, .. }) => {
+                let mut entries = match self.open.pop() {
+                    Some(Open::Unordered { entries, .. }) => entries,
+                    _ => unreachable!(),
+                };
+                // Stable, for the last of duplicate keys to still win.
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                let header =
+                    encoded_data::encode_container_header(data_type::OBJECT, entries.len());
+                self.emit(&header)?;
+                for (k, parts) in entries {
+                    self.emit(&encoded_data::encode_key(&k))?;
+                    for part in parts {
+                        match part {
+                            Part::Data(data) => self.emit(&data)?,
+                            Part::Value(o) => self.emit_value(&o)?,
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Some(Open::Object { key: true, .. }) | Some(Open::Unordered { key: true, .. }) => {
+                Err(invalid_input("object key without value"))
+            }
+            Some(Open::Object { left, .. }) => Err(invalid_input(format!(
+                "{} entries missing from the object",
+                left
+            ))),
+            _ => Err(invalid_input("no object to end")),
+        }
+    }
+
+    // Ends a chunk between two top level values: the aliases of the compressor are dropped and a
+    // reset marker is written, then `w` is flushed. A StreamDecoder joining at any chunk boundary
+    // decodes what follows without the previous chunks, at the cost of repeated strings and
+    // subtrees being defined again in each chunk instead of aliased across chunks.
+    pub fn flush_chunk(&mut self) -> io::Result<()> {
+        if !self.open.is_empty() {
+            return Err(invalid_input("chunk flushed inside a container"));
+        }
+        if let Some(compressor) = self.compressor.as_mut() {
+            compressor.reset();
+        }
+        self.w.write_all(&[RESET_MARKER])?;
+        self.w.flush()
+    }
+
+    // Fails if a container is still open, as the output would not decode.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if !self.open.is_empty() {
+            return Err(invalid_input(format!(
+                "{} containers not ended",
+                self.open.len()
+            )));
+        }
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoded_data::EncodedInteger;
+    use crate::stream_compressor::{Conf, StreamDecoder};
+
+    #[test]
+    fn stream_array() {
+        let n = 100_000;
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_array(n).unwrap();
+        for i in 0..n {
+            encoder
+                .write_element(&EncodedData::Integer(EncodedInteger::Positive(i)))
+                .unwrap();
+        }
+        encoder.end_array().unwrap();
+        let data = encoder.into_inner().unwrap();
+
+        let expected = EncodedData::Array(
+            (0..n)
+                .map(|i| EncodedData::Integer(EncodedInteger::Positive(i)))
+                .collect(),
+        );
+        assert_eq!(data, expected.encode());
+        assert_eq!(EncodedData::decode(&data), Ok((expected, data.len())));
+    }
+
+    #[test]
+    fn nested() {
+        let value = EncodedData::from(serde_json::json!([[1, "a"], [], {"b": null}]));
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_array(3).unwrap();
+        encoder.begin_array(2).unwrap();
+        encoder
+            .write_element(&EncodedData::from(serde_json::json!(1)))
+            .unwrap();
+        encoder
+            .write_element(&EncodedData::from(serde_json::json!("a")))
+            .unwrap();
+        encoder.end_array().unwrap();
+        encoder.begin_array(0).unwrap();
+        encoder.end_array().unwrap();
+        encoder
+            .write_element(&EncodedData::from(serde_json::json!({"b": null})))
+            .unwrap();
+        encoder.end_array().unwrap();
+        // A value of its own after the array.
+        encoder.write_element(&value).unwrap();
+        let data = encoder.into_inner().unwrap();
+        assert_eq!(data, [value.encode(), value.encode()].concat());
+    }
+
+    #[test]
+    fn stream_object() {
+        let fields: Vec<_> = (0..10)
+            .map(|i| {
+                (
+                    format!("field_{}", i),
+                    EncodedData::from(serde_json::json!(i * 10)),
+                )
+            })
+            .collect();
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_object(fields.len() as u64).unwrap();
+        for (k, o) in fields.iter() {
+            encoder.write_key(k).unwrap();
+            encoder.write_value(o).unwrap();
+        }
+        encoder.end_object().unwrap();
+        let data = encoder.into_inner().unwrap();
+
+        let expected = EncodedData::Object(fields.into_iter().collect());
+        assert_eq!(EncodedData::decode(&data), Ok((expected, data.len())));
+    }
+
+    #[test]
+    fn nested_object() {
+        let value = EncodedData::from(serde_json::json!({"a": [1, {"b": "c"}], "d": {}}));
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_object(2).unwrap();
+        encoder.write_key("a").unwrap();
+        encoder.begin_array(2).unwrap();
+        encoder
+            .write_element(&EncodedData::from(serde_json::json!(1)))
+            .unwrap();
+        encoder.begin_object(1).unwrap();
+        encoder.write_key("b").unwrap();
+        encoder
+            .write_value(&EncodedData::from(serde_json::json!("c")))
+            .unwrap();
+        encoder.end_object().unwrap();
+        encoder.end_array().unwrap();
+        encoder.write_key("d").unwrap();
+        encoder.begin_object(0).unwrap();
+        encoder.end_object().unwrap();
+        encoder.end_object().unwrap();
+        let data = encoder.into_inner().unwrap();
+        assert_eq!(data, value.encode());
+    }
+
+    #[test]
+    fn unordered_object() {
+        let fields: Vec<_> = (0..10)
+            .map(|i| {
+                (
+                    format!("field_{}", i),
+                    EncodedData::from(serde_json::json!(i * 10)),
+                )
+            })
+            .collect();
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_unordered_object().unwrap();
+        for (k, o) in fields.iter().rev() {
+            encoder.write_key(k).unwrap();
+            encoder.write_value(o).unwrap();
+        }
+        encoder.end_object().unwrap();
+        let data = encoder.into_inner().unwrap();
+
+        let expected = EncodedData::Object(fields.into_iter().collect());
+        assert_eq!(data, expected.encode());
+        assert_eq!(EncodedData::decode(&data), Ok((expected, data.len())));
+    }
+
+    #[test]
+    fn nested_unordered_object() {
+        let value =
+            EncodedData::from(serde_json::json!([{"a": [1, {"b": 2, "c": 3}], "d": {}}, 4]));
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_array(2).unwrap();
+        encoder.begin_unordered_object().unwrap();
+        encoder.write_key("d").unwrap();
+        encoder.begin_object(0).unwrap();
+        encoder.end_object().unwrap();
+        encoder.write_key("a").unwrap();
+        encoder.begin_array(2).unwrap();
+        encoder
+            .write_element(&EncodedData::from(serde_json::json!(1)))
+            .unwrap();
+        encoder.begin_unordered_object().unwrap();
+        encoder.write_key("c").unwrap();
+        encoder
+            .write_value(&EncodedData::from(serde_json::json!(3)))
+            .unwrap();
+        encoder.write_key("b").unwrap();
+        encoder
+            .write_value(&EncodedData::from(serde_json::json!(2)))
+            .unwrap();
+        encoder.end_object().unwrap();
+        encoder.end_array().unwrap();
+        encoder.end_object().unwrap();
+        encoder
+            .write_element(&EncodedData::from(serde_json::json!(4)))
+            .unwrap();
+        encoder.end_array().unwrap();
+        let data = encoder.into_inner().unwrap();
+        assert_eq!(data, value.encode());
+
+        let null = EncodedData::from(serde_json::Value::Null);
+        let kind = |e: io::Error| e.kind();
+        let invalid = Err(io::ErrorKind::InvalidInput);
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_unordered_object().unwrap();
+        assert_eq!(encoder.write_value(&null).map_err(kind), invalid);
+        assert_eq!(encoder.write_element(&null).map_err(kind), invalid);
+        encoder.write_key("a").unwrap();
+        assert_eq!(encoder.end_object().map_err(kind), invalid);
+        encoder.write_value(&null).unwrap();
+        assert!(encoder.into_inner().is_err());
+    }
+
+    #[test]
+    fn compressed_unordered_object() {
+        let long = EncodedData::String("a string long enough to be aliased".to_string());
+        let mut encoder =
+            StreamEncoder::with_compressor(vec![], StreamCompressor::new(Conf::default()));
+        encoder.begin_unordered_object().unwrap();
+        for k in ["c", "b", "a"] {
+            encoder.write_key(k).unwrap();
+            encoder.begin_unordered_object().unwrap();
+            encoder.write_key("s").unwrap();
+            encoder.write_value(&long).unwrap();
+            encoder.end_object().unwrap();
+        }
+        encoder.end_object().unwrap();
+        let data = encoder.into_inner().unwrap();
+
+        let expected = EncodedData::from(serde_json::json!({
+            "a": {"s": "a string long enough to be aliased"},
+            "b": {"s": "a string long enough to be aliased"},
+            "c": {"s": "a string long enough to be aliased"},
+        }));
+        assert_eq!(
+            StreamDecoder::new().decompress(&data),
+            Ok((expected.clone(), data.len()))
+        );
+        // Defined once, in the first entry written to the output.
+        assert!(data.len() < expected.encode().len());
+    }
+
+    #[test]
+    fn chunks() {
+        let records: Vec<_> = (0..12)
+            .map(|i| {
+                EncodedData::from(serde_json::json!({
+                    "sensor": format!("sensor-{}", i % 2),
+                    "location": "building-a",
+                    "value": i,
+                }))
+            })
+            .collect();
+        let mut encoder =
+            StreamEncoder::with_compressor(vec![], StreamCompressor::new(Conf::default()));
+        // Chunk boundaries, in bytes.
+        let mut boundaries = vec![];
+        for (i, o) in records.iter().enumerate() {
+            if i > 0 && i % 4 == 0 {
+                encoder.flush_chunk().unwrap();
+                boundaries.push(encoder.w.len());
+            }
+            encoder.write_element(o).unwrap();
+        }
+        let data = encoder.into_inner().unwrap();
+        let plain: usize = records.iter().map(|o| o.encode().len()).sum();
+        assert!(data.len() < plain);
+
+        let decode = |mut data: &[u8]| {
+            let mut decoder = StreamDecoder::new();
+            let mut ret = vec![];
+            while !data.is_empty() {
+                let (o, size) = decoder.decompress(data).unwrap();
+                ret.push(o);
+                data = &data[size..];
+            }
+            ret
+        };
+        assert_eq!(decode(&data), records);
+        // A decoder joining at a boundary, after the reset marker, or at the marker of the
+        // previous one.
+        for (i, boundary) in boundaries.iter().enumerate() {
+            assert_eq!(decode(&data[*boundary..]), records[4 * (i + 1)..]);
+            assert_eq!(decode(&data[*boundary - 1..]), records[4 * (i + 1)..]);
+        }
+        // Without the chunks, the later records need the aliases of the first ones.
+        let mut joined =
+            StreamEncoder::with_compressor(vec![], StreamCompressor::new(Conf::default()));
+        let mut offset = 0;
+        for (i, o) in records.iter().enumerate() {
+            if i == 4 {
+                offset = joined.w.len();
+            }
+            joined.write_element(o).unwrap();
+        }
+        let joined = joined.into_inner().unwrap();
+        assert!(joined.len() < data.len());
+        assert!(StreamDecoder::new().decompress(&joined[offset..]).is_err());
+
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_array(1).unwrap();
+        assert_eq!(
+            encoder.flush_chunk().map_err(|e| e.kind()),
+            Err(io::ErrorKind::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn object_mismatch() {
+        let null = EncodedData::from(serde_json::Value::Null);
+        let kind = |e: io::Error| e.kind();
+        let invalid = Err(io::ErrorKind::InvalidInput);
+
+        let mut encoder = StreamEncoder::new(vec![]);
+        assert_eq!(encoder.write_key("a").map_err(kind), invalid);
+        assert_eq!(encoder.write_value(&null).map_err(kind), invalid);
+        encoder.begin_object(1).unwrap();
+        assert_eq!(encoder.write_value(&null).map_err(kind), invalid);
+        assert_eq!(encoder.write_element(&null).map_err(kind), invalid);
+        encoder.write_key("a").unwrap();
+        assert_eq!(encoder.write_key("b").map_err(kind), invalid);
+        assert_eq!(encoder.end_object().map_err(kind), invalid);
+        encoder.write_value(&null).unwrap();
+        assert_eq!(encoder.write_key("b").map_err(kind), invalid);
+        assert_eq!(encoder.end_array().map_err(kind), invalid);
+        encoder.end_object().unwrap();
+
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_object(2).unwrap();
+        encoder.write_key("a").unwrap();
+        encoder.write_value(&null).unwrap();
+        assert_eq!(encoder.end_object().map_err(kind), invalid);
+        assert!(encoder.into_inner().is_err());
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let null = EncodedData::from(serde_json::Value::Null);
+        let kind = |e: io::Error| e.kind();
+
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_array(1).unwrap();
+        encoder.write_element(&null).unwrap();
+        assert_eq!(
+            encoder.write_element(&null).map_err(kind),
+            Err(io::ErrorKind::InvalidInput)
+        );
+        assert_eq!(
+            encoder.begin_array(0).map_err(kind),
+            Err(io::ErrorKind::InvalidInput)
+        );
+        encoder.end_array().unwrap();
+        assert_eq!(
+            encoder.end_array().map_err(kind),
+            Err(io::ErrorKind::InvalidInput)
+        );
+
+        let mut encoder = StreamEncoder::new(vec![]);
+        encoder.begin_array(2).unwrap();
+        encoder.write_element(&null).unwrap();
+        assert_eq!(
+            encoder.end_array().map_err(kind),
+            Err(io::ErrorKind::InvalidInput)
+        );
+        assert_eq!(
+            encoder.into_inner().map_err(kind),
+            Err(io::ErrorKind::InvalidInput)
+        );
+    }
+}
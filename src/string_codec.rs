@@ -0,0 +1,368 @@
+use crate::define::{data_type, special_type};
+use crate::encoded_data::{self, DecodeError, EncodedData, Extension};
+use crate::varint;
+#[cfg(feature = "string_codecs")]
+use std::convert::{TryFrom, TryInto};
+
+// Compact form of strings of a known format, e.g. the 16 bytes of a UUID instead of its 36
+// characters.
+pub trait StringCodec {
+    // Payload standing for `s`, or None when `s` is not in the format of the codec.
+    fn encode(&self, s: &str) -> Option<Vec<u8>>;
+    // String of the payload at the start of `data`, and the size of the payload.
+    fn decode(&self, data: &[u8]) -> Option<(String, usize)>;
+}
+
+// Codecs shared by both ends, by codec id: their registration order. Strings are stored by the
+// first codec accepting them, as long as it is smaller than the plain string and gives the exact
+// same string back, so that the conversion is lossless whatever the codec.
+//
+// Codec ids only mean something to the codecs that wrote them, so the plain decoders
+// (EncodedData::decode, codec::from_bytes, codec::decode_to_json...) reject CodecString specials
+// with UnknownSpecialType. JSON is obtained by converting the value StringCodecs::decode returns.
+#[derive(Default)]
+pub struct StringCodecs {
+    codecs: Vec<Box<dyn StringCodec>>,
+}
+
+impl StringCodecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // UuidCodec, Ipv4Codec then HexCodec.
+    #[cfg(feature = "string_codecs")]
+    pub fn with_defaults() -> Self {
+        let mut codecs = Self::new();
+        codecs.register(Box::new(UuidCodec));
+        codecs.register(Box::new(Ipv4Codec));
+        codecs.register(Box::new(HexCodec));
+        codecs
+    }
+
+    // Returns the codec id of `codec`.
+    pub fn register(&mut self, codec: Box<dyn StringCodec>) -> u64 {
+        self.codecs.push(codec);
+        self.codecs.len() as u64 - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.codecs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codecs.is_empty()
+    }
+
+    fn encode_str(&self, s: &str) -> Option<Vec<u8>> {
+        let plain_len = EncodedData::String(s.to_string()).encode().len();
+        self.codecs.iter().enumerate().find_map(|(id, codec)| {
+            let payload = codec.encode(s)?;
+            match codec.decode(&payload) {
+                Some((decoded, size)) if decoded == s && size == payload.len() => Some(
+                    [
+                        vec![data_type::SPECIAL << 5 | special_type::CODEC_STRING],
+                        varint::encode(id as u64),
+                        payload,
+                    ]
+                    .concat(),
+                ),
+                _ => None,
+            }
+            .filter(|data| data.len() < plain_len)
+        })
+    }
+
+    // Same as EncodedData::encode, with the strings a codec accepts stored as a CodecString
+    // special. The result can only be decoded by StringCodecs::decode with the same codecs.
+    pub fn encode(&self, o: &EncodedData) -> Vec<u8> {
+        let mut out = vec![];
+        encoded_data::encode_extended(self, o, &mut out);
+        out
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<(EncodedData, usize), DecodeError> {
+        encoded_data::decode_extended(self, data, encoded_data::MAX_DEPTH)
+    }
+}
+
+impl Extension for StringCodecs {
+    fn encode_special(&self, o: &EncodedData, out: &mut Vec<u8>) -> bool {
+        match o {
+            EncodedData::String(s) => match self.encode_str(s) {
+                Some(data) => out.extend(data),
+                None => out.extend(o.encode()),
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn decode_special(
+        &self,
+        data: &[u8],
+        _depth: usize,
+    ) -> Option<Result<(EncodedData, usize), DecodeError>> {
+        if data[0] != data_type::SPECIAL << 5 | special_type::CODEC_STRING {
+            return None;
+        }
+        Some((|| {
+            let (id, size) = encoded_data::decode_varint(&data[1..])?;
+            let codec = self
+                .codecs
+                .get(id as usize)
+                .ok_or(DecodeError::UnknownStringCodec(id))?;
+            let payload = &data[1 + size..];
+            // Custom codecs may claim more bytes than they were given.
+            let (s, payload_size) = codec
+                .decode(payload)
+                .filter(|(_, payload_size)| *payload_size <= payload.len())
+                .ok_or(DecodeError::BadCodecString(id))?;
+            Ok((EncodedData::String(s), 1 + size + payload_size))
+        })())
+    }
+}
+
+#[cfg(feature = "string_codecs")]
+fn hex_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "string_codecs")]
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.chunks(2)
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+#[cfg(feature = "string_codecs")]
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Lowercase hyphenated UUIDs (8-4-4-4-12 hex digits), as their 16 bytes.
+#[cfg(feature = "string_codecs")]
+pub struct UuidCodec;
+
+#[cfg(feature = "string_codecs")]
+impl StringCodec for UuidCodec {
+    fn encode(&self, s: &str) -> Option<Vec<u8>> {
+        let groups: Vec<_> = s.split('-').collect();
+        if groups
+            .iter()
+            .map(|g| g.len())
+            .ne([8, 4, 4, 4, 12].iter().copied())
+        {
+            return None;
+        }
+        decode_hex(groups.concat().as_bytes())
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<(String, usize)> {
+        let data = data.get(..16)?;
+        let s = [
+            &data[..4],
+            &data[4..6],
+            &data[6..8],
+            &data[8..10],
+            &data[10..],
+        ]
+        .iter()
+        .map(|g| encode_hex(g))
+        .collect::<Vec<_>>()
+        .join("-");
+        Some((s, 16))
+    }
+}
+
+// Dotted decimal IPv4 addresses, as their 4 bytes.
+#[cfg(feature = "string_codecs")]
+pub struct Ipv4Codec;
+
+#[cfg(feature = "string_codecs")]
+impl StringCodec for Ipv4Codec {
+    fn encode(&self, s: &str) -> Option<Vec<u8>> {
+        let ip: std::net::Ipv4Addr = s.parse().ok()?;
+        Some(ip.octets().to_vec())
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<(String, usize)> {
+        let octets: [u8; 4] = data.get(..4)?.try_into().ok()?;
+        Some((std::net::Ipv4Addr::from(octets).to_string(), 4))
+    }
+}
+
+// Lowercase hex strings of an even number of digits, e.g. hashes, as their varint byte length
+// then their bytes.
+#[cfg(feature = "string_codecs")]
+pub struct HexCodec;
+
+#[cfg(feature = "string_codecs")]
+impl StringCodec for HexCodec {
+    fn encode(&self, s: &str) -> Option<Vec<u8>> {
+        let data = decode_hex(s.as_bytes()).filter(|data| !data.is_empty())?;
+        Some([varint::encode(data.len() as u64), data].concat())
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<(String, usize)> {
+        let (length, size) = varint::decode(data).ok()?;
+        let end = (size as usize).checked_add(usize::try_from(length).ok()?)?;
+        Some((encode_hex(data.get(size as usize..end)?), end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    // Strings of a single repeated character, as the character and the count.
+    struct RunCodec;
+
+    impl StringCodec for RunCodec {
+        fn encode(&self, s: &str) -> Option<Vec<u8>> {
+            let first = *s.as_bytes().first()?;
+            if s.len() > 255 || !s.bytes().all(|b| b == first) {
+                return None;
+            }
+            Some(vec![first, s.len() as u8])
+        }
+
+        fn decode(&self, data: &[u8]) -> Option<(String, usize)> {
+            let (c, n) = (*data.first()?, *data.get(1)?);
+            Some((String::from_utf8(vec![c; n as usize]).ok()?, 2))
+        }
+    }
+
+    // Accepts everything, but loses the case.
+    struct LowercaseCodec;
+
+    impl StringCodec for LowercaseCodec {
+        fn encode(&self, s: &str) -> Option<Vec<u8>> {
+            Some(encoded_data::encode_key(&s.to_lowercase()))
+        }
+
+        fn decode(&self, data: &[u8]) -> Option<(String, usize)> {
+            let (length, size) = varint::decode(data).ok()?;
+            let end = size as usize + length as usize;
+            Some((
+                String::from_utf8(data.get(size as usize..end)?.to_vec()).ok()?,
+                end,
+            ))
+        }
+    }
+
+    #[test]
+    fn string_codecs() {
+        let mut codecs = StringCodecs::new();
+        assert!(codecs.is_empty());
+        codecs.register(Box::new(RunCodec));
+        assert_eq!(codecs.register(Box::new(LowercaseCodec)), 1);
+        assert_eq!(codecs.len(), 2);
+
+        let o = EncodedData::from(json!({"a": ["zzzzzzzzzz", "Mixed", "zz"], "b": 1}));
+        let data = codecs.encode(&o);
+        assert_eq!(codecs.decode(&data), Ok((o.clone(), data.len())));
+        assert!(data.len() < o.encode().len());
+        // Lossy codecs are never used, nor the ones that do not pay off ("zz").
+        assert!(data.windows(5).any(|w| w == b"Mixed"));
+        assert!(data.windows(2).any(|w| w == b"zz"));
+
+        let unknown = DecodeError::UnknownSpecialType(special_type::CODEC_STRING);
+        assert_eq!(EncodedData::decode(&data), Err(unknown.clone()));
+        assert_eq!(
+            crate::codec::from_bytes(&data),
+            Err(crate::codec::CodecError::BadFormat(unknown.clone()))
+        );
+        assert_eq!(
+            crate::codec::decode_to_json(&data),
+            Err(crate::codec::CodecError::BadFormat(unknown))
+        );
+        assert_eq!(
+            serde_json::Value::try_from(codecs.decode(&data).unwrap().0),
+            Ok(json!({"a": ["zzzzzzzzzz", "Mixed", "zz"], "b": 1}))
+        );
+        assert_eq!(
+            StringCodecs::new().decode(&data),
+            Err(DecodeError::UnknownStringCodec(0))
+        );
+        let truncated = codecs.encode(&EncodedData::String("zzzzz".to_string()));
+        assert_eq!(truncated.len(), 4);
+        assert_eq!(
+            codecs.decode(&truncated[..truncated.len() - 1]),
+            Err(DecodeError::BadCodecString(0))
+        );
+    }
+
+    // Claims more bytes than it was given.
+    struct LyingCodec;
+
+    impl StringCodec for LyingCodec {
+        fn encode(&self, _: &str) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn decode(&self, data: &[u8]) -> Option<(String, usize)> {
+            Some((String::new(), data.len() + 1))
+        }
+    }
+
+    #[test]
+    fn bad_input() {
+        let mut codecs = StringCodecs::new();
+        codecs.register(Box::new(LyingCodec));
+        let data = [
+            data_type::SPECIAL << 5 | special_type::CODEC_STRING,
+            0,
+            1,
+            2,
+        ];
+        assert_eq!(codecs.decode(&data), Err(DecodeError::BadCodecString(0)));
+
+        let mut data = vec![data_type::ARRAY << 5 | 1; encoded_data::MAX_DEPTH];
+        data.push(data_type::SPECIAL << 5 | special_type::NULL);
+        assert_eq!(codecs.decode(&data), Err(DecodeError::TooDeep));
+        assert!(codecs.decode(&data[1..]).is_ok());
+    }
+
+    #[cfg(feature = "string_codecs")]
+    #[test]
+    fn default_codecs() {
+        let codecs = StringCodecs::with_defaults();
+        let uuid = EncodedData::String("123e4567-e89b-12d3-a456-426614174000".to_string());
+        let data = codecs.encode(&uuid);
+        assert_eq!(uuid.encode().len(), 38);
+        assert_eq!(data.len(), 18);
+        assert_eq!(codecs.decode(&data), Ok((uuid, data.len())));
+
+        let o = EncodedData::from(json!({
+            "ip": "192.168.0.1",
+            "hash": "9e107d9d372bb6826bd81d3542a419d6",
+            "text": "hello",
+            // Not given back as is, so kept as strings.
+            "upper": "123E4567-E89B-12D3-A456-426614174000",
+            "padded": "192.168.000.001",
+            "odd": "abc",
+        }));
+        let data = codecs.encode(&o);
+        assert_eq!(codecs.decode(&data), Ok((o.clone(), data.len())));
+        for s in ["hello", "123E4567", "192.168.000.001", "abc"].iter() {
+            assert!(data.windows(s.len()).any(|w| w == s.as_bytes()), "{}", s);
+        }
+        for s in ["192.168.0.1", "9e107d9d"].iter() {
+            assert!(!data.windows(s.len()).any(|w| w == s.as_bytes()), "{}", s);
+        }
+        assert_eq!(
+            serde_json::Value::try_from(codecs.decode(&data).unwrap().0),
+            serde_json::Value::try_from(o)
+        );
+    }
+}
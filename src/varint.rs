@@ -30,24 +30,34 @@ const BASE_VALUE: [u64; 10] = [
         + LIMITS[8],
 ];
 
-fn std_encode(mut n: u64, nb_bytes: usize) -> Vec<u8> {
-    let mut ret = vec![];
+fn std_write(mut n: u64, nb_bytes: usize, buf: &mut Vec<u8>) {
     for _ in 0..nb_bytes {
-        ret.push(((n & 0x7F) as u8) | 0x80);
+        buf.push(((n & 0x7F) as u8) | 0x80);
         n >>= 7;
     }
-    ret[nb_bytes - 1] &= 0x7F;
+    *buf.last_mut().unwrap() &= 0x7F;
+}
+
+pub fn encode(n: u64) -> Vec<u8> {
+    let mut ret = vec![];
+    write(n, &mut ret);
     ret
 }
 
-pub fn encode(mut n: u64) -> Vec<u8> {
+// Same as encode, appending to `buf` instead of allocating.
+pub fn write(mut n: u64, buf: &mut Vec<u8>) {
     for (nb_bytes, limit) in LIMITS.iter().enumerate() {
         if n < *limit {
-            return std_encode(n, nb_bytes + 1);
+            return std_write(n, nb_bytes + 1, buf);
         }
         n -= limit;
     }
-    std_encode(n, LIMITS.len() + 1)
+    std_write(n, LIMITS.len() + 1, buf)
+}
+
+// Zigzag mapping, so that small negative numbers encode as short as small positive ones.
+pub fn encode_signed(n: i64) -> Vec<u8> {
+    encode(((n << 1) ^ (n >> 63)) as u64)
 }
 
 #[derive(Debug)]
@@ -59,7 +69,12 @@ pub enum DecodeError {
 fn std_decode(data: &[u8]) -> Result<(u64, u8), DecodeError> {
     let mut ret = 0u64;
     for (i, part) in data.iter().enumerate().take(LIMITS.len() + 1) {
-        ret |= ((part & 0x7F) as u64) << (7 * i);
+        let bits = (part & 0x7F) as u64;
+        // Only the lowest bit of the 10th byte fits in a u64.
+        if i == LIMITS.len() && bits > 1 {
+            return Err(DecodeError::ValueTooBig);
+        }
+        ret |= bits << (7 * i);
 
         if part & 0x80 == 0x00 {
             return Ok((ret, i as u8 + 1));
@@ -74,7 +89,39 @@ fn std_decode(data: &[u8]) -> Result<(u64, u8), DecodeError> {
 
 pub fn decode(data: &[u8]) -> Result<(u64, u8), DecodeError> {
     let (extra, nb_bytes) = std_decode(data)?;
-    Ok((extra + BASE_VALUE[nb_bytes as usize - 1], nb_bytes))
+    match extra.checked_add(BASE_VALUE[nb_bytes as usize - 1]) {
+        Some(n) => Ok((n, nb_bytes)),
+        None => Err(DecodeError::ValueTooBig),
+    }
+}
+
+pub fn decode_signed(data: &[u8]) -> Result<(i64, u8), DecodeError> {
+    let (n, nb_bytes) = decode(data)?;
+    Ok(((n >> 1) as i64 ^ -((n & 1) as i64), nb_bytes))
+}
+
+// Reads a single varint byte by byte, so that it can span several reads of `r`.
+pub fn read_from<R: std::io::Read>(r: &mut R) -> std::io::Result<u64> {
+    let mut ret = 0u64;
+    for (i, base) in BASE_VALUE.iter().enumerate() {
+        let mut part = [0u8; 1];
+        r.read_exact(&mut part)?;
+        let part = part[0];
+        let bits = (part & 0x7F) as u64;
+        if i == LIMITS.len() && bits > 1 {
+            return Err(too_big());
+        }
+        ret |= bits << (7 * i);
+
+        if part & 0x80 == 0x00 {
+            return ret.checked_add(*base).ok_or_else(too_big);
+        }
+    }
+    Err(too_big())
+}
+
+fn too_big() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "varint value too big")
 }
 
 #[cfg(test)]
@@ -109,4 +156,83 @@ mod tests {
         check(0x00_7F_FF_FF_FF_FF_FF_FF_FF, 9);
         check(0x00_FF_FF_FF_FF_FF_FF_FF_FF, 10);
     }
+
+    #[test]
+    fn signed() {
+        for (n, size) in [
+            (0, 1),
+            (-1, 1),
+            (1, 1),
+            (-64, 1),
+            (64, 2),
+            (i64::MAX, 10),
+            (i64::MIN, 10),
+        ] {
+            let data = encode_signed(n);
+            assert_eq!(data.len(), size);
+            assert_eq!(decode_signed(&data).unwrap(), (n, size as u8));
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let max = encode(u64::MAX);
+        assert_eq!(max.len(), 10);
+        assert_eq!(decode(&max).unwrap(), (u64::MAX, 10));
+
+        // One past u64::MAX, which wraps in the BASE_VALUE addition.
+        let mut beyond = vec![];
+        std_write(u64::MAX - BASE_VALUE[9] + 1, 10, &mut beyond);
+        assert!(matches!(decode(&beyond), Err(DecodeError::ValueTooBig)));
+        assert!(super::read_from(&mut &beyond[..]).is_err());
+        // Bits that do not fit in a u64 at all.
+        let mut too_wide = max.clone();
+        too_wide[9] = 0x02;
+        assert!(matches!(decode(&too_wide), Err(DecodeError::ValueTooBig)));
+    }
+
+    #[test]
+    fn read_from() {
+        struct OneByteReader<'a>(&'a [u8]);
+
+        impl std::io::Read for OneByteReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[0];
+                self.0 = &self.0[1..];
+                Ok(1)
+            }
+        }
+
+        for n in [
+            0,
+            0x7F,
+            0x80,
+            0x3F_FF + BASE_VALUE[1] + 1,
+            0x00_FF_FF_FF_FF_FF_FF_FF + BASE_VALUE[7] + 1,
+            u64::MAX,
+        ] {
+            let data = encode(n);
+            let mut reader = OneByteReader(&data);
+            assert_eq!(super::read_from(&mut reader).unwrap(), n);
+            assert!(reader.0.is_empty());
+        }
+
+        let truncated = &encode(0x80)[..1];
+        assert_eq!(
+            super::read_from(&mut OneByteReader(truncated))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        let too_long = [0xFFu8; 11];
+        assert_eq!(
+            super::read_from(&mut OneByteReader(&too_long))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
 }